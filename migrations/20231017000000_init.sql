@@ -0,0 +1,40 @@
+CREATE TABLE IF NOT EXISTS users (
+    domain TEXT NOT NULL,
+    username TEXT NOT NULL,
+    -- reserved, blocked, user or pending
+    kind TEXT NOT NULL,
+    user TEXT NOT NULL,
+    PRIMARY KEY (domain, username)
+);
+
+CREATE TABLE IF NOT EXISTS pending_invoices (
+    hash TEXT PRIMARY KEY,
+    invoice TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS paid_fees (
+    hash TEXT PRIMARY KEY,
+    fee_msat INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS received_fees (
+    hash TEXT PRIMARY KEY,
+    fee_msat INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS payer_invoices (
+    hash TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    bolt11 TEXT NOT NULL,
+    settled BOOLEAN NOT NULL,
+    preimage TEXT
+);
+
+CREATE TABLE IF NOT EXISTS claims (
+    id TEXT PRIMARY KEY,
+    token TEXT,
+    expire INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS claims_expire ON claims (expire);
@@ -0,0 +1,5 @@
+-- Single values kept by the service, such as the last pay index
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
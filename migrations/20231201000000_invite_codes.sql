@@ -0,0 +1,8 @@
+-- Codes letting signups through while signups are disabled
+CREATE TABLE IF NOT EXISTS invite_codes (
+    code TEXT PRIMARY KEY,
+    max_uses INTEGER NOT NULL,
+    uses INTEGER NOT NULL,
+    expire INTEGER,
+    created_at INTEGER NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Outcome of posting a paid invoice to its user's webhook
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    hash TEXT PRIMARY KEY,
+    domain TEXT NOT NULL,
+    username TEXT NOT NULL,
+    url TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    delivered BOOLEAN NOT NULL,
+    status INTEGER,
+    error TEXT,
+    last_attempt INTEGER NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Invoices paid to users, kept for accounting after their pending invoice is removed
+CREATE TABLE IF NOT EXISTS payments (
+    hash TEXT PRIMARY KEY,
+    domain TEXT NOT NULL,
+    username TEXT NOT NULL,
+    mint TEXT NOT NULL,
+    amount_msat INTEGER NOT NULL,
+    fee_msat INTEGER NOT NULL,
+    minted_msat INTEGER,
+    proxied BOOLEAN NOT NULL,
+    paid_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS payments_paid_at ON payments (paid_at);
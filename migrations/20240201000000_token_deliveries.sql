@@ -0,0 +1,11 @@
+-- Minted tokens whose DM failed, waiting to be sent again
+CREATE TABLE IF NOT EXISTS token_deliveries (
+    hash TEXT PRIMARY KEY,
+    delivery TEXT NOT NULL
+);
+
+-- Tokens that could not be DMed after every retry, left for an operator
+CREATE TABLE IF NOT EXISTS dead_letters (
+    hash TEXT PRIMARY KEY,
+    delivery TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Comment of the payer and whether the token was DMed, exported with the payment history
+ALTER TABLE payments ADD COLUMN comment TEXT;
+ALTER TABLE payments ADD COLUMN delivered BOOLEAN NOT NULL DEFAULT 0;
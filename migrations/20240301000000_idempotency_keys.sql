@@ -0,0 +1,3 @@
+-- Repeated invoice requests are looked up by their idempotency key
+CREATE INDEX IF NOT EXISTS pending_invoices_idempotency_key
+    ON pending_invoices (json_extract(invoice, '$.idempotency_key'));
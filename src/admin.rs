@@ -0,0 +1,122 @@
+//! Bearer token authentication of the admin api
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+
+use crate::error::LnurlError;
+
+/// Request is authorized by the configured admin bearer token
+pub fn is_admin(admin_token: &Option<String>, headers: &HeaderMap) -> bool {
+    let (admin_token, authorization) = match (admin_token, headers.get(header::AUTHORIZATION)) {
+        (Some(admin_token), Some(authorization)) => (admin_token, authorization),
+        _ => return false,
+    };
+
+    match authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+    {
+        // Compare digests so the comparison time does not depend on the token
+        Some(token) => Sha256::digest(token.as_bytes()) == Sha256::digest(admin_token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Middleware guarding the `/admin` routes with the admin token
+///
+/// Responds `401` unless the request has the token as a bearer header. Without an
+/// `admin_token` configured the routes respond `404` as if they did not exist.
+pub async fn require_admin<B>(
+    State(admin_token): State<Option<String>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if admin_token.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    if !is_admin(&admin_token, request.headers()) {
+        return LnurlError::new(StatusCode::UNAUTHORIZED, "Admin token required").into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::middleware;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn test_is_admin() {
+        let admin_token = Some("secret".to_string());
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_admin(&admin_token, &headers));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(is_admin(&admin_token, &headers));
+        assert!(!is_admin(&None, &headers));
+
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!is_admin(&admin_token, &headers));
+    }
+
+    #[tokio::test]
+    async fn test_require_admin() {
+        let router = |admin_token: Option<&str>| {
+            Router::new()
+                .route("/admin/users", get(|| async { "users" }))
+                .route_layer(middleware::from_fn_with_state(
+                    admin_token.map(str::to_string),
+                    require_admin,
+                ))
+        };
+        let request = |authorization: Option<&str>| {
+            let request = Request::builder().uri("/admin/users");
+            let request = match authorization {
+                Some(authorization) => request.header(header::AUTHORIZATION, authorization),
+                None => request,
+            };
+            request.body(Body::empty()).unwrap()
+        };
+
+        let missing = router(Some("secret")).oneshot(request(None)).await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+
+        let wrong = router(Some("secret"))
+            .oneshot(request(Some("Bearer wrong")))
+            .await
+            .unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+
+        // The token must be sent as a bearer token
+        let basic = router(Some("secret"))
+            .oneshot(request(Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(basic.status(), StatusCode::UNAUTHORIZED);
+
+        let correct = router(Some("secret"))
+            .oneshot(request(Some("Bearer secret")))
+            .await
+            .unwrap();
+        assert_eq!(correct.status(), StatusCode::OK);
+
+        // Hidden when no token is configured, even to requests with one
+        let unconfigured = router(None)
+            .oneshot(request(Some("Bearer secret")))
+            .await
+            .unwrap();
+        assert_eq!(unconfigured.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,691 @@
+//! Lightning backends used to create and pay invoices
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use cashu_sdk::{Amount, Bolt11Invoice};
+use cln_rpc::model::requests::{
+    DelinvoiceRequest, DelinvoiceStatus, GetinfoRequest, InvoiceRequest, ListinvoicesRequest,
+    ListpaysRequest, PayRequest, WaitanyinvoiceRequest,
+};
+use cln_rpc::model::responses::{
+    ListinvoicesInvoicesStatus, ListpaysPaysStatus, WaitanyinvoiceResponse,
+};
+use cln_rpc::primitives::{Amount as CLN_Amount, AmountOrAny, Secret, Sha256 as Sha256Hash};
+use cln_rpc::{ClnRpc, RpcError};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tonic_lnd::{lnrpc, routerrpc};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Invoice paid to the backend
+#[derive(Debug, Clone)]
+pub struct PaidInvoice {
+    pub payment_hash: String,
+    /// Hex preimage
+    pub preimage: Option<String>,
+    /// Amount paid, the only amount known for amountless invoices
+    pub amount_received: Option<Amount>,
+    /// Index to resume waiting from after this invoice
+    pub pay_index: Option<u64>,
+}
+
+/// Invoice paid by the backend
+#[derive(Debug, Clone)]
+pub struct Payment {
+    pub payment_hash: String,
+    /// Routing fee paid
+    pub fee: Amount,
+}
+
+/// State of an invoice paid by the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Succeeded,
+    Pending,
+    Failed,
+    /// The backend has no payment for the hash
+    Unknown,
+}
+
+/// Stream of invoices paid to the backend
+pub type InvoiceStream = BoxStream<'static, PaidInvoice>;
+
+#[async_trait]
+pub trait PaymentBackend: Send + Sync {
+    /// Create an invoice
+    ///
+    /// Only the hash of the description is committed to when `description_hash_only` is set.
+    /// The backend picks the preimage unless one is given, and the expiry in seconds.
+    /// Without an `amount` the invoice is amountless, see [`Self::supports_amountless`].
+    async fn create_invoice(
+        &self,
+        amount: Option<Amount>,
+        description: String,
+        description_hash_only: bool,
+        preimage: Option<[u8; 32]>,
+        expiry: Option<u64>,
+    ) -> Result<Bolt11Invoice>;
+
+    /// Pay an invoice spending at most `max_fee` on routing
+    async fn pay(&self, bolt11: &Bolt11Invoice, max_fee: Amount) -> Result<Payment>;
+
+    /// Stream invoices paid after `last_pay_index`
+    async fn wait_any_invoice(&self, last_pay_index: Option<u64>) -> Result<InvoiceStream>;
+
+    /// Status of a payment made by the backend
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus>;
+
+    /// Invoice created by the backend if it has been paid, `None` while it is unpaid
+    ///
+    /// Backends that cannot look up invoices leave paid invoices to [`Self::wait_any_invoice`]
+    async fn invoice_status(&self, _payment_hash: &str) -> Result<Option<PaidInvoice>> {
+        Ok(None)
+    }
+
+    /// Check the backend responds
+    async fn ping(&self) -> Result<()>;
+
+    /// Stop accepting payment of an unpaid invoice created by the backend
+    ///
+    /// Backends that cannot cancel invoices leave them to expire
+    async fn cancel_invoice(&self, _payment_hash: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Backend can create amountless invoices and reports the amount they are paid
+    fn supports_amountless(&self) -> bool {
+        false
+    }
+}
+
+/// Delay before the first reconnect to CLN
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Longest delay between reconnects to CLN
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Core Lightning backend over its rpc socket
+pub struct ClnBackend {
+    rpc_socket: PathBuf,
+    /// `None` after the connection dropped, reconnected on the next call
+    client: Mutex<Option<ClnRpc>>,
+}
+
+impl ClnBackend {
+    pub async fn new(rpc_socket: PathBuf) -> Result<Self> {
+        let client = ClnRpc::new(&rpc_socket).await?;
+
+        Ok(Self {
+            rpc_socket,
+            client: Mutex::new(Some(client)),
+        })
+    }
+
+    /// Make a request, reconnecting first if the last connection dropped
+    async fn call(&self, request: cln_rpc::Request) -> Result<cln_rpc::Response> {
+        let mut client = self.client.lock().await;
+
+        // Put back once the call succeeds so a dropped connection is not reused
+        let mut cln_client = match client.take() {
+            Some(cln_client) => cln_client,
+            None => {
+                info!("Reconnecting to CLN");
+                ClnRpc::new(&self.rpc_socket).await?
+            }
+        };
+
+        match cln_client.call(request).await {
+            Ok(response) => {
+                *client = Some(cln_client);
+                Ok(response)
+            }
+            Err(err) if is_connection_error(&err) => {
+                warn!("CLN connection dropped: {:?}", err);
+                bail!("CLN RPC error: {:?}", err)
+            }
+            Err(err) => {
+                *client = Some(cln_client);
+                bail!("CLN RPC error: {:?}", err)
+            }
+        }
+    }
+}
+
+/// Errors without a code come from the socket rather than CLN
+fn is_connection_error(err: &RpcError) -> bool {
+    err.code.is_none()
+}
+
+/// Double the reconnect delay up to the max
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(RECONNECT_BACKOFF_MAX)
+}
+
+#[async_trait]
+impl PaymentBackend for ClnBackend {
+    async fn create_invoice(
+        &self,
+        amount: Option<Amount>,
+        description: String,
+        description_hash_only: bool,
+        preimage: Option<[u8; 32]>,
+        expiry: Option<u64>,
+    ) -> Result<Bolt11Invoice> {
+        let cln_response = self
+            .call(cln_rpc::Request::Invoice(InvoiceRequest {
+                amount_msat: amount.map_or(AmountOrAny::Any, |amount| {
+                    AmountOrAny::Amount(CLN_Amount::from_msat(amount.to_msat()))
+                }),
+                description,
+                label: Uuid::new_v4().to_string(),
+                expiry,
+                fallbacks: None,
+                preimage: preimage.map(hex::encode),
+                cltv: None,
+                deschashonly: Some(description_hash_only),
+            }))
+            .await?;
+
+        match cln_response {
+            cln_rpc::Response::Invoice(invoice_response) => {
+                Ok(Bolt11Invoice::from_str(&invoice_response.bolt11)?)
+            }
+            res => bail!("Wrong CLN response: {:?}", res),
+        }
+    }
+
+    async fn pay(&self, bolt11: &Bolt11Invoice, max_fee: Amount) -> Result<Payment> {
+        let cln_response = self
+            .call(cln_rpc::Request::Pay(PayRequest {
+                bolt11: bolt11.to_string(),
+                amount_msat: None,
+                label: None,
+                riskfactor: None,
+                maxfeepercent: None,
+                retry_for: None,
+                maxdelay: None,
+                exemptfee: None,
+                localinvreqid: None,
+                exclude: None,
+                maxfee: Some(CLN_Amount::from_sat(max_fee.to_sat())),
+                description: None,
+            }))
+            .await?;
+
+        match cln_response {
+            cln_rpc::Response::Pay(pay_response) => Ok(Payment {
+                payment_hash: pay_response.payment_hash.to_string(),
+                fee: Amount::from_msat(
+                    (pay_response.amount_sent_msat - pay_response.amount_msat).msat(),
+                ),
+            }),
+            res => bail!("Wrong CLN response: {:?}", res),
+        }
+    }
+
+    async fn wait_any_invoice(&self, last_pay_index: Option<u64>) -> Result<InvoiceStream> {
+        // Waiting blocks the connection so it gets its own
+        let cln_client = ClnRpc::new(&self.rpc_socket).await?;
+
+        Ok(futures::stream::unfold(
+            (self.rpc_socket.clone(), Some(cln_client), last_pay_index),
+            |(rpc_socket, mut cln_client, last_pay_idx)| async move {
+                let mut backoff = RECONNECT_BACKOFF_MIN;
+
+                loop {
+                    let mut client = match cln_client.take() {
+                        Some(client) => client,
+                        None => match ClnRpc::new(&rpc_socket).await {
+                            Ok(client) => {
+                                info!("Reconnected to CLN, resuming after pay index {last_pay_idx:?}");
+                                client
+                            }
+                            Err(err) => {
+                                warn!("Could not reconnect to CLN, retrying in {backoff:?}: {err}");
+                                tokio::time::sleep(backoff).await;
+                                backoff = next_backoff(backoff);
+                                continue;
+                            }
+                        },
+                    };
+
+                    let invoice_res = client
+                        .call(cln_rpc::Request::WaitAnyInvoice(WaitanyinvoiceRequest {
+                            timeout: None,
+                            lastpay_index: last_pay_idx,
+                        }))
+                        .await;
+
+                    let invoice: WaitanyinvoiceResponse = match invoice_res {
+                        Ok(response) => match response.try_into() {
+                            Ok(invoice) => invoice,
+                            Err(err) => {
+                                error!("Wrong response from CLN: {:?}", err);
+                                cln_client = Some(client);
+                                tokio::time::sleep(backoff).await;
+                                backoff = next_backoff(backoff);
+                                continue;
+                            }
+                        },
+                        Err(err) => {
+                            warn!("Error fetching invoice: {:?}", err);
+                            if !is_connection_error(&err) {
+                                cln_client = Some(client);
+                            }
+                            // Let's not spam CLN with requests on failure
+                            tokio::time::sleep(backoff).await;
+                            backoff = next_backoff(backoff);
+                            // Retry same request
+                            continue;
+                        }
+                    };
+
+                    let pay_idx = invoice.pay_index;
+
+                    break Some((
+                        PaidInvoice {
+                            payment_hash: invoice.payment_hash.to_string(),
+                            preimage: invoice.payment_preimage.and_then(secret_hex),
+                            amount_received: invoice
+                                .amount_received_msat
+                                .map(|amount| Amount::from_msat(amount.msat())),
+                            pay_index: pay_idx,
+                        },
+                        (rpc_socket, Some(client), pay_idx),
+                    ));
+                }
+            },
+        )
+        .boxed())
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let cln_response = self
+            .call(cln_rpc::Request::ListPays(ListpaysRequest {
+                bolt11: None,
+                payment_hash: Some(Sha256Hash::from_str(payment_hash)?),
+                status: None,
+            }))
+            .await?;
+
+        let pays = match cln_response {
+            cln_rpc::Response::ListPays(listpays_response) => listpays_response.pays,
+            res => bail!("Wrong CLN response: {:?}", res),
+        };
+
+        // A payment can be attempted more than once
+        let status = if pays
+            .iter()
+            .any(|pay| matches!(pay.status, ListpaysPaysStatus::COMPLETE))
+        {
+            PaymentStatus::Succeeded
+        } else if pays
+            .iter()
+            .any(|pay| matches!(pay.status, ListpaysPaysStatus::PENDING))
+        {
+            PaymentStatus::Pending
+        } else if pays.is_empty() {
+            PaymentStatus::Unknown
+        } else {
+            PaymentStatus::Failed
+        };
+
+        Ok(status)
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<Option<PaidInvoice>> {
+        let cln_response = self
+            .call(cln_rpc::Request::ListInvoices(ListinvoicesRequest {
+                label: None,
+                invstring: None,
+                payment_hash: Some(payment_hash.to_string()),
+                offer_id: None,
+            }))
+            .await?;
+
+        let invoices = match cln_response {
+            cln_rpc::Response::ListInvoices(listinvoices_response) => {
+                listinvoices_response.invoices
+            }
+            res => bail!("Wrong CLN response: {:?}", res),
+        };
+
+        Ok(invoices
+            .into_iter()
+            .find(|invoice| matches!(invoice.status, ListinvoicesInvoicesStatus::PAID))
+            .map(|invoice| PaidInvoice {
+                payment_hash: payment_hash.to_string(),
+                preimage: invoice.payment_preimage.and_then(secret_hex),
+                amount_received: invoice
+                    .amount_received_msat
+                    .map(|amount| Amount::from_msat(amount.msat())),
+                pay_index: invoice.pay_index,
+            }))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        match self
+            .call(cln_rpc::Request::Getinfo(GetinfoRequest {}))
+            .await?
+        {
+            cln_rpc::Response::Getinfo(_) => Ok(()),
+            res => bail!("Wrong CLN response: {:?}", res),
+        }
+    }
+
+    async fn cancel_invoice(&self, payment_hash: &str) -> Result<()> {
+        let cln_response = self
+            .call(cln_rpc::Request::ListInvoices(ListinvoicesRequest {
+                label: None,
+                invstring: None,
+                payment_hash: Some(payment_hash.to_string()),
+                offer_id: None,
+            }))
+            .await?;
+
+        let invoices = match cln_response {
+            cln_rpc::Response::ListInvoices(listinvoices_response) => {
+                listinvoices_response.invoices
+            }
+            res => bail!("Wrong CLN response: {:?}", res),
+        };
+
+        // Expired invoices can no longer be paid and are left alone
+        for invoice in invoices
+            .into_iter()
+            .filter(|invoice| matches!(invoice.status, ListinvoicesInvoicesStatus::UNPAID))
+        {
+            match self
+                .call(cln_rpc::Request::DelInvoice(DelinvoiceRequest {
+                    label: invoice.label,
+                    status: DelinvoiceStatus::UNPAID,
+                    desconly: None,
+                }))
+                .await?
+            {
+                cln_rpc::Response::DelInvoice(_) => (),
+                res => bail!("Wrong CLN response: {:?}", res),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn supports_amountless(&self) -> bool {
+        true
+    }
+}
+
+/// Hex encoding of a CLN secret
+fn secret_hex(secret: Secret) -> Option<String> {
+    serde_json::to_value(secret)
+        .ok()
+        .and_then(|secret| secret.as_str().map(str::to_string))
+}
+
+/// LND backend over its gRPC api
+pub struct LndBackend {
+    address: String,
+    cert_path: PathBuf,
+    macaroon_path: PathBuf,
+    client: Mutex<tonic_lnd::Client>,
+}
+
+impl LndBackend {
+    pub async fn new(address: String, cert_path: PathBuf, macaroon_path: PathBuf) -> Result<Self> {
+        let client =
+            tonic_lnd::connect(address.clone(), cert_path.clone(), macaroon_path.clone()).await?;
+
+        Ok(Self {
+            address,
+            cert_path,
+            macaroon_path,
+            client: Mutex::new(client),
+        })
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for LndBackend {
+    async fn create_invoice(
+        &self,
+        amount: Option<Amount>,
+        description: String,
+        description_hash_only: bool,
+        preimage: Option<[u8; 32]>,
+        expiry: Option<u64>,
+    ) -> Result<Bolt11Invoice> {
+        let (memo, description_hash) = if description_hash_only {
+            (
+                String::new(),
+                Sha256::digest(description.as_bytes()).to_vec(),
+            )
+        } else {
+            (description, vec![])
+        };
+
+        let invoice_response = self
+            .client
+            .lock()
+            .await
+            .lightning()
+            .add_invoice(lnrpc::Invoice {
+                memo,
+                description_hash,
+                r_preimage: preimage
+                    .map(|preimage| preimage.to_vec())
+                    .unwrap_or_default(),
+                // Zero is an amountless invoice
+                value_msat: amount.map_or(0, |amount| amount.to_msat() as i64),
+                expiry: expiry.unwrap_or_default() as i64,
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+
+        Ok(Bolt11Invoice::from_str(&invoice_response.payment_request)?)
+    }
+
+    async fn pay(&self, bolt11: &Bolt11Invoice, max_fee: Amount) -> Result<Payment> {
+        let mut payments = self
+            .client
+            .lock()
+            .await
+            .router()
+            .send_payment_v2(routerrpc::SendPaymentRequest {
+                payment_request: bolt11.to_string(),
+                fee_limit_msat: max_fee.to_msat() as i64,
+                timeout_seconds: 60,
+                no_inflight_updates: true,
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+
+        // Updates are streamed until the payment reaches a final state
+        while let Some(payment) = payments.message().await? {
+            match lnrpc::payment::PaymentStatus::from_i32(payment.status) {
+                Some(lnrpc::payment::PaymentStatus::Succeeded) => {
+                    return Ok(Payment {
+                        payment_hash: payment.payment_hash,
+                        fee: Amount::from_msat(payment.fee_msat as u64),
+                    })
+                }
+                Some(lnrpc::payment::PaymentStatus::Failed) => {
+                    bail!(
+                        "LND payment failed: {:?}",
+                        lnrpc::PaymentFailureReason::from_i32(payment.failure_reason)
+                    )
+                }
+                _ => continue,
+            }
+        }
+
+        bail!("LND payment stream ended before the payment completed")
+    }
+
+    async fn wait_any_invoice(&self, last_pay_index: Option<u64>) -> Result<InvoiceStream> {
+        // Subscriptions get their own connection so they do not hold the client lock
+        let client = tonic_lnd::connect(
+            self.address.clone(),
+            self.cert_path.clone(),
+            self.macaroon_path.clone(),
+        )
+        .await?;
+
+        // LND's settle index is persisted as the pay index
+        Ok(futures::stream::unfold(
+            (client, None, last_pay_index.unwrap_or(0)),
+            |(mut client, mut invoices, mut settle_index)| async move {
+                loop {
+                    let message = match invoices.as_mut() {
+                        Some(subscription) => subscription.message().await,
+                        None => {
+                            match client
+                                .lightning()
+                                .subscribe_invoices(lnrpc::InvoiceSubscription {
+                                    add_index: 0,
+                                    settle_index,
+                                })
+                                .await
+                            {
+                                Ok(response) => invoices = Some(response.into_inner()),
+                                Err(e) => {
+                                    warn!("Error subscribing to invoices: {e}");
+                                    // Let's not spam LND with requests on failure
+                                    tokio::time::sleep(Duration::from_secs(1)).await;
+                                }
+                            }
+                            continue;
+                        }
+                    };
+
+                    let invoice = match message {
+                        Ok(Some(invoice)) => invoice,
+                        Ok(None) | Err(_) => {
+                            warn!("LND invoice subscription ended, resubscribing");
+                            invoices = None;
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    };
+
+                    // Subscriptions also stream newly added invoices
+                    if invoice.state != lnrpc::invoice::InvoiceState::Settled as i32 {
+                        continue;
+                    }
+
+                    settle_index = invoice.settle_index;
+
+                    break Some((
+                        PaidInvoice {
+                            payment_hash: hex::encode(&invoice.r_hash),
+                            preimage: Some(hex::encode(&invoice.r_preimage)),
+                            amount_received: Some(Amount::from_msat(invoice.amt_paid_msat as u64)),
+                            pay_index: Some(invoice.settle_index),
+                        },
+                        (client, invoices, settle_index),
+                    ));
+                }
+            },
+        )
+        .boxed())
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let payments = self
+            .client
+            .lock()
+            .await
+            .router()
+            .track_payment_v2(routerrpc::TrackPaymentRequest {
+                payment_hash: hex::decode(payment_hash)?,
+                no_inflight_updates: true,
+            })
+            .await;
+
+        let mut payments = match payments {
+            Ok(payments) => payments.into_inner(),
+            Err(status) if status.code() == tonic::Code::NotFound => {
+                return Ok(PaymentStatus::Unknown)
+            }
+            Err(status) => return Err(status.into()),
+        };
+
+        // The first update is the current state of the payment
+        let status = match payments.message().await {
+            Ok(Some(payment)) => match lnrpc::payment::PaymentStatus::from_i32(payment.status) {
+                Some(lnrpc::payment::PaymentStatus::Succeeded) => PaymentStatus::Succeeded,
+                Some(lnrpc::payment::PaymentStatus::Failed) => PaymentStatus::Failed,
+                _ => PaymentStatus::Pending,
+            },
+            Ok(None) => PaymentStatus::Unknown,
+            Err(status) if status.code() == tonic::Code::NotFound => PaymentStatus::Unknown,
+            Err(status) => return Err(status.into()),
+        };
+
+        Ok(status)
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<Option<PaidInvoice>> {
+        let invoice = self
+            .client
+            .lock()
+            .await
+            .lightning()
+            .lookup_invoice(lnrpc::PaymentHash {
+                r_hash: hex::decode(payment_hash)?,
+                ..Default::default()
+            })
+            .await;
+
+        let invoice = match invoice {
+            Ok(invoice) => invoice.into_inner(),
+            Err(status) if status.code() == tonic::Code::NotFound => return Ok(None),
+            Err(status) => return Err(status.into()),
+        };
+        if invoice.state != lnrpc::invoice::InvoiceState::Settled as i32 {
+            return Ok(None);
+        }
+
+        Ok(Some(PaidInvoice {
+            payment_hash: payment_hash.to_string(),
+            preimage: Some(hex::encode(&invoice.r_preimage)),
+            amount_received: Some(Amount::from_msat(invoice.amt_paid_msat as u64)),
+            pay_index: Some(invoice.settle_index),
+        }))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.client
+            .lock()
+            .await
+            .lightning()
+            .get_info(lnrpc::GetInfoRequest {})
+            .await?;
+
+        Ok(())
+    }
+
+    fn supports_amountless(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_backoff() {
+        assert_eq!(next_backoff(RECONNECT_BACKOFF_MIN), Duration::from_secs(2));
+        assert_eq!(next_backoff(Duration::from_secs(40)), RECONNECT_BACKOFF_MAX);
+        assert_eq!(next_backoff(RECONNECT_BACKOFF_MAX), RECONNECT_BACKOFF_MAX);
+    }
+}
@@ -0,0 +1,130 @@
+//! Tcp addresses the api and metrics are served on
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Connections waiting to be accepted before new ones are refused
+const BACKLOG: i32 = 1024;
+
+/// Address to bind from an ip such as `0.0.0.0` or `::`, on `port`, or an ip and port such as
+/// `[::]:8080`
+pub fn parse_bind_addr(address: &str, port: u16) -> Result<SocketAddr> {
+    let address = address.trim();
+    let ip = address
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(address);
+
+    SocketAddr::from_str(address)
+        .or_else(|_| IpAddr::from_str(ip).map(|ip| SocketAddr::new(ip, port)))
+        .map_err(|_| {
+            anyhow!(
+                "Invalid address {:?}, expected an ip such as 0.0.0.0 or ::, or an ip and port such as [::]:8080",
+                address
+            )
+        })
+}
+
+/// Every address to bind, ips without a port are bound on `port`
+pub fn parse_bind_addrs(addresses: &[String], port: u16) -> Result<Vec<SocketAddr>> {
+    if addresses.is_empty() {
+        bail!("No address to bind, set address or addresses");
+    }
+
+    let mut addrs = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let addr = parse_bind_addr(address, port)?;
+        if addrs.contains(&addr) {
+            bail!("Address {} is bound more than once", addr);
+        }
+        addrs.push(addr);
+    }
+
+    Ok(addrs)
+}
+
+/// Listen on each of `addrs`
+///
+/// An IPv6 address also takes IPv4 clients on most systems, unless an IPv4 address is bound
+/// on the same port, so `0.0.0.0` and `::` can be listed together
+pub fn bind_tcp(addrs: &[SocketAddr]) -> Result<Vec<TcpListener>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let v6_only = addr.is_ipv6()
+                && addrs
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == addr.port());
+            bind(*addr, v6_only).map_err(|err| anyhow!("Could not bind {}: {}", addr, err))
+        })
+        .collect()
+}
+
+fn bind(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    // As std does, so a restart can bind while connections of the last run close
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind_addrs() {
+        let addr = |addr: &str| SocketAddr::from_str(addr).unwrap();
+
+        assert_eq!(
+            parse_bind_addr("0.0.0.0", 8080).unwrap(),
+            addr("0.0.0.0:8080")
+        );
+        assert_eq!(parse_bind_addr("::", 8080).unwrap(), addr("[::]:8080"));
+        assert_eq!(parse_bind_addr("[::1]", 8080).unwrap(), addr("[::1]:8080"));
+        assert_eq!(
+            parse_bind_addr("127.0.0.1:9000", 8080).unwrap(),
+            addr("127.0.0.1:9000")
+        );
+        assert_eq!(
+            parse_bind_addr("[::]:9000", 8080).unwrap(),
+            addr("[::]:9000")
+        );
+
+        let err = parse_bind_addr("localhost", 8080).unwrap_err();
+        assert!(err.to_string().contains("Invalid address \"localhost\""));
+        assert!(parse_bind_addr("[::1", 8080).is_err());
+
+        let addrs = ["0.0.0.0".to_string(), "[::]:8080".to_string()];
+        assert_eq!(
+            parse_bind_addrs(&addrs, 8080).unwrap(),
+            vec![addr("0.0.0.0:8080"), addr("[::]:8080")]
+        );
+        assert!(parse_bind_addrs(&[], 8080).is_err());
+        let err = parse_bind_addrs(&["::".to_string(), "[::]:8080".to_string()], 8080).unwrap_err();
+        assert!(err.to_string().contains("more than once"));
+    }
+
+    #[test]
+    fn test_bind_tcp() {
+        let listeners = bind_tcp(&[SocketAddr::from_str("127.0.0.1:0").unwrap()]).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(addr.ip(), IpAddr::from_str("127.0.0.1").unwrap());
+
+        // The address is in use while the first listener is open
+        let err = bind_tcp(&[addr]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("Could not bind {}", addr)));
+    }
+}
@@ -0,0 +1,88 @@
+//! Time limited cache of responses that rarely change
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Seconds an LNURL pay response is cached for by default
+pub const DEFAULT_LNURL_CACHE_TTL: u64 = 60;
+
+/// Seconds clients may cache an LNURL pay response for by default
+pub const DEFAULT_LNURL_MAX_AGE: u64 = 300;
+
+/// Entries kept before expired entries are dropped
+const MAX_ENTRIES: usize = 10_000;
+
+/// Values by domain and username, each kept for `ttl` after it is inserted
+#[derive(Debug)]
+pub struct TtlCache<V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, String), (Instant, V)>>,
+}
+
+impl<V: Clone> TtlCache<V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Value of a user inserted less than `ttl` before `now`
+    pub fn get(&self, domain: &str, username: &str, now: Instant) -> Option<V> {
+        let entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+
+        entries
+            .get(&(domain.to_string(), username.to_string()))
+            .filter(|(inserted, _)| now.duration_since(*inserted) < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn insert(&self, domain: &str, username: &str, value: V, now: Instant) {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (inserted, _)| now.duration_since(*inserted) < self.ttl);
+        }
+        // Still full of live entries, start over rather than grow without bound
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+
+        entries.insert((domain.to_string(), username.to_string()), (now, value));
+    }
+
+    /// Drop the value of a user, called when the user is changed or removed
+    pub fn invalidate(&self, domain: &str, username: &str) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&(domain.to_string(), username.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_cache() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(cache.get("example.com", "alice", now), None);
+        cache.insert("example.com", "alice", 1, now);
+        assert_eq!(cache.get("example.com", "alice", now), Some(1));
+        // Users are per domain
+        assert_eq!(cache.get("example.org", "alice", now), None);
+
+        let later = now + Duration::from_secs(59);
+        assert_eq!(cache.get("example.com", "alice", later), Some(1));
+        let expired = now + Duration::from_secs(60);
+        assert_eq!(cache.get("example.com", "alice", expired), None);
+
+        cache.insert("example.com", "alice", 2, now);
+        cache.invalidate("example.com", "alice");
+        assert_eq!(cache.get("example.com", "alice", now), None);
+    }
+}
@@ -1,43 +1,225 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use cashu_sdk::client::Client;
 use cashu_sdk::nuts::nut00::wallet::Token;
 use cashu_sdk::nuts::nut03::RequestMintResponse;
 use cashu_sdk::wallet::Wallet as CashuWallet;
 use cashu_sdk::Amount;
+use nostr_sdk::secp256k1::rand::random;
 use nostr_sdk::Url;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 use tokio::time::{sleep, Duration};
-use tracing::{debug, warn};
+use tracing::{debug, error, info, warn, Instrument};
 
-use crate::config::Settings;
+use crate::backend::{PaidInvoice, PaymentBackend, PaymentStatus};
+use crate::config::{mint_allowed, Settings};
 use crate::database::Db;
+use crate::dry_run::{dry_run_invoice, dry_run_token};
 use crate::error::Error;
+use crate::metrics::{outcome, Metrics};
+use crate::mint_api;
 use crate::nostr::Nostr;
-use crate::types::{unix_time, PendingInvoice, UserKind};
+use crate::notifications::{Notifier, PaymentNotification};
+use crate::outbound;
+use crate::p2pk;
+use crate::types::{
+    unix_time, Claim, PaymentRecord, PendingInvoice, TokenDelivery, User, UserKind,
+};
+
+/// Default seconds a minted token can be claimed for
+const DEFAULT_CLAIM_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Seconds between removing expired claims
+const CLAIM_SWEEP_INTERVAL: u64 = 60;
+
+/// Default seconds between reconciling stale invoices
+const DEFAULT_RECONCILE_INTERVAL: u64 = 300;
+
+/// Default seconds a proxied invoice is pending before it is reconciled
+const DEFAULT_RECONCILE_AFTER: u64 = 3600;
+
+/// Seconds past its expiry an invoice is kept, so a payment made just before is still handled
+const EXPIRED_INVOICE_GRACE: u64 = 600;
+
+/// Seconds the result of checking a mint is reused for
+const MINT_CHECK_TTL: u64 = 60;
+
+/// Most mint check results kept, the oldest are dropped first
+const MAX_MINT_CHECKS: usize = 1000;
+
+/// Default retries of a token DM before it is dead lettered
+const DEFAULT_TOKEN_DELIVERY_MAX_RETRIES: u32 = 10;
+
+/// Seconds between checks for queued token DMs that are due
+const TOKEN_DELIVERY_INTERVAL: u64 = 30;
+/// Seconds before the first retry of a token DM, doubled for each one after
+const TOKEN_DELIVERY_BACKOFF_MIN: u64 = 60;
+/// Longest seconds between retries of a token DM
+const TOKEN_DELIVERY_BACKOFF_MAX: u64 = 3600;
+
+/// Result of retrying a stuck invoice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetryOutcome {
+    /// Minted and sent
+    Minted,
+    /// Marked failed, paying the mint is no longer possible
+    Failed,
+    /// Removed, the payer never paid and the invoice expired
+    Removed,
+    /// Left pending, still waiting for a payment or needing an operator
+    Pending,
+}
+
+/// Recent results of checking mints, keyed by mint url
+#[derive(Debug, Default)]
+struct MintChecks {
+    checks: HashMap<String, (u64, Result<(), String>)>,
+}
+
+/// Seconds before retrying a token DM that failed `attempts` times
+fn token_delivery_backoff(attempts: u32) -> u64 {
+    TOKEN_DELIVERY_BACKOFF_MIN
+        .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+        .min(TOKEN_DELIVERY_BACKOFF_MAX)
+}
+
+impl MintChecks {
+    /// Result of checking `mint_url` if it was checked within [`MINT_CHECK_TTL`]
+    fn get(&self, mint_url: &str, now: u64) -> Option<Result<(), String>> {
+        match self.checks.get(mint_url) {
+            Some((checked, result)) if now < checked + MINT_CHECK_TTL => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, mint_url: &str, now: u64, result: Result<(), String>) {
+        self.checks
+            .retain(|_, (checked, _)| now < *checked + MINT_CHECK_TTL);
+        while self.checks.len() >= MAX_MINT_CHECKS && !self.checks.contains_key(mint_url) {
+            let oldest = self
+                .checks
+                .iter()
+                .min_by_key(|(_, (checked, _))| *checked)
+                .map(|(url, _)| url.clone());
+            match oldest {
+                Some(url) => self.checks.remove(&url),
+                None => break,
+            };
+        }
+        self.checks.insert(mint_url.to_string(), (now, result));
+    }
+}
+
+/// Fee taken from a payment for routing to the mint, at least `base_fee`
+pub fn fee_for_invoice(amount: Amount, fee_percent: f32, base_fee: Amount) -> Amount {
+    let fee = Amount::from_msat((amount.to_msat() as f32 * fee_percent).ceil() as u64);
+
+    if base_fee.gt(&fee) {
+        base_fee
+    } else {
+        fee
+    }
+}
+
+/// Most to spend routing the payment of a mint invoice, given the fee taken from the payer
+///
+/// In the case of small invoices that will likely not incur a routing fee
+/// or when no fee is configured no fee is taken.
+/// However it must be ensured that it is always
+/// > 1 sat as that is the min for cashu tokens
+/// In this small case a max fee of 10 sats is set.
+/// As I would rather the service eat the fees
+/// TO avoid the poor user experience of failed payments
+pub fn max_routing_fee(fee: Amount) -> Amount {
+    if fee.eq(&Amount::ZERO) {
+        Amount::from_sat(10)
+    } else {
+        fee
+    }
+}
+
+/// Amount a paid invoice is minted for, what was received for amountless invoices
+fn paid_amount(amount: Amount, amount_received: Option<Amount>) -> Option<Amount> {
+    if amount == Amount::ZERO {
+        amount_received
+    } else {
+        Some(amount)
+    }
+}
+
+/// Amount left to mint after the fee
+///
+/// `None` if less than the 1 sat minimum of a cashu token is left
+fn mint_amount(amount: Amount, fee: Amount) -> Option<Amount> {
+    amount
+        .to_msat()
+        .checked_sub(fee.to_msat())
+        .filter(|msat| *msat >= 1000)
+        .map(Amount::from_msat)
+}
+
+/// Least whole sats a proxied invoice can be for and leave something to mint after the fee
+///
+/// `None` if the fee percent takes the whole payment
+pub fn min_proxied_amount(fee_percent: f32, base_fee: Amount) -> Option<Amount> {
+    if fee_percent >= 1.0 {
+        return None;
+    }
+    let floor_msat = (base_fee.to_msat() + 1000).max((1000.0 / (1.0 - fee_percent)) as u64);
+
+    let mut amount = Amount::from_sat((floor_msat + 999) / 1000);
+    while mint_amount(amount, fee_for_invoice(amount, fee_percent, base_fee)).is_none() {
+        amount = Amount::from_sat(amount.to_sat() + 1);
+    }
+
+    Some(amount)
+}
 
 #[derive(Debug, Clone)]
 pub struct Cashu {
     mints: Arc<Mutex<HashMap<String, Option<CashuWallet>>>>,
+    /// Client of the mint requests made without cashu-sdk
+    http: reqwest::Client,
+    /// Proxy mints are reached through, with `http` as cashu-sdk cannot use a proxy
+    socks_proxy: Option<SocketAddr>,
+    mint_checks: Arc<Mutex<MintChecks>>,
     db: Db,
     nostr: Nostr,
     settings: Settings,
+    metrics: Metrics,
+    notifier: Notifier,
 }
 
 impl Cashu {
-    pub fn new(db: Db, nostr: Nostr, settings: Settings) -> Self {
-        Self {
+    pub fn new(
+        db: Db,
+        nostr: Nostr,
+        settings: Settings,
+        metrics: Metrics,
+        notifier: Notifier,
+        socks_proxy: Option<SocketAddr>,
+    ) -> Result<Self> {
+        let http = outbound::with_socks_proxy(outbound::client_builder(), socks_proxy)?.build()?;
+
+        Ok(Self {
             mints: Arc::new(Mutex::new(HashMap::new())),
+            http,
+            socks_proxy,
+            mint_checks: Arc::new(Mutex::new(MintChecks::default())),
             db,
             nostr,
             settings,
-        }
+            metrics,
+            notifier,
+        })
     }
 
     /// Get wallet for uri
     async fn wallet_for_url(&self, mint_url: &nostr_sdk::Url) -> Result<CashuWallet, Error> {
+        check_not_onion(mint_url)?;
         let mint_url = mint_url
             .as_str()
             .strip_suffix('/')
@@ -59,88 +241,923 @@ impl Cashu {
         Ok(cashu_wallet)
     }
 
-    pub async fn run(&self) -> Result<()> {
+    /// Check a mint is reachable and serves keys and keysets this wallet understands
+    ///
+    /// Results are cached for [`MINT_CHECK_TTL`] seconds so signup bursts do not hammer the mint.
+    /// Without a proxy only mints on public addresses are checked unless `allow_private`, so a
+    /// signup cannot make the service request its own network
+    pub async fn check_mint(&self, mint_url: &Url, allow_private: bool) -> Result<(), Error> {
+        if self.socks_proxy.is_none() {
+            check_not_onion(mint_url)?;
+        }
+        if self.dry_run() {
+            return Ok(());
+        }
+        if self.socks_proxy.is_none() && !allow_private {
+            outbound::check_public_url(mint_url)
+                .await
+                .map_err(|err| Error::MintUnavailable(err.to_string()))?;
+        }
+        let key = mint_url
+            .as_str()
+            .strip_suffix('/')
+            .unwrap_or(mint_url.as_str());
+
+        if let Some(result) = self.mint_checks.lock().await.get(key, unix_time()) {
+            return result.map_err(Error::MintUnavailable);
+        }
+
+        let result = match self.socks_proxy {
+            Some(_) => mint_api::check_mint(&self.http, mint_url)
+                .await
+                .map_err(|err| err.to_string()),
+            None => async {
+                let client = Client::new(key)?;
+                client.get_keys().await?;
+                client.get_keysets().await?;
+                Ok::<(), Error>(())
+            }
+            .await
+            .map_err(|err| err.to_string()),
+        };
+        if let Err(err) = &result {
+            debug!("Mint {} failed check: {}", key, err);
+        }
+
+        self.mint_checks
+            .lock()
+            .await
+            .insert(key, unix_time(), result.clone());
+
+        result.map_err(Error::MintUnavailable)
+    }
+
+    /// Mint and send tokens for paid invoices until shutdown
+    pub async fn run(&self, shutdown: watch::Receiver<bool>) -> Result<()> {
         loop {
-            if let Err(err) = self.check_invoice().await {
+            if let Err(err) = self.check_invoice(&shutdown).await {
                 warn!("{}", err);
             }
+
+            if *shutdown.borrow() {
+                return Ok(());
+            }
         }
     }
 
-    async fn check_invoice(&self) -> Result<()> {
+    /// Seconds a minted token can be claimed for
+    fn claim_ttl(&self) -> u64 {
+        self.settings.info.claim_ttl.unwrap_or(DEFAULT_CLAIM_TTL)
+    }
+
+    async fn check_invoice(&self, shutdown: &watch::Receiver<bool>) -> Result<()> {
+        let mut last_claim_sweep = 0;
+
         loop {
+            // Checked between batches so an in flight mint is never interrupted
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+
+            if unix_time() - last_claim_sweep >= CLAIM_SWEEP_INTERVAL {
+                let removed = self.db.remove_expired_claims(unix_time()).await?;
+                debug!("Removed {} expired claims", removed);
+                last_claim_sweep = unix_time();
+            }
+
             let pending_invoices = self.db.get_pending_invoices().await?;
             for invoice in pending_invoices {
+                if invoice.failed {
+                    continue;
+                }
+
                 let time_since_checked = match invoice.last_checked {
                     Some(time) => unix_time() - time,
                     None => 10000,
                 };
                 if time_since_checked.gt(&15) {
                     let cashu = self.clone();
-                    match cashu.mint(&invoice).await {
-                        Ok(token) => {
-                            debug!("Invoice Paid: {:?}", invoice);
-                            // DM token to nostr npub
-                            let user = cashu.db.get_user(&invoice.username).await?;
+                    let span = invoice.span();
+                    async move {
+                        match cashu.mint(&invoice).await {
+                            Ok(token) => cashu.send_minted(invoice, token).await,
+                            Err(err) => {
+                                // Err or token is just unpaid
+                                // Update checked time
+                                warn!("{}", err);
 
-                            if let Some(UserKind::User(user)) = user {
-                                cashu
-                                    .nostr
-                                    .send_token(&user.pubkey, token, &user.relays)
-                                    .await?;
-
-                                if invoice.proxied && self.settings.info.zapper.unwrap_or(false) {
-                                    if let Some(description) = invoice.description {
-                                        if let Err(err) = self
-                                            .nostr
-                                            .broadcast_zap(
-                                                invoice.bolt11,
-                                                &description,
-                                                &user.relays,
-                                            )
-                                            .await
-                                        {
-                                            warn!("Could not broadcast zap: {}", err);
-                                        }
-                                    }
+                                if cashu.remove_expired_invoice(&invoice, unix_time()).await? {
+                                    return Ok(());
                                 }
-                            }
 
-                            // Remove token from pending
-                            cashu.db.remove_pending_invoice(&invoice.hash).await?;
+                                let updated_invoice = invoice.update_checked_time();
+
+                                cashu
+                                    .db
+                                    .add_pending_invoice(&invoice.hash, &updated_invoice)
+                                    .await
+                            }
                         }
-                        Err(err) => {
-                            // Err or token is just unpaid
-                            // Update checked time
-                            warn!("{}", err);
+                    }
+                    .instrument(span)
+                    .await?;
+                }
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Remove an invoice the payer can no longer pay, returns if it was removed
+    ///
+    /// Only invoices handed to a payer and not settled are removed. Mint invoices of proxied
+    /// payments have been paid for and are left to reconciliation.
+    async fn remove_expired_invoice(&self, invoice: &PendingInvoice, now: u64) -> Result<bool> {
+        if now < invoice.expires_at().saturating_add(EXPIRED_INVOICE_GRACE) {
+            return Ok(false);
+        }
+
+        match self.db.get_payer_invoice(&invoice.hash).await? {
+            Some(payer_invoice) if !payer_invoice.settled => {
+                debug!("Removing expired invoice {}", invoice.hash);
+                self.db.remove_pending_invoice(&invoice.hash).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Recover proxied invoices stuck after a failed payment or crash until shutdown
+    pub async fn reconcile(
+        &self,
+        ln_backend: Arc<dyn PaymentBackend>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let interval = Duration::from_secs(
+            self.settings
+                .info
+                .reconcile_interval
+                .unwrap_or(DEFAULT_RECONCILE_INTERVAL),
+        );
+        let stale_after = self
+            .settings
+            .info
+            .reconcile_after
+            .unwrap_or(DEFAULT_RECONCILE_AFTER);
+
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => {}
+                _ = shutdown.changed() => return Ok(()),
+            }
+
+            if let Err(err) = self
+                .reconcile_invoices(ln_backend.as_ref(), stale_after)
+                .await
+            {
+                warn!("Could not reconcile invoices: {}", err);
+            }
+        }
+    }
+
+    /// Check proxied invoices pending for longer than `stale_after`
+    ///
+    /// Invoices handed to payers are looked up as received invoices, and proxied once paid
+    /// in case the payment was missed. Mint invoices the service pays are looked up as
+    /// payments, paid ones are minted and sent and expired unpaid ones are marked failed.
+    async fn reconcile_invoices(
+        &self,
+        ln_backend: &dyn PaymentBackend,
+        stale_after: u64,
+    ) -> Result<()> {
+        let now = unix_time();
 
-                            let updated_invoice = invoice.update_checked_time();
+        for invoice in self.db.get_pending_invoices().await? {
+            if !invoice.proxied || invoice.failed || now.saturating_sub(invoice.time) < stale_after
+            {
+                continue;
+            }
 
-                            cashu
-                                .db
-                                .add_pending_invoice(&invoice.hash, &updated_invoice)
-                                .await?;
+            let span = invoice.span();
+            match invoice.payer_hash {
+                Some(_) => {
+                    self.reconcile_invoice(ln_backend, invoice, now)
+                        .instrument(span)
+                        .await?
+                }
+                None => {
+                    self.reconcile_payer_invoice(ln_backend, invoice)
+                        .instrument(span)
+                        .await?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Proxy the payment of an invoice handed to a payer if it was paid
+    ///
+    /// Unpaid invoices are left for the payer, and removed by [`Self::run`] once expired
+    async fn reconcile_payer_invoice(
+        &self,
+        ln_backend: &dyn PaymentBackend,
+        invoice: PendingInvoice,
+    ) -> Result<()> {
+        let paid_invoice = match ln_backend.invoice_status(&invoice.hash).await? {
+            Some(paid_invoice) => paid_invoice,
+            None => return Ok(()),
+        };
+        // Settled when the payment was seen, its mint invoice is reconciled instead
+        if let Some(payer_invoice) = self.db.get_payer_invoice(&invoice.hash).await? {
+            if payer_invoice.settled {
+                return Ok(());
+            }
+        }
+
+        warn!("Proxying missed payment of invoice {}", invoice.hash);
+        self.proxy_payment(ln_backend, invoice, paid_invoice).await;
+
+        Ok(())
+    }
+
+    /// Mint a paid mint invoice of a proxied payment, or mark it failed
+    ///
+    /// Unpaid invoices are left alone until they expire, so they can still be retried
+    async fn reconcile_invoice(
+        &self,
+        ln_backend: &dyn PaymentBackend,
+        invoice: PendingInvoice,
+        now: u64,
+    ) -> Result<()> {
+        match ln_backend.payment_status(&invoice.hash).await? {
+            PaymentStatus::Succeeded => match self.mint(&invoice).await {
+                Ok(token) => self.send_minted(invoice, token).await?,
+                Err(err) => {
+                    self.metrics.mint_failures.inc();
+                    warn!("Could not mint paid invoice {}: {}", invoice.hash, err)
+                }
+            },
+            PaymentStatus::Pending => {
+                debug!("Payment of invoice {} still pending", invoice.hash)
+            }
+            PaymentStatus::Failed | PaymentStatus::Unknown if now < invoice.expires_at() => {
+                debug!("Invoice {} is unpaid until it expires", invoice.hash)
+            }
+            PaymentStatus::Failed | PaymentStatus::Unknown => {
+                warn!("Marking invoice {} failed", invoice.hash);
+                let failed_invoice = PendingInvoice {
+                    failed: true,
+                    ..invoice
+                };
+                self.db
+                    .add_pending_invoice(&failed_invoice.hash, &failed_invoice)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drive a stuck invoice to completion where possible
+    ///
+    /// Paid invoices are minted and sent. Mint invoices of proxied payments that were never
+    /// paid or whose payment failed are paid again, spending at most `max_fee`, until they
+    /// expire and are marked failed.
+    pub async fn retry_invoice(
+        &self,
+        ln_backend: Option<&dyn PaymentBackend>,
+        invoice: PendingInvoice,
+        max_fee: Amount,
+    ) -> Result<RetryOutcome> {
+        match self.mint(&invoice).await {
+            Ok(token) => {
+                self.send_minted(invoice, token).await?;
+                return Ok(RetryOutcome::Minted);
+            }
+            Err(err) => debug!("Could not mint invoice {}: {}", invoice.hash, err),
+        }
+
+        let now = unix_time();
+        if let Some(payer_invoice) = self.db.get_payer_invoice(&invoice.hash).await? {
+            if payer_invoice.settled {
+                warn!(
+                    "Invoice {} was paid but never forwarded to the mint",
+                    invoice.hash
+                );
+            } else if self.remove_expired_invoice(&invoice, now).await? {
+                return Ok(RetryOutcome::Removed);
+            }
+            return Ok(RetryOutcome::Pending);
+        }
+
+        // Mint invoices of proxied payments are paid by the service
+        let ln_backend = ln_backend
+            .ok_or_else(|| anyhow!("A lightning backend is needed to retry proxied invoices"))?;
+
+        match ln_backend.payment_status(&invoice.hash).await? {
+            PaymentStatus::Succeeded => {
+                warn!("Invoice {} was paid but could not be minted", invoice.hash);
+                Ok(RetryOutcome::Pending)
+            }
+            PaymentStatus::Pending => Ok(RetryOutcome::Pending),
+            PaymentStatus::Failed | PaymentStatus::Unknown if now < invoice.expires_at() => {
+                match ln_backend.pay(&invoice.bolt11, max_fee).await {
+                    Ok(payment) => {
+                        self.db
+                            .add_fee_paid(&payment.payment_hash, payment.fee.to_msat())
+                            .await?;
+                        let invoice = PendingInvoice {
+                            failed: false,
+                            ..invoice
+                        };
+                        self.db.add_pending_invoice(&invoice.hash, &invoice).await?;
+
+                        match self.mint(&invoice).await {
+                            Ok(token) => {
+                                self.send_minted(invoice, token).await?;
+                                Ok(RetryOutcome::Minted)
+                            }
+                            Err(err) => {
+                                warn!("Paid invoice {} could not be minted: {}", invoice.hash, err);
+                                Ok(RetryOutcome::Pending)
+                            }
                         }
                     }
+                    Err(err) => {
+                        warn!("Could not pay invoice {}: {}", invoice.hash, err);
+                        Ok(RetryOutcome::Pending)
+                    }
                 }
             }
-            sleep(Duration::from_millis(100)).await;
+            PaymentStatus::Failed | PaymentStatus::Unknown => {
+                warn!("Marking expired invoice {} failed", invoice.hash);
+                let failed_invoice = PendingInvoice {
+                    failed: true,
+                    ..invoice
+                };
+                self.db
+                    .add_pending_invoice(&failed_invoice.hash, &failed_invoice)
+                    .await?;
+                Ok(RetryOutcome::Failed)
+            }
+        }
+    }
+
+    /// Mint the payment of a proxied invoice once the payer has paid it
+    ///
+    /// The payment is recorded and notified, then an invoice for the amount left after the
+    /// routing fee is requested from the user's mint and paid. The token is minted and sent
+    /// by [`Self::run`] once the mint sees the payment.
+    pub async fn proxy_payment(
+        &self,
+        ln_backend: &dyn PaymentBackend,
+        invoice: PendingInvoice,
+        paid_invoice: PaidInvoice,
+    ) {
+        self.metrics.invoices_paid.inc();
+
+        if let Err(err) = self
+            .db
+            .settle_payer_invoice(&invoice.hash, paid_invoice.preimage.clone())
+            .await
+        {
+            warn!("Could not settle payer invoice: {:?}", err);
+        }
+
+        // Published once the payer has paid, minting may still fail
+        if self.settings.info.zapper.unwrap_or(false) {
+            if let Err(err) = self
+                .nostr
+                .broadcast_zap(&invoice, paid_invoice.preimage)
+                .await
+            {
+                warn!("Could not broadcast zap: {:?}", err);
+            }
+        }
+
+        let paid_amount = match paid_amount(invoice.amount, paid_invoice.amount_received) {
+            Some(amount) => amount,
+            None => {
+                error!("Amount paid for invoice {} is unknown", invoice.hash);
+                return;
+            }
+        };
+
+        self.notifier.notify(PaymentNotification::new(
+            &invoice.domain,
+            &invoice.username,
+            &paid_invoice.payment_hash,
+            paid_amount,
+            invoice.comment.clone(),
+            unix_time(),
+        ));
+
+        // Fee to account for routing fee
+
+        let fee = fee_for_invoice(
+            paid_amount,
+            self.settings.info.routing_fee_percent.unwrap_or(0.0),
+            Amount::from_sat(self.settings.info.routing_fee_base_sat.unwrap_or(0)),
+        );
+
+        let amount = match mint_amount(paid_amount, fee) {
+            Some(amount) => amount,
+            None => {
+                // Only an amountless invoice can be paid less than the min sendable
+                error!(
+                    "Fee {:?} leaves nothing to mint for invoice {}, keeping it as fee",
+                    fee, invoice.hash
+                );
+                if let Err(err) = self
+                    .db
+                    .add_fee_received(&invoice.hash, paid_amount.to_msat())
+                    .await
+                {
+                    warn!("Could not add received fee to DB: {:?}", err);
+                }
+                let record = PaymentRecord {
+                    hash: invoice.hash.clone(),
+                    domain: invoice.domain.clone(),
+                    username: invoice.username.clone(),
+                    mint: invoice.mint.clone(),
+                    amount_msat: paid_amount.to_msat(),
+                    fee_msat: paid_amount.to_msat(),
+                    minted_msat: None,
+                    proxied: true,
+                    comment: invoice.comment.clone(),
+                    delivered: false,
+                    paid_at: unix_time(),
+                };
+                if let Err(err) = self.db.add_payment_record(&record).await {
+                    warn!("Could not add payment record: {:?}", err);
+                }
+                if let Err(err) = self.db.remove_pending_invoice(&invoice.hash).await {
+                    warn!("Could not remove pending invoice {:?}", err);
+                }
+                return;
+            }
+        };
+
+        if let Err(err) = self.db.add_fee_received(&invoice.hash, fee.to_msat()).await {
+            warn!("Could not add received fee to DB: {:?}", err);
+            info!("Fee received: {:?}", fee.to_msat());
+        }
+
+        let max_fee = max_routing_fee(fee);
+
+        let mints = self.proxy_mints(&invoice, amount).await;
+        let request_mint = self.request_mint(amount, &mints).await;
+
+        // Completed with the amount minted once the mint invoice is paid
+        let record = PaymentRecord {
+            hash: invoice.hash.clone(),
+            domain: invoice.domain.clone(),
+            username: invoice.username.clone(),
+            mint: match &request_mint {
+                Ok((mint, _)) => mint.clone(),
+                Err(_) => invoice.mint.clone(),
+            },
+            amount_msat: paid_amount.to_msat(),
+            fee_msat: fee.to_msat(),
+            minted_msat: None,
+            proxied: true,
+            comment: invoice.comment.clone(),
+            delivered: false,
+            paid_at: unix_time(),
+        };
+        if let Err(err) = self.db.add_payment_record(&record).await {
+            warn!("Could not add payment record: {:?}", err);
+        }
+
+        let (mint, request_mint_response) = match request_mint {
+            Ok(request_mint) => request_mint,
+            Err(err) => {
+                warn!("{:?}", err);
+                return;
+            }
+        };
+
+        // The mint that issued the invoice is the one that mints once it is paid
+        let pending_invoice = PendingInvoice {
+            mint,
+            username: invoice.username,
+            domain: invoice.domain,
+            alias: invoice.alias,
+            description: invoice.description,
+            comment: invoice.comment,
+            payer_data: invoice.payer_data,
+            claim_id: invoice.claim_id,
+            request_id: invoice.request_id,
+            // The payer's invoice has been paid and can no longer be reused
+            idempotency_key: None,
+            success_action: None,
+            amount,
+            hash: request_mint_response.hash,
+            bolt11: request_mint_response.pr.clone(),
+            last_checked: None,
+            proxied: true,
+            failed: false,
+            payer_hash: Some(invoice.hash.clone()),
+            time: unix_time(),
+        };
+
+        // Replace the paid invoice with the mint invoice, along with the pay index it was paid at
+        if let Err(err) = self
+            .db
+            .replace_pending_invoice(&invoice.hash, &pending_invoice, paid_invoice.pay_index)
+            .await
+        {
+            warn!("Could not replace pending invoice: {:?}", err)
+        }
+
+        // Pay mint invoice
+        match ln_backend.pay(&request_mint_response.pr, max_fee).await {
+            Ok(payment) => {
+                debug!("Invoice paid: {:?}", payment);
+                if let Err(err) = self
+                    .db
+                    .add_fee_paid(&payment.payment_hash, payment.fee.to_msat())
+                    .await
+                {
+                    warn!("Could not add paid fee to DB: {:?}", err);
+
+                    info!("Fee Paid: {:?}", payment.fee);
+                }
+            }
+            Err(err) => warn!("Error paying mint invoice: {:?}", err),
+        };
+    }
+
+    /// Record the amount minted for a paid invoice
+    ///
+    /// Proxied payments were recorded when the payer paid, other invoices are paid to the
+    /// mint so the payment is recorded once it is minted
+    async fn record_minted(&self, invoice: &PendingInvoice, delivered: bool) -> Result<()> {
+        let minted_msat = invoice.amount.to_msat();
+        let record = match &invoice.payer_hash {
+            Some(payer_hash) => match self.db.get_payment_record(payer_hash).await? {
+                Some(record) => PaymentRecord {
+                    minted_msat: Some(minted_msat),
+                    delivered,
+                    ..record
+                },
+                None => bail!("No payment record for {}", payer_hash),
+            },
+            None => PaymentRecord {
+                hash: invoice.hash.clone(),
+                domain: invoice.domain.clone(),
+                username: invoice.username.clone(),
+                mint: invoice.mint.clone(),
+                amount_msat: minted_msat,
+                fee_msat: 0,
+                minted_msat: Some(minted_msat),
+                proxied: invoice.proxied,
+                comment: invoice.comment.clone(),
+                delivered,
+                paid_at: unix_time(),
+            },
+        };
+
+        self.db.add_payment_record(&record).await
+    }
+
+    /// Mark the payment record of a token DMed by a retry as delivered
+    async fn record_delivered(&self, delivery: &TokenDelivery) -> Result<()> {
+        let hash = delivery.payer_hash.as_ref().unwrap_or(&delivery.hash);
+        match self.db.get_payment_record(hash).await? {
+            Some(record) => {
+                self.db
+                    .add_payment_record(&PaymentRecord {
+                        delivered: true,
+                        ..record
+                    })
+                    .await
+            }
+            None => bail!("No payment record for {}", hash),
+        }
+    }
+
+    /// Store the token of a paid invoice as a claim, DM it and remove the invoice from pending
+    async fn send_minted(&self, invoice: PendingInvoice, token: Token) -> Result<()> {
+        debug!("Invoice Paid: {:?}", invoice);
+
+        // Proxied invoices are counted and notified when the payer's invoice is paid
+        if !invoice.proxied {
+            self.metrics.invoices_paid.inc();
+            self.notifier.notify(PaymentNotification::new(
+                &invoice.domain,
+                &invoice.username,
+                &invoice.hash,
+                invoice.amount,
+                invoice.comment.clone(),
+                unix_time(),
+            ));
         }
+        self.metrics.tokens_minted.inc();
+
+        // Only success actions with a claim url have the token claimable, others rely on the DM
+        let token = token.convert_to_string()?;
+        if let Some(claim_id) = &invoice.claim_id {
+            let claim = Claim {
+                token: Some(token.clone()),
+                expire: unix_time() + self.claim_ttl(),
+            };
+            // The token is still DMed and the invoice settled if it cannot be stored
+            if let Err(err) = self.db.add_claim(claim_id, &claim).await {
+                error!(
+                    "Could not store claim of invoice {}: {:?}",
+                    invoice.hash, err
+                );
+            }
+        }
+
+        // DM token to nostr npub
+        let user = self.invoice_user(&invoice).await?;
+
+        let delivery = TokenDelivery {
+            hash: invoice.hash.clone(),
+            payer_hash: invoice.payer_hash.clone(),
+            domain: invoice.domain.clone(),
+            username: invoice.username.clone(),
+            claim_id: invoice.claim_id.clone(),
+            pubkey: String::new(),
+            token,
+            comment: invoice.comment.clone(),
+            payer_data: invoice.payer_data.clone(),
+            alias: invoice
+                .alias
+                .as_ref()
+                .map(|alias| format!("{}@{}", alias, invoice.domain)),
+            relays: HashSet::new(),
+            attempts: 0,
+            next_attempt: unix_time(),
+            error: None,
+            created_at: unix_time(),
+        };
+        let delivered = match user {
+            Some(user) => {
+                let delivery = TokenDelivery {
+                    pubkey: user.pubkey,
+                    relays: user.relays,
+                    ..delivery
+                };
+                match self.deliver_token(delivery).await {
+                    Ok(delivered) => delivered,
+                    Err(err) => {
+                        error!(
+                            "Could not queue token DM of invoice {}: {:?}",
+                            invoice.hash, err
+                        );
+                        false
+                    }
+                }
+            }
+            // Dead lettered for the operator to return, there is no one to DM
+            None => {
+                let delivery = TokenDelivery {
+                    error: Some("User was removed".to_string()),
+                    ..delivery
+                };
+                match self.db.dead_letter_token_delivery(&delivery).await {
+                    Ok(()) => error!(
+                        "User of invoice {} was removed, dead lettered its token",
+                        invoice.hash
+                    ),
+                    Err(err) => error!(
+                        "Could not dead letter token of invoice {}: {:?}",
+                        invoice.hash, err
+                    ),
+                }
+                false
+            }
+        };
+
+        if let Err(err) = self.record_minted(&invoice, delivered).await {
+            warn!(
+                "Could not record minted invoice {}: {:?}",
+                invoice.hash, err
+            );
+        }
+
+        // Proxied invoices are receipted when the payer's invoice settles
+        if !invoice.proxied && self.settings.info.zapper.unwrap_or(false) {
+            if let Err(err) = self.nostr.broadcast_zap(&invoice, None).await {
+                warn!("Could not broadcast zap: {}", err);
+            }
+        }
+
+        // Proxied mint invoices have no payer record and are skipped
+        self.db.settle_payer_invoice(&invoice.hash, None).await?;
+
+        // Remove token from pending
+        self.db.remove_pending_invoice(&invoice.hash).await?;
+
+        Ok(())
+    }
+
+    /// Retries of a token DM before it is dead lettered
+    fn token_delivery_max_retries(&self) -> u32 {
+        self.settings
+            .info
+            .token_delivery_max_retries
+            .unwrap_or(DEFAULT_TOKEN_DELIVERY_MAX_RETRIES)
     }
 
+    /// DM a token, queueing it to be sent again with backoff if no relay is reached
+    ///
+    /// Tokens that still fail after `token_delivery_max_retries` retries are dead lettered
+    /// for an operator to send by hand. Returns whether the token was sent
+    async fn deliver_token(&self, mut delivery: TokenDelivery) -> Result<bool> {
+        let sent = self
+            .nostr
+            .send_token(
+                &delivery.pubkey,
+                &delivery.token,
+                delivery.comment.as_deref(),
+                delivery.payer_data.as_ref(),
+                delivery.alias.as_deref(),
+                &delivery.relays,
+            )
+            .await;
+        self.metrics
+            .dm_send
+            .with_label_values(&[outcome(&sent)])
+            .inc();
+        delivery.attempts += 1;
+
+        let err = match sent {
+            // Only retries were queued
+            Ok(()) if delivery.attempts == 1 => return Ok(true),
+            Ok(()) => {
+                info!(
+                    "DMed token of invoice {} after {} attempts",
+                    delivery.hash, delivery.attempts
+                );
+                self.db.remove_token_delivery(&delivery.hash).await?;
+                if let Err(err) = self.record_delivered(&delivery).await {
+                    warn!(
+                        "Could not record delivery of invoice {}: {:?}",
+                        delivery.hash, err
+                    );
+                }
+                return Ok(true);
+            }
+            Err(err) => err,
+        };
+        delivery.error = Some(err.to_string());
+
+        if delivery.attempts > self.token_delivery_max_retries() {
+            self.db.dead_letter_token_delivery(&delivery).await?;
+            error!(
+                "Gave up DMing token of invoice {} after {} attempts: {}",
+                delivery.hash, delivery.attempts, err
+            );
+        } else {
+            let backoff = token_delivery_backoff(delivery.attempts);
+            delivery.next_attempt = unix_time() + backoff;
+            self.db.add_token_delivery(&delivery).await?;
+            warn!(
+                "Could not DM token of invoice {}, retrying in {}s: {}",
+                delivery.hash, backoff, err
+            );
+        }
+
+        Ok(false)
+    }
+
+    /// DM queued tokens as their retries come due until shutdown
+    pub async fn retry_token_deliveries(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        loop {
+            tokio::select! {
+                _ = sleep(Duration::from_secs(TOKEN_DELIVERY_INTERVAL)) => {}
+                _ = shutdown.changed() => return Ok(()),
+            }
+
+            if let Err(err) = self.retry_due_token_deliveries(unix_time()).await {
+                warn!("Could not retry token DMs: {}", err);
+            }
+        }
+    }
+
+    async fn retry_due_token_deliveries(&self, now: u64) -> Result<()> {
+        // Soonest first, so the rest are not due either
+        for delivery in self.db.get_token_deliveries().await? {
+            if delivery.next_attempt > now {
+                break;
+            }
+            self.deliver_token(delivery).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Request an invoice from the first of `mints` that issues one
+    ///
+    /// Mints are tried in order and the mint the invoice is from is returned with it.
+    /// Only fails once every mint has failed.
     pub async fn request_mint(
+        &self,
+        amount: Amount,
+        mints: &[Url],
+    ) -> Result<(Url, RequestMintResponse), Error> {
+        let mut errors = vec![];
+
+        for mint_url in mints {
+            match self.request_mint_from(amount, mint_url).await {
+                Ok(invoice) => return Ok((mint_url.clone(), invoice)),
+                Err(err) => {
+                    warn!("Could not get invoice from {}: {:?}", mint_url, err);
+                    errors.push((mint_url, err));
+                }
+            }
+        }
+
+        match errors.len() {
+            // A single mint keeps its own error
+            1 => Err(errors.remove(0).1),
+            _ => Err(Error::MintsFailed(
+                errors
+                    .iter()
+                    .map(|(mint_url, err)| format!("{}: {}", mint_url, err))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        }
+    }
+
+    async fn request_mint_from(
         &self,
         amount: Amount,
         mint_url: &Url,
     ) -> Result<RequestMintResponse, Error> {
-        let wallet = self.wallet_for_url(mint_url).await?;
-        debug!("Got wallet");
-        let invoice = wallet.request_mint(amount).await?;
+        if !mint_allowed(&self.settings.info.allowed_mints, mint_url) {
+            return Err(Error::MintNotAllowed(mint_url.to_string()));
+        }
+
+        if self.dry_run() {
+            let pr = dry_run_invoice(Some(amount), String::new(), false, random(), None)
+                .map_err(|err| Error::MintUnavailable(err.to_string()))?;
+            let hash = pr.payment_hash().to_string();
+            return Ok(RequestMintResponse { pr, hash });
+        }
+
+        let _timer = self.metrics.mint_request_seconds.start_timer();
 
-        Ok(invoice)
+        let invoice = match self.socks_proxy {
+            Some(_) => mint_api::request_mint(&self.http, mint_url, amount)
+                .await
+                .map_err(|err| Error::MintUnavailable(err.to_string())),
+            None => {
+                async {
+                    let wallet = self.wallet_for_url(mint_url).await?;
+                    debug!("Got wallet");
+                    wallet.request_mint(amount).await.map_err(Error::from)
+                }
+                .await
+            }
+        };
+
+        self.metrics
+            .mint_requests
+            .with_label_values(&[mint_url.as_str(), outcome(&invoice)])
+            .inc();
+        if invoice.is_err() {
+            self.metrics.mint_failures.inc();
+        }
+
+        invoice
     }
 
+    /// Mint the token of a paid invoice
+    ///
+    /// Tokens of users with `lock_to_pubkey` are locked to their pubkey, others are bearer tokens
     pub async fn mint(&self, pending_invoice: &PendingInvoice) -> Result<Token> {
+        if self.dry_run() {
+            return dry_run_token(&pending_invoice.mint, pending_invoice.amount);
+        }
+
+        if let Some(pubkey) = self.lock_pubkey(pending_invoice).await? {
+            return p2pk::mint_locked(
+                &self.http,
+                &pending_invoice.mint,
+                pending_invoice.amount,
+                &pending_invoice.hash,
+                &pubkey,
+            )
+            .await;
+        }
+
+        if self.socks_proxy.is_some() {
+            return mint_api::mint(
+                &self.http,
+                &pending_invoice.mint,
+                pending_invoice.amount,
+                &pending_invoice.hash,
+                mint_api::random_secret,
+            )
+            .await;
+        }
+
         let wallet = self.wallet_for_url(&pending_invoice.mint).await?;
 
         Ok(wallet
@@ -148,6 +1165,53 @@ impl Cashu {
             .await?)
     }
 
+    /// Mints to request the mint invoice of a paid proxied invoice from, in the order they
+    /// are tried
+    ///
+    /// The user's mints and mint rules for the amount minted, or the mint recorded with the
+    /// invoice if the user is gone
+    async fn proxy_mints(&self, invoice: &PendingInvoice, amount: Amount) -> Vec<Url> {
+        let service_rules = self.settings.info.mint_rules.as_deref().unwrap_or_default();
+        match self.invoice_user(invoice).await {
+            Ok(Some(user)) => user.mints_for(amount, service_rules),
+            Ok(None) => vec![invoice.mint.clone()],
+            Err(err) => {
+                warn!("Could not get user of invoice {}: {:?}", invoice.hash, err);
+                vec![invoice.mint.clone()]
+            }
+        }
+    }
+
+    /// Pubkey the token of an invoice is locked to, for users with `lock_to_pubkey`
+    async fn lock_pubkey(&self, pending_invoice: &PendingInvoice) -> Result<Option<String>> {
+        match self.invoice_user(pending_invoice).await? {
+            Some(user) if user.lock_to_pubkey => Ok(Some(user.pubkey)),
+            _ => Ok(None),
+        }
+    }
+
+    /// User an invoice was made for, `None` once they are removed
+    ///
+    /// Invoices are kept when their user is removed, a user who signed up with the username
+    /// since is not the one they were made for
+    async fn invoice_user(&self, invoice: &PendingInvoice) -> Result<Option<User>> {
+        match self.db.get_user(&invoice.domain, &invoice.username).await? {
+            Some(UserKind::User(user))
+                if user
+                    .created_at
+                    .map_or(true, |created_at| created_at <= invoice.time) =>
+            {
+                Ok(Some(user))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Mints are not contacted, their invoices and tokens are faked
+    fn dry_run(&self) -> bool {
+        self.settings.info.dry_run.unwrap_or(false)
+    }
+
     pub async fn add_pending_invoice(&self, pending_invoice: &PendingInvoice) -> Result<()> {
         self.db
             .add_pending_invoice(&pending_invoice.hash, pending_invoice)
@@ -156,3 +1220,562 @@ impl Cashu {
         Ok(())
     }
 }
+
+/// Refuse onion mints, which would be looked up with the system resolver
+///
+/// The cashu-sdk mint client connects directly and cannot be routed through `socks_proxy`,
+/// only nostr relay connections are proxied
+fn check_not_onion(mint_url: &Url) -> Result<(), Error> {
+    match mint_url.host_str() {
+        Some(host) if host.trim_end_matches('.').ends_with(".onion") => Err(
+            Error::MintUnavailable(format!("Onion mint {} cannot be reached", mint_url)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cashu_sdk::Bolt11Invoice;
+    use nostr_sdk::Keys;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backend::{InvoiceStream, Payment};
+    use crate::config::DbBackendKind;
+    use crate::database;
+    use crate::routes::tests::{test_invoice, test_user};
+    use crate::types::{ClaimStatus, MintRule, PayerInvoice};
+
+    async fn test_cashu() -> Cashu {
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.redb", Uuid::new_v4()));
+        let db = database::open(DbBackendKind::Redb, path, "example.com")
+            .await
+            .unwrap();
+        let nostr = Nostr::new(
+            db.clone(),
+            "example.com".to_string(),
+            Keys::generate(),
+            HashSet::new(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        Cashu::new(
+            db,
+            nostr,
+            Settings::default(),
+            Metrics::new().unwrap(),
+            Notifier::new(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_not_onion() {
+        let onion = Url::from_str("http://mintabcdefghijklmnop.onion/").unwrap();
+        assert!(matches!(
+            check_not_onion(&onion),
+            Err(Error::MintUnavailable(_))
+        ));
+
+        let mint = Url::from_str("https://mint.example.com").unwrap();
+        assert!(check_not_onion(&mint).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_mint_fallbacks() {
+        let cashu = test_cashu().await;
+        let first = Url::from_str("http://firstmintabcdefghijk.onion/").unwrap();
+        let second = Url::from_str("http://secondmintabcdefghij.onion/").unwrap();
+
+        // Every mint is tried before failing
+        match cashu
+            .request_mint(Amount::from_sat(10), &[first.clone(), second.clone()])
+            .await
+        {
+            Err(Error::MintsFailed(errors)) => {
+                assert!(errors.contains(first.as_str()));
+                assert!(errors.contains(second.as_str()));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        assert!(matches!(
+            cashu.request_mint(Amount::from_sat(10), &[first]).await,
+            Err(Error::MintUnavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired_invoice() {
+        let cashu = test_cashu().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5ve584t0cv27hwmy0cx9ca8uwyqyfw9y9dm3r8vus9fv36r2l9yjssp5qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqsdq6vdshx6r494kxuatjdss8getnwsxqrrssy4mqr435ppy3thzxfddsg27yr3ckll6yaq7nthlv0g3n0crerpnyqfjhc279587kp9uhzphvpnfpz7689xcrmhsl5w8ts5z4prpga2sq4gaxnq").unwrap();
+        let invoice = test_invoice(bolt11.clone());
+        cashu
+            .db
+            .add_pending_invoice(&invoice.hash, &invoice)
+            .await
+            .unwrap();
+        let expired = invoice.expires_at() + EXPIRED_INVOICE_GRACE;
+
+        // A mint invoice of a proxied payment has no payer invoice and is kept
+        assert!(!cashu
+            .remove_expired_invoice(&invoice, expired)
+            .await
+            .unwrap());
+
+        cashu
+            .db
+            .add_payer_invoice(
+                &invoice.hash,
+                &PayerInvoice {
+                    username: "alice".to_string(),
+                    domain: "example.com".to_string(),
+                    bolt11,
+                    settled: false,
+                    preimage: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(!cashu
+            .remove_expired_invoice(&invoice, expired - 1)
+            .await
+            .unwrap());
+        assert!(cashu
+            .db
+            .get_pending_invoice(&invoice.hash)
+            .await
+            .unwrap()
+            .is_some());
+
+        assert!(cashu
+            .remove_expired_invoice(&invoice, expired)
+            .await
+            .unwrap());
+        assert!(cashu
+            .db
+            .get_pending_invoice(&invoice.hash)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    /// Backend that has neither been paid nor paid anything
+    struct UnpaidBackend;
+
+    #[async_trait::async_trait]
+    impl PaymentBackend for UnpaidBackend {
+        async fn create_invoice(
+            &self,
+            _amount: Option<Amount>,
+            _description: String,
+            _description_hash_only: bool,
+            _preimage: Option<[u8; 32]>,
+            _expiry: Option<u64>,
+        ) -> Result<Bolt11Invoice> {
+            bail!("Invoices are made by the test")
+        }
+
+        async fn pay(&self, _bolt11: &Bolt11Invoice, _max_fee: Amount) -> Result<Payment> {
+            bail!("Nothing is paid by the test")
+        }
+
+        async fn wait_any_invoice(&self, _last_pay_index: Option<u64>) -> Result<InvoiceStream> {
+            bail!("Nothing is paid by the test")
+        }
+
+        async fn payment_status(&self, _payment_hash: &str) -> Result<PaymentStatus> {
+            Ok(PaymentStatus::Unknown)
+        }
+
+        async fn ping(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_invoices() {
+        let cashu = test_cashu().await;
+
+        let expired_bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5ve584t0cv27hwmy0cx9ca8uwyqyfw9y9dm3r8vus9fv36r2l9yjssp5qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqsdq6vdshx6r494kxuatjdss8getnwsxqrrssy4mqr435ppy3thzxfddsg27yr3ckll6yaq7nthlv0g3n0crerpnyqfjhc279587kp9uhzphvpnfpz7689xcrmhsl5w8ts5z4prpga2sq4gaxnq").unwrap();
+        let invoice = |bolt11: Bolt11Invoice, payer_hash: Option<&str>| PendingInvoice {
+            proxied: true,
+            payer_hash: payer_hash.map(str::to_string),
+            ..test_invoice(bolt11)
+        };
+        let fresh_bolt11 = || {
+            dry_run_invoice(
+                Some(Amount::from_sat(1000)),
+                "test".to_string(),
+                false,
+                random(),
+                None,
+            )
+            .unwrap()
+        };
+
+        // Handed to a payer, a mint invoice waiting to be paid and one that expired unpaid
+        let payer = invoice(fresh_bolt11(), None);
+        let unpaid = invoice(fresh_bolt11(), Some("0000"));
+        let expired = invoice(expired_bolt11, Some("0001"));
+        for invoice in [&payer, &unpaid, &expired] {
+            cashu
+                .db
+                .add_pending_invoice(&invoice.hash, invoice)
+                .await
+                .unwrap();
+        }
+
+        cashu.reconcile_invoices(&UnpaidBackend, 0).await.unwrap();
+
+        let failed = |hash: String| {
+            let db = cashu.db.clone();
+            async move { db.get_pending_invoice(&hash).await.unwrap().unwrap().failed }
+        };
+        // An unpaid payer invoice has no payment of the service to look up
+        assert!(!failed(payer.hash).await);
+        assert!(!failed(unpaid.hash).await);
+        assert!(failed(expired.hash).await);
+    }
+
+    #[tokio::test]
+    async fn test_send_minted_claims() {
+        let cashu = test_cashu().await;
+        let mint = Url::from_str("https://mint.example.com").unwrap();
+        let invoice = |claim_id: Option<&str>| {
+            let bolt11 = dry_run_invoice(
+                Some(Amount::from_sat(1000)),
+                "test".to_string(),
+                false,
+                random(),
+                None,
+            )
+            .unwrap();
+            PendingInvoice {
+                mint: mint.clone(),
+                claim_id: claim_id.map(str::to_string),
+                ..test_invoice(bolt11)
+            }
+        };
+
+        // Only an invoice whose success action has a claim url keeps the token claimable
+        for invoice in [invoice(None), invoice(Some("claim"))] {
+            cashu.add_pending_invoice(&invoice).await.unwrap();
+            let token = dry_run_token(&mint, invoice.amount).unwrap();
+            cashu.send_minted(invoice.clone(), token).await.unwrap();
+
+            assert!(cashu
+                .db
+                .get_pending_invoice(&invoice.hash)
+                .await
+                .unwrap()
+                .is_none());
+        }
+        assert!(matches!(
+            cashu.db.claim_token("claim", unix_time()).await.unwrap(),
+            ClaimStatus::Claimed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invoice_user() {
+        let cashu = test_cashu().await;
+        let mint = Url::from_str("https://mint.example.com").unwrap();
+        let bolt11 = dry_run_invoice(
+            Some(Amount::from_sat(1000)),
+            "test".to_string(),
+            false,
+            random(),
+            None,
+        )
+        .unwrap();
+        let invoice = PendingInvoice {
+            mint: mint.clone(),
+            ..test_invoice(bolt11)
+        };
+        assert!(cashu.invoice_user(&invoice).await.unwrap().is_none());
+
+        // Only a user who signed up before the invoice is the one it was made for
+        for (created_at, made_for) in [
+            (None, true),
+            (Some(invoice.time - 1), true),
+            (Some(invoice.time + 1), false),
+        ] {
+            let user = User {
+                created_at,
+                ..test_user(&Keys::generate().public_key().to_string())
+            };
+            cashu
+                .db
+                .add_user("example.com", "alice", &UserKind::User(user))
+                .await
+                .unwrap();
+            assert_eq!(
+                cashu.invoice_user(&invoice).await.unwrap().is_some(),
+                made_for
+            );
+        }
+
+        // The token of a removed user's invoice is dead lettered for the operator
+        cashu.db.remove_user("example.com", "alice").await.unwrap();
+        let token = dry_run_token(&mint, invoice.amount).unwrap();
+        cashu.send_minted(invoice.clone(), token).await.unwrap();
+        let dead_letters = cashu.db.get_dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].hash, invoice.hash);
+        assert_eq!(dead_letters[0].error.as_deref(), Some("User was removed"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_mints() {
+        let mut cashu = test_cashu().await;
+        let small_mint = Url::from_str("https://small.example.com").unwrap();
+        cashu.settings.info.mint_rules = Some(vec![MintRule {
+            max_amount: Amount::from_sat(1000),
+            mint: small_mint.clone(),
+        }]);
+
+        let user = User {
+            fallback_mints: vec![Url::from_str("https://fallback.example.com").unwrap()],
+            ..test_user(&Keys::generate().public_key().to_string())
+        };
+        cashu
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user.clone()))
+            .await
+            .unwrap();
+
+        let bolt11 = dry_run_invoice(None, "test".to_string(), false, random(), None).unwrap();
+        let mut invoice = PendingInvoice {
+            mint: user.mint.clone(),
+            amount: Amount::ZERO,
+            proxied: true,
+            ..test_invoice(bolt11)
+        };
+
+        // The rule for the amount paid comes first, then the user's mints in order
+        assert_eq!(
+            cashu.proxy_mints(&invoice, Amount::from_sat(500)).await,
+            vec![
+                small_mint,
+                user.mint.clone(),
+                user.fallback_mints[0].clone()
+            ]
+        );
+        assert_eq!(
+            cashu.proxy_mints(&invoice, Amount::from_sat(5000)).await,
+            user.mints()
+        );
+
+        // Only the invoice's mint is known once the user is gone
+        invoice.username = "bob".to_string();
+        assert_eq!(
+            cashu.proxy_mints(&invoice, Amount::from_sat(500)).await,
+            vec![user.mint]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_mint_private() {
+        let cashu = test_cashu().await;
+        let mint = Url::from_str("http://127.0.0.1:1").unwrap();
+
+        match cashu.check_mint(&mint, false).await {
+            Err(Error::MintUnavailable(err)) => assert!(err.contains("not a public address")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // Allowed private mints are checked, and fail here as nothing listens
+        match cashu.check_mint(&mint, true).await {
+            Err(Error::MintUnavailable(err)) => assert!(!err.contains("not a public address")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mint_checks_expire() {
+        let mut checks = MintChecks::default();
+        assert_eq!(checks.get("https://mint.example", 100), None);
+
+        checks.insert("https://mint.example", 100, Err("unreachable".to_string()));
+        assert_eq!(
+            checks.get("https://mint.example", 100 + MINT_CHECK_TTL - 1),
+            Some(Err("unreachable".to_string()))
+        );
+        assert_eq!(
+            checks.get("https://mint.example", 100 + MINT_CHECK_TTL),
+            None
+        );
+
+        // Expired checks are dropped when another mint is checked
+        checks.insert("https://other.example", 100 + MINT_CHECK_TTL, Ok(()));
+        assert_eq!(checks.checks.len(), 1);
+
+        // The oldest checks are dropped once full
+        let mut checks = MintChecks::default();
+        checks.insert("https://oldest.example", 100, Ok(()));
+        for i in 0..MAX_MINT_CHECKS {
+            checks.insert(&format!("https://{}.example", i), 101, Ok(()));
+        }
+        assert_eq!(checks.checks.len(), MAX_MINT_CHECKS);
+        assert_eq!(checks.get("https://oldest.example", 101), None);
+        assert_eq!(checks.get("https://0.example", 101), Some(Ok(())));
+    }
+
+    #[test]
+    fn test_token_delivery_backoff() {
+        assert_eq!(token_delivery_backoff(1), 60);
+        assert_eq!(token_delivery_backoff(2), 120);
+        assert_eq!(token_delivery_backoff(6), 1920);
+        assert_eq!(token_delivery_backoff(7), TOKEN_DELIVERY_BACKOFF_MAX);
+        assert_eq!(token_delivery_backoff(u32::MAX), TOKEN_DELIVERY_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn test_fee_calculation() {
+        let amount = Amount::from_sat(1);
+
+        assert_eq!(fee_for_invoice(amount, 0.0, Amount::ZERO), Amount::ZERO);
+
+        let amount = Amount::from_sat(100);
+
+        assert_eq!(
+            fee_for_invoice(amount, 0.01, Amount::ZERO),
+            Amount::from_sat(1)
+        );
+
+        // Base fee is used when greater than the percent
+        assert_eq!(
+            fee_for_invoice(amount, 0.01, Amount::from_sat(5)),
+            Amount::from_sat(5)
+        );
+        assert_eq!(
+            fee_for_invoice(amount, 0.1, Amount::from_sat(5)),
+            Amount::from_sat(10)
+        );
+    }
+
+    #[test]
+    fn test_mint_amount() {
+        let amount = Amount::from_sat(100);
+
+        assert_eq!(
+            mint_amount(amount, Amount::from_sat(10)),
+            Some(Amount::from_sat(90))
+        );
+        assert_eq!(
+            mint_amount(amount, Amount::from_sat(99)),
+            Some(Amount::from_sat(1))
+        );
+        assert_eq!(mint_amount(amount, Amount::from_sat(100)), None);
+        assert_eq!(mint_amount(amount, Amount::from_sat(200)), None);
+    }
+
+    #[test]
+    fn test_min_proxied_amount() {
+        assert_eq!(
+            min_proxied_amount(0.0, Amount::ZERO),
+            Some(Amount::from_sat(1))
+        );
+        assert_eq!(
+            min_proxied_amount(0.0, Amount::from_sat(10)),
+            Some(Amount::from_sat(11))
+        );
+        assert_eq!(
+            min_proxied_amount(0.5, Amount::from_sat(1)),
+            Some(Amount::from_sat(2))
+        );
+        assert_eq!(
+            min_proxied_amount(0.01, Amount::from_sat(10)),
+            Some(Amount::from_sat(11))
+        );
+        assert_eq!(min_proxied_amount(1.0, Amount::ZERO), None);
+
+        for (fee_percent, base_fee) in [(0.0, 0), (0.02, 5), (0.3, 0), (0.9, 2)] {
+            let base_fee = Amount::from_sat(base_fee);
+            let amount = min_proxied_amount(fee_percent, base_fee).unwrap();
+            let fee = fee_for_invoice(amount, fee_percent, base_fee);
+            assert!(mint_amount(amount, fee).is_some());
+            let less = Amount::from_sat(amount.to_sat() - 1);
+            assert!(mint_amount(less, fee_for_invoice(less, fee_percent, base_fee)).is_none());
+        }
+    }
+
+    #[test]
+    fn test_paid_amount() {
+        let amount = Amount::from_sat(100);
+
+        // The invoice amount is minted even if more was received
+        assert_eq!(
+            paid_amount(amount, Some(Amount::from_sat(150))),
+            Some(amount)
+        );
+        assert_eq!(
+            paid_amount(Amount::ZERO, Some(Amount::from_sat(150))),
+            Some(Amount::from_sat(150))
+        );
+        assert_eq!(paid_amount(Amount::ZERO, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_token_delivery_dead_letters() {
+        let mut cashu = test_cashu().await;
+        cashu.settings.info.token_delivery_max_retries = Some(1);
+
+        let delivery = TokenDelivery {
+            hash: "hash".to_string(),
+            payer_hash: None,
+            domain: "example.com".to_string(),
+            username: "alice".to_string(),
+            claim_id: Some("claim".to_string()),
+            pubkey: Keys::generate().public_key().to_string(),
+            token: "cashuA".to_string(),
+            comment: None,
+            payer_data: None,
+            alias: None,
+            relays: HashSet::new(),
+            attempts: 0,
+            next_attempt: 0,
+            error: None,
+            created_at: 100,
+        };
+
+        // There are no relays to reach so the DM is queued
+        assert!(!cashu.deliver_token(delivery).await.unwrap());
+        let queued = cashu.db.get_token_deliveries().await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 1);
+        assert!(queued[0].next_attempt >= unix_time() + TOKEN_DELIVERY_BACKOFF_MIN - 1);
+
+        // Not due yet
+        cashu.retry_due_token_deliveries(unix_time()).await.unwrap();
+        assert_eq!(cashu.db.get_token_deliveries().await.unwrap(), queued);
+        assert!(cashu.db.get_dead_letters().await.unwrap().is_empty());
+
+        // The retry fails too and uses up the retries
+        cashu
+            .retry_due_token_deliveries(queued[0].next_attempt)
+            .await
+            .unwrap();
+        assert!(cashu.db.get_token_deliveries().await.unwrap().is_empty());
+        let dead_letters = cashu.db.get_dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 2);
+        assert_eq!(dead_letters[0].token, "cashuA");
+        assert_eq!(
+            dead_letters[0].error.as_deref(),
+            Some("Could not send event to any relay")
+        );
+    }
+}
@@ -1,6 +1,9 @@
+use std::net::IpAddr;
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::config::{DbBackendKind, SuccessActionKind};
 
 #[derive(Parser)]
 #[command(about = "A service to dm cashu tokens for lnurl address", author = env!("CARGO_PKG_AUTHORS"), version = env!("CARGO_PKG_VERSION"))]
@@ -9,12 +12,51 @@ pub struct CLIArgs {
     pub config: Option<String>,
     #[arg(short, long, help = "Url of service", required = false)]
     pub url: Option<String>,
+    #[arg(
+        long,
+        help = "Domains to serve ln addresses on",
+        action = clap::ArgAction::Append, required = false
+    )]
+    pub domains: Vec<String>,
     #[arg(short, long, help = "Default Mint", required = false)]
     pub mint: Option<String>,
+    #[arg(
+        long,
+        help = "Mints users can sign up with",
+        action = clap::ArgAction::Append, required = false
+    )]
+    pub allowed_mints: Vec<String>,
     #[arg(short, long, help = "Default Invice Description", required = false)]
     pub invoice_description: Option<String>,
-    #[arg(short, long, help = "Nostr Nsec to send dms", required = false)]
+    #[arg(
+        long,
+        help = "Png shown by wallets for addresses without an avatar of their own",
+        required = false
+    )]
+    pub avatar_path: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        env = "CASHU_LNURL_NSEC",
+        help = "Nostr Nsec to send dms",
+        required = false
+    )]
     pub nsec: Option<String>,
+    #[arg(
+        long,
+        env = "CASHU_LNURL_NSEC_FILE",
+        help = "File to read the nsec from, may be age encrypted",
+        required = false
+    )]
+    pub nsec_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "CASHU_LNURL_NSEC_PASSPHRASE",
+        help = "Passphrase of an age encrypted nsec",
+        hide_env_values = true,
+        required = false
+    )]
+    pub nsec_passphrase: Option<String>,
     #[arg(
         short,
         long,
@@ -22,19 +64,54 @@ pub struct CLIArgs {
         action = clap::ArgAction::Append, required = false
     )]
     pub relays: Vec<String>,
+    #[arg(
+        long,
+        help = "Directory of the database and pay index, defaults to cashu-lnurl in XDG_DATA_HOME",
+        required = false
+    )]
+    pub data_dir: Option<PathBuf>,
     #[arg(short, long, help = "Path to Database", required = false)]
     pub db_path: Option<String>,
+    #[arg(long, help = "Database backend", required = false)]
+    pub db_backend: Option<DbBackendKind>,
     #[arg(long, help = "Whether or not to proxy ln invoice", required = false)]
     pub proxy: Option<bool>,
     #[arg(
-        short,
         long,
+        help = "Socks5 proxy to connect to nostr relays through, host:port",
+        required = false
+    )]
+    pub socks_proxy: Option<String>,
+    #[arg(
+        short = 'f',
+        long,
+        alias = "fee",
         help = "Fee to collect to account for routing fee percent as a decimal",
         required = false
     )]
-    pub fee: Option<f32>,
+    pub routing_fee_percent: Option<f32>,
+    #[arg(
+        long,
+        help = "Min fee in sats to collect to account for routing fee",
+        required = false
+    )]
+    pub routing_fee_base_sat: Option<u64>,
     #[arg(long, help = "cln path", required = false)]
     pub cln_path: Option<String>,
+    #[arg(long, help = "LND gRPC url", required = false)]
+    pub lnd_grpc_url: Option<String>,
+    #[arg(long, help = "LND tls cert path", required = false)]
+    pub lnd_cert_path: Option<PathBuf>,
+    #[arg(long, help = "LND macaroon path", required = false)]
+    pub lnd_macaroon_path: Option<PathBuf>,
+    #[arg(long, help = "Nostr Wallet Connect uri", required = false)]
+    pub nwc_uri: Option<String>,
+    #[arg(
+        long,
+        help = "Dry run, invoices pay themselves and minted tokens are fake",
+        required = false
+    )]
+    pub dry_run: Option<bool>,
     #[arg(long, help = "Min Sendable in sats", required = false)]
     pub min_sendable: Option<u64>,
     #[arg(long, help = "Max Sendable in sats", required = false)]
@@ -43,10 +120,49 @@ pub struct CLIArgs {
     pub zapper: Option<bool>,
     #[arg(long, help = "Pay index path", required = false)]
     pub pay_index_path: Option<PathBuf>,
-    #[arg(short, long, help = "Network address to bind", required = false)]
-    pub address: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Network address to bind, an ip or ip:port, repeat to bind several",
+        action = clap::ArgAction::Append,
+        required = false
+    )]
+    pub address: Vec<String>,
     #[arg(long, help = "Network port to bind", required = false)]
     pub port: Option<u16>,
+    #[arg(
+        long,
+        help = "Unix socket to serve the api on instead of address and port, unix:<path>",
+        conflicts_with_all = ["address", "port"],
+        required = false
+    )]
+    pub listen: Option<String>,
+    #[arg(
+        long,
+        help = "Octal permissions of the unix socket file",
+        required = false
+    )]
+    pub socket_mode: Option<String>,
+    #[arg(
+        long,
+        help = "Pem certificate chain to serve https with",
+        required = false
+    )]
+    pub tls_cert_path: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Pem private key of the tls certificate",
+        required = false
+    )]
+    pub tls_key_path: Option<PathBuf>,
+    #[arg(long, help = "Network port to serve metrics on", required = false)]
+    pub metrics_port: Option<u16>,
+    #[arg(
+        long,
+        help = "Reverse proxies to read the client ip from X-Forwarded-For or X-Real-IP behind",
+        action = clap::ArgAction::Append, required = false
+    )]
+    pub trusted_proxies: Vec<IpAddr>,
     #[arg(long, help = "Price for two char or less username", required = false)]
     pub two_char_price: Option<u64>,
     #[arg(long, help = "Price for three char username", required = false)]
@@ -55,4 +171,270 @@ pub struct CLIArgs {
     pub four_char_price: Option<u64>,
     #[arg(long, help = "Price for 5+ char username", required = false)]
     pub other_char_price: Option<u64>,
+    #[arg(
+        long,
+        help = "Seconds a username with a price is held for its invoice to be paid",
+        required = false
+    )]
+    pub signup_expiry_secs: Option<u64>,
+    #[arg(
+        long,
+        help = "Allow deprecated signup via GET query params",
+        required = false
+    )]
+    pub allow_get_signup: Option<bool>,
+    #[arg(long, help = "Serve the built in signup page", required = false)]
+    pub serve_signup_page: Option<bool>,
+    #[arg(long, help = "Refuse signups without an invite code", required = false)]
+    pub signups_disabled: Option<bool>,
+    #[arg(
+        long,
+        help = "User paid when no user has the requested username",
+        required = false
+    )]
+    pub catch_all_user: Option<String>,
+    #[arg(long, help = "Regex usernames must match to sign up", required = false)]
+    pub username_pattern: Option<String>,
+    #[arg(long, help = "Fewest characters in a username", required = false)]
+    pub username_min_length: Option<usize>,
+    #[arg(long, help = "Most characters in a username", required = false)]
+    pub username_max_length: Option<usize>,
+    #[arg(long, help = "Allow non ascii usernames", required = false)]
+    pub allow_unicode_usernames: Option<bool>,
+    #[arg(
+        long,
+        help = "Username that cannot be signed up",
+        action = clap::ArgAction::Append, required = false
+    )]
+    pub reserved_usernames: Vec<String>,
+    #[arg(
+        long,
+        help = "File of usernames that cannot be signed up, one per line",
+        required = false
+    )]
+    pub reserved_usernames_file: Option<PathBuf>,
+    #[arg(long, help = "Serve public user info", required = false)]
+    pub expose_user_info: Option<bool>,
+    #[arg(long, help = "Include relays in public user info", required = false)]
+    pub expose_user_relays: Option<bool>,
+    #[arg(long, help = "Max length of payer comments", required = false)]
+    pub comment_allowed: Option<u32>,
+    #[arg(
+        long,
+        help = "Only create invoices for whole sat amounts",
+        required = false
+    )]
+    pub sat_only: Option<bool>,
+    #[arg(
+        long,
+        help = "Create amountless invoices for requests without an amount, proxied invoices only",
+        required = false
+    )]
+    pub allow_amountless: Option<bool>,
+    #[arg(
+        long,
+        alias = "signup-auth-window",
+        help = "Seconds a signed auth event is valid for",
+        required = false
+    )]
+    pub auth_window: Option<u64>,
+    #[arg(
+        long,
+        help = "Message shown to the payer, {username} is replaced",
+        required = false
+    )]
+    pub success_message: Option<String>,
+    #[arg(long, help = "Success action returned with invoices", required = false)]
+    pub success_action: Option<SuccessActionKind>,
+    #[arg(
+        long,
+        help = "Seconds a minted token can be claimed for",
+        required = false
+    )]
+    pub claim_ttl: Option<u64>,
+    #[arg(
+        long,
+        help = "Seconds between checking the payment of stale proxied invoices",
+        required = false
+    )]
+    pub reconcile_interval: Option<u64>,
+    #[arg(
+        long,
+        help = "Seconds a proxied invoice is pending before its payment is checked",
+        required = false
+    )]
+    pub reconcile_after: Option<u64>,
+    #[arg(
+        long,
+        help = "Send errors with their http status instead of 200",
+        required = false
+    )]
+    pub http_error_status: Option<bool>,
+    #[arg(
+        long,
+        help = "Bearer token of the /admin api and deletion of any user",
+        required = false
+    )]
+    pub admin_token: Option<String>,
+    #[arg(
+        long,
+        help = "Invoice requests allowed per minute from a client ip and to a username",
+        required = false
+    )]
+    pub invoice_rate_limit: Option<u32>,
+    #[arg(
+        long,
+        help = "Seconds LNURL pay responses are cached for, 0 disables caching",
+        required = false
+    )]
+    pub lnurl_cache_ttl: Option<u64>,
+    #[arg(
+        long,
+        help = "Seconds clients may cache LNURL pay responses for, 0 to always revalidate",
+        required = false
+    )]
+    pub lnurl_max_age: Option<u64>,
+    #[arg(
+        long,
+        help = "Retries of a failed webhook post before giving up",
+        required = false
+    )]
+    pub webhook_max_retries: Option<u32>,
+    #[arg(
+        long,
+        help = "Retries of a failed token DM before it is dead lettered",
+        required = false
+    )]
+    pub token_delivery_max_retries: Option<u32>,
+    #[arg(
+        long,
+        help = "Seconds an invoice can be paid for, defaults to the lightning node's",
+        required = false
+    )]
+    pub invoice_expiry_secs: Option<u64>,
+    #[arg(
+        long,
+        help = "Seconds an invoice request waits for the lightning backend or the mints",
+        required = false
+    )]
+    pub upstream_timeout_secs: Option<u64>,
+    #[arg(
+        long,
+        help = "Signups allowed per minute from a client ip",
+        required = false
+    )]
+    pub signup_rate_limit: Option<u32>,
+    #[arg(
+        long,
+        help = "Signups allowed per minute from all clients",
+        required = false
+    )]
+    pub signup_global_rate_limit: Option<u32>,
+    #[arg(
+        long,
+        help = "Accounts that can be created per UTC day",
+        required = false
+    )]
+    pub signup_daily_limit: Option<u32>,
+    #[arg(
+        long,
+        help = "Origins allowed to sign up and change users from a browser, * for any",
+        action = clap::ArgAction::Append, required = false
+    )]
+    pub cors_origins: Vec<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Operator commands run instead of the service
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage pending invoices
+    Invoices {
+        #[command(subcommand)]
+        command: InvoicesCommand,
+    },
+    /// Inspect minted tokens that could not be DMed
+    Tokens {
+        #[command(subcommand)]
+        command: TokensCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InvoicesCommand {
+    /// Re-check stuck invoices against the lightning backend and mint and resolve them
+    Retry {
+        #[arg(
+            long,
+            help = "Payment hash of the invoice to retry",
+            conflicts_with = "all",
+            required_unless_present = "all"
+        )]
+        hash: Option<String>,
+        #[arg(long, help = "Retry every pending invoice, including failed ones")]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokensCommand {
+    /// List tokens dead lettered after every retry of their DM failed
+    DeadLetters,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invoices_retry() {
+        let args =
+            CLIArgs::try_parse_from(["cashu-lnurl", "config.toml", "invoices", "retry", "--all"])
+                .unwrap();
+        assert_eq!(args.config.as_deref(), Some("config.toml"));
+        assert!(matches!(
+            args.command,
+            Some(Command::Invoices {
+                command: InvoicesCommand::Retry {
+                    hash: None,
+                    all: true
+                }
+            })
+        ));
+
+        let args =
+            CLIArgs::try_parse_from(["cashu-lnurl", "invoices", "retry", "--hash", "abc"]).unwrap();
+        assert!(args.config.is_none());
+        assert!(matches!(
+            args.command,
+            Some(Command::Invoices {
+                command: InvoicesCommand::Retry { hash: Some(hash), all: false }
+            }) if hash == "abc"
+        ));
+
+        assert!(CLIArgs::try_parse_from(["cashu-lnurl", "invoices", "retry"]).is_err());
+        assert!(CLIArgs::try_parse_from([
+            "cashu-lnurl",
+            "invoices",
+            "retry",
+            "--all",
+            "--hash",
+            "abc"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_tokens_dead_letters() {
+        let args =
+            CLIArgs::try_parse_from(["cashu-lnurl", "config.toml", "tokens", "dead-letters"])
+                .unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Tokens {
+                command: TokensCommand::DeadLetters
+            })
+        ));
+    }
 }
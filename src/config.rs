@@ -27,25 +27,90 @@ THE SOFTWARE.
 */
 
 use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use cashu_sdk::Amount;
+use clap::ValueEnum;
 use config::{Config, ConfigError, File};
+use nostr_sdk::nips::nip19::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::SecretKey;
+use nostr_sdk::{Keys, Url};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
+use crate::secrets::Secret;
+use crate::types::MintRule;
+
+/// Success action returned with invoices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SuccessActionKind {
+    /// Message with the success message
+    #[default]
+    Message,
+    /// Url to the claim page of the token
+    Url,
+    /// Claim page url encrypted with the payment preimage, as a url for non proxied invoices
+    Aes,
+    /// No success action
+    None,
+}
+
+/// Database used to store users and invoices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackendKind {
+    #[default]
+    Redb,
+    Sqlite,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Info {
     pub url: String,
-    pub nostr_nsec: Option<String>,
+    /// Domains ln addresses are served on
+    pub domains: HashSet<String>,
+    /// Only read at startup, the settings shared with the service never hold it
+    #[serde(skip_serializing)]
+    pub nostr_nsec: Option<Secret>,
+    /// File the nsec is read from when `nostr_nsec` is not set
+    pub nostr_nsec_file: Option<PathBuf>,
     pub relays: HashSet<String>,
     pub mint: String,
+    /// Mints users can sign up with, any mint is allowed when unset
+    pub allowed_mints: Option<HashSet<String>>,
+    /// Mints picked by invoice amount for users without a matching rule of their own
+    pub mint_rules: Option<Vec<MintRule>>,
     pub invoice_description: Option<String>,
+    /// Png wallets show for addresses whose user has not set an avatar
+    pub avatar_path: Option<PathBuf>,
     pub proxy: bool,
-    pub fee: Option<f32>,
+    /// Socks5 proxy nostr relays are connected through, `host:port` or a `socks5h://` url
+    pub socks_proxy: Option<String>,
+    /// Fee kept from proxied payments as a decimal percent
+    #[serde(alias = "fee")]
+    pub routing_fee_percent: Option<f32>,
+    /// Min fee in sats kept from proxied payments
+    pub routing_fee_base_sat: Option<u64>,
     pub cln_path: Option<String>,
+    /// LND gRPC url, cannot be used with `cln_path`
+    pub lnd_grpc_url: Option<String>,
+    pub lnd_cert_path: Option<PathBuf>,
+    pub lnd_macaroon_path: Option<PathBuf>,
+    /// `nostr+walletconnect://` uri of a remote wallet, instead of a CLN or LND node
+    ///
+    /// It holds the wallet's secret, so as `nostr_nsec` it is only read at startup
+    #[serde(skip_serializing)]
+    pub nwc_uri: Option<Secret>,
+    /// Settle invoices and mint tokens with fakes instead of a lightning backend and mints
+    pub dry_run: Option<bool>,
     pub zapper: Option<bool>,
+    /// Directory of the database and pay index when their paths are not set
+    pub data_dir: Option<PathBuf>,
     pub db_path: Option<String>,
+    pub db_backend: Option<DbBackendKind>,
     pub pay_index_path: Option<PathBuf>,
     pub min_sendable: Option<Amount>,
     pub max_sendable: Option<Amount>,
@@ -53,12 +118,183 @@ pub struct Info {
     pub three_char_cost: Option<Amount>,
     pub four_char_cost: Option<Amount>,
     pub other_char_cost: Option<Amount>,
+    /// Seconds a username with a cost is held for its invoice to be paid
+    pub signup_expiry_secs: Option<u64>,
+    pub allow_get_signup: Option<bool>,
+    /// Serve the built in signup page on `/`
+    pub serve_signup_page: Option<bool>,
+    /// Refuse signups without an invite code, users can still be added by operators
+    pub signups_disabled: Option<bool>,
+    /// User invoices are created for when no user has the requested username
+    pub catch_all_user: Option<String>,
+    /// Regex usernames must match to sign up
+    pub username_pattern: Option<String>,
+    /// Fewest characters in a username
+    pub username_min_length: Option<usize>,
+    /// Most characters in a username
+    pub username_max_length: Option<usize>,
+    /// Allow non ascii characters in usernames
+    pub allow_unicode_usernames: Option<bool>,
+    /// Usernames that cannot be signed up, on top of the built in ones like admin
+    pub reserved_usernames: Option<HashSet<String>>,
+    /// File of reserved usernames, one per line, read at startup
+    pub reserved_usernames_file: Option<PathBuf>,
+    /// Serve public user info on `/users/:username`
+    pub expose_user_info: Option<bool>,
+    /// Include relays in public user info
+    pub expose_user_relays: Option<bool>,
+    pub comment_allowed: Option<u32>,
+    /// Reject msat amounts even when the invoice is proxied
+    pub sat_only: Option<bool>,
+    /// Create amountless invoices for requests without an amount, proxied invoices only
+    pub allow_amountless: Option<bool>,
+    #[serde(alias = "signup_auth_window")]
+    pub auth_window: Option<u64>,
+    /// Success action message template, `{username}` is replaced
+    pub success_message: Option<String>,
+    pub success_action: Option<SuccessActionKind>,
+    /// Seconds a minted token can be claimed for
+    pub claim_ttl: Option<u64>,
+    /// Seconds between checking the payment of stale proxied invoices
+    pub reconcile_interval: Option<u64>,
+    /// Seconds a proxied invoice is pending before its payment is checked
+    pub reconcile_after: Option<u64>,
+    /// Send errors with their http status, otherwise `200` as LNURL expects
+    pub http_error_status: Option<bool>,
+    /// Bearer token of the `/admin` api, which is hidden when unset
+    ///
+    /// Also allows operators to delete any user
+    pub admin_token: Option<String>,
+    /// Invoice requests allowed per minute from a client ip and to a username
+    pub invoice_rate_limit: Option<u32>,
+    /// Seconds LNURL pay responses are cached for, `0` disables caching
+    pub lnurl_cache_ttl: Option<u64>,
+    /// Seconds clients may cache LNURL pay responses for, `0` to always revalidate
+    pub lnurl_max_age: Option<u64>,
+    /// Retries of a failed webhook post before giving up
+    pub webhook_max_retries: Option<u32>,
+    /// Retries of a failed token DM before it is dead lettered
+    pub token_delivery_max_retries: Option<u32>,
+    /// Seconds a proxied invoice can be paid for, the lightning node's default when unset
+    pub invoice_expiry_secs: Option<u64>,
+    /// Seconds an invoice request waits for the lightning backend or the mints
+    pub upstream_timeout_secs: Option<u64>,
+    /// Signups allowed per minute from a client ip
+    pub signup_rate_limit: Option<u32>,
+    /// Signups allowed per minute from all clients
+    pub signup_global_rate_limit: Option<u32>,
+    /// Accounts that can be created per UTC day
+    pub signup_daily_limit: Option<u32>,
+    /// Origins allowed to sign up and change users from a browser, `*` for any
+    ///
+    /// Any origin can make read only requests
+    pub cors_origins: Option<HashSet<String>>,
+}
+
+/// Normalize a mint url so urls only differing by a trailing slash or case match
+///
+/// Urls without a scheme are assumed to be `https`
+pub fn normalize_mint_url(mint: &str) -> anyhow::Result<String> {
+    let mint = mint.trim();
+    let url = if mint.contains("://") {
+        Url::parse(mint)?
+    } else {
+        Url::parse(&format!("https://{}", mint))?
+    };
+
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// Address of a socks5 proxy given as `host:port` or a `socks5://` or `socks5h://` url
+///
+/// Hostnames are always resolved by the proxy, so both schemes behave as `socks5h`
+pub fn socks_proxy_addr(proxy: &str) -> anyhow::Result<SocketAddr> {
+    let proxy = proxy.trim();
+    let addr = proxy
+        .strip_prefix("socks5h://")
+        .or_else(|| proxy.strip_prefix("socks5://"))
+        .unwrap_or(proxy)
+        .trim_end_matches('/');
+
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Could not resolve socks proxy {}", proxy))
+}
+
+/// Keys of an `nsec1` bech32 or hex secret key
+///
+/// `field` names where the key was set in errors, which never include the key itself
+pub fn parse_nsec(field: &str, nsec: &str) -> anyhow::Result<Keys> {
+    let secret_key = if nsec.starts_with("nsec1") {
+        SecretKey::from_bech32(nsec)
+            .map_err(|err| anyhow::anyhow!("{} is not a valid nsec: {}", field, err))?
+    } else {
+        SecretKey::from_str(nsec).map_err(|_| {
+            anyhow::anyhow!(
+                "{} must be an nsec1 bech32 or 64 char hex secret key",
+                field
+            )
+        })?
+    };
+
+    Ok(Keys::new(secret_key))
+}
+
+/// Bech32 `npub1` of the public key of `keys`
+pub fn get_npub(keys: &Keys) -> anyhow::Result<String> {
+    Ok(keys.public_key().to_bech32()?)
+}
+
+/// Mint is on the normalized allow list, or there is no allow list
+pub fn mint_allowed(allowed_mints: &Option<HashSet<String>>, mint: &Url) -> bool {
+    match allowed_mints {
+        Some(allowed_mints) => allowed_mints.contains(mint.as_str().trim_end_matches('/')),
+        None => true,
+    }
+}
+
+/// Ips or CIDR networks, such as `10.8.0.0/24`, allowed or denied
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct IpRulesConfig {
+    /// Only these are allowed when set
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+/// Ip rules shared by every route group, a group's own lists replace them for the group
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct IpAccessConfig {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    /// LNURL, user and claim routes
+    pub public: Option<IpRulesConfig>,
+    /// Signup and the signup page
+    pub signup: Option<IpRulesConfig>,
+    /// `/admin` and the user management routes
+    pub admin: Option<IpRulesConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Network {
     pub port: u16,
+    /// Ip to bind on `port`, such as `0.0.0.0` or `::`, or an ip and port such as `[::]:8080`
     pub address: String,
+    /// Addresses to bind instead of `address`, to listen on several such as IPv4 and IPv6
+    pub addresses: Option<Vec<String>>,
+    /// `unix:<path>` of a unix socket to serve the api on, instead of `address` and `port`
+    pub listen: Option<String>,
+    /// Octal permissions of the unix socket file, such as `660`
+    pub socket_mode: Option<String>,
+    /// Pem certificate chain to serve https with, reloaded when it changes
+    pub tls_cert_path: Option<PathBuf>,
+    /// Pem private key of `tls_cert_path`
+    pub tls_key_path: Option<PathBuf>,
+    /// Serve `/metrics` on this port instead of `port`
+    pub metrics_port: Option<u16>,
+    /// Reverse proxies the client ip is read from `X-Forwarded-For` or `X-Real-IP` behind
+    pub trusted_proxies: Option<HashSet<IpAddr>>,
+    /// Client ips allowed or denied for each group of routes
+    pub ip_access: Option<IpAccessConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -109,3 +345,82 @@ impl Settings {
         Ok(settings)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::secp256k1::XOnlyPublicKey;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize_mint_url() {
+        let normalized = normalize_mint_url("https://mint.example").unwrap();
+        assert_eq!(normalized, "https://mint.example");
+        assert_eq!(
+            normalize_mint_url("https://mint.example/").unwrap(),
+            normalized
+        );
+        assert_eq!(
+            normalize_mint_url("HTTPS://Mint.Example").unwrap(),
+            normalized
+        );
+        assert_eq!(normalize_mint_url("mint.example").unwrap(), normalized);
+
+        assert_eq!(
+            normalize_mint_url("http://mint.example:3338/cashu/").unwrap(),
+            "http://mint.example:3338/cashu"
+        );
+    }
+
+    #[test]
+    fn test_socks_proxy_addr() {
+        let addr: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+        assert_eq!(socks_proxy_addr("127.0.0.1:9050").unwrap(), addr);
+        assert_eq!(socks_proxy_addr("socks5h://127.0.0.1:9050/").unwrap(), addr);
+        assert_eq!(socks_proxy_addr("socks5://127.0.0.1:9050").unwrap(), addr);
+        assert!(socks_proxy_addr("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_mint_allowed() {
+        let mint = Url::from_str("https://mint.example/").unwrap();
+        assert!(mint_allowed(&None, &mint));
+
+        let allowed_mints = Some(HashSet::from([normalize_mint_url("mint.example").unwrap()]));
+        assert!(mint_allowed(&allowed_mints, &mint));
+
+        let mint = Url::from_str("https://other.example").unwrap();
+        assert!(!mint_allowed(&allowed_mints, &mint));
+    }
+
+    #[test]
+    fn test_parse_nsec() {
+        let nsec = "nsec1j4c6269y9w0q2er2xjw8sv2ehyrtfxq3jwgdlxj6qfn8z4gjsq5qfvfk99";
+        let keys = parse_nsec("nostr_nsec", nsec).unwrap();
+        assert_eq!(keys.secret_key().unwrap().to_bech32().unwrap(), nsec);
+
+        // The same key as hex
+        let hex = keys.secret_key().unwrap().display_secret().to_string();
+        let hex_keys = parse_nsec("nostr_nsec", &hex).unwrap();
+        assert_eq!(hex_keys.public_key(), keys.public_key());
+
+        let npub = get_npub(&keys).unwrap();
+        assert!(npub.starts_with("npub1"));
+        assert_eq!(
+            XOnlyPublicKey::from_bech32(&npub).unwrap(),
+            keys.public_key()
+        );
+
+        // Errors name the field without the key
+        let err = parse_nsec("nostr_nsec_file", "nsec1invalid").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("nostr_nsec_file is not a valid nsec"));
+        let err = parse_nsec("nostr_nsec", "abcd").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "nostr_nsec must be an nsec1 bech32 or 64 char hex secret key"
+        );
+        assert!(parse_nsec("nostr_nsec", &get_npub(&keys).unwrap()).is_err());
+    }
+}
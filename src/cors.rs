@@ -0,0 +1,134 @@
+//! CORS for browser wallets and signup pages on other origins
+
+use std::collections::HashSet;
+
+use axum::http::header::{ACCESS_CONTROL_REQUEST_METHOD, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::request_id::REQUEST_ID_HEADER;
+
+/// Origin allowing any origin in the configured origins
+pub const ANY_ORIGIN: &str = "*";
+
+/// CORS allowing any origin to read and only `origins` to make changes
+///
+/// Reads are `GET` and `HEAD` requests, which covers the LNURL endpoints. Other requests such
+/// as signup get no CORS headers unless their origin is configured or `*` is.
+pub fn cors_layer(origins: Option<HashSet<String>>) -> CorsLayer {
+    let origins: HashSet<String> = origins
+        .unwrap_or_default()
+        .into_iter()
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .collect();
+
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, parts| {
+            origin_allowed(&origins, origin, parts)
+        }))
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .expose_headers([RETRY_AFTER, HeaderName::from_static(REQUEST_ID_HEADER)])
+}
+
+/// Method of a request, or the method a preflight request asks to use
+fn request_method(parts: &Parts) -> Option<Method> {
+    if parts.method == Method::OPTIONS {
+        parts
+            .headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+    } else {
+        Some(parts.method.clone())
+    }
+}
+
+fn origin_allowed(origins: &HashSet<String>, origin: &HeaderValue, parts: &Parts) -> bool {
+    let read_only = request_method(parts).map_or(false, |method| {
+        method == Method::GET || method == Method::HEAD
+    });
+
+    read_only
+        || origins.contains(ANY_ORIGIN)
+        || origin
+            .to_str()
+            .map_or(false, |origin| origins.contains(origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN};
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router(origins: &[&str]) -> Router {
+        Router::new()
+            .route("/lnurlp/alice", get(|| async { "lnurl" }))
+            .route("/signup", axum::routing::post(|| async { "signup" }))
+            .layer(cors_layer(Some(
+                origins.iter().map(|origin| origin.to_string()).collect(),
+            )))
+    }
+
+    async fn allow_origin(router: Router, request: Request<Body>) -> Option<HeaderValue> {
+        let response = router.oneshot(request).await.unwrap();
+        assert!(response.status().is_success());
+        response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).cloned()
+    }
+
+    fn preflight(method: &str, origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/signup")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cross_origin_get() {
+        let request = Request::builder()
+            .uri("/lnurlp/alice")
+            .header(ORIGIN, "https://wallet.example")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            allow_origin(router(&[]), request).await,
+            Some(HeaderValue::from_static("https://wallet.example"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signup_origins() {
+        // Mutating requests are not allowed cross origin by default
+        assert_eq!(
+            allow_origin(router(&[]), preflight("POST", "https://signup.example")).await,
+            None
+        );
+
+        let signup = router(&["https://signup.example/"]);
+        assert_eq!(
+            allow_origin(signup.clone(), preflight("POST", "https://signup.example")).await,
+            Some(HeaderValue::from_static("https://signup.example"))
+        );
+        assert_eq!(
+            allow_origin(signup, preflight("POST", "https://other.example")).await,
+            None
+        );
+
+        assert!(allow_origin(
+            router(&[ANY_ORIGIN]),
+            preflight("POST", "https://other.example")
+        )
+        .await
+        .is_some());
+    }
+}
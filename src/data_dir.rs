@@ -0,0 +1,170 @@
+//! Where the database and the files kept next to it are stored
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tracing::info;
+
+use crate::config::DbBackendKind;
+
+/// Name of the data dir under the user's data home
+const APP_DIR: &str = "cashu-lnurl";
+
+/// Directory under the user's data home the pay index file used to be kept in
+const LEGACY_PAY_INDEX_DIR: &str = "cln-zapper";
+
+/// File the last pay index tip was kept in before it moved to the database
+const PAY_INDEX_FILE: &str = "last_pay_index";
+
+/// User's data home, `XDG_DATA_HOME` when set to an absolute path or the platform's data dir
+fn data_home(xdg_data_home: Option<OsString>) -> Option<PathBuf> {
+    xdg_data_home
+        .map(PathBuf::from)
+        // Relative paths are invalid per the XDG spec and ignored
+        .filter(|path| path.is_absolute())
+        .or_else(dirs::data_dir)
+}
+
+/// Paths of the files kept in the data dir
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    path: PathBuf,
+    /// Data home files were kept directly under before there was a data dir, checked for
+    /// files missing from the default data dir
+    legacy_home: Option<PathBuf>,
+}
+
+impl DataDir {
+    /// Configured data dir, or `cashu-lnurl` under the user's data home
+    pub fn new(path: Option<PathBuf>) -> Result<Self> {
+        match path {
+            Some(path) => Ok(Self {
+                path,
+                legacy_home: None,
+            }),
+            None => {
+                let home = data_home(std::env::var_os("XDG_DATA_HOME"))
+                    .ok_or_else(|| anyhow!("Could not get data dir"))?;
+
+                Ok(Self {
+                    path: home.join(APP_DIR),
+                    legacy_home: dirs::data_dir(),
+                })
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Database of the backend, at its old location when only that one exists
+    pub fn db_path(&self, backend: DbBackendKind) -> PathBuf {
+        let (file, legacy_file) = match backend {
+            DbBackendKind::Redb => ("cashu-lnurl.redb", APP_DIR.to_string()),
+            DbBackendKind::Sqlite => ("cashu-lnurl.sqlite", format!("{}.sqlite", APP_DIR)),
+        };
+
+        self.existing_or_legacy(self.path.join(file), |home| home.join(legacy_file))
+    }
+
+    /// Pay index file to import, at its old location when only that one exists
+    pub fn pay_index_path(&self) -> PathBuf {
+        self.existing_or_legacy(self.path.join(PAY_INDEX_FILE), |home| {
+            home.join(LEGACY_PAY_INDEX_DIR).join(PAY_INDEX_FILE)
+        })
+    }
+
+    fn existing_or_legacy(&self, path: PathBuf, legacy: impl FnOnce(&Path) -> PathBuf) -> PathBuf {
+        if path.exists() {
+            return path;
+        }
+
+        match self.legacy_home.as_deref().map(legacy) {
+            // The old redb file has the name of the data dir, so check it is a file
+            Some(legacy) if legacy.is_file() => {
+                info!(
+                    "Using {:?} from before the data dir, move it to {:?}",
+                    legacy, path
+                );
+                legacy
+            }
+            _ => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_data_home() {
+        assert_eq!(
+            data_home(Some("/xdg/data".into())),
+            Some(PathBuf::from("/xdg/data"))
+        );
+        assert_eq!(data_home(Some("relative".into())), dirs::data_dir());
+        assert_eq!(data_home(None), dirs::data_dir());
+    }
+
+    #[test]
+    fn test_data_dir_paths() {
+        let home = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(Uuid::new_v4().to_string());
+        let data_dir = DataDir {
+            path: home.join(APP_DIR),
+            legacy_home: Some(home.clone()),
+        };
+
+        // Nothing exists yet so the new locations are used
+        assert_eq!(
+            data_dir.db_path(DbBackendKind::Redb),
+            home.join("cashu-lnurl").join("cashu-lnurl.redb")
+        );
+        assert_eq!(
+            data_dir.pay_index_path(),
+            home.join("cashu-lnurl").join("last_pay_index")
+        );
+
+        // Files left where they were kept before
+        fs::create_dir_all(home.join("cln-zapper")).unwrap();
+        fs::write(home.join("cashu-lnurl"), b"redb").unwrap();
+        fs::write(home.join("cashu-lnurl.sqlite"), b"sqlite").unwrap();
+        fs::write(home.join("cln-zapper").join("last_pay_index"), b"index").unwrap();
+        assert_eq!(
+            data_dir.db_path(DbBackendKind::Redb),
+            home.join("cashu-lnurl")
+        );
+        assert_eq!(
+            data_dir.db_path(DbBackendKind::Sqlite),
+            home.join("cashu-lnurl.sqlite")
+        );
+        assert_eq!(
+            data_dir.pay_index_path(),
+            home.join("cln-zapper").join("last_pay_index")
+        );
+
+        // The new location wins once it exists
+        fs::remove_file(home.join("cashu-lnurl")).unwrap();
+        fs::create_dir_all(data_dir.path()).unwrap();
+        fs::write(data_dir.path().join("last_pay_index"), b"index").unwrap();
+        assert_eq!(
+            data_dir.pay_index_path(),
+            home.join("cashu-lnurl").join("last_pay_index")
+        );
+
+        // Configured data dirs do not fall back to the old locations
+        let configured = DataDir::new(Some(home.join("configured"))).unwrap();
+        assert_eq!(
+            configured.db_path(DbBackendKind::Sqlite),
+            home.join("configured").join("cashu-lnurl.sqlite")
+        );
+    }
+}
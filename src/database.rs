@@ -1,30 +1,372 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
-use redb::{Database, ReadableTable, TableDefinition};
-use tokio::sync::Mutex;
-use tracing::warn;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use redb::{Database, ReadableTable, Table, TableDefinition};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+use tracing::{info, warn};
 
-use crate::types::{PendingInvoice, PendingUser, User, UserKind};
+use crate::config::DbBackendKind;
+use crate::sqlite::SqliteDb;
+use crate::types::{
+    normalize_username, user_key, Claim, ClaimStatus, InviteCode, InviteStatus, PayerInvoice,
+    PaymentRecord, PendingInvoice, PendingUser, TokenDelivery, User, UserKind, WebhookDelivery,
+};
+
+/// Database shared by the service
+pub type Db = Arc<dyn DbBackend>;
+
+/// Criteria pending invoices must all match to be found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingInvoiceFilter {
+    pub username: Option<String>,
+    /// Only invoices created at or before this unix time
+    pub created_before: Option<u64>,
+}
+
+impl PendingInvoiceFilter {
+    pub fn matches(&self, invoice: &PendingInvoice) -> bool {
+        self.username.as_ref().map_or(true, |username| {
+            invoice.username == normalize_username(username)
+        }) && self
+            .created_before
+            .map_or(true, |created_before| invoice.time <= created_before)
+    }
+}
+
+/// Criteria payment records must all match to be found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PaymentRecordFilter {
+    pub username: Option<String>,
+    /// Only payments made at or after this unix time
+    pub from: Option<u64>,
+    /// Only payments made before this unix time
+    pub to: Option<u64>,
+}
+
+impl PaymentRecordFilter {
+    pub fn matches(&self, record: &PaymentRecord) -> bool {
+        self.username.as_ref().map_or(true, |username| {
+            record.username == normalize_username(username)
+        }) && self.from.map_or(true, |from| record.paid_at >= from)
+            && self.to.map_or(true, |to| record.paid_at < to)
+    }
+}
+
+/// Storage of users, invoices and claims
+#[async_trait]
+pub trait DbBackend: Debug + Send + Sync {
+    /// Check the database can be read
+    async fn ping(&self) -> Result<()>;
+
+    /// Wait for in flight transactions and close the database
+    ///
+    /// Any later call returns an error so nothing is written after shutdown
+    async fn close(&self) -> Result<()>;
+
+    async fn add_fee_paid(&self, payment_hash: &str, fee_msat: u64) -> Result<()>;
+
+    async fn add_fee_received(&self, payment_hash: &str, fee_msat: u64) -> Result<()>;
+
+    async fn add_user(&self, domain: &str, username: &str, user: &UserKind) -> Result<()>;
+
+    async fn get_user(&self, domain: &str, username: &str) -> Result<Option<UserKind>>;
+
+    /// Replace an active user, returning `false` if there is no active user to update
+    async fn update_user(&self, domain: &str, username: &str, user: &User) -> Result<bool>;
+
+    async fn get_all_users(&self) -> Result<Vec<User>>;
+
+    /// Page of active users ordered by ln address, and the total number of active users
+    async fn get_users(&self, offset: usize, limit: usize) -> Result<(Vec<User>, usize)>;
+
+    async fn get_pending_users(&self) -> Result<Vec<PendingUser>>;
+
+    async fn delete_user(&self, domain: &str, username: &str) -> Result<()>;
+
+    /// Remove an active user
+    ///
+    /// Their pending invoices are kept until they are settled or expire, as some may have been
+    /// paid. Returns them, or `None` if there is no active user to remove
+    async fn remove_user(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Option<Vec<PendingInvoice>>>;
+
+    async fn get_user_pending_invoices(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Vec<PendingInvoice>>;
+
+    async fn add_pending_invoice(&self, hash: &str, invoice: &PendingInvoice) -> Result<()>;
+
+    async fn get_pending_invoice(&self, hash: &str) -> Result<Option<PendingInvoice>>;
+
+    /// Most recent pending invoice created for the idempotency key
+    async fn get_pending_invoice_by_key(&self, key: &str) -> Result<Option<PendingInvoice>>;
+
+    async fn get_pending_invoices(&self) -> Result<Vec<PendingInvoice>>;
+
+    /// Pending invoices matching `filter`, oldest first
+    async fn find_pending_invoices(
+        &self,
+        filter: &PendingInvoiceFilter,
+    ) -> Result<Vec<PendingInvoice>>;
+
+    async fn remove_pending_invoice(&self, hash: &str) -> Result<()>;
+
+    /// Replace a paid pending invoice with the invoice it is minted through
+    ///
+    /// The pay index it was paid at is recorded in the same transaction, so a restart either
+    /// sees the payment again or finds the replacement. The pay index is never moved back.
+    async fn replace_pending_invoice(
+        &self,
+        paid_hash: &str,
+        invoice: &PendingInvoice,
+        pay_index: Option<u64>,
+    ) -> Result<()>;
+
+    async fn add_payer_invoice(&self, hash: &str, invoice: &PayerInvoice) -> Result<()>;
+
+    async fn get_payer_invoice(&self, hash: &str) -> Result<Option<PayerInvoice>>;
+
+    /// Mark a payer invoice as paid
+    ///
+    /// Hashes that were not handed to a payer, such as mint invoices paid by a proxy, are ignored
+    async fn settle_payer_invoice(&self, hash: &str, preimage: Option<String>) -> Result<()>;
+
+    async fn add_claim(&self, id: &str, claim: &Claim) -> Result<()>;
+
+    /// Take the token of a claim so it can only be claimed once
+    async fn claim_token(&self, id: &str, now: u64) -> Result<ClaimStatus>;
+
+    /// Remove claims that expired before `now`, returning how many were removed
+    async fn remove_expired_claims(&self, now: u64) -> Result<usize>;
+
+    async fn add_invite_code(&self, invite: &InviteCode) -> Result<()>;
+
+    /// Invite codes ordered by code
+    async fn get_invite_codes(&self) -> Result<Vec<InviteCode>>;
+
+    async fn get_invite_code(&self, code: &str) -> Result<Option<InviteCode>>;
+
+    /// Count a use of an invite code, in one transaction so a use is never counted twice
+    async fn use_invite_code(&self, code: &str, now: u64) -> Result<InviteStatus>;
+
+    /// Remove an invite code, returning `false` if there was none
+    async fn remove_invite_code(&self, code: &str) -> Result<bool>;
+
+    /// Record a paid invoice, replacing an earlier record of it
+    async fn add_payment_record(&self, record: &PaymentRecord) -> Result<()>;
+
+    async fn get_payment_record(&self, hash: &str) -> Result<Option<PaymentRecord>>;
+
+    /// Payment records matching `filter`, oldest first
+    async fn find_payment_records(
+        &self,
+        filter: &PaymentRecordFilter,
+    ) -> Result<Vec<PaymentRecord>>;
+
+    /// Up to `limit` payment records matching `filter` that come after the paid time and hash
+    /// of `after`, oldest first, so all of them can be read a page at a time
+    async fn find_payment_records_page(
+        &self,
+        filter: &PaymentRecordFilter,
+        after: Option<(u64, String)>,
+        limit: usize,
+    ) -> Result<Vec<PaymentRecord>>;
+
+    /// Up to `limit` payment records of a user that come after the paid time and hash of
+    /// `after`, oldest first
+    async fn get_user_payment_records_page(
+        &self,
+        domain: &str,
+        username: &str,
+        after: Option<(u64, String)>,
+        limit: usize,
+    ) -> Result<Vec<PaymentRecord>>;
+
+    /// Record the latest attempt to post a paid invoice to a webhook, replacing earlier ones
+    async fn add_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()>;
+
+    async fn get_webhook_delivery(&self, hash: &str) -> Result<Option<WebhookDelivery>>;
+
+    /// Queue a token to be DMed again, replacing an earlier attempt at it
+    async fn add_token_delivery(&self, delivery: &TokenDelivery) -> Result<()>;
+
+    /// Queued tokens, soonest next attempt first
+    async fn get_token_deliveries(&self) -> Result<Vec<TokenDelivery>>;
+
+    async fn remove_token_delivery(&self, hash: &str) -> Result<()>;
+
+    /// Move a token from the queue to the dead letters in one transaction
+    async fn dead_letter_token_delivery(&self, delivery: &TokenDelivery) -> Result<()>;
+
+    /// Tokens that could not be DMed, oldest first
+    async fn get_dead_letters(&self) -> Result<Vec<TokenDelivery>>;
+
+    /// Tokens of a user that are queued or dead lettered, oldest first
+    async fn get_user_token_deliveries(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Vec<TokenDelivery>>;
+
+    /// Pay index of the last invoice paid to the lightning backend, `None` if never set
+    async fn get_pay_index(&self) -> Result<Option<u64>>;
+
+    async fn set_pay_index(&self, pay_index: u64) -> Result<()>;
+}
+
+/// Users to rename to their normalized username, as domain, username and normalized username
+///
+/// Fails if two users of a domain have the same normalized username, as one of them would be
+/// lost. They have to be renamed by hand before the service can start.
+pub(crate) fn usernames_to_normalize(
+    users: &[(String, String)],
+) -> Result<Vec<(String, String, String)>> {
+    let mut normalized: HashMap<(&str, String), Vec<&str>> = HashMap::new();
+    for (domain, username) in users {
+        normalized
+            .entry((domain.as_str(), normalize_username(username)))
+            .or_default()
+            .push(username.as_str());
+    }
+
+    if let Some(((domain, normalized_username), usernames)) = normalized
+        .iter()
+        .find(|(_normalized, usernames)| usernames.len() > 1)
+    {
+        bail!(
+            "Users {:?} on {} would all become {}, rename all but one of them",
+            usernames,
+            domain,
+            normalized_username
+        );
+    }
+
+    Ok(normalized
+        .into_iter()
+        .map(|((domain, normalized_username), usernames)| {
+            (
+                domain.to_string(),
+                usernames[0].to_string(),
+                normalized_username,
+            )
+        })
+        .filter(|(_domain, username, normalized_username)| username != normalized_username)
+        .collect())
+}
+
+/// User with its stored username normalized
+pub(crate) fn normalize_user(user: UserKind) -> UserKind {
+    match user {
+        UserKind::User(mut user) => {
+            user.username = normalize_username(&user.username);
+            UserKind::User(user)
+        }
+        UserKind::Pending(mut pending_user) => {
+            pending_user.user.username = normalize_username(&pending_user.user.username);
+            UserKind::Pending(pending_user)
+        }
+        user => user,
+    }
+}
+
+/// Open the configured database backend
+pub async fn open(backend: DbBackendKind, path: PathBuf, primary_domain: &str) -> Result<Db> {
+    let db: Db = match backend {
+        DbBackendKind::Redb => Arc::new(RedbDb::new(path, primary_domain).await?),
+        DbBackendKind::Sqlite => Arc::new(SqliteDb::new(path).await?),
+    };
+
+    Ok(db)
+}
 
 const USERS: TableDefinition<&str, &str> = TableDefinition::new("mint_info");
 
 const PENDING: TableDefinition<&str, &str> = TableDefinition::new("pending");
 
+/// Hash of the pending invoice created for each idempotency key
+const IDEMPOTENCY_KEYS: TableDefinition<&str, &str> = TableDefinition::new("idempotency_keys");
+
 const PAID_FEES: TableDefinition<&str, u64> = TableDefinition::new("paid_fees");
 
 const RECEIVED_FEES: TableDefinition<&str, u64> = TableDefinition::new("received_fees");
 
+const PAYER_INVOICES: TableDefinition<&str, &str> = TableDefinition::new("payer_invoices");
+
+const CLAIMS: TableDefinition<&str, &str> = TableDefinition::new("claims");
+
+const INVITE_CODES: TableDefinition<&str, &str> = TableDefinition::new("invite_codes");
+
+const WEBHOOK_DELIVERIES: TableDefinition<&str, &str> = TableDefinition::new("webhook_deliveries");
+
+const PAYMENTS: TableDefinition<&str, &str> = TableDefinition::new("payments");
+
+const TOKEN_DELIVERIES: TableDefinition<&str, &str> = TableDefinition::new("token_deliveries");
+
+const DEAD_LETTERS: TableDefinition<&str, &str> = TableDefinition::new("dead_letters");
+
+/// Single values kept by the service
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+
+const PAY_INDEX_KEY: &str = "pay_index";
+
+/// Set once stored usernames have been normalized
+const USERNAMES_NORMALIZED_KEY: &str = "usernames_normalized";
+
+/// Pending invoices of a user
+fn user_pending_invoices(
+    pending_table: &impl ReadableTable<&'static str, &'static str>,
+    domain: &str,
+    username: &str,
+) -> Result<Vec<PendingInvoice>> {
+    Ok(pending_table
+        .iter()?
+        .flatten()
+        .flat_map(|(_k, v)| serde_json::from_str::<PendingInvoice>(v.value()))
+        .filter(|invoice| invoice.domain == domain && invoice.username == username)
+        .collect())
+}
+
+/// Drop the idempotency key of a removed pending invoice, unless it has been reused since
+fn remove_idempotency_key(
+    keys_table: &mut Table<'_, '_, &'static str, &'static str>,
+    invoice: &PendingInvoice,
+) -> Result<()> {
+    let key = match &invoice.idempotency_key {
+        Some(key) => key.as_str(),
+        None => return Ok(()),
+    };
+    let indexed = keys_table
+        .get(key)?
+        .map_or(false, |hash| hash.value() == invoice.hash);
+    if indexed {
+        keys_table.remove(key)?;
+    }
+
+    Ok(())
+}
+
+/// Embedded redb store, values are kept as json
 #[derive(Debug, Clone)]
-pub struct Db {
-    db: Arc<Mutex<Database>>,
+pub struct RedbDb {
+    /// `None` once closed
+    db: Arc<Mutex<Option<Database>>>,
 }
 
-impl Db {
+impl RedbDb {
     /// Init Database
-    pub async fn new(path: PathBuf) -> Result<Self> {
+    ///
+    /// Users stored before multi domain support are moved to `primary_domain`
+    pub async fn new(path: PathBuf, primary_domain: &str) -> Result<Self> {
         let directory = path
             .parent()
             .ok_or(anyhow!("Path is not set".to_string()))?;
@@ -39,18 +381,170 @@ impl Db {
         {
             let _ = write_txn.open_table(USERS)?;
             let _ = write_txn.open_table(PENDING)?;
+            let _ = write_txn.open_table(IDEMPOTENCY_KEYS)?;
             let _ = write_txn.open_table(PAID_FEES)?;
             let _ = write_txn.open_table(RECEIVED_FEES)?;
+            let _ = write_txn.open_table(PAYER_INVOICES)?;
+            let _ = write_txn.open_table(CLAIMS)?;
+            let _ = write_txn.open_table(INVITE_CODES)?;
+            let _ = write_txn.open_table(WEBHOOK_DELIVERIES)?;
+            let _ = write_txn.open_table(PAYMENTS)?;
+            let _ = write_txn.open_table(TOKEN_DELIVERIES)?;
+            let _ = write_txn.open_table(DEAD_LETTERS)?;
+            let _ = write_txn.open_table(META)?;
         }
         write_txn.commit()?;
 
+        Self::migrate_domains(&database, primary_domain)?;
+        Self::migrate_usernames(&database)?;
+
         Ok(Self {
-            db: Arc::new(Mutex::new(database)),
+            db: Arc::new(Mutex::new(Some(database))),
         })
     }
 
-    pub async fn add_fee_paid(&self, payment_hash: &str, fee_msat: u64) -> Result<()> {
-        let db = self.db.lock().await;
+    /// Lock the database, failing if it has been closed
+    async fn db(&self) -> Result<MappedMutexGuard<'_, Database>> {
+        MutexGuard::try_map(self.db.lock().await, |db| db.as_mut())
+            .map_err(|_| anyhow!("Database is closed"))
+    }
+
+    /// Key users by ln address and set missing domains
+    fn migrate_domains(database: &Database, primary_domain: &str) -> Result<()> {
+        let write_txn = database.begin_write()?;
+        {
+            let mut users_table = write_txn.open_table(USERS)?;
+
+            let legacy_users: Vec<(String, String)> = users_table
+                .iter()?
+                .flatten()
+                .filter(|(k, _v)| !k.value().contains('@'))
+                .map(|(k, v)| (k.value().to_string(), v.value().to_string()))
+                .collect();
+
+            for (username, user) in legacy_users {
+                let user = match serde_json::from_str(&user)? {
+                    UserKind::User(mut user) => {
+                        user.domain = primary_domain.to_string();
+                        UserKind::User(user)
+                    }
+                    UserKind::Pending(mut pending_user) => {
+                        pending_user.user.domain = primary_domain.to_string();
+                        UserKind::Pending(pending_user)
+                    }
+                    user => user,
+                };
+
+                users_table.remove(username.as_str())?;
+                users_table.insert(
+                    user_key(primary_domain, &username).as_str(),
+                    user.as_json().as_str(),
+                )?;
+                info!("Migrated user {} to {}", username, primary_domain);
+            }
+
+            let mut pending_table = write_txn.open_table(PENDING)?;
+
+            let legacy_invoices: Vec<(String, PendingInvoice)> = pending_table
+                .iter()?
+                .flatten()
+                .flat_map(|(k, v)| {
+                    serde_json::from_str::<PendingInvoice>(v.value())
+                        .map(|invoice| (k.value().to_string(), invoice))
+                })
+                .filter(|(_k, invoice)| invoice.domain.is_empty())
+                .collect();
+
+            for (hash, mut invoice) in legacy_invoices {
+                invoice.domain = primary_domain.to_string();
+                pending_table.insert(hash.as_str(), invoice.as_json().as_str())?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Rename users and their pending invoices to normalized usernames, once
+    fn migrate_usernames(database: &Database) -> Result<()> {
+        let write_txn = database.begin_write()?;
+        {
+            let mut meta_table = write_txn.open_table(META)?;
+            if meta_table.get(USERNAMES_NORMALIZED_KEY)?.is_some() {
+                return Ok(());
+            }
+
+            let mut users_table = write_txn.open_table(USERS)?;
+
+            let users: Vec<(String, String)> = users_table
+                .iter()?
+                .flatten()
+                .flat_map(|(k, _v)| {
+                    k.value()
+                        .rsplit_once('@')
+                        .map(|(username, domain)| (domain.to_string(), username.to_string()))
+                })
+                .collect();
+
+            for (domain, username, normalized_username) in usernames_to_normalize(&users)? {
+                let key = user_key(&domain, &username);
+                let user = match users_table.remove(key.as_str())? {
+                    Some(user) => serde_json::from_str::<UserKind>(user.value())?,
+                    None => continue,
+                };
+
+                users_table.insert(
+                    user_key(&domain, &normalized_username).as_str(),
+                    normalize_user(user).as_json().as_str(),
+                )?;
+                info!("Renamed user {} to {}", key, normalized_username);
+            }
+
+            let mut pending_table = write_txn.open_table(PENDING)?;
+
+            let invoices: Vec<(String, PendingInvoice)> = pending_table
+                .iter()?
+                .flatten()
+                .flat_map(|(k, v)| {
+                    serde_json::from_str::<PendingInvoice>(v.value())
+                        .map(|invoice| (k.value().to_string(), invoice))
+                })
+                .filter(|(_k, invoice)| invoice.username != normalize_username(&invoice.username))
+                .collect();
+
+            for (hash, mut invoice) in invoices {
+                invoice.username = normalize_username(&invoice.username);
+                pending_table.insert(hash.as_str(), invoice.as_json().as_str())?;
+            }
+
+            meta_table.insert(USERNAMES_NORMALIZED_KEY, 1)?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DbBackend for RedbDb {
+    async fn ping(&self) -> Result<()> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let _ = read_txn.open_table(USERS)?;
+
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        // Dropping the database once in flight transactions finish closes it cleanly
+        self.db.lock().await.take();
+
+        Ok(())
+    }
+
+    async fn add_fee_paid(&self, payment_hash: &str, fee_msat: u64) -> Result<()> {
+        let db = self.db().await?;
 
         let write_txn = db.begin_write()?;
         {
@@ -63,8 +557,8 @@ impl Db {
         Ok(())
     }
 
-    pub async fn add_fee_received(&self, payment_hash: &str, fee_msat: u64) -> Result<()> {
-        let db = self.db.lock().await;
+    async fn add_fee_received(&self, payment_hash: &str, fee_msat: u64) -> Result<()> {
+        let db = self.db().await?;
 
         let write_txn = db.begin_write()?;
         {
@@ -77,27 +571,29 @@ impl Db {
         Ok(())
     }
 
-    pub async fn add_user(&self, username: &str, user: &UserKind) -> Result<()> {
-        let db = self.db.lock().await;
+    async fn add_user(&self, domain: &str, username: &str, user: &UserKind) -> Result<()> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
 
         let write_txn = db.begin_write()?;
         {
             let mut users_table = write_txn.open_table(USERS)?;
 
-            users_table.insert(username, user.as_json().as_str())?;
+            users_table.insert(user_key(domain, username).as_str(), user.as_json().as_str())?;
         }
         write_txn.commit()?;
 
         Ok(())
     }
 
-    pub async fn get_user(&self, username: &str) -> Result<Option<UserKind>> {
-        let db = self.db.lock().await;
+    async fn get_user(&self, domain: &str, username: &str) -> Result<Option<UserKind>> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
 
         let read_txn = db.begin_read()?;
         let users_table = read_txn.open_table(USERS)?;
 
-        let user = match users_table.get(username)? {
+        let user = match users_table.get(user_key(domain, username).as_str())? {
             Some(contact) => Some(serde_json::from_str(contact.value())?),
             None => None,
         };
@@ -105,8 +601,58 @@ impl Db {
         Ok(user)
     }
 
-    pub async fn get_all_users(&self) -> Result<Vec<User>> {
-        let db = self.db.lock().await;
+    async fn update_user(&self, domain: &str, username: &str, user: &User) -> Result<bool> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
+
+        let key = user_key(domain, username);
+        let write_txn = db.begin_write()?;
+        let updated = {
+            let mut users_table = write_txn.open_table(USERS)?;
+
+            let existing = match users_table.get(key.as_str())? {
+                Some(existing) => Some(serde_json::from_str::<UserKind>(existing.value())?),
+                None => None,
+            };
+
+            if matches!(existing, Some(UserKind::User(_))) {
+                users_table.insert(
+                    key.as_str(),
+                    UserKind::User(user.clone()).as_json().as_str(),
+                )?;
+                true
+            } else {
+                false
+            }
+        };
+        write_txn.commit()?;
+
+        Ok(updated)
+    }
+
+    async fn get_users(&self, offset: usize, limit: usize) -> Result<(Vec<User>, usize)> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let users_table = read_txn.open_table(USERS)?;
+
+        // Only the requested page is kept while counting
+        let mut users = vec![];
+        let mut total = 0;
+        for (_k, v) in users_table.iter()?.flatten() {
+            if let Ok(UserKind::User(user)) = serde_json::from_str(v.value()) {
+                if total >= offset && users.len() < limit {
+                    users.push(user);
+                }
+                total += 1;
+            }
+        }
+
+        Ok((users, total))
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>> {
+        let db = self.db().await?;
 
         let read_txn = db.begin_read()?;
         let users_table = read_txn.open_table(USERS)?;
@@ -114,13 +660,16 @@ impl Db {
         let users = users_table
             .iter()?
             .flatten()
-            .flat_map(|(_k, v)| serde_json::from_str(v.value()))
+            .filter_map(|(_k, v)| match serde_json::from_str(v.value()) {
+                Ok(UserKind::User(user)) => Some(user),
+                _ => None,
+            })
             .collect();
         Ok(users)
     }
 
-    pub async fn get_pending_users(&self) -> Result<Vec<PendingUser>> {
-        let db = self.db.lock().await;
+    async fn get_pending_users(&self) -> Result<Vec<PendingUser>> {
+        let db = self.db().await?;
 
         let read_txn = db.begin_read()?;
         let users_table = read_txn.open_table(USERS)?;
@@ -136,36 +685,88 @@ impl Db {
         Ok(users)
     }
 
-    pub async fn delete_user(&self, username: &str) -> Result<()> {
-        let db = self.db.lock().await;
+    async fn delete_user(&self, domain: &str, username: &str) -> Result<()> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
 
         let write_txn = db.begin_write()?;
         {
             let mut users_table = write_txn.open_table(USERS)?;
 
-            users_table.remove(username)?;
+            users_table.remove(user_key(domain, username).as_str())?;
         }
         write_txn.commit()?;
 
         Ok(())
     }
 
-    pub async fn add_pending_invoice(&self, hash: &str, invoice: &PendingInvoice) -> Result<()> {
-        let db = self.db.lock().await;
+    async fn remove_user(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Option<Vec<PendingInvoice>>> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
+
+        let key = user_key(domain, username);
+        let write_txn = db.begin_write()?;
+        let removed = {
+            let mut users_table = write_txn.open_table(USERS)?;
+
+            let existing = match users_table.get(key.as_str())? {
+                Some(existing) => Some(serde_json::from_str::<UserKind>(existing.value())?),
+                None => None,
+            };
+
+            if matches!(existing, Some(UserKind::User(_))) {
+                users_table.remove(key.as_str())?;
+
+                let pending_table = write_txn.open_table(PENDING)?;
+                Some(user_pending_invoices(&pending_table, domain, username)?)
+            } else {
+                None
+            }
+        };
+        write_txn.commit()?;
+
+        Ok(removed)
+    }
+
+    async fn get_user_pending_invoices(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Vec<PendingInvoice>> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let pending_table = read_txn.open_table(PENDING)?;
+
+        user_pending_invoices(&pending_table, domain, username)
+    }
+
+    async fn add_pending_invoice(&self, hash: &str, invoice: &PendingInvoice) -> Result<()> {
+        let db = self.db().await?;
 
         let write_txn = db.begin_write()?;
         {
             let mut pending_table = write_txn.open_table(PENDING)?;
 
             pending_table.insert(hash, invoice.as_json().as_str())?;
+
+            if let Some(key) = &invoice.idempotency_key {
+                let mut keys_table = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+                keys_table.insert(key.as_str(), hash)?;
+            }
         }
         write_txn.commit()?;
 
         Ok(())
     }
 
-    pub async fn get_pending_invoice(&self, hash: &str) -> Result<Option<PendingInvoice>> {
-        let db = self.db.lock().await;
+    async fn get_pending_invoice(&self, hash: &str) -> Result<Option<PendingInvoice>> {
+        let db = self.db().await?;
 
         let read_txn = db.begin_read()?;
         let pending_table = read_txn.open_table(PENDING)?;
@@ -178,8 +779,27 @@ impl Db {
         Ok(user)
     }
 
-    pub async fn get_pending_invoices(&self) -> Result<Vec<PendingInvoice>> {
-        let db = self.db.lock().await;
+    async fn get_pending_invoice_by_key(&self, key: &str) -> Result<Option<PendingInvoice>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let keys_table = read_txn.open_table(IDEMPOTENCY_KEYS)?;
+        let pending_table = read_txn.open_table(PENDING)?;
+
+        let hash = match keys_table.get(key)? {
+            Some(hash) => hash.value().to_string(),
+            None => return Ok(None),
+        };
+        let invoice = match pending_table.get(hash.as_str())? {
+            Some(invoice) => Some(serde_json::from_str(invoice.value())?),
+            None => None,
+        };
+
+        Ok(invoice)
+    }
+
+    async fn get_pending_invoices(&self) -> Result<Vec<PendingInvoice>> {
+        let db = self.db().await?;
 
         let read_txn = db.begin_read()?;
         let pending_table = read_txn.open_table(PENDING)?;
@@ -197,17 +817,1130 @@ impl Db {
         Ok(pending_invoices)
     }
 
-    pub async fn remove_pending_invoice(&self, hash: &str) -> Result<()> {
-        let db = self.db.lock().await;
+    async fn find_pending_invoices(
+        &self,
+        filter: &PendingInvoiceFilter,
+    ) -> Result<Vec<PendingInvoice>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let pending_table = read_txn.open_table(PENDING)?;
+
+        let mut pending_invoices: Vec<PendingInvoice> = pending_table
+            .iter()?
+            .flatten()
+            .flat_map(|(_key, value)| serde_json::from_str(value.value()))
+            .filter(|invoice| filter.matches(invoice))
+            .collect();
+        pending_invoices.sort_by(|a, b| (a.time, &a.hash).cmp(&(b.time, &b.hash)));
+
+        Ok(pending_invoices)
+    }
+
+    async fn remove_pending_invoice(&self, hash: &str) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut pending_table = write_txn.open_table(PENDING)?;
+            let mut keys_table = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+
+            let removed = match pending_table.remove(hash)? {
+                Some(invoice) => Some(serde_json::from_str::<PendingInvoice>(invoice.value())?),
+                None => None,
+            };
+            if let Some(invoice) = removed {
+                remove_idempotency_key(&mut keys_table, &invoice)?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn replace_pending_invoice(
+        &self,
+        paid_hash: &str,
+        invoice: &PendingInvoice,
+        pay_index: Option<u64>,
+    ) -> Result<()> {
+        let db = self.db().await?;
 
         let write_txn = db.begin_write()?;
         {
             let mut pending_table = write_txn.open_table(PENDING)?;
+            let mut keys_table = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+
+            let removed = match pending_table.remove(paid_hash)? {
+                Some(paid) => Some(serde_json::from_str::<PendingInvoice>(paid.value())?),
+                None => None,
+            };
+            if let Some(paid) = removed {
+                remove_idempotency_key(&mut keys_table, &paid)?;
+            }
+
+            pending_table.insert(invoice.hash.as_str(), invoice.as_json().as_str())?;
+            if let Some(key) = &invoice.idempotency_key {
+                keys_table.insert(key.as_str(), invoice.hash.as_str())?;
+            }
+
+            if let Some(pay_index) = pay_index {
+                let mut meta_table = write_txn.open_table(META)?;
+                let tip = meta_table.get(PAY_INDEX_KEY)?.map(|index| index.value());
+                if tip.map_or(true, |tip| tip < pay_index) {
+                    meta_table.insert(PAY_INDEX_KEY, pay_index)?;
+                }
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn add_payer_invoice(&self, hash: &str, invoice: &PayerInvoice) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut invoice_table = write_txn.open_table(PAYER_INVOICES)?;
 
-            pending_table.remove(hash)?;
+            invoice_table.insert(hash, invoice.as_json().as_str())?;
         }
         write_txn.commit()?;
 
         Ok(())
     }
+
+    async fn get_payer_invoice(&self, hash: &str) -> Result<Option<PayerInvoice>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let invoice_table = read_txn.open_table(PAYER_INVOICES)?;
+
+        let invoice = match invoice_table.get(hash)? {
+            Some(invoice) => Some(serde_json::from_str(invoice.value())?),
+            None => None,
+        };
+
+        Ok(invoice)
+    }
+
+    async fn settle_payer_invoice(&self, hash: &str, preimage: Option<String>) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut invoice_table = write_txn.open_table(PAYER_INVOICES)?;
+
+            let invoice = match invoice_table.get(hash)? {
+                Some(invoice) => Some(serde_json::from_str::<PayerInvoice>(invoice.value())?),
+                None => None,
+            };
+
+            if let Some(invoice) = invoice {
+                let invoice = PayerInvoice {
+                    settled: true,
+                    preimage: preimage.or(invoice.preimage),
+                    ..invoice
+                };
+                invoice_table.insert(hash, invoice.as_json().as_str())?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn add_claim(&self, id: &str, claim: &Claim) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut claims_table = write_txn.open_table(CLAIMS)?;
+
+            claims_table.insert(id, claim.as_json().as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn claim_token(&self, id: &str, now: u64) -> Result<ClaimStatus> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        let status = {
+            let mut claims_table = write_txn.open_table(CLAIMS)?;
+
+            let claim = match claims_table.get(id)? {
+                Some(claim) => Some(serde_json::from_str::<Claim>(claim.value())?),
+                None => None,
+            };
+
+            match claim {
+                None => ClaimStatus::NotFound,
+                Some(claim) if claim.expire <= now => ClaimStatus::Gone,
+                Some(Claim { token: None, .. }) => ClaimStatus::Gone,
+                Some(Claim {
+                    token: Some(token),
+                    expire,
+                }) => {
+                    // Kept until expiry so later requests are told it is gone
+                    let claimed = Claim {
+                        token: None,
+                        expire,
+                    };
+                    claims_table.insert(id, claimed.as_json().as_str())?;
+                    ClaimStatus::Claimed(token)
+                }
+            }
+        };
+        write_txn.commit()?;
+
+        Ok(status)
+    }
+
+    async fn remove_expired_claims(&self, now: u64) -> Result<usize> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        let removed = {
+            let mut claims_table = write_txn.open_table(CLAIMS)?;
+
+            let expired: Vec<String> = claims_table
+                .iter()?
+                .flatten()
+                .filter(|(_k, v)| {
+                    serde_json::from_str::<Claim>(v.value())
+                        .map(|claim| claim.expire <= now)
+                        .unwrap_or(true)
+                })
+                .map(|(k, _v)| k.value().to_string())
+                .collect();
+
+            for id in &expired {
+                claims_table.remove(id.as_str())?;
+            }
+
+            expired.len()
+        };
+        write_txn.commit()?;
+
+        Ok(removed)
+    }
+
+    async fn add_invite_code(&self, invite: &InviteCode) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut invites_table = write_txn.open_table(INVITE_CODES)?;
+
+            invites_table.insert(invite.code.as_str(), invite.as_json().as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_invite_codes(&self) -> Result<Vec<InviteCode>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let invites_table = read_txn.open_table(INVITE_CODES)?;
+
+        let invites = invites_table
+            .iter()?
+            .flatten()
+            .flat_map(|(_k, v)| serde_json::from_str::<InviteCode>(v.value()))
+            .collect();
+
+        Ok(invites)
+    }
+
+    async fn get_invite_code(&self, code: &str) -> Result<Option<InviteCode>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let invites_table = read_txn.open_table(INVITE_CODES)?;
+
+        let invite = match invites_table.get(code)? {
+            Some(invite) => Some(serde_json::from_str(invite.value())?),
+            None => None,
+        };
+
+        Ok(invite)
+    }
+
+    async fn use_invite_code(&self, code: &str, now: u64) -> Result<InviteStatus> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        let status = {
+            let mut invites_table = write_txn.open_table(INVITE_CODES)?;
+
+            let invite = match invites_table.get(code)? {
+                Some(invite) => Some(serde_json::from_str::<InviteCode>(invite.value())?),
+                None => None,
+            };
+
+            match invite {
+                None => InviteStatus::NotFound,
+                Some(invite) if !invite.is_usable(now) => InviteStatus::Gone,
+                Some(invite) => {
+                    let used = InviteCode {
+                        uses: invite.uses + 1,
+                        ..invite
+                    };
+                    invites_table.insert(code, used.as_json().as_str())?;
+                    InviteStatus::Used
+                }
+            }
+        };
+        write_txn.commit()?;
+
+        Ok(status)
+    }
+
+    async fn remove_invite_code(&self, code: &str) -> Result<bool> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        let removed = {
+            let mut invites_table = write_txn.open_table(INVITE_CODES)?;
+            let removed = invites_table.remove(code)?.is_some();
+            removed
+        };
+        write_txn.commit()?;
+
+        Ok(removed)
+    }
+
+    async fn add_payment_record(&self, record: &PaymentRecord) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut payments_table = write_txn.open_table(PAYMENTS)?;
+
+            payments_table.insert(record.hash.as_str(), record.as_json().as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_payment_record(&self, hash: &str) -> Result<Option<PaymentRecord>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let payments_table = read_txn.open_table(PAYMENTS)?;
+
+        let record = match payments_table.get(hash)? {
+            Some(record) => Some(serde_json::from_str(record.value())?),
+            None => None,
+        };
+
+        Ok(record)
+    }
+
+    async fn find_payment_records(
+        &self,
+        filter: &PaymentRecordFilter,
+    ) -> Result<Vec<PaymentRecord>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let payments_table = read_txn.open_table(PAYMENTS)?;
+
+        let mut records: Vec<PaymentRecord> = payments_table
+            .iter()?
+            .flatten()
+            .flat_map(|(_key, value)| serde_json::from_str(value.value()))
+            .filter(|record| filter.matches(record))
+            .collect();
+        records.sort_by(|a, b| (a.paid_at, &a.hash).cmp(&(b.paid_at, &b.hash)));
+
+        Ok(records)
+    }
+
+    async fn find_payment_records_page(
+        &self,
+        filter: &PaymentRecordFilter,
+        after: Option<(u64, String)>,
+        limit: usize,
+    ) -> Result<Vec<PaymentRecord>> {
+        // Payments are keyed by hash so each page scans the table
+        let records = self.find_payment_records(filter).await?;
+
+        Ok(records
+            .into_iter()
+            .filter(|record| {
+                after.as_ref().map_or(true, |(paid_at, hash)| {
+                    (record.paid_at, &record.hash) > (*paid_at, hash)
+                })
+            })
+            .take(limit)
+            .collect())
+    }
+
+    async fn get_user_payment_records_page(
+        &self,
+        domain: &str,
+        username: &str,
+        after: Option<(u64, String)>,
+        limit: usize,
+    ) -> Result<Vec<PaymentRecord>> {
+        let filter = PaymentRecordFilter {
+            username: Some(username.to_string()),
+            ..Default::default()
+        };
+        let records = self.find_payment_records(&filter).await?;
+
+        Ok(records
+            .into_iter()
+            .filter(|record| {
+                record.domain == domain
+                    && after.as_ref().map_or(true, |(paid_at, hash)| {
+                        (record.paid_at, &record.hash) > (*paid_at, hash)
+                    })
+            })
+            .take(limit)
+            .collect())
+    }
+
+    async fn add_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut deliveries_table = write_txn.open_table(WEBHOOK_DELIVERIES)?;
+
+            deliveries_table.insert(delivery.hash.as_str(), delivery.as_json().as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_webhook_delivery(&self, hash: &str) -> Result<Option<WebhookDelivery>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let deliveries_table = read_txn.open_table(WEBHOOK_DELIVERIES)?;
+
+        let delivery = match deliveries_table.get(hash)? {
+            Some(delivery) => Some(serde_json::from_str(delivery.value())?),
+            None => None,
+        };
+
+        Ok(delivery)
+    }
+
+    async fn add_token_delivery(&self, delivery: &TokenDelivery) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut deliveries_table = write_txn.open_table(TOKEN_DELIVERIES)?;
+
+            deliveries_table.insert(delivery.hash.as_str(), delivery.as_json().as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_token_deliveries(&self) -> Result<Vec<TokenDelivery>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let deliveries_table = read_txn.open_table(TOKEN_DELIVERIES)?;
+
+        let mut deliveries: Vec<TokenDelivery> = deliveries_table
+            .iter()?
+            .flatten()
+            .flat_map(|(_key, value)| serde_json::from_str(value.value()))
+            .collect();
+        deliveries.sort_by(|a, b| (a.next_attempt, &a.hash).cmp(&(b.next_attempt, &b.hash)));
+
+        Ok(deliveries)
+    }
+
+    async fn remove_token_delivery(&self, hash: &str) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut deliveries_table = write_txn.open_table(TOKEN_DELIVERIES)?;
+            deliveries_table.remove(hash)?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn dead_letter_token_delivery(&self, delivery: &TokenDelivery) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut deliveries_table = write_txn.open_table(TOKEN_DELIVERIES)?;
+            let mut dead_letters_table = write_txn.open_table(DEAD_LETTERS)?;
+
+            deliveries_table.remove(delivery.hash.as_str())?;
+            dead_letters_table.insert(delivery.hash.as_str(), delivery.as_json().as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> Result<Vec<TokenDelivery>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let dead_letters_table = read_txn.open_table(DEAD_LETTERS)?;
+
+        let mut dead_letters: Vec<TokenDelivery> = dead_letters_table
+            .iter()?
+            .flatten()
+            .flat_map(|(_key, value)| serde_json::from_str(value.value()))
+            .collect();
+        dead_letters.sort_by(|a, b| (a.created_at, &a.hash).cmp(&(b.created_at, &b.hash)));
+
+        Ok(dead_letters)
+    }
+
+    async fn get_user_token_deliveries(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Vec<TokenDelivery>> {
+        let username = &normalize_username(username);
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let deliveries_table = read_txn.open_table(TOKEN_DELIVERIES)?;
+        let dead_letters_table = read_txn.open_table(DEAD_LETTERS)?;
+
+        let mut deliveries: Vec<TokenDelivery> = deliveries_table
+            .iter()?
+            .chain(dead_letters_table.iter()?)
+            .flatten()
+            .flat_map(|(_key, value)| serde_json::from_str::<TokenDelivery>(value.value()))
+            .filter(|delivery| delivery.domain == domain && &delivery.username == username)
+            .collect();
+        deliveries.sort_by(|a, b| (a.created_at, &a.hash).cmp(&(b.created_at, &b.hash)));
+
+        Ok(deliveries)
+    }
+
+    async fn get_pay_index(&self) -> Result<Option<u64>> {
+        let db = self.db().await?;
+
+        let read_txn = db.begin_read()?;
+        let meta_table = read_txn.open_table(META)?;
+
+        let pay_index = meta_table.get(PAY_INDEX_KEY)?.map(|index| index.value());
+
+        Ok(pay_index)
+    }
+
+    async fn set_pay_index(&self, pay_index: u64) -> Result<()> {
+        let db = self.db().await?;
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut meta_table = write_txn.open_table(META)?;
+            meta_table.insert(PAY_INDEX_KEY, pay_index)?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use cashu_sdk::Bolt11Invoice;
+    use nostr_sdk::Url;
+
+    use super::*;
+    use crate::routes::tests::test_invoice;
+
+    fn test_path() -> PathBuf {
+        std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.redb", uuid::Uuid::new_v4()))
+    }
+
+    async fn test_db() -> RedbDb {
+        RedbDb::new(test_path(), "example.com").await.unwrap()
+    }
+
+    fn test_user(username: &str) -> UserKind {
+        UserKind::User(User {
+            username: username.to_string(),
+            domain: "example.com".to_string(),
+            mint: Url::parse("https://mint.example.com").unwrap(),
+            fallback_mints: vec![],
+            mint_rules: vec![],
+            pubkey: "npub".to_string(),
+            relays: HashSet::new(),
+            proxy: false,
+            min_sendable: None,
+            max_sendable: None,
+            success_message: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: false,
+            created_at: None,
+        })
+    }
+
+    /// Database holding users stored under `keys` before usernames were normalized
+    fn legacy_db(keys: &[&str]) -> PathBuf {
+        let path = test_path();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let database = Database::create(&path).unwrap();
+        let write_txn = database.begin_write().unwrap();
+        {
+            let mut users_table = write_txn.open_table(USERS).unwrap();
+            for key in keys {
+                let (username, _domain) = key.rsplit_once('@').unwrap();
+                users_table
+                    .insert(key, test_user(username).as_json().as_str())
+                    .unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+
+        path
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_lookup() {
+        let db = test_db().await;
+
+        db.add_user("example.com", "alice", &test_user("alice"))
+            .await
+            .unwrap();
+
+        for username in ["alice", "Alice", "ALICE"] {
+            assert!(matches!(
+                db.get_user("example.com", username).await.unwrap(),
+                Some(UserKind::User(_))
+            ));
+        }
+        assert_eq!(db.get_all_users().await.unwrap().len(), 1);
+
+        // Adding a differently cased username replaces the user rather than adding one
+        db.add_user("example.com", "Alice", &UserKind::Blocked)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.get_user("example.com", "alice").await.unwrap(),
+            Some(UserKind::Blocked)
+        ));
+        assert_eq!(db.get_users(0, 10).await.unwrap().1, 0);
+        assert!(db.get_all_users().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_usernames() {
+        let path = legacy_db(&["Alice@example.com", "bob@example.com"]);
+
+        let db = RedbDb::new(path, "example.com").await.unwrap();
+        match db.get_user("example.com", "alice").await.unwrap() {
+            Some(UserKind::User(user)) => assert_eq!(user.username, "alice"),
+            other => panic!("Unexpected user {:?}", other),
+        }
+        assert_eq!(db.get_users(0, 10).await.unwrap().1, 2);
+
+        // Users that would become one are not merged
+        let path = legacy_db(&["Bob@example.com", "bob@example.com"]);
+        assert!(RedbDb::new(path, "example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pay_index() {
+        let db = test_db().await;
+
+        assert_eq!(db.get_pay_index().await.unwrap(), None);
+        db.set_pay_index(5).await.unwrap();
+        db.set_pay_index(6).await.unwrap();
+        assert_eq!(db.get_pay_index().await.unwrap(), Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_replace_pending_invoice() {
+        let db = test_db().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        let paid = PendingInvoice {
+            idempotency_key: Some("key".to_string()),
+            time: 100,
+            hash: "paid".to_string(),
+            proxied: true,
+            ..test_invoice(bolt11)
+        };
+        let invoice = PendingInvoice {
+            idempotency_key: None,
+            hash: "mint".to_string(),
+            payer_hash: Some(paid.hash.clone()),
+            ..paid.clone()
+        };
+        db.add_pending_invoice(&paid.hash, &paid).await.unwrap();
+
+        db.replace_pending_invoice(&paid.hash, &invoice, Some(7))
+            .await
+            .unwrap();
+        assert!(db.get_pending_invoice(&paid.hash).await.unwrap().is_none());
+        assert!(db
+            .get_pending_invoice_by_key("key")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            db.get_pending_invoice(&invoice.hash)
+                .await
+                .unwrap()
+                .unwrap()
+                .payer_hash,
+            Some(paid.hash)
+        );
+        assert_eq!(db.get_pay_index().await.unwrap(), Some(7));
+
+        // Backends without a pay index, and invoices paid earlier, leave it as it is
+        db.replace_pending_invoice(&invoice.hash, &invoice, None)
+            .await
+            .unwrap();
+        db.replace_pending_invoice(&invoice.hash, &invoice, Some(3))
+            .await
+            .unwrap();
+        assert_eq!(db.get_pay_index().await.unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_keys() {
+        let db = test_db().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        for (hash, key) in [("a", Some("key")), ("b", Some("key")), ("c", None)] {
+            let invoice = PendingInvoice {
+                idempotency_key: key.map(str::to_string),
+                time: 100,
+                hash: hash.to_string(),
+                ..test_invoice(bolt11.clone())
+            };
+            db.add_pending_invoice(hash, &invoice).await.unwrap();
+        }
+
+        // The key leads to the invoice created last for it
+        let latest = db.get_pending_invoice_by_key("key").await.unwrap().unwrap();
+        assert_eq!(latest.hash, "b");
+        assert!(db
+            .get_pending_invoice_by_key("other")
+            .await
+            .unwrap()
+            .is_none());
+
+        // An older invoice removed later leaves the key to the latest
+        db.remove_pending_invoice("a").await.unwrap();
+        let latest = db.get_pending_invoice_by_key("key").await.unwrap().unwrap();
+        assert_eq!(latest.hash, "b");
+
+        db.remove_pending_invoice("b").await.unwrap();
+        assert!(db
+            .get_pending_invoice_by_key("key")
+            .await
+            .unwrap()
+            .is_none());
+        let database = db.db().await.unwrap();
+        let read_txn = database.begin_read().unwrap();
+        let keys_table = read_txn.open_table(IDEMPOTENCY_KEYS).unwrap();
+        assert!(keys_table.get("key").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_claim_once() {
+        let db = test_db().await;
+
+        let claim = Claim {
+            token: Some("cashuAtoken".to_string()),
+            expire: 200,
+        };
+        db.add_claim("id", &claim).await.unwrap();
+
+        assert_eq!(
+            db.claim_token("id", 100).await.unwrap(),
+            ClaimStatus::Claimed("cashuAtoken".to_string())
+        );
+        assert_eq!(db.claim_token("id", 100).await.unwrap(), ClaimStatus::Gone);
+        assert_eq!(
+            db.claim_token("unknown", 100).await.unwrap(),
+            ClaimStatus::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claim_expiry() {
+        let db = test_db().await;
+
+        let claim = Claim {
+            token: Some("cashuAtoken".to_string()),
+            expire: 200,
+        };
+        db.add_claim("expired", &claim).await.unwrap();
+        db.add_claim(
+            "valid",
+            &Claim {
+                expire: 400,
+                ..claim
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            db.claim_token("expired", 200).await.unwrap(),
+            ClaimStatus::Gone
+        );
+
+        assert_eq!(db.remove_expired_claims(300).await.unwrap(), 1);
+        assert_eq!(
+            db.claim_token("expired", 300).await.unwrap(),
+            ClaimStatus::NotFound
+        );
+        assert_eq!(
+            db.claim_token("valid", 300).await.unwrap(),
+            ClaimStatus::Claimed("cashuAtoken".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_records() {
+        let db = test_db().await;
+
+        let record = |hash: &str, username: &str, paid_at| PaymentRecord {
+            hash: hash.to_string(),
+            domain: "example.com".to_string(),
+            username: username.to_string(),
+            mint: Url::parse("https://mint.example.com").unwrap(),
+            amount_msat: 10_000,
+            fee_msat: 100,
+            minted_msat: None,
+            proxied: true,
+            comment: None,
+            delivered: false,
+            paid_at,
+        };
+        db.add_payment_record(&record("late", "alice", 300))
+            .await
+            .unwrap();
+        db.add_payment_record(&record("early", "alice", 100))
+            .await
+            .unwrap();
+        db.add_payment_record(&record("bob", "bob", 200))
+            .await
+            .unwrap();
+
+        // Minting completes the record
+        let minted = PaymentRecord {
+            minted_msat: Some(9_900),
+            comment: Some("Thanks, see you\nsoon".to_string()),
+            delivered: true,
+            ..record("early", "alice", 100)
+        };
+        db.add_payment_record(&minted).await.unwrap();
+        assert_eq!(db.get_payment_record("early").await.unwrap(), Some(minted));
+        assert_eq!(db.get_payment_record("unknown").await.unwrap(), None);
+
+        let find = |username: Option<&str>, from, to| {
+            let filter = PaymentRecordFilter {
+                username: username.map(str::to_string),
+                from,
+                to,
+            };
+            let db = db.clone();
+            async move {
+                db.find_payment_records(&filter)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(find(None, None, None).await, vec!["early", "bob", "late"]);
+        assert_eq!(find(Some("Alice"), None, None).await, vec!["early", "late"]);
+        // From is inclusive and to is exclusive
+        assert_eq!(find(None, Some(200), Some(300)).await, vec!["bob"]);
+
+        // Pages continue after the paid time and hash of the last record of the previous one
+        db.add_payment_record(&record("carol", "carol", 200))
+            .await
+            .unwrap();
+        let page = |after: Option<(u64, &str)>| {
+            let db = db.clone();
+            let after = after.map(|(paid_at, hash)| (paid_at, hash.to_string()));
+            async move {
+                db.find_payment_records_page(&PaymentRecordFilter::default(), after, 2)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(page(None).await, vec!["early", "bob"]);
+        assert_eq!(page(Some((200, "bob"))).await, vec!["carol", "late"]);
+        assert!(page(Some((300, "late"))).await.is_empty());
+
+        // A user's records are only those on their domain
+        db.add_payment_record(&PaymentRecord {
+            domain: "example.org".to_string(),
+            ..record("other", "alice", 150)
+        })
+        .await
+        .unwrap();
+        let user_page = |after: Option<(u64, &str)>| {
+            let db = db.clone();
+            let after = after.map(|(paid_at, hash)| (paid_at, hash.to_string()));
+            async move {
+                db.get_user_payment_records_page("example.com", "Alice", after, 1)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(user_page(None).await, vec!["early"]);
+        assert_eq!(user_page(Some((100, "early"))).await, vec!["late"]);
+        assert!(user_page(Some((300, "late"))).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_deliveries() {
+        let db = test_db().await;
+
+        assert_eq!(db.get_webhook_delivery("hash").await.unwrap(), None);
+
+        let failed = WebhookDelivery {
+            hash: "hash".to_string(),
+            domain: "example.com".to_string(),
+            username: "alice".to_string(),
+            url: Url::parse("https://shop.example.com/paid").unwrap(),
+            attempts: 1,
+            delivered: false,
+            status: None,
+            error: Some("connection refused".to_string()),
+            last_attempt: 100,
+        };
+        db.add_webhook_delivery(&failed).await.unwrap();
+        assert_eq!(
+            db.get_webhook_delivery("hash").await.unwrap(),
+            Some(failed.clone())
+        );
+
+        // The latest attempt replaces the earlier one
+        let delivered = WebhookDelivery {
+            attempts: 2,
+            delivered: true,
+            status: Some(200),
+            error: None,
+            last_attempt: 110,
+            ..failed
+        };
+        db.add_webhook_delivery(&delivered).await.unwrap();
+        assert_eq!(
+            db.get_webhook_delivery("hash").await.unwrap(),
+            Some(delivered)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_deliveries() {
+        let db = test_db().await;
+
+        let delivery = |hash: &str, next_attempt| TokenDelivery {
+            hash: hash.to_string(),
+            payer_hash: None,
+            domain: "example.com".to_string(),
+            username: "alice".to_string(),
+            claim_id: Some(format!("claim-{}", hash)),
+            pubkey: "pubkey".to_string(),
+            token: "cashuA".to_string(),
+            comment: Some("Thanks".to_string()),
+            payer_data: None,
+            alias: None,
+            relays: HashSet::from(["wss://relay.example.com".to_string()]),
+            attempts: 1,
+            next_attempt,
+            error: Some("Could not reach any relay".to_string()),
+            created_at: 100,
+        };
+        let hashes = |deliveries: Vec<TokenDelivery>| {
+            deliveries
+                .into_iter()
+                .map(|delivery| delivery.hash)
+                .collect::<Vec<_>>()
+        };
+
+        db.add_token_delivery(&delivery("late", 300)).await.unwrap();
+        db.add_token_delivery(&delivery("soon", 200)).await.unwrap();
+        db.add_token_delivery(&delivery("sent", 100)).await.unwrap();
+        assert_eq!(
+            hashes(db.get_token_deliveries().await.unwrap()),
+            vec!["sent", "soon", "late"]
+        );
+
+        // A later attempt replaces the earlier one
+        let retried = TokenDelivery {
+            attempts: 2,
+            ..delivery("soon", 400)
+        };
+        db.add_token_delivery(&retried).await.unwrap();
+        assert_eq!(
+            hashes(db.get_token_deliveries().await.unwrap()),
+            vec!["sent", "late", "soon"]
+        );
+
+        db.remove_token_delivery("sent").await.unwrap();
+        db.dead_letter_token_delivery(&retried).await.unwrap();
+        assert_eq!(
+            hashes(db.get_token_deliveries().await.unwrap()),
+            vec!["late"]
+        );
+        assert_eq!(db.get_dead_letters().await.unwrap(), vec![retried]);
+
+        // Queued and dead lettered tokens of a user, oldest first
+        db.add_token_delivery(&TokenDelivery {
+            username: "bob".to_string(),
+            ..delivery("bob", 100)
+        })
+        .await
+        .unwrap();
+        db.add_token_delivery(&TokenDelivery {
+            created_at: 50,
+            ..delivery("first", 100)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            hashes(
+                db.get_user_token_deliveries("example.com", "alice")
+                    .await
+                    .unwrap()
+            ),
+            vec!["first", "late", "soon"]
+        );
+        assert!(db
+            .get_user_token_deliveries("example.org", "alice")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invite_codes() {
+        let db = test_db().await;
+
+        let invite = InviteCode {
+            code: "single".to_string(),
+            max_uses: 1,
+            uses: 0,
+            expire: Some(200),
+            created_at: 100,
+        };
+        db.add_invite_code(&invite).await.unwrap();
+        db.add_invite_code(&InviteCode {
+            code: "twice".to_string(),
+            max_uses: 2,
+            expire: None,
+            ..invite.clone()
+        })
+        .await
+        .unwrap();
+
+        // Concurrent signups cannot both use a single use code
+        let uses = futures::future::join_all(
+            (0..5).map(|_| async { db.use_invite_code("single", 150).await.unwrap() }),
+        )
+        .await;
+        assert_eq!(
+            uses.iter()
+                .filter(|status| **status == InviteStatus::Used)
+                .count(),
+            1
+        );
+        assert_eq!(
+            db.use_invite_code("single", 150).await.unwrap(),
+            InviteStatus::Gone
+        );
+
+        assert_eq!(
+            db.use_invite_code("twice", 1000).await.unwrap(),
+            InviteStatus::Used
+        );
+        assert_eq!(
+            db.use_invite_code("twice", 1000).await.unwrap(),
+            InviteStatus::Used
+        );
+        assert_eq!(
+            db.use_invite_code("twice", 1000).await.unwrap(),
+            InviteStatus::Gone
+        );
+        assert_eq!(
+            db.use_invite_code("unknown", 150).await.unwrap(),
+            InviteStatus::NotFound
+        );
+
+        assert_eq!(db.get_invite_code("twice").await.unwrap().unwrap().uses, 2);
+        assert_eq!(db.get_invite_code("unknown").await.unwrap(), None);
+
+        let invites = db.get_invite_codes().await.unwrap();
+        assert_eq!(
+            invites
+                .iter()
+                .map(|invite| (invite.code.as_str(), invite.uses))
+                .collect::<Vec<_>>(),
+            vec![("single", 1), ("twice", 2)]
+        );
+
+        assert!(db.remove_invite_code("single").await.unwrap());
+        assert!(!db.remove_invite_code("single").await.unwrap());
+        assert_eq!(
+            db.use_invite_code("single", 150).await.unwrap(),
+            InviteStatus::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invite_code_expiry() {
+        let db = test_db().await;
+
+        db.add_invite_code(&InviteCode {
+            code: "expiring".to_string(),
+            max_uses: 1,
+            uses: 0,
+            expire: Some(200),
+            created_at: 100,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            db.use_invite_code("expiring", 200).await.unwrap(),
+            InviteStatus::Gone
+        );
+    }
 }
@@ -0,0 +1,281 @@
+//! Dry run mode, exercising the invoice, mint and DM flow without moving any sats
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use cashu_sdk::lightning_invoice::bitcoin_hashes::{sha256, Hash};
+use cashu_sdk::lightning_invoice::secp256k1::{Secp256k1, SecretKey};
+use cashu_sdk::lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+use cashu_sdk::nuts::nut00::wallet::Token;
+use cashu_sdk::{Amount, Bolt11Invoice};
+use futures::StreamExt;
+use nostr_sdk::secp256k1::rand;
+use nostr_sdk::Url;
+use tokio::sync::mpsc;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::backend::{InvoiceStream, PaidInvoice, Payment, PaymentBackend, PaymentStatus};
+
+/// Delay before a dry run invoice pays itself
+pub const DRY_RUN_SETTLE_DELAY: Duration = Duration::from_secs(5);
+
+/// Expiry of dry run invoices when none is asked for
+const DEFAULT_EXPIRY: u64 = 3600;
+
+/// Sats amountless dry run invoices are paid
+const AMOUNTLESS_PAYMENT_SAT: u64 = 1000;
+
+/// Signature of the proofs in dry run tokens, the secp256k1 generator so it parses as a point
+const DRY_RUN_PROOF_C: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+/// Regtest invoice signed by a throwaway node key, nobody can pay it
+pub fn dry_run_invoice(
+    amount: Option<Amount>,
+    description: String,
+    description_hash_only: bool,
+    preimage: [u8; 32],
+    expiry: Option<u64>,
+) -> Result<Bolt11Invoice> {
+    let builder = InvoiceBuilder::new(Currency::Regtest);
+    let builder = if description_hash_only {
+        builder.description_hash(sha256::Hash::hash(description.as_bytes()))
+    } else {
+        builder.description(description)
+    };
+    let builder = builder
+        .payment_hash(sha256::Hash::hash(&preimage))
+        .payment_secret(PaymentSecret(rand::random()))
+        .current_timestamp()
+        .min_final_cltv_expiry_delta(144)
+        .expiry_time(Duration::from_secs(expiry.unwrap_or(DEFAULT_EXPIRY)));
+    let builder = match amount {
+        Some(amount) => builder.amount_milli_satoshis(amount.to_msat()),
+        None => builder,
+    };
+
+    let node_key = SecretKey::from_slice(&rand::random::<[u8; 32]>())?;
+    let secp = Secp256k1::new();
+    builder
+        .build_signed(|message| secp.sign_ecdsa_recoverable(message, &node_key))
+        .map_err(|err| anyhow!("Could not create dry run invoice: {:?}", err))
+}
+
+/// Token from `mint` with a single made up proof, the mint will refuse to redeem it
+pub fn dry_run_token(mint: &Url, amount: Amount) -> Result<Token> {
+    let token = serde_json::json!({
+        "token": [{
+            "mint": mint.as_str().trim_end_matches('/'),
+            "proofs": [{
+                "amount": amount.to_sat(),
+                "secret": format!("dry-run-{}", Uuid::new_v4().simple()),
+                "C": DRY_RUN_PROOF_C,
+            }],
+        }],
+        "memo": "Dry run token, it cannot be redeemed",
+    });
+
+    Ok(serde_json::from_value(token)?)
+}
+
+/// Backend whose invoices pay themselves after a delay
+pub struct DryRunBackend {
+    settle_delay: Duration,
+    sender: mpsc::UnboundedSender<PaidInvoice>,
+    /// Taken by the first call to wait for invoices
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<PaidInvoice>>>,
+    /// Hashes of invoices that have not paid themselves yet
+    unpaid: Arc<Mutex<HashSet<String>>>,
+    /// Hashes of invoices paid by the backend
+    payments: Mutex<HashSet<String>>,
+    pay_index: Arc<AtomicU64>,
+}
+
+impl DryRunBackend {
+    pub fn new(settle_delay: Duration) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        Self {
+            settle_delay,
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            unpaid: Arc::new(Mutex::new(HashSet::new())),
+            payments: Mutex::new(HashSet::new()),
+            pay_index: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for DryRunBackend {
+    async fn create_invoice(
+        &self,
+        amount: Option<Amount>,
+        description: String,
+        description_hash_only: bool,
+        preimage: Option<[u8; 32]>,
+        expiry: Option<u64>,
+    ) -> Result<Bolt11Invoice> {
+        let preimage = preimage.unwrap_or_else(rand::random);
+        let invoice =
+            dry_run_invoice(amount, description, description_hash_only, preimage, expiry)?;
+        let payment_hash = invoice.payment_hash().to_string();
+        self.unpaid.lock().unwrap().insert(payment_hash.clone());
+
+        let settle_delay = self.settle_delay;
+        let sender = self.sender.clone();
+        let unpaid = self.unpaid.clone();
+        let pay_index = self.pay_index.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(settle_delay).await;
+
+            // Cancelled invoices are never paid
+            if !unpaid.lock().unwrap().remove(&payment_hash) {
+                return;
+            }
+
+            debug!("Dry run paying invoice {}", payment_hash);
+            let _ = sender.send(PaidInvoice {
+                payment_hash,
+                preimage: Some(hex::encode(preimage)),
+                amount_received: Some(amount.unwrap_or(Amount::from_sat(AMOUNTLESS_PAYMENT_SAT))),
+                pay_index: Some(pay_index.fetch_add(1, Ordering::SeqCst) + 1),
+            });
+        });
+
+        Ok(invoice)
+    }
+
+    async fn pay(&self, bolt11: &Bolt11Invoice, _max_fee: Amount) -> Result<Payment> {
+        let payment_hash = bolt11.payment_hash().to_string();
+        debug!("Dry run paying out invoice {}", payment_hash);
+        self.payments.lock().unwrap().insert(payment_hash.clone());
+
+        Ok(Payment {
+            payment_hash,
+            fee: Amount::ZERO,
+        })
+    }
+
+    async fn wait_any_invoice(&self, last_pay_index: Option<u64>) -> Result<InvoiceStream> {
+        // Indexes keep counting from where the last run stopped
+        if let Some(last_pay_index) = last_pay_index {
+            self.pay_index.fetch_max(last_pay_index, Ordering::SeqCst);
+        }
+
+        let receiver = match self.receiver.lock().unwrap().take() {
+            Some(receiver) => receiver,
+            None => bail!("Dry run invoices are already being waited on"),
+        };
+
+        Ok(
+            futures::stream::unfold(receiver, |mut receiver| async move {
+                receiver.recv().await.map(|invoice| (invoice, receiver))
+            })
+            .boxed(),
+        )
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        match self.payments.lock().unwrap().contains(payment_hash) {
+            true => Ok(PaymentStatus::Succeeded),
+            false => Ok(PaymentStatus::Unknown),
+        }
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn cancel_invoice(&self, payment_hash: &str) -> Result<()> {
+        self.unpaid.lock().unwrap().remove(payment_hash);
+        Ok(())
+    }
+
+    fn supports_amountless(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dry_run_backend() {
+        let backend = DryRunBackend::new(Duration::from_millis(10));
+        let mut paid = backend.wait_any_invoice(Some(41)).await.unwrap();
+        assert!(backend.wait_any_invoice(None).await.is_err());
+
+        let preimage = [7; 32];
+        let invoice = backend
+            .create_invoice(
+                Some(Amount::from_sat(21)),
+                "Dry run".to_string(),
+                false,
+                Some(preimage),
+                Some(600),
+            )
+            .await
+            .unwrap();
+        assert_eq!(invoice.amount_milli_satoshis(), Some(21000));
+        assert_eq!(invoice.expiry_time(), Duration::from_secs(600));
+        assert_eq!(
+            invoice.payment_hash().to_string(),
+            sha256::Hash::hash(&preimage).to_string()
+        );
+        // Round trips through the same parser the real backends use
+        assert_eq!(
+            Bolt11Invoice::from_str(&invoice.to_string()).unwrap(),
+            invoice
+        );
+
+        // Cancelled invoices never pay themselves
+        let cancelled = backend
+            .create_invoice(None, "Cancelled".to_string(), true, None, None)
+            .await
+            .unwrap();
+        backend
+            .cancel_invoice(&cancelled.payment_hash().to_string())
+            .await
+            .unwrap();
+
+        let first = paid.next().await.unwrap();
+        assert_eq!(first.payment_hash, invoice.payment_hash().to_string());
+        assert_eq!(first.preimage, Some(hex::encode(preimage)));
+        assert_eq!(first.amount_received, Some(Amount::from_sat(21)));
+        assert_eq!(first.pay_index, Some(42));
+
+        let amountless = backend
+            .create_invoice(None, "Amountless".to_string(), true, None, None)
+            .await
+            .unwrap();
+        assert_eq!(amountless.amount_milli_satoshis(), None);
+
+        let second = paid.next().await.unwrap();
+        assert_eq!(second.payment_hash, amountless.payment_hash().to_string());
+        assert_eq!(
+            second.amount_received,
+            Some(Amount::from_sat(AMOUNTLESS_PAYMENT_SAT))
+        );
+        assert_eq!(second.pay_index, Some(43));
+
+        let payment_hash = invoice.payment_hash().to_string();
+        assert_eq!(
+            backend.payment_status(&payment_hash).await.unwrap(),
+            PaymentStatus::Unknown
+        );
+        let payment = backend.pay(&invoice, Amount::ZERO).await.unwrap();
+        assert_eq!(payment.fee, Amount::ZERO);
+        assert_eq!(
+            backend.payment_status(&payment_hash).await.unwrap(),
+            PaymentStatus::Succeeded
+        );
+    }
+}
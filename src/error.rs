@@ -1,5 +1,12 @@
 // use cashu_crab::error::Error as CashuCrabError;
 
+use std::time::Duration;
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Cashu Crab Error: {0}")]
@@ -8,4 +15,123 @@ pub enum Error {
     CashuCrabClient(#[from] cashu_sdk::client::Error),
     #[error("Cashu Crab wallet Error: {0}")]
     Wallet(#[from] cashu_sdk::wallet::Error),
+    #[error("Mint not allowed: {0}")]
+    MintNotAllowed(String),
+    #[error("Mint unavailable: {0}")]
+    MintUnavailable(String),
+    #[error("No mint could issue an invoice: {0}")]
+    MintsFailed(String),
+}
+
+/// Status of an LNURL response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum LnurlStatus {
+    Ok,
+    Error,
+}
+
+/// Error returned to http clients
+///
+/// Serialized as the LNURL error envelope `{"status":"ERROR","reason":"..."}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LnurlError {
+    #[serde(skip)]
+    pub code: StatusCode,
+    pub status: LnurlStatus,
+    pub reason: String,
+    /// Sent as the `Retry-After` header
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl LnurlError {
+    pub fn new(code: StatusCode, reason: &str) -> Self {
+        Self {
+            code,
+            status: LnurlStatus::Error,
+            reason: reason.to_string(),
+            retry_after: None,
+        }
+    }
+
+    pub fn bad_request(reason: &str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, reason)
+    }
+
+    pub fn not_found(reason: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, reason)
+    }
+
+    pub fn internal(reason: &str) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, reason)
+    }
+
+    pub fn too_many_requests(reason: &str, retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(StatusCode::TOO_MANY_REQUESTS, reason)
+        }
+    }
+}
+
+/// Marks a response as an LNURL error
+#[derive(Debug, Clone, Copy)]
+struct LnurlErrorResponse;
+
+impl IntoResponse for LnurlError {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after;
+        let mut response = (self.code, Json(self)).into_response();
+        response.extensions_mut().insert(LnurlErrorResponse);
+
+        if let Some(retry_after) = retry_after {
+            // Rounded up so retrying after it always succeeds
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        response
+    }
+}
+
+/// Send LNURL errors with a `200` status as the LNURL spec expects
+///
+/// Used as a `map_response` middleware when http error statuses are disabled
+pub async fn lnurl_error_ok<B>(mut response: axum::http::Response<B>) -> axum::http::Response<B> {
+    if response.extensions().get::<LnurlErrorResponse>().is_some() {
+        *response.status_mut() = StatusCode::OK;
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lnurl_error_ok() {
+        let response =
+            lnurl_error_ok(LnurlError::bad_request("Amount out of range").into_response()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Only LNURL errors are rewritten
+        let response = lnurl_error_ok(StatusCode::BAD_REQUEST.into_response()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let response =
+            LnurlError::too_many_requests("Too many requests", Duration::from_millis(1500))
+                .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+
+        let response = LnurlError::bad_request("Amount out of range").into_response();
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
 }
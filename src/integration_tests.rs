@@ -0,0 +1,412 @@
+//! The invoice, pay, mint and DM path against a mock lightning node and a mock mint
+
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use axum::body::{Body, HttpBody};
+use axum::http::{header, Request, StatusCode};
+use axum::routing::get;
+use axum::Router;
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use cashu_sdk::{Amount, Bolt11Invoice};
+use nostr_sdk::secp256k1::rand;
+use nostr_sdk::{Keys, Url};
+use tokio::sync::watch;
+use tower::ServiceExt;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Respond, ResponseTemplate};
+
+use crate::backend::{InvoiceStream, PaidInvoice, Payment, PaymentBackend, PaymentStatus};
+use crate::dry_run::dry_run_invoice;
+use crate::mint_api::hash_to_curve;
+use crate::p2pk::mint_locked;
+use crate::routes::get_user_invoice;
+use crate::routes::tests::test_state;
+use crate::types::{User, UserKind};
+
+/// Keyset id the mock mint signs with
+const KEYSET_ID: &str = "I2yN+iRYfkzT";
+
+/// Largest power of two the mock mint has a key for
+const MAX_ORDER: u32 = 20;
+
+/// Hashes of mint invoices the mock lightning node has paid
+type PaidHashes = Arc<Mutex<HashSet<String>>>;
+
+/// Private key of the mock mint for each amount
+fn mint_keys() -> BTreeMap<u64, SecretKey> {
+    (0..=MAX_ORDER)
+        .map(|order| {
+            let key = SecretKey::from_slice(&[order as u8 + 1; 32]).unwrap();
+            (2u64.pow(order), key)
+        })
+        .collect()
+}
+
+/// Invoices for `GET /mint?amount=`, the hash is the invoice's payment hash
+struct RequestMint;
+
+impl Respond for RequestMint {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let amount = request
+            .url
+            .query_pairs()
+            .find(|(key, _)| key == "amount")
+            .and_then(|(_, amount)| amount.parse().ok())
+            .map(Amount::from_sat);
+        let pr = dry_run_invoice(amount, String::new(), false, rand::random(), None).unwrap();
+
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "pr": pr.to_string(),
+            "hash": pr.payment_hash().to_string(),
+        }))
+    }
+}
+
+/// Blind signatures for `POST /mint?hash=`, once the invoice has been paid
+struct Mint {
+    keys: BTreeMap<u64, SecretKey>,
+    paid: PaidHashes,
+}
+
+impl Respond for Mint {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let hash = request
+            .url
+            .query_pairs()
+            .find(|(key, _)| key == "hash")
+            .map(|(_, hash)| hash.to_string())
+            .unwrap_or_default();
+        if !self.paid.lock().unwrap().contains(&hash) {
+            return ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "code": 0,
+                "error": "Lightning invoice not paid yet.",
+            }));
+        }
+
+        let secp = Secp256k1::new();
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        let promises: Vec<serde_json::Value> = body["outputs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|output| {
+                let amount = output["amount"].as_u64().unwrap();
+                let blinded = PublicKey::from_str(output["B_"].as_str().unwrap()).unwrap();
+                let signed = blinded
+                    .mul_tweak(&secp, &Scalar::from(self.keys[&amount]))
+                    .unwrap();
+
+                serde_json::json!({
+                    "id": KEYSET_ID,
+                    "amount": amount,
+                    "C_": signed.to_string(),
+                })
+            })
+            .collect();
+
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "promises": promises }))
+    }
+}
+
+/// Mint serving the v0 mint api, it mints once the mock lightning node pays its invoice
+struct MockMint {
+    server: MockServer,
+    paid: PaidHashes,
+}
+
+impl MockMint {
+    async fn start() -> Self {
+        let server = MockServer::start().await;
+        let paid = PaidHashes::default();
+        let secp = Secp256k1::new();
+
+        let keys: BTreeMap<String, String> = mint_keys()
+            .into_iter()
+            .map(|(amount, key)| (amount.to_string(), key.public_key(&secp).to_string()))
+            .collect();
+        Mock::given(method("GET"))
+            .and(path("/keys"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(keys))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/keysets"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "keysets": [KEYSET_ID] })),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/mint"))
+            .respond_with(RequestMint)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/mint"))
+            .respond_with(Mint {
+                keys: mint_keys(),
+                paid: paid.clone(),
+            })
+            .mount(&server)
+            .await;
+
+        Self { server, paid }
+    }
+
+    fn url(&self) -> Url {
+        Url::from_str(&self.server.uri()).unwrap()
+    }
+}
+
+/// Lightning node whose invoices are paid by the test and whose payments always succeed
+struct MockLightning {
+    /// Shared with the mock mint, which mints the invoices paid here
+    paid: PaidHashes,
+}
+
+#[async_trait]
+impl PaymentBackend for MockLightning {
+    async fn create_invoice(
+        &self,
+        amount: Option<Amount>,
+        description: String,
+        description_hash_only: bool,
+        preimage: Option<[u8; 32]>,
+        expiry: Option<u64>,
+    ) -> Result<Bolt11Invoice> {
+        dry_run_invoice(
+            amount,
+            description,
+            description_hash_only,
+            preimage.unwrap_or_else(rand::random),
+            expiry,
+        )
+    }
+
+    async fn pay(&self, bolt11: &Bolt11Invoice, _max_fee: Amount) -> Result<Payment> {
+        let payment_hash = bolt11.payment_hash().to_string();
+        self.paid.lock().unwrap().insert(payment_hash.clone());
+
+        Ok(Payment {
+            payment_hash,
+            fee: Amount::ZERO,
+        })
+    }
+
+    async fn wait_any_invoice(&self, _last_pay_index: Option<u64>) -> Result<InvoiceStream> {
+        bail!("Payments are made by the test")
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        match self.paid.lock().unwrap().contains(payment_hash) {
+            true => Ok(PaymentStatus::Succeeded),
+            false => Ok(PaymentStatus::Unknown),
+        }
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Json of a token string, `cashuA` followed by url safe base64
+fn decode_token(token: &str) -> serde_json::Value {
+    let engine = GeneralPurpose::new(
+        &alphabet::URL_SAFE,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+    let json = engine
+        .decode(token.strip_prefix("cashuA").unwrap())
+        .unwrap();
+
+    serde_json::from_slice(&json).unwrap()
+}
+
+#[tokio::test]
+async fn test_proxied_invoice_is_minted_and_dmed() {
+    let mint = MockMint::start().await;
+    let ln_backend = Arc::new(MockLightning {
+        paid: mint.paid.clone(),
+    });
+
+    let mut state = test_state().await;
+    state.proxy = true;
+    state.ln_backend = Some(ln_backend.clone());
+    state.comment_allowed = 255;
+
+    let user = User {
+        username: "alice".to_string(),
+        domain: "example.com".to_string(),
+        mint: mint.url(),
+        fallback_mints: vec![],
+        mint_rules: vec![],
+        pubkey: Keys::generate().public_key().to_string(),
+        relays: HashSet::new(),
+        proxy: true,
+        min_sendable: None,
+        max_sendable: None,
+        success_message: None,
+        description: None,
+        avatar: None,
+        webhook_url: None,
+        webhook_secret: None,
+        lock_to_pubkey: false,
+        created_at: None,
+    };
+    state
+        .db
+        .add_user("example.com", "alice", &UserKind::User(user))
+        .await
+        .unwrap();
+
+    let router = Router::new()
+        .route("/lnurlp/:username/invoice", get(get_user_invoice))
+        .with_state(state.clone());
+    let response = router
+        .oneshot(
+            Request::builder()
+                .uri("/lnurlp/alice/invoice?amount=21000&comment=Thanks")
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut body = response.into_body();
+    let mut json = Vec::new();
+    while let Some(chunk) = body.data().await {
+        json.extend_from_slice(&chunk.unwrap());
+    }
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let pr = Bolt11Invoice::from_str(json["pr"].as_str().unwrap()).unwrap();
+    assert_eq!(pr.amount_milli_satoshis(), Some(21_000));
+    let payer_hash = pr.payment_hash().to_string();
+
+    // The payer pays the invoice from the lightning node
+    let invoice = state
+        .db
+        .get_pending_invoice(&payer_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    state
+        .cashu
+        .proxy_payment(
+            ln_backend.as_ref(),
+            invoice,
+            PaidInvoice {
+                payment_hash: payer_hash.clone(),
+                preimage: None,
+                amount_received: Some(Amount::from_sat(21)),
+                pay_index: Some(1),
+            },
+        )
+        .await;
+    assert_eq!(mint.paid.lock().unwrap().len(), 1);
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let cashu = state.cashu.clone();
+    let cashu_task = tokio::spawn(async move { cashu.run(shutdown_rx).await });
+
+    // There are no relays in tests so the DM is queued with its token
+    let deliveries = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let deliveries = state.db.get_token_deliveries().await.unwrap();
+            if !deliveries.is_empty() {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Token was never minted");
+    shutdown_tx.send(true).unwrap();
+    cashu_task.await.unwrap().unwrap();
+
+    assert_eq!(deliveries.len(), 1);
+    let delivery = &deliveries[0];
+    assert_eq!(delivery.username, "alice");
+    assert_eq!(delivery.payer_hash.as_deref(), Some(payer_hash.as_str()));
+    assert_eq!(delivery.comment.as_deref(), Some("Thanks"));
+
+    let token = decode_token(&delivery.token);
+    assert_eq!(
+        token["token"][0]["mint"]
+            .as_str()
+            .unwrap()
+            .trim_end_matches('/'),
+        mint.url().as_str().trim_end_matches('/')
+    );
+    let minted: u64 = token["token"][0]["proofs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|proof| proof["amount"].as_u64().unwrap())
+        .sum();
+    assert_eq!(minted, 21);
+
+    // Nothing is left pending and the payment is recorded as minted
+    assert!(state.db.get_pending_invoices().await.unwrap().is_empty());
+    let record = state
+        .db
+        .get_payment_record(&payer_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(record.amount_msat, 21_000);
+    assert_eq!(record.minted_msat, Some(21_000));
+    assert!(!record.delivered);
+    assert_eq!(record.comment.as_deref(), Some("Thanks"));
+}
+
+#[tokio::test]
+async fn test_mint_locked_token() {
+    let mint = MockMint::start().await;
+    let secp = Secp256k1::new();
+    let pubkey = Keys::generate().public_key().to_string();
+    let hash = "00".repeat(32);
+    let client = reqwest::Client::new();
+
+    // Nothing is minted before the invoice is paid
+    assert!(
+        mint_locked(&client, &mint.url(), Amount::from_sat(21), &hash, &pubkey)
+            .await
+            .is_err()
+    );
+
+    mint.paid.lock().unwrap().insert(hash.clone());
+    let token = mint_locked(&client, &mint.url(), Amount::from_sat(21), &hash, &pubkey)
+        .await
+        .unwrap();
+
+    let token = decode_token(&token.convert_to_string().unwrap());
+    let proofs = token["token"][0]["proofs"].as_array().unwrap();
+    assert_eq!(proofs.len(), 3);
+    let keys = mint_keys();
+    for proof in proofs {
+        let secret = proof["secret"].as_str().unwrap();
+        let condition: serde_json::Value = serde_json::from_str(secret).unwrap();
+        assert_eq!(condition[0], "P2PK");
+        assert_eq!(condition[1]["data"], format!("02{}", pubkey));
+        assert_eq!(proof["id"], KEYSET_ID);
+
+        // Unblinded to the mint's signature of the secret, so the mint accepts the proof
+        let key = keys[&proof["amount"].as_u64().unwrap()];
+        let signature = hash_to_curve(secret.as_bytes())
+            .mul_tweak(&secp, &Scalar::from(key))
+            .unwrap();
+        assert_eq!(proof["C"], signature.to_string());
+    }
+}
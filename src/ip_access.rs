@@ -0,0 +1,307 @@
+//! Allow and deny lists of client ips for each group of routes
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use ipnet::IpNet;
+use tracing::info;
+
+use crate::config::{IpAccessConfig, IpRulesConfig};
+use crate::error::LnurlError;
+use crate::rate_limit::client_ip;
+
+/// Routes that share ip rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    /// LNURL, user and claim routes
+    Public,
+    /// Signup and the signup page
+    Signup,
+    /// `/admin` and the user management routes
+    Admin,
+}
+
+impl fmt::Display for RouteGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteGroup::Public => write!(f, "public"),
+            RouteGroup::Signup => write!(f, "signup"),
+            RouteGroup::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Network of an ip or CIDR entry, such as `10.8.0.0/24` or `127.0.0.1`
+fn parse_network(entry: &str, list: &str) -> Result<IpNet> {
+    let entry = entry.trim();
+    IpNet::from_str(entry)
+        .or_else(|_| IpAddr::from_str(entry).map(IpNet::from))
+        .map_err(|_| {
+            anyhow!(
+                "Invalid entry {:?} in {}, expected an ip or CIDR network such as 10.8.0.0/24",
+                entry,
+                list
+            )
+        })
+}
+
+/// Ip as matched against the lists, IPv4 clients of a dual stack listener are seen mapped to IPv6
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Parsed allow and deny lists of a route group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpRules {
+    /// Only these networks are allowed when set
+    allow: Option<Vec<IpNet>>,
+    deny: Vec<IpNet>,
+}
+
+impl IpRules {
+    /// Parse the lists of a group, its own lists replace the lists shared by every group
+    fn new(shared: &IpRulesConfig, group: Option<&IpRulesConfig>, name: &str) -> Result<Self> {
+        let (allow, allow_list) = match group.and_then(|group| group.allow.as_ref()) {
+            Some(allow) => (Some(allow), format!("ip_access.{}.allow", name)),
+            None => (shared.allow.as_ref(), "ip_access.allow".to_string()),
+        };
+        let (deny, deny_list) = match group.and_then(|group| group.deny.as_ref()) {
+            Some(deny) => (Some(deny), format!("ip_access.{}.deny", name)),
+            None => (shared.deny.as_ref(), "ip_access.deny".to_string()),
+        };
+
+        Ok(Self {
+            allow: allow
+                .map(|allow| {
+                    allow
+                        .iter()
+                        .map(|entry| parse_network(entry, &allow_list))
+                        .collect::<Result<_>>()
+                })
+                .transpose()?,
+            deny: deny
+                .into_iter()
+                .flatten()
+                .map(|entry| parse_network(entry, &deny_list))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Client is not denied and is allowed if there is an allow list
+    ///
+    /// Clients whose ip is unknown are only allowed without an allow list
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let ip = ip.map(canonical_ip);
+        if let Some(ip) = ip {
+            if self.deny.iter().any(|network| network.contains(&ip)) {
+                return false;
+            }
+        }
+
+        match (&self.allow, ip) {
+            (Some(allow), Some(ip)) => allow.iter().any(|network| network.contains(&ip)),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+/// Ip rules of every route group, parsed at startup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpAccess {
+    pub public: IpRules,
+    pub signup: IpRules,
+    pub admin: IpRules,
+}
+
+impl IpAccess {
+    pub fn new(config: &IpAccessConfig) -> Result<Self> {
+        let shared = IpRulesConfig {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        };
+
+        Ok(Self {
+            public: IpRules::new(&shared, config.public.as_ref(), "public")?,
+            signup: IpRules::new(&shared, config.signup.as_ref(), "signup")?,
+            admin: IpRules::new(&shared, config.admin.as_ref(), "admin")?,
+        })
+    }
+}
+
+/// State of the middleware guarding one route group
+#[derive(Debug, Clone)]
+pub struct IpGuard {
+    group: RouteGroup,
+    rules: Arc<IpRules>,
+    trusted_proxies: Arc<HashSet<IpAddr>>,
+}
+
+impl IpGuard {
+    pub fn new(group: RouteGroup, rules: IpRules, trusted_proxies: Arc<HashSet<IpAddr>>) -> Self {
+        Self {
+            group,
+            rules: Arc::new(rules),
+            trusted_proxies,
+        }
+    }
+}
+
+/// Middleware responding `403` to clients the ip rules of the route group do not allow
+///
+/// The client ip is read from `X-Forwarded-For` behind trusted proxies, as for rate limits
+pub async fn restrict_ips<B>(
+    State(guard): State<IpGuard>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let ip = client_ip(peer, request.headers(), &guard.trusted_proxies);
+
+    if !guard.rules.allows(ip) {
+        info!(
+            "Denied {} to {} by the {} ip rules",
+            ip.map_or("unknown ip".to_string(), |ip| ip.to_string()),
+            request.uri().path(),
+            guard.group
+        );
+        return LnurlError::new(StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::middleware;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn strings(entries: &[&str]) -> Option<Vec<String>> {
+        Some(entries.iter().map(|entry| entry.to_string()).collect())
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(IpAddr::from_str(ip).unwrap())
+    }
+
+    #[test]
+    fn test_ip_rules() {
+        let access = IpAccess::new(&IpAccessConfig {
+            allow: None,
+            deny: strings(&["203.0.113.7"]),
+            public: None,
+            signup: Some(IpRulesConfig {
+                allow: strings(&["10.8.0.0/24", "fd00::/8"]),
+                deny: None,
+            }),
+            admin: Some(IpRulesConfig {
+                allow: strings(&["127.0.0.1"]),
+                deny: strings(&[]),
+            }),
+        })
+        .unwrap();
+
+        assert!(access.public.allows(ip("198.51.100.1")));
+        assert!(access.public.allows(None));
+        assert!(!access.public.allows(ip("203.0.113.7")));
+
+        assert!(access.signup.allows(ip("10.8.0.42")));
+        assert!(access.signup.allows(ip("fd00::1")));
+        assert!(access.signup.allows(ip("::ffff:10.8.0.42")));
+        assert!(!access.signup.allows(ip("10.8.1.1")));
+        assert!(!access.signup.allows(None));
+        // The shared deny list applies to groups without their own
+        assert!(!access.signup.allows(ip("203.0.113.7")));
+
+        assert!(access.admin.allows(ip("127.0.0.1")));
+        assert!(!access.admin.allows(ip("10.8.0.42")));
+
+        let err = IpAccess::new(&IpAccessConfig {
+            admin: Some(IpRulesConfig {
+                allow: strings(&["10.8.0.0/33"]),
+                deny: None,
+            }),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("\"10.8.0.0/33\" in ip_access.admin.allow"));
+        assert!(IpAccess::new(&IpAccessConfig {
+            deny: strings(&["vpn"]),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restrict_ips() {
+        let rules = IpRules {
+            allow: Some(vec![IpNet::from_str("10.8.0.0/24").unwrap()]),
+            deny: vec![],
+        };
+        let proxy = IpAddr::from_str("127.0.0.1").unwrap();
+        let router = Router::new()
+            .route("/signup", get(|| async { "signup" }))
+            .route_layer(middleware::from_fn_with_state(
+                IpGuard::new(RouteGroup::Signup, rules, Arc::new(HashSet::from([proxy]))),
+                restrict_ips,
+            ));
+        let request = |peer: &str, forwarded: Option<&str>| {
+            let request = Request::builder().uri("/signup");
+            let request = match forwarded {
+                Some(forwarded) => request.header("x-forwarded-for", forwarded),
+                None => request,
+            };
+            let mut request = request.body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(ip(peer).unwrap(), 40000)));
+            request
+        };
+
+        let allowed = router
+            .clone()
+            .oneshot(request("10.8.0.2", None))
+            .await
+            .unwrap();
+        assert_eq!(allowed.status(), StatusCode::OK);
+
+        let denied = router
+            .clone()
+            .oneshot(request("198.51.100.1", None))
+            .await
+            .unwrap();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+
+        // Behind the trusted proxy the forwarded client is checked
+        let forwarded = router
+            .clone()
+            .oneshot(request("127.0.0.1", Some("10.8.0.3")))
+            .await
+            .unwrap();
+        assert_eq!(forwarded.status(), StatusCode::OK);
+        let forwarded = router
+            .oneshot(request("127.0.0.1", Some("198.51.100.1")))
+            .await
+            .unwrap();
+        assert_eq!(forwarded.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,47 +1,106 @@
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, bail};
-use axum::routing::{delete, get, post};
+use axum::extract::{ConnectInfo, Extension};
+use axum::middleware::{self, map_response};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
+use backend::{ClnBackend, LndBackend, PaymentBackend};
+use cache::{TtlCache, DEFAULT_LNURL_CACHE_TTL, DEFAULT_LNURL_MAX_AGE};
 use cashu::Cashu;
 use cashu_sdk::Amount;
 use clap::Parser;
-use cln_rpc::model::requests::{PayRequest, WaitanyinvoiceRequest};
-use cln_rpc::model::responses::WaitanyinvoiceResponse;
-use cln_rpc::primitives::Amount as CLN_Amount;
-use cln_rpc::ClnRpc;
 use database::Db;
-use dirs::data_dir;
-use futures::{Stream, StreamExt};
-use nostr_sdk::Url;
-use tokio::sync::Mutex;
+use futures::future::{try_join_all, BoxFuture};
+use futures::{FutureExt, StreamExt, TryFutureExt};
+use nostr_sdk::{Keys, Url};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
-use tracing::{debug, info, warn};
-use types::{unix_time, PendingInvoice, PendingUser, UserKind};
-
-use crate::cli::CLIArgs;
-use crate::config::{Info, Network, Settings};
+use tracing::{debug, info, warn, Instrument};
+use types::{unix_time, InviteStatus, MintRule, PendingUser, UserKind};
+use zeroize::Zeroizing;
+
+use crate::admin::require_admin;
+use crate::bind::{bind_tcp, parse_bind_addrs};
+use crate::cashu::{fee_for_invoice, max_routing_fee, min_proxied_amount, RetryOutcome};
+use crate::cli::{CLIArgs, Command, InvoicesCommand, TokensCommand};
+use crate::config::{
+    get_npub, mint_allowed, normalize_mint_url, parse_nsec, socks_proxy_addr, Info, Network,
+    Settings, SuccessActionKind,
+};
+use crate::cors::cors_layer;
+use crate::data_dir::DataDir;
+use crate::dry_run::{DryRunBackend, DRY_RUN_SETTLE_DELAY};
+use crate::error::lnurl_error_ok;
+use crate::ip_access::{restrict_ips, IpAccess, IpGuard, RouteGroup};
+use crate::metrics::{track_requests, Metrics};
+use crate::nip98::UsedEvents;
 use crate::nostr::Nostr;
+use crate::notifications::Notifier;
+use crate::nwc::NwcBackend;
+use crate::payer_auth::PayerAuthK1s;
+use crate::rate_limit::{limit_invoice_requests, RateLimiter, SignupLimiter};
+use crate::request_id::assign_request_id;
 use crate::routes::{
-    delete_user, get_list_users, get_user_invoice, get_user_lnurl_struct, post_add_user,
-    post_block_user, post_reserve_user, post_sign_up,
+    delete_admin_invite, delete_admin_invoice, delete_user, delete_user_account, get_admin_invites,
+    get_admin_invoice, get_admin_payments_csv, get_admin_pending_invoices, get_admin_report,
+    get_admin_users, get_claim, get_health, get_list_users, get_metrics, get_redeem, get_sign_up,
+    get_signup_page, get_user_export, get_user_info, get_user_invoice, get_user_lnurl,
+    get_user_lnurl_struct, get_user_qr, get_verify, get_ws_notifications, post_add_user,
+    post_admin_invite, post_block_user, post_reserve_user, post_sign_up, put_user, put_user_limits,
+    read_avatar, LnurlResponse, DEFAULT_SIGNUP_EXPIRY_SECS, DEFAULT_UPSTREAM_TIMEOUT_SECS,
 };
-
+use crate::secrets::{read_nsec, Secret};
+use crate::tls::TlsFiles;
+use crate::unix_socket::{parse_listen, parse_socket_mode, remove_socket, UnixAccept};
+use crate::username::{read_reserved_usernames, UsernameRules};
+use crate::webhooks::{Webhooks, DEFAULT_WEBHOOK_MAX_RETRIES};
+
+mod admin;
+mod backend;
+mod bind;
+mod cache;
 mod cashu;
 mod cli;
 mod config;
+mod cors;
+mod data_dir;
 mod database;
+mod dry_run;
 mod error;
+#[cfg(test)]
+mod integration_tests;
+mod ip_access;
+mod metrics;
+mod mint_api;
+mod nip98;
 mod nostr;
+mod notifications;
+mod nwc;
+mod outbound;
+mod p2pk;
+mod payer_auth;
+mod qr;
+mod rate_limit;
+mod report;
+mod request_id;
 mod routes;
+mod secrets;
+mod sqlite;
+mod tls;
 mod types;
+mod unix_socket;
+mod username;
+mod webhooks;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -61,16 +120,128 @@ async fn main() -> anyhow::Result<()> {
         None => config_file_settings.info.url,
     };
 
+    let domains = if args.domains.is_empty() {
+        config_file_settings.info.domains
+    } else {
+        args.domains.into_iter().collect()
+    };
+
     let mint = args.mint.unwrap_or(config_file_settings.info.mint);
 
+    let allowed_mints = if args.allowed_mints.is_empty() {
+        config_file_settings.info.allowed_mints
+    } else {
+        Some(args.allowed_mints.into_iter().collect())
+    };
+    let allowed_mints = allowed_mints
+        .map(|mints| {
+            mints
+                .iter()
+                .map(|mint| normalize_mint_url(mint))
+                .collect::<anyhow::Result<HashSet<String>>>()
+        })
+        .transpose()?;
+
+    if let Ok(default_mint) = Url::from_str(&mint) {
+        if !mint_allowed(&allowed_mints, &default_mint) {
+            warn!("Default mint {} is not an allowed mint", mint);
+        }
+    }
+
+    let mint_rules = config_file_settings.info.mint_rules.unwrap_or_default();
+    if let Some(rule) = mint_rules
+        .iter()
+        .find(|rule| !mint_allowed(&allowed_mints, &rule.mint))
+    {
+        bail!("Mint rule mint {} is not an allowed mint", rule.mint);
+    }
+
     let invoice_description = args
         .invoice_description
         .or(config_file_settings.info.invoice_description);
+    let avatar_path = args.avatar_path.or(config_file_settings.info.avatar_path);
+
+    let success_message = args
+        .success_message
+        .or(config_file_settings.info.success_message);
+
+    let claim_ttl = args.claim_ttl.or(config_file_settings.info.claim_ttl);
+
+    let reconcile_interval = args
+        .reconcile_interval
+        .or(config_file_settings.info.reconcile_interval);
+
+    let reconcile_after = args
+        .reconcile_after
+        .or(config_file_settings.info.reconcile_after);
+
+    let http_error_status = args
+        .http_error_status
+        .or(config_file_settings.info.http_error_status);
+
+    let admin_token = args.admin_token.or(config_file_settings.info.admin_token);
+
+    let invoice_rate_limit = args
+        .invoice_rate_limit
+        .or(config_file_settings.info.invoice_rate_limit);
+
+    let lnurl_cache_ttl = args
+        .lnurl_cache_ttl
+        .or(config_file_settings.info.lnurl_cache_ttl);
+
+    let lnurl_max_age = args
+        .lnurl_max_age
+        .or(config_file_settings.info.lnurl_max_age);
+
+    let webhook_max_retries = args
+        .webhook_max_retries
+        .or(config_file_settings.info.webhook_max_retries);
+
+    let token_delivery_max_retries = args
+        .token_delivery_max_retries
+        .or(config_file_settings.info.token_delivery_max_retries);
+
+    let invoice_expiry_secs = args
+        .invoice_expiry_secs
+        .or(config_file_settings.info.invoice_expiry_secs);
+
+    let upstream_timeout_secs = args
+        .upstream_timeout_secs
+        .or(config_file_settings.info.upstream_timeout_secs);
+    if upstream_timeout_secs == Some(0) {
+        bail!("upstream_timeout_secs must be at least 1");
+    }
+
+    let signup_rate_limit = args
+        .signup_rate_limit
+        .or(config_file_settings.info.signup_rate_limit);
+
+    let signup_global_rate_limit = args
+        .signup_global_rate_limit
+        .or(config_file_settings.info.signup_global_rate_limit);
+
+    let signup_daily_limit = args
+        .signup_daily_limit
+        .or(config_file_settings.info.signup_daily_limit);
+
+    let cors_origins = if args.cors_origins.is_empty() {
+        config_file_settings.info.cors_origins
+    } else {
+        Some(args.cors_origins.into_iter().collect())
+    };
 
+    let success_action = args
+        .success_action
+        .unwrap_or(config_file_settings.info.success_action.unwrap_or_default());
+
+    // Secrets are kept out of the settings shared with the service
     let nostr_nsec = match args.nsec {
-        Some(nsec) => Some(nsec),
+        Some(nsec) => Some(Secret::new(nsec)),
         None => config_file_settings.info.nostr_nsec,
     };
+    let nostr_nsec_file = args.nsec_file.or(config_file_settings.info.nostr_nsec_file);
+    // Only used to decrypt the nsec, never kept in settings
+    let nsec_passphrase = args.nsec_passphrase.map(Zeroizing::new);
 
     let relays = if args.relays.is_empty() {
         config_file_settings.info.relays
@@ -78,30 +249,74 @@ async fn main() -> anyhow::Result<()> {
         args.relays.into_iter().collect()
     };
 
-    let max_sendable: Amount = args.max_sendable.map(Amount::from_sat).unwrap_or(
-        config_file_settings
-            .info
-            .max_sendable
-            .unwrap_or(Amount::from_sat(1000000)),
-    );
-
-    let min_sendable: Amount = args.max_sendable.map(Amount::from_sat).unwrap_or(
-        config_file_settings
-            .info
-            .min_sendable
-            .unwrap_or(Amount::from_sat(1)),
+    let (min_sendable, max_sendable) = sendable_range(
+        args.min_sendable,
+        args.max_sendable,
+        &config_file_settings.info,
     );
 
+    let data_dir = args.data_dir.or(config_file_settings.info.data_dir);
     let db_path = args.db_path.or(config_file_settings.info.db_path);
+    let db_backend = args.db_backend.or(config_file_settings.info.db_backend);
 
     let proxy = args.proxy.unwrap_or(config_file_settings.info.proxy);
+    let socks_proxy = args.socks_proxy.or(config_file_settings.info.socks_proxy);
 
-    let fee = args
-        .fee
-        .unwrap_or(config_file_settings.info.fee.unwrap_or(0.0));
+    let routing_fee_percent = args
+        .routing_fee_percent
+        .unwrap_or(config_file_settings.info.routing_fee_percent.unwrap_or(0.0));
+
+    let routing_fee_base_sat = args
+        .routing_fee_base_sat
+        .unwrap_or(config_file_settings.info.routing_fee_base_sat.unwrap_or(0));
 
     let cln_path = args.cln_path.or(config_file_settings.info.cln_path);
 
+    let lnd_grpc_url = args.lnd_grpc_url.or(config_file_settings.info.lnd_grpc_url);
+
+    let lnd_cert_path = args
+        .lnd_cert_path
+        .or(config_file_settings.info.lnd_cert_path);
+
+    let lnd_macaroon_path = args
+        .lnd_macaroon_path
+        .or(config_file_settings.info.lnd_macaroon_path);
+
+    let nwc_uri = args
+        .nwc_uri
+        .map(Secret::new)
+        .or(config_file_settings.info.nwc_uri);
+
+    if [
+        cln_path.is_some(),
+        lnd_grpc_url.is_some(),
+        nwc_uri.is_some(),
+    ]
+    .iter()
+    .filter(|set| **set)
+    .count()
+        > 1
+    {
+        bail!("Only one of cln_path, lnd_grpc_url and nwc_uri can be set");
+    }
+
+    // Proxying pays mint invoices, and NWC wallets cannot be held to a routing fee limit
+    if nwc_uri.is_some() && proxy {
+        bail!("Invoices cannot be proxied with nwc_uri");
+    }
+
+    // Proxied invoices are minted less the fee, so the smallest must leave something to mint
+    let min_proxied =
+        match min_proxied_amount(routing_fee_percent, Amount::from_sat(routing_fee_base_sat)) {
+            Some(min_proxied) => min_proxied,
+            None if proxy => bail!("routing_fee_percent must be below 1 to proxy invoices"),
+            None => Amount::ZERO,
+        };
+
+    let dry_run = args
+        .dry_run
+        .unwrap_or(config_file_settings.info.dry_run.unwrap_or_default());
+
     let zapper = Some(
         args.zapper
             .unwrap_or(config_file_settings.info.zapper.unwrap_or_default()),
@@ -111,10 +326,64 @@ async fn main() -> anyhow::Result<()> {
         .pay_index_path
         .or(config_file_settings.info.pay_index_path);
 
-    let address = args.address.unwrap_or(config_file_settings.network.address);
+    // Addresses given on the command line replace both address and addresses
+    let address = config_file_settings.network.address;
+    let addresses = match args.address.is_empty() {
+        true => config_file_settings
+            .network
+            .addresses
+            .unwrap_or_else(|| vec![address.clone()]),
+        false => args.address,
+    };
 
     let port = args.port.unwrap_or(config_file_settings.network.port);
 
+    let metrics_port = args
+        .metrics_port
+        .or(config_file_settings.network.metrics_port);
+
+    // Takes precedence over address and port, which clap keeps off the command line with it
+    let listen = args.listen.or(config_file_settings.network.listen);
+    let socket_mode = args
+        .socket_mode
+        .or(config_file_settings.network.socket_mode);
+    let unix_socket = listen.as_deref().map(parse_listen).transpose()?;
+    let unix_socket_mode = socket_mode.as_deref().map(parse_socket_mode).transpose()?;
+    if unix_socket.is_none() && unix_socket_mode.is_some() {
+        bail!("socket_mode is only used when listen is a unix socket");
+    }
+    // With a unix socket the addresses are only bound for metrics_port
+    let bind_addrs = match unix_socket.is_none() || metrics_port.is_some() {
+        true => parse_bind_addrs(&addresses, port)?,
+        false => vec![],
+    };
+
+    let tls_cert_path = args
+        .tls_cert_path
+        .or(config_file_settings.network.tls_cert_path);
+    let tls_key_path = args
+        .tls_key_path
+        .or(config_file_settings.network.tls_key_path);
+    let tls_files = TlsFiles::new(tls_cert_path.clone(), tls_key_path.clone())?;
+    if unix_socket.is_some() && tls_files.is_some() {
+        bail!("tls_cert_path and tls_key_path are not used when listen is a unix socket");
+    }
+    // Loaded before anything else is started so a key that does not match fails startup
+    let tls = match tls_files {
+        Some(tls_files) => Some((tls_files.rustls_config()?, tls_files)),
+        None => None,
+    };
+
+    let trusted_proxies = if args.trusted_proxies.is_empty() {
+        config_file_settings.network.trusted_proxies
+    } else {
+        Some(args.trusted_proxies.into_iter().collect())
+    };
+
+    let ip_access = config_file_settings.network.ip_access;
+    // Malformed networks stop startup rather than leaving routes open
+    let ip_rules = IpAccess::new(&ip_access.clone().unwrap_or_default())?;
+
     let two_char_cost: Amount = args.two_char_price.map(Amount::from_sat).unwrap_or(
         config_file_settings
             .info
@@ -143,35 +412,228 @@ async fn main() -> anyhow::Result<()> {
             .unwrap_or(Amount::from_sat(0)),
     );
 
+    let signup_expiry_secs = args.signup_expiry_secs.unwrap_or(
+        config_file_settings
+            .info
+            .signup_expiry_secs
+            .unwrap_or(DEFAULT_SIGNUP_EXPIRY_SECS),
+    );
+
+    let comment_allowed = args
+        .comment_allowed
+        .unwrap_or(config_file_settings.info.comment_allowed.unwrap_or(0));
+
+    let sat_only = args.sat_only.or(config_file_settings.info.sat_only);
+
+    let allow_amountless = args
+        .allow_amountless
+        .or(config_file_settings.info.allow_amountless);
+
+    let auth_window = args
+        .auth_window
+        .unwrap_or(config_file_settings.info.auth_window.unwrap_or(300));
+
+    let allow_get_signup = args
+        .allow_get_signup
+        .unwrap_or(config_file_settings.info.allow_get_signup.unwrap_or(false));
+
+    let serve_signup_page = args
+        .serve_signup_page
+        .unwrap_or(config_file_settings.info.serve_signup_page.unwrap_or(false));
+
+    let signups_disabled = args
+        .signups_disabled
+        .unwrap_or(config_file_settings.info.signups_disabled.unwrap_or(false));
+
+    let catch_all_user = args
+        .catch_all_user
+        .or(config_file_settings.info.catch_all_user);
+
+    let username_pattern = args
+        .username_pattern
+        .or(config_file_settings.info.username_pattern);
+    let username_min_length = args
+        .username_min_length
+        .or(config_file_settings.info.username_min_length);
+    let username_max_length = args
+        .username_max_length
+        .or(config_file_settings.info.username_max_length);
+    let allow_unicode_usernames = args.allow_unicode_usernames.unwrap_or(
+        config_file_settings
+            .info
+            .allow_unicode_usernames
+            .unwrap_or(false),
+    );
+    let username_rules = UsernameRules::new(
+        username_pattern.as_deref(),
+        username_min_length,
+        username_max_length,
+        allow_unicode_usernames,
+    )
+    .map_err(|err| anyhow!("Invalid username_pattern: {}", err))?;
+
+    let reserved_usernames = if args.reserved_usernames.is_empty() {
+        config_file_settings.info.reserved_usernames
+    } else {
+        Some(args.reserved_usernames.into_iter().collect())
+    };
+    let reserved_usernames_file = args
+        .reserved_usernames_file
+        .or(config_file_settings.info.reserved_usernames_file);
+    let mut reserved = reserved_usernames.clone().unwrap_or_default();
+    if let Some(path) = &reserved_usernames_file {
+        reserved.extend(read_reserved_usernames(path)?);
+    }
+    let username_rules = username_rules.with_reserved(reserved);
+
+    let expose_user_info = args
+        .expose_user_info
+        .unwrap_or(config_file_settings.info.expose_user_info.unwrap_or(true));
+
+    let expose_user_relays = args.expose_user_relays.unwrap_or(
+        config_file_settings
+            .info
+            .expose_user_relays
+            .unwrap_or(false),
+    );
+
     let settings = Settings {
         info: Info {
             url,
-            nostr_nsec,
+            domains,
+            nostr_nsec: None,
+            nostr_nsec_file,
             relays,
             mint,
+            allowed_mints: allowed_mints.clone(),
+            mint_rules: Some(mint_rules.clone()),
             invoice_description,
+            avatar_path,
             proxy,
-            fee: Some(fee),
+            socks_proxy: socks_proxy.clone(),
+            routing_fee_percent: Some(routing_fee_percent),
+            routing_fee_base_sat: Some(routing_fee_base_sat),
             cln_path,
+            lnd_grpc_url,
+            lnd_cert_path,
+            lnd_macaroon_path,
+            nwc_uri: None,
+            dry_run: Some(dry_run),
             min_sendable: Some(min_sendable),
             max_sendable: Some(max_sendable),
             zapper,
+            data_dir,
             db_path,
+            db_backend,
             pay_index_path,
             two_char_cost: Some(two_char_cost),
             three_char_cost: Some(three_char_cost),
             four_char_cost: Some(three_char_cost),
             other_char_cost: Some(other_char_cost),
+            signup_expiry_secs: Some(signup_expiry_secs),
+            allow_get_signup: Some(allow_get_signup),
+            serve_signup_page: Some(serve_signup_page),
+            signups_disabled: Some(signups_disabled),
+            catch_all_user: catch_all_user.clone(),
+            username_pattern,
+            username_min_length,
+            username_max_length,
+            allow_unicode_usernames: Some(allow_unicode_usernames),
+            reserved_usernames,
+            reserved_usernames_file,
+            expose_user_info: Some(expose_user_info),
+            expose_user_relays: Some(expose_user_relays),
+            comment_allowed: Some(comment_allowed),
+            sat_only,
+            allow_amountless,
+            auth_window: Some(auth_window),
+            success_message,
+            success_action: Some(success_action),
+            claim_ttl,
+            reconcile_interval,
+            reconcile_after,
+            http_error_status,
+            admin_token: admin_token.clone(),
+            invoice_rate_limit,
+            lnurl_cache_ttl,
+            lnurl_max_age,
+            webhook_max_retries,
+            token_delivery_max_retries,
+            invoice_expiry_secs,
+            upstream_timeout_secs,
+            signup_rate_limit,
+            signup_global_rate_limit,
+            signup_daily_limit,
+            cors_origins,
+        },
+        network: Network {
+            port,
+            address,
+            addresses: Some(addresses),
+            listen,
+            socket_mode,
+            tls_cert_path,
+            tls_key_path,
+            metrics_port,
+            trusted_proxies: trusted_proxies.clone(),
+            ip_access,
         },
-        network: Network { port, address },
     };
 
     let api_base_address = Url::from_str(&settings.info.url)?;
+    let primary_domain = api_base_address
+        .host_str()
+        .ok_or(anyhow!("Url must have a host".to_string()))?
+        .to_lowercase();
+    let mut domains: HashSet<String> = settings
+        .info
+        .domains
+        .iter()
+        .map(|d| d.to_lowercase())
+        .collect();
+    domains.insert(primary_domain.clone());
+
+    debug!("Domains: {:?}", domains);
+
     let description = match settings.info.invoice_description.clone() {
         Some(des) => des,
         None => "Hello World".to_string(),
     };
-    let nostr_nsec = settings.info.nostr_nsec.clone();
+    let avatar = settings
+        .info
+        .avatar_path
+        .as_deref()
+        .map(read_avatar)
+        .transpose()?;
+    let success_message = match settings.info.success_message.clone() {
+        Some(message) => message,
+        None => "Your ecash will be DM'd to {username} on Nostr".to_string(),
+    };
+    let nsec = read_nsec(
+        nostr_nsec.as_ref(),
+        settings.info.nostr_nsec_file.as_deref(),
+        nsec_passphrase.as_deref().map(String::as_str),
+    )?;
+    // Checked before anything starts so a bad key fails startup
+    let nostr_keys = match nsec {
+        Some(nsec) => {
+            let field = match nostr_nsec {
+                Some(_) => "nostr_nsec",
+                None => "nostr_nsec_file",
+            };
+            parse_nsec(field, &nsec)?
+        }
+        None => {
+            warn!("No nostr_nsec set, using a new key that is lost on restart");
+            Keys::generate()
+        }
+    };
+    info!("Nostr public key: {}", get_npub(&nostr_keys)?);
+    // Keyed by the nostr key so payer auth k1s stay valid across restarts
+    let payer_auth_k1s = Arc::new(PayerAuthK1s::new(
+        &nostr_keys.secret_key()?.secret_bytes(),
+        settings.info.lnurl_max_age.unwrap_or(DEFAULT_LNURL_MAX_AGE),
+    ));
     let relays = settings.info.relays.clone();
 
     debug!("Relays: {:?}", relays);
@@ -180,41 +642,132 @@ async fn main() -> anyhow::Result<()> {
         bail!("Must define at least one relay");
     }
 
+    let data_dir = DataDir::new(settings.info.data_dir.clone())?;
+    debug!("Data dir: {:?}", data_dir.path());
+    let db_backend = settings.info.db_backend.unwrap_or_default();
     let db_path = match settings.info.db_path.clone() {
         Some(path) => PathBuf::from_str(&path)?,
-        None => {
-            let data_dir = dirs::data_dir().ok_or(anyhow!("Could not get data dir".to_string()))?;
-            data_dir.join("cashu-lnurl")
-        }
+        None => data_dir.db_path(db_backend),
     };
 
-    let db = Db::new(db_path).await?;
+    let db = database::open(db_backend, db_path, &primary_domain).await?;
+    let shutdown_db = db.clone();
+
+    if let Some(Command::Tokens {
+        command: TokensCommand::DeadLetters,
+    }) = args.command
+    {
+        return print_dead_letters(&db).await;
+    }
+
+    // Signals long running tasks to stop taking new work
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    // A proxy that cannot be parsed fails startup rather than connecting directly
+    let socks_proxy = socks_proxy.as_deref().map(socks_proxy_addr).transpose()?;
 
     let nostr = Nostr::new(
         db.clone(),
-        api_base_address.to_string(),
-        &nostr_nsec,
+        primary_domain.clone(),
+        nostr_keys,
         relays,
+        allowed_mints.clone(),
+        socks_proxy,
     )
     .await?;
 
-    let cashu = Cashu::new(db.clone(), nostr.clone(), settings.clone());
+    let metrics = Metrics::new()?;
+
+    let notifier = Notifier::new();
+
+    let webhooks = Webhooks::new(
+        db.clone(),
+        settings
+            .info
+            .webhook_max_retries
+            .unwrap_or(DEFAULT_WEBHOOK_MAX_RETRIES),
+        socks_proxy,
+    )?;
+    tokio::spawn(webhooks.run(notifier.subscribe()));
+    let cashu = Cashu::new(
+        db.clone(),
+        nostr.clone(),
+        settings.clone(),
+        metrics.clone(),
+        notifier.clone(),
+        socks_proxy,
+    )?;
+
+    let ln_backend: Option<Arc<dyn PaymentBackend>> =
+        match (&settings.info.cln_path, &settings.info.lnd_grpc_url) {
+            _ if dry_run => {
+                warn!("==============================================================");
+                warn!(
+                    "DRY RUN: invoices pay themselves after {:?}, minted tokens are fake",
+                    DRY_RUN_SETTLE_DELAY
+                );
+                warn!("DRY RUN: no sats are moved, the configured lightning backend is ignored");
+                warn!("==============================================================");
+                Some(Arc::new(DryRunBackend::new(DRY_RUN_SETTLE_DELAY)))
+            }
+            (Some(cln_path), _) => Some(Arc::new(ClnBackend::new(PathBuf::from(cln_path)).await?)),
+            (None, Some(lnd_grpc_url)) => {
+                let cert_path = settings
+                    .info
+                    .lnd_cert_path
+                    .clone()
+                    .ok_or(anyhow!("lnd_cert_path is required with lnd_grpc_url"))?;
+                let macaroon_path = settings
+                    .info
+                    .lnd_macaroon_path
+                    .clone()
+                    .ok_or(anyhow!("lnd_macaroon_path is required with lnd_grpc_url"))?;
+
+                Some(Arc::new(
+                    LndBackend::new(lnd_grpc_url.clone(), cert_path, macaroon_path).await?,
+                ))
+            }
+            (None, None) => match &nwc_uri {
+                Some(nwc_uri) => Some(Arc::new(
+                    NwcBackend::new(nwc_uri.expose(), socks_proxy).await?,
+                )),
+                None => None,
+            },
+        };
+
+    if let Some(Command::Invoices {
+        command: InvoicesCommand::Retry { hash, .. },
+    }) = args.command
+    {
+        return retry_invoices(&cashu, &db, ln_backend.as_deref(), &settings, hash).await;
+    }
 
     let mut nostr_clone = nostr.clone();
     let nostr_task = tokio::spawn(async move { nostr_clone.run().await });
 
+    let nostr_monitor = nostr.clone();
+    let relay_shutdown = shutdown_rx.clone();
+    tokio::spawn(async move { nostr_monitor.monitor_relays(relay_shutdown).await });
+
     let cashu_clone = cashu.clone();
-    let cashu_task = tokio::spawn(async move { cashu_clone.run().await });
+    let cashu_shutdown = shutdown_rx.clone();
+    let mut cashu_task = tokio::spawn(async move { cashu_clone.run(cashu_shutdown).await });
 
-    let cln_client = if let Some(cln_path) = settings.info.cln_path.clone() {
-        Arc::new(Mutex::new(Some(ClnRpc::new(cln_path).await?)))
-    } else {
-        Arc::new(Mutex::new(None))
-    };
+    // Task that DMs again the tokens no relay could be reached for
+    let cashu_clone = cashu.clone();
+    let token_delivery_shutdown = shutdown_rx.clone();
+    let mut token_delivery_task = tokio::spawn(async move {
+        cashu_clone
+            .retry_token_deliveries(token_delivery_shutdown)
+            .await
+    });
 
     let db_clone = db.clone();
     let cashu_clone = cashu.clone();
-    let cln_client_clone = cln_client.clone();
+    let ln_backend_clone = ln_backend.clone();
+    let ln_backend_reconcile = ln_backend.clone();
+    let cashu_reconcile = cashu.clone();
+    let nostr_zapper = nostr.clone();
 
     let pending_users = Arc::new(Mutex::new(
         db_clone
@@ -225,43 +778,282 @@ async fn main() -> anyhow::Result<()> {
             .collect(),
     ));
 
+    let lnurl_cache_ttl = settings
+        .info
+        .lnurl_cache_ttl
+        .unwrap_or(DEFAULT_LNURL_CACHE_TTL);
+    let lnurl_cache = (lnurl_cache_ttl > 0)
+        .then(|| Arc::new(TtlCache::new(Duration::from_secs(lnurl_cache_ttl))));
+
     let state = LnurlState {
         api_base_address,
+        domains,
+        primary_domain,
         min_sendable,
         max_sendable,
         description,
+        avatar,
+        success_message,
+        success_action: settings.info.success_action.unwrap_or_default(),
+        comment_allowed,
+        sat_only: settings.info.sat_only.unwrap_or(false),
+        allow_amountless: settings.info.allow_amountless.unwrap_or(false),
+        signups_disabled,
+        catch_all_user,
+        username_rules,
+        auth_window,
+        used_auth_events: Arc::new(UsedEvents::default()),
+        allowed_mints,
+        mint_rules,
+        expose_user_relays,
+        admin_token,
+        invoice_rate_limiter: invoice_rate_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+        lnurl_cache: lnurl_cache.clone(),
+        lnurl_max_age: settings.info.lnurl_max_age.unwrap_or(DEFAULT_LNURL_MAX_AGE),
+        invoice_expiry_secs,
+        trusted_proxies: trusted_proxies.unwrap_or_default(),
+        signup_limiter: (signup_rate_limit.is_some()
+            || signup_global_rate_limit.is_some()
+            || signup_daily_limit.is_some())
+        .then(|| {
+            Arc::new(SignupLimiter::new(
+                signup_rate_limit,
+                signup_global_rate_limit,
+                signup_daily_limit,
+            ))
+        }),
+        payer_auth_k1s,
         nostr_pubkey: Some(nostr.get_pubkey()),
         proxy: settings.info.proxy,
+        min_proxied,
         cashu,
         db,
-        cln_client,
-        _nostr: nostr,
+        ln_backend,
+        nostr,
+        metrics,
+        notifier,
         pending_users: pending_users.clone(),
         two_char_cost,
         three_char_cost,
         four_char_cost,
         other_char_cost,
+        signup_expiry: signup_expiry_secs,
+        upstream_timeout: Duration::from_secs(
+            upstream_timeout_secs.unwrap_or(DEFAULT_UPSTREAM_TIMEOUT_SECS),
+        ),
     };
 
-    let lnurl_service = Router::new()
+    let signup_route = if allow_get_signup {
+        warn!("Deprecated GET signup is enabled");
+        get(get_sign_up).post(post_sign_up)
+    } else {
+        post(post_sign_up)
+    };
+
+    let mut public_service = Router::new()
         .route("/.well-known/lnurlp/:username", get(get_user_lnurl_struct))
-        .route("/lnurlp/:username/invoice", get(get_user_invoice))
-        .route("/signup", post(post_sign_up))
+        .route("/lnurl/:username", get(get_user_lnurl))
+        .route("/lnurlp/:username", delete(delete_user_account))
+        .route(
+            "/lnurlp/:username/invoice",
+            get(get_user_invoice).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                limit_invoice_requests,
+            )),
+        )
+        .route("/lnurlp/:username/qr", get(get_user_qr))
+        .route("/lnurlp/:username/verify/:payment_hash", get(get_verify))
+        .route("/ws/:username", get(get_ws_notifications))
+        .route("/claim/:id", get(get_claim))
+        .route("/redeem/:id", get(get_redeem).post(get_claim))
+        .route(
+            "/users/:username",
+            put(put_user).delete(delete_user_account),
+        )
+        .route("/users/:username/limits", put(put_user_limits))
+        .route("/users/:username/export", get(get_user_export))
+        .route("/health", get(get_health));
+
+    if expose_user_info {
+        public_service = public_service.route("/users/:username", get(get_user_info));
+    }
+
+    let mut signup_service = Router::new().route("/signup", signup_route);
+
+    if serve_signup_page && !signups_disabled {
+        signup_service = signup_service.route("/", get(get_signup_page));
+    }
+
+    let admin_service = Router::new()
         .route("/add_user", post(post_add_user))
         .route("/remove_user", delete(delete_user))
         .route("/list_users", get(get_list_users))
         .route("/reserve", post(post_reserve_user))
         .route("/block", post(post_block_user))
+        .route("/admin/users", get(get_admin_users))
+        .route("/admin/pending_invoices", get(get_admin_pending_invoices))
+        .route("/admin/report", get(get_admin_report))
+        .route("/admin/payments.csv", get(get_admin_payments_csv))
+        .route(
+            "/admin/invoice/:hash",
+            get(get_admin_invoice).delete(delete_admin_invoice),
+        )
+        .route(
+            "/admin/invites",
+            get(get_admin_invites).post(post_admin_invite),
+        )
+        .route("/admin/invites/:code", delete(delete_admin_invite))
+        .route_layer(middleware::from_fn_with_state(
+            state.admin_token.clone(),
+            require_admin,
+        ));
+
+    let trusted_proxies = Arc::new(state.trusted_proxies.clone());
+
+    // Each group is checked against its ip rules before anything else, admin auth included
+    let ip_guard = |group, rules| {
+        middleware::from_fn_with_state(
+            IpGuard::new(group, rules, trusted_proxies.clone()),
+            restrict_ips,
+        )
+    };
+    let mut lnurl_service = public_service
+        .route_layer(ip_guard(RouteGroup::Public, ip_rules.public))
+        .merge(signup_service.route_layer(ip_guard(RouteGroup::Signup, ip_rules.signup)))
+        .merge(admin_service.route_layer(ip_guard(RouteGroup::Admin, ip_rules.admin)));
+
+    // Resolves once shutdown starts, so each server finishes its requests and stops
+    let shutdown = || {
+        let mut shutdown_rx = shutdown_rx.clone();
+        async move {
+            let _ = shutdown_rx.changed().await;
+        }
+    };
+
+    // Metrics are served with the api unless they have their own port
+    let metrics_service = Router::new().route("/metrics", get(get_metrics));
+    let mut metrics_task = None;
+    match settings.network.metrics_port {
+        Some(metrics_port) => {
+            // On the ip of each address the api is bound to
+            let mut metrics_addrs = vec![];
+            for addr in &bind_addrs {
+                let metrics_addr = SocketAddr::new(addr.ip(), metrics_port);
+                if !metrics_addrs.contains(&metrics_addr) {
+                    metrics_addrs.push(metrics_addr);
+                }
+            }
+
+            let metrics_service = metrics_service
+                .with_state(state.clone())
+                .into_make_service();
+            let servers = bind_tcp(&metrics_addrs)?
+                .into_iter()
+                .map(|listener| {
+                    Ok(axum::Server::from_tcp(listener)?
+                        .serve(metrics_service.clone())
+                        .with_graceful_shutdown(shutdown()))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            metrics_task = Some(tokio::spawn(try_join_all(servers)));
+        }
+        None => lnurl_service = lnurl_service.merge(metrics_service),
+    }
+
+    let mut lnurl_service = lnurl_service
+        .route_layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            track_requests,
+        ))
         .with_state(state);
 
-    let address = settings.network.address;
-    let ip = Ipv4Addr::from_str(&address)?;
+    if !settings.info.http_error_status.unwrap_or(true) {
+        lnurl_service = lnurl_service.layer(map_response(lnurl_error_ok));
+    }
 
-    let port = settings.network.port;
+    // Outside the error rewrite so errors and preflight requests get CORS headers
+    // and every response is logged with its request id
+    let lnurl_service = lnurl_service
+        .layer(cors_layer(settings.info.cors_origins.clone()))
+        .layer(middleware::from_fn_with_state(
+            trusted_proxies.clone(),
+            assign_request_id,
+        ));
+
+    let mut axum_task = match unix_socket {
+        Some(path) => {
+            if !trusted_proxies.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)) {
+                warn!("Unix socket clients are seen as 127.0.0.1, trust it to read forwarded ips");
+            }
+            let accept = UnixAccept::bind(&path, unix_socket_mode)?;
+            info!("Listening on {:?}", path);
+
+            // Peers of a unix socket are on this host, seen as localhost so the proxy can be trusted
+            let lnurl_service = lnurl_service.layer(Extension(ConnectInfo(SocketAddr::from((
+                Ipv4Addr::LOCALHOST,
+                0,
+            )))));
+            tokio::spawn(async move {
+                let served = axum::Server::builder(accept)
+                    .serve(lnurl_service.into_make_service())
+                    .with_graceful_shutdown(shutdown())
+                    .await;
+                remove_socket(&path);
+                served.map_err(anyhow::Error::from)
+            })
+        }
+        None => {
+            let listeners = bind_tcp(&bind_addrs)?;
+            let lnurl_service = lnurl_service.into_make_service_with_connect_info::<SocketAddr>();
+
+            // One server per address, the task ends as soon as any of them fails
+            let servers: Vec<BoxFuture<'static, anyhow::Result<()>>> = match tls {
+                Some((tls_config, tls_files)) => {
+                    tokio::spawn(
+                        tls_files.reload_on_change(tls_config.clone(), shutdown_rx.clone()),
+                    );
+
+                    listeners
+                        .into_iter()
+                        .map(|listener| {
+                            let handle = axum_server::Handle::new();
+                            let shutdown_handle = handle.clone();
+                            let shutdown = shutdown();
+                            tokio::spawn(async move {
+                                shutdown.await;
+                                shutdown_handle.graceful_shutdown(None);
+                            });
+
+                            axum_server::from_tcp_rustls(listener, tls_config.clone())
+                                .handle(handle)
+                                .serve(lnurl_service.clone())
+                                .map_err(anyhow::Error::from)
+                                .boxed()
+                        })
+                        .collect()
+                }
+                None => listeners
+                    .into_iter()
+                    .map(|listener| {
+                        Ok(axum::Server::from_tcp(listener)?
+                            .serve(lnurl_service.clone())
+                            .with_graceful_shutdown(shutdown())
+                            .map_err(anyhow::Error::from)
+                            .boxed())
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            };
+            for addr in &bind_addrs {
+                info!("Listening on {}", addr);
+            }
 
-    let listen_addr = SocketAddr::new(std::net::IpAddr::V4(ip), port);
+            tokio::spawn(try_join_all(servers).map_ok(|_| ()))
+        }
+    };
 
-    let axum_task = axum::Server::bind(&listen_addr).serve(lnurl_service.into_make_service());
+    let mut wait_invoice_task = None;
+    let mut remove_expired_pending_users_task = None;
+    let mut pay_index = None;
 
     // Task that waits for invoice to be paid
     // When an invoice paid check db if invoice exists request mint and pay and mint
@@ -270,351 +1062,704 @@ async fn main() -> anyhow::Result<()> {
     if settings.info.proxy
         | ((two_char_cost + three_char_cost + four_char_cost + other_char_cost).gt(&Amount::ZERO))
     {
-        let rpc_socket = settings
-            .info
-            .cln_path
-            .clone()
-            .expect("CLN RPC socket path required");
+        let ln_backend = ln_backend_clone.ok_or(anyhow!(
+            "A CLN, LND or NWC backend is required to proxy invoices or charge for usernames"
+        ))?;
         let pending_users_clone = pending_users.clone();
+        let db_expire = db_clone.clone();
+
+        let last_pay_index = match shutdown_db.get_pay_index().await? {
+            Some(idx) => idx,
+            None => {
+                let pay_index_path = match settings.info.pay_index_path.clone() {
+                    Some(path) => path,
+                    None => data_dir.pay_index_path(),
+                };
+                let idx = import_pay_index_file(&pay_index_path);
+                shutdown_db.set_pay_index(idx).await?;
+                idx
+            }
+        };
+        info!("Starting at pay index: {last_pay_index}");
 
-        let wait_invoice_task = tokio::spawn(async move {
-            let pay_index_path = match settings.info.pay_index_path {
-                Some(path) => path,
-                None => index_file_path().expect("Could not get path to pay index file"),
-            };
+        let pay_index_tip = Arc::new(AtomicU64::new(last_pay_index));
+        pay_index = Some(pay_index_tip.clone());
 
-            let last_pay_index = match read_last_pay_index(&pay_index_path) {
-                Ok(idx) => idx,
-                Err(e) => {
-                    warn!("Could not read last pay index: {e}");
-                    if let Err(e) = write_last_pay_index(&pay_index_path, 0) {
-                        warn!("Write error: {e}");
-                    }
-                    0
-                }
-            };
-            info!("Starting at pay index: {last_pay_index}");
+        let mut shutdown = shutdown_rx.clone();
 
-            let mut invoices = invoice_stream(&rpc_socket, pay_index_path, Some(last_pay_index))
+        wait_invoice_task = Some(tokio::spawn(async move {
+            let mut invoices = ln_backend
+                .wait_any_invoice(Some(last_pay_index))
                 .await
                 .unwrap();
             let db = db_clone;
             let cashu = cashu_clone;
-            let cln_client = cln_client_clone;
+            let nostr = nostr_zapper;
+
+            loop {
+                // Stop waiting for new invoices on shutdown
+                // an invoice already being handled is allowed to finish
+                let paid_invoice = tokio::select! {
+                    invoice = invoices.next() => match invoice {
+                        Some(invoice) => invoice,
+                        None => break,
+                    },
+                    _ = shutdown.changed() => {
+                        info!("Wait invoice task stopping");
+                        break;
+                    }
+                };
+
+                let pay_index = paid_invoice.pay_index;
+                if let Some(idx) = pay_index {
+                    pay_index_tip.store(idx, Ordering::SeqCst);
+                }
+
+                let hash = paid_invoice.payment_hash.clone();
 
-            while let Some((hash, _invoice)) = invoices.next().await {
                 // Check if invoice is for a pending user
 
                 let mut pending = pending_users.lock().await;
                 if let Some(pending_user) = pending.get(&hash) {
                     debug!("Invoice for pending user paid: {:?}", pending_user);
-                    if let Err(err) = db
+                    match db
                         .add_user(
+                            &pending_user.user.domain,
                             &pending_user.user.username,
                             &UserKind::User(pending_user.user.clone()),
                         )
                         .await
                     {
-                        warn!(
+                        Ok(()) => {
+                            // Paid for, so the user is kept even if the code was used up since
+                            if let Some(invite) = &pending_user.invite {
+                                match db.use_invite_code(invite, unix_time()).await {
+                                    Ok(InviteStatus::Used) => (),
+                                    Ok(status) => warn!(
+                                        "Invite code of {} could not be used: {:?}",
+                                        pending_user.user.username, status
+                                    ),
+                                    Err(err) => warn!(
+                                        "Could not use invite code of {}: {:?}",
+                                        pending_user.user.username, err
+                                    ),
+                                }
+                            }
+
+                            // Welcome message is best effort and should not hold up the loop
+                            let nostr = nostr.clone();
+                            let user = pending_user.user.clone();
+                            tokio::spawn(async move {
+                                if let Err(err) =
+                                    nostr.send_sign_up_message(&user.username, &user).await
+                                {
+                                    warn!(
+                                        "Could not send sign up message to {}: {:?}",
+                                        user.username, err
+                                    );
+                                }
+                            });
+                        }
+                        Err(err) => warn!(
                             "Could not move pending user to user {}: {:?}",
                             pending_user.user.username, err
-                        );
+                        ),
+                    }
+                    if let Some(cache) = &lnurl_cache {
+                        cache.invalidate(&pending_user.user.domain, &pending_user.user.username);
                     }
 
                     pending.remove(&hash);
                 }
                 // Check if invoice is in db and proxied
                 // If it is request mint from selected mint
+                // The pay index is written with the pending invoice it replaces
                 else if let Ok(Some(invoice)) = db.get_pending_invoice(&hash).await {
                     drop(pending);
-                    // Fee to account for routing fee
-
-                    let fee = fee_for_invoice(invoice.amount, settings.info.fee.unwrap_or(0.0));
-
-                    if let Err(err) = db.add_fee_received(&invoice.hash, fee.to_msat()).await {
-                        warn!("Could not add received fee to DB: {:?}", err);
-                        info!("Fee received: {:?}", fee.to_msat());
-                    }
-
-                    // In the case of small invoices that will likely not incur a routing fee
-                    // No fee is taken, This should be configurable.
-                    // However it must be ensured that it is always
-                    // > 1 sat as that is the min for cashu tokens
-                    // In this small case a max fee of 10 sats is set.
-                    // As I would rather the service eat the fees
-                    // TO avoid the poor user experience of failed payments
-                    let max_fee = if fee.eq(&Amount::ZERO) {
-                        Amount::from_sat(10)
-                    } else {
-                        fee
-                    };
-
-                    let amount = invoice.amount - fee;
-
-                    let request_mint_response =
-                        match cashu.request_mint(amount, &invoice.mint).await {
-                            Ok(res) => res,
-                            Err(err) => {
-                                warn!("{:?}", err);
-                                continue;
-                            }
-                        };
-
-                    let pending_invoice = PendingInvoice {
-                        mint: invoice.mint,
-                        username: invoice.username,
-                        description: invoice.description,
-                        amount,
-                        hash: request_mint_response.hash,
-                        bolt11: request_mint_response.pr.clone(),
-                        last_checked: None,
-                        proxied: true,
-                        time: unix_time(),
-                    };
-
-                    // Add mint pending ivoice to DB
-                    if let Err(err) = cashu.add_pending_invoice(&pending_invoice).await {
-                        warn!("Could not add pending invoice: {:?}", err)
-                    }
-
-                    // Remove paid invoice from pending
-                    if let Err(err) = db.remove_pending_invoice(&invoice.hash).await {
-                        warn!("Could not remove pending invoice {:?}", err);
-                    }
-
-                    // Pay mint invoice
-                    let mut cln_client = cln_client.lock().await;
-
-                    let cln_response = cln_client
-                        .as_mut()
-                        .unwrap()
-                        .call(cln_rpc::Request::Pay(PayRequest {
-                            bolt11: request_mint_response.pr.to_string(),
-                            amount_msat: None,
-                            label: None,
-                            riskfactor: None,
-                            maxfeepercent: None,
-                            retry_for: None,
-                            maxdelay: None,
-                            exemptfee: None,
-                            localinvreqid: None,
-                            exclude: None,
-                            maxfee: Some(CLN_Amount::from_sat(max_fee.to_sat())),
-                            description: None,
-                        }))
+                    let span = invoice.span();
+                    cashu
+                        .proxy_payment(ln_backend.as_ref(), invoice, paid_invoice)
+                        .instrument(span)
                         .await;
+                    continue;
+                }
 
-                    match cln_response {
-                        Ok(cln_rpc::Response::Pay(pay_response)) => {
-                            if let Ok(pay_response) =
-                                serde_json::to_string(&pay_response.payment_preimage)
-                            {
-                                // let invoice =
-                                // Amount::from_msat(pay_response.amount_sent_msat.msat());
-                                debug!("Invoice paid: {:?}", pay_response);
-                            }
-                            if let Err(err) = db
-                                .add_fee_paid(
-                                    &pay_response.payment_hash.to_string(),
-                                    (pay_response.amount_sent_msat - pay_response.amount_msat)
-                                        .msat(),
-                                )
-                                .await
-                            {
-                                warn!("Could not add paid fee to DB: {:?}", err);
-
-                                info!(
-                                    "Fee Paid: {:?}",
-                                    pay_response.amount_sent_msat - pay_response.amount_msat
-                                );
-                            }
-                        }
-                        Ok(res) => warn!("Wrong CLN response: {:?}", res),
-                        Err(err) => warn!("Error paying mint invoice: {:?}", err),
-                    };
+                // Written once the invoice is handled, so one paid during a crash is seen again
+                if let Some(idx) = pay_index {
+                    if let Err(e) = db.set_pay_index(idx).await {
+                        warn!("Could not write index tip: {e}");
+                    }
                 }
             }
-        });
+        }));
 
-        let remove_expired_pending_users_task = tokio::spawn(async move {
+        remove_expired_pending_users_task = Some(tokio::spawn(async move {
             loop {
                 let mut pending_users = pending_users_clone.lock().await;
 
                 let current_time = unix_time();
 
-                let pending_users_count = pending_users.len();
-
-                pending_users.retain(|_k, v| v.expire.gt(&current_time));
-                debug!(
-                    "Removed {} expired pending users.",
-                    pending_users_count - pending_users.len()
-                );
+                let expired: Vec<String> = pending_users
+                    .iter()
+                    .filter(|(_hash, pending_user)| pending_user.expire <= current_time)
+                    .map(|(hash, _pending_user)| hash.clone())
+                    .collect();
+
+                // Held while freeing so a payment arriving now cannot promote the user
+                for hash in &expired {
+                    if let Some(pending_user) = pending_users.remove(hash) {
+                        if let Err(err) = free_expired_signup(&db_expire, &pending_user).await {
+                            warn!(
+                                "Could not free username {}: {:?}",
+                                pending_user.user.username, err
+                            );
+                        }
+                    }
+                }
+                debug!("Removed {} expired pending users.", expired.len());
                 drop(pending_users);
 
                 sleep(Duration::from_secs(15)).await;
             }
-        });
+        }));
+    }
 
-        tokio::select! {
-            _ = nostr_task => {
-                warn!("Nostr task ended");
-            }
-            _ = cashu_task => {
-                warn!("Cashu task ended");
-            }
-            _ = axum_task => {
-                warn!("Axum task ended");
-            }
-            _ = wait_invoice_task => {
-                warn!("Wait invoice task ended");
-            }
-            _ = remove_expired_pending_users_task => {
-                warn!("Remove expired users task ended")
-            }
+    // Task that recovers proxied invoices whose payment failed or was interrupted
+    let mut reconcile_task = None;
+    if settings.info.proxy {
+        if let Some(ln_backend) = ln_backend_reconcile {
+            let reconcile_shutdown = shutdown_rx.clone();
+            reconcile_task = Some(tokio::spawn(async move {
+                cashu_reconcile
+                    .reconcile(ln_backend, reconcile_shutdown)
+                    .await
+            }));
         }
-    } else {
-        tokio::select! {
-            _ = nostr_task => {
-                warn!("Nostr task ended");
-            }
-            _ = cashu_task => {
-                warn!("Cashu task ended");
-            }
-            _ = axum_task => {
-                warn!("Axum task ended");
-            }
+    }
+
+    tokio::select! {
+        _ = nostr_task => {
+            warn!("Nostr task ended");
+        }
+        _ = &mut cashu_task => {
+            warn!("Cashu task ended");
+        }
+        _ = &mut token_delivery_task => {
+            warn!("Token delivery task ended");
+        }
+        _ = &mut axum_task => {
+            warn!("Axum task ended");
+        }
+        _ = join_optional(&mut wait_invoice_task) => {
+            warn!("Wait invoice task ended");
+        }
+        _ = join_optional(&mut remove_expired_pending_users_task) => {
+            warn!("Remove expired users task ended")
+        }
+        _ = join_optional(&mut reconcile_task) => {
+            warn!("Reconcile task ended");
+        }
+        _ = join_optional(&mut metrics_task) => {
+            warn!("Metrics task ended");
+        }
+        _ = shutdown_signal() => {
+            info!("Shutdown signal received");
         }
     }
 
+    // Stop accepting new requests and invoices,
+    // in flight mint requests and payments are given time to finish
+    let _ = shutdown_tx.send(true);
+
+    let drain_tasks = async {
+        drain(axum_task).await;
+        drain(cashu_task).await;
+        drain(token_delivery_task).await;
+        if let Some(task) = wait_invoice_task {
+            drain(task).await;
+        }
+        if let Some(task) = reconcile_task {
+            drain(task).await;
+        }
+    };
+
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, drain_tasks)
+        .await
+        .is_err()
+    {
+        warn!("Tasks did not finish within shutdown grace period");
+    }
+
+    if let Some(pay_index_tip) = pay_index {
+        let last_pay_index = pay_index_tip.load(Ordering::SeqCst);
+        match shutdown_db.set_pay_index(last_pay_index).await {
+            Ok(()) => info!("Flushed pay index: {last_pay_index}"),
+            Err(e) => warn!("Could not write index tip: {e}"),
+        }
+    }
+
+    // Closed last so nothing writes to the db after this point
+    if let Err(err) = shutdown_db.close().await {
+        warn!("Could not close database: {:?}", err);
+    }
+
+    info!("Shutdown complete");
+
     Ok(())
 }
 
-async fn invoice_stream(
-    socket_addr: &str,
-    pay_index_path: PathBuf,
-    last_pay_index: Option<u64>,
-) -> anyhow::Result<impl Stream<Item = (String, WaitanyinvoiceResponse)>> {
-    let cln_client = cln_rpc::ClnRpc::new(&socket_addr).await?;
-
-    Ok(futures::stream::unfold(
-        (cln_client, pay_index_path, last_pay_index),
-        |(mut cln_client, pay_index_path, mut last_pay_idx)| async move {
-            // We loop here since some invoices aren't zaps, in which case we wait for the
-            // next one and don't yield
-            loop {
-                // info!("Waiting for index: {last_pay_idx:?}");
-                let invoice_res = cln_client
-                    .call(cln_rpc::Request::WaitAnyInvoice(WaitanyinvoiceRequest {
-                        timeout: None,
-                        lastpay_index: last_pay_idx,
-                    }))
-                    .await;
+/// Time in flight tasks have to finish on shutdown
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
-                let invoice: WaitanyinvoiceResponse = match invoice_res {
-                    Ok(invoice) => invoice,
-                    Err(e) => {
-                        warn!("Error fetching invoice: {e}");
-                        // Let's not spam CLN with requests on failure
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        // Retry same request
-                        continue;
-                    }
-                }
-                .try_into()
-                .expect("Wrong response from CLN");
+/// Resolves on SIGINT or SIGTERM
+/// Min and max sendable in sats from the command line, else the config file, else the defaults
+fn sendable_range(min_sat: Option<u64>, max_sat: Option<u64>, info: &Info) -> (Amount, Amount) {
+    let min_sendable = min_sat
+        .map(Amount::from_sat)
+        .unwrap_or(info.min_sendable.unwrap_or(Amount::from_sat(1)));
+    let max_sendable = max_sat
+        .map(Amount::from_sat)
+        .unwrap_or(info.max_sendable.unwrap_or(Amount::from_sat(1000000)));
 
-                last_pay_idx = invoice.pay_index;
-                if let Some(idx) = last_pay_idx {
-                    if let Err(e) = write_last_pay_index(&pay_index_path, idx) {
-                        warn!("Could not write index tip: {e}");
-                    }
-                };
-                let pay_idx = last_pay_idx;
+    (min_sendable, max_sendable)
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Could not listen for ctrl-c: {:?}", err);
+            futures::future::pending::<()>().await;
+        }
+    };
 
-                break Some((
-                    (invoice.payment_hash.to_string(), invoice),
-                    (cln_client, pay_index_path, pay_idx),
-                ));
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
             }
-        },
-    )
-    .boxed())
+            Err(err) => {
+                warn!("Could not listen for SIGTERM: {:?}", err);
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
-/// Calculate fee for invoice
-// REVIEW: This is a fairly naive way to handle fees
-// Simply takes 1%
-fn fee_for_invoice(amount: Amount, fee_percent: f32) -> Amount {
-    Amount::from_msat((amount.to_msat() as f32 * fee_percent).ceil() as u64)
+/// Wait for an optional task, pending forever if there is none
+async fn join_optional<T>(task: &mut Option<JoinHandle<T>>) {
+    match task {
+        Some(task) => {
+            let _ = task.await;
+        }
+        None => futures::future::pending().await,
+    }
 }
 
-/// Default file path for last pay index tip
-fn index_file_path() -> anyhow::Result<PathBuf> {
-    let mut file_path = match data_dir() {
-        Some(path) => path,
-        None => return Err(anyhow!("no data dir")),
+/// Wait for a task to finish if it has not already
+async fn drain<T>(task: JoinHandle<T>) {
+    if !task.is_finished() {
+        let _ = task.await;
+    }
+}
+
+/// Free the username of an unpaid signup, or reserve it again if it was reserved
+///
+/// Left alone if the username has since been taken by another signup
+async fn free_expired_signup(db: &Db, pending_user: &PendingUser) -> anyhow::Result<()> {
+    let user = &pending_user.user;
+    match db.get_user(&user.domain, &user.username).await? {
+        Some(UserKind::Pending(stored))
+            if stored.pr.payment_hash() == pending_user.pr.payment_hash() =>
+        {
+            match pending_user.expired_kind() {
+                Some(kind) => db.add_user(&user.domain, &user.username, &kind).await?,
+                None => db.delete_user(&user.domain, &user.username).await?,
+            }
+            debug!("Signup of {} expired", user.username);
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Retry the pending invoice with `hash`, or all pending invoices, and print what happened
+async fn retry_invoices(
+    cashu: &Cashu,
+    db: &Db,
+    ln_backend: Option<&dyn PaymentBackend>,
+    settings: &Settings,
+    hash: Option<String>,
+) -> anyhow::Result<()> {
+    let invoices = match hash {
+        Some(hash) => vec![db
+            .get_pending_invoice(&hash)
+            .await?
+            .ok_or_else(|| anyhow!("No pending invoice {}", hash))?],
+        None => db.get_pending_invoices().await?,
     };
 
-    file_path.push("cln-zapper");
-    file_path.push("last_pay_index");
+    let mut outcomes: HashMap<RetryOutcome, usize> = HashMap::new();
+    for invoice in invoices {
+        let hash = invoice.hash.clone();
+        let max_fee = max_routing_fee(fee_for_invoice(
+            invoice.amount,
+            settings.info.routing_fee_percent.unwrap_or(0.0),
+            Amount::from_sat(settings.info.routing_fee_base_sat.unwrap_or(0)),
+        ));
+
+        let span = invoice.span();
+        match cashu
+            .retry_invoice(ln_backend, invoice, max_fee)
+            .instrument(span)
+            .await
+        {
+            Ok(outcome) => {
+                println!("{}: {:?}", hash, outcome);
+                *outcomes.entry(outcome).or_default() += 1;
+            }
+            Err(err) => println!("{}: error {}", hash, err),
+        }
+    }
 
-    Ok(file_path)
+    println!(
+        "Minted {}, failed {}, removed {}, still pending {}",
+        outcomes.get(&RetryOutcome::Minted).unwrap_or(&0),
+        outcomes.get(&RetryOutcome::Failed).unwrap_or(&0),
+        outcomes.get(&RetryOutcome::Removed).unwrap_or(&0),
+        outcomes.get(&RetryOutcome::Pending).unwrap_or(&0),
+    );
+
+    Ok(())
+}
+
+/// Print the tokens that could not be DMed so they can be sent by hand
+async fn print_dead_letters(db: &Db) -> anyhow::Result<()> {
+    let dead_letters = db.get_dead_letters().await?;
+
+    for delivery in &dead_letters {
+        println!(
+            "{}: {}@{} ({}) after {} attempts: {}",
+            delivery.hash,
+            delivery.username,
+            delivery.domain,
+            delivery.pubkey,
+            delivery.attempts,
+            delivery.error.as_deref().unwrap_or("unknown error"),
+        );
+        if let Some(claim_id) = &delivery.claim_id {
+            println!("  claim id: {}", claim_id);
+        }
+        println!("  token: {}", delivery.token);
+    }
+    println!("{} dead lettered tokens", dead_letters.len());
+
+    Ok(())
+}
+
+/// First byte of a pay index file
+const PAY_INDEX_MAGIC: u8 = 0xCA;
+/// Version of the pay index file format
+const PAY_INDEX_VERSION: u8 = 1;
+/// Magic, version, little endian index and crc32 of the preceding bytes
+const PAY_INDEX_LEN: usize = 14;
+/// Unversioned files only held the index in native byte order
+const LEGACY_PAY_INDEX_LEN: usize = 8;
+
+/// Pay index to start from when the db has none, read from the file it used to be kept in
+fn import_pay_index_file(file_path: &PathBuf) -> u64 {
+    if !file_path.exists() {
+        return 0;
+    }
+
+    match read_last_pay_index(file_path) {
+        Ok(idx) => {
+            info!("Imported pay index {} from {:?}", idx, file_path);
+            idx
+        }
+        Err(e) => {
+            warn!("Could not read last pay index: {e}");
+            0
+        }
+    }
 }
 
 /// Read last pay index tip from file
 fn read_last_pay_index(file_path: &PathBuf) -> anyhow::Result<u64> {
     let mut file = File::open(file_path)?;
-    let mut buffer = [0; 8];
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer)?;
 
-    file.read_exact(&mut buffer)?;
-    Ok(u64::from_ne_bytes(buffer))
+    decode_pay_index(&buffer)
 }
 
-/// Write last pay index tip to file
-fn write_last_pay_index(file_path: &PathBuf, last_pay_index: u64) -> anyhow::Result<()> {
-    // Create the directory if it doesn't exist
-    if let Some(parent_dir) = file_path.parent() {
-        fs::create_dir_all(parent_dir)?;
+fn decode_pay_index(buffer: &[u8]) -> anyhow::Result<u64> {
+    if buffer.len() == LEGACY_PAY_INDEX_LEN {
+        warn!("Read unversioned pay index, it is rewritten versioned once an invoice is paid");
+        return Ok(u64::from_ne_bytes(buffer.try_into()?));
     }
 
-    let mut file = File::create(file_path)?;
-    file.write_all(&last_pay_index.to_ne_bytes())?;
-    Ok(())
+    if buffer.len() != PAY_INDEX_LEN {
+        bail!("Pay index file has unexpected length {}", buffer.len());
+    }
+    if buffer[0] != PAY_INDEX_MAGIC {
+        bail!("Pay index file has wrong magic byte {:#x}", buffer[0]);
+    }
+    if buffer[1] != PAY_INDEX_VERSION {
+        bail!("Unsupported pay index file version {}", buffer[1]);
+    }
+
+    let (data, checksum) = buffer.split_at(PAY_INDEX_LEN - 4);
+    if crc32fast::hash(data).to_le_bytes() != checksum {
+        bail!("Pay index file checksum mismatch");
+    }
+
+    Ok(u64::from_le_bytes(data[2..].try_into()?))
 }
 
 #[derive(Clone)]
 pub struct LnurlState {
     api_base_address: Url,
+    domains: HashSet<String>,
+    primary_domain: String,
     min_sendable: Amount,
     max_sendable: Amount,
     description: String,
+    // Base64 png for addresses whose user has not set an avatar
+    avatar: Option<String>,
+    // Template of the success action message
+    success_message: String,
+    success_action: SuccessActionKind,
+    comment_allowed: u32,
+    // Only create invoices for whole sats
+    sat_only: bool,
+    // Create amountless proxied invoices for requests without an amount
+    allow_amountless: bool,
+    // Refuse signups without an invite code
+    signups_disabled: bool,
+    // User paid when no user has the requested username
+    catch_all_user: Option<String>,
+    // Usernames that can be signed up
+    username_rules: UsernameRules,
+    // Seconds a signed auth event is valid for
+    auth_window: u64,
+    // Auth events already used, which are refused until they expire
+    used_auth_events: Arc<UsedEvents>,
+    // Normalized mints users can sign up with
+    allowed_mints: Option<HashSet<String>>,
+    // Mints picked by invoice amount when the user has no matching rule
+    mint_rules: Vec<MintRule>,
+    // Include relays in public user info
+    expose_user_relays: bool,
+    // Bearer token allowing deletion of any user
+    admin_token: Option<String>,
+    // Limits invoice requests when set
+    invoice_rate_limiter: Option<Arc<RateLimiter>>,
+    // LNURL pay responses by domain and username, unless caching is disabled
+    lnurl_cache: Option<Arc<TtlCache<LnurlResponse>>>,
+    // Seconds clients may cache LNURL pay responses for
+    lnurl_max_age: u64,
+    // Seconds proxied invoices can be paid for, the lightning node's default when unset
+    invoice_expiry_secs: Option<u64>,
+    // Proxies the client ip is read from X-Forwarded-For or X-Real-IP behind
+    trusted_proxies: HashSet<IpAddr>,
+    // Limits signups when any signup limit is set
+    signup_limiter: Option<Arc<SignupLimiter>>,
+    // Issues the LUD-18 challenges payers sign to prove their identity
+    payer_auth_k1s: Arc<PayerAuthK1s>,
     nostr_pubkey: Option<String>,
     // If proxied cashu-lnurl created the invoice
     proxy: bool,
+    // Least a proxied invoice can be for and leave something to mint after the fee
+    min_proxied: Amount,
     cashu: Cashu,
-    cln_client: Arc<Mutex<Option<ClnRpc>>>,
+    ln_backend: Option<Arc<dyn PaymentBackend>>,
     db: Db,
-    _nostr: Nostr,
+    nostr: Nostr,
+    metrics: Metrics,
+    // Paid invoices pushed to websocket subscribers
+    notifier: Notifier,
     pending_users: Arc<Mutex<HashMap<String, PendingUser>>>,
     two_char_cost: Amount,
     three_char_cost: Amount,
     four_char_cost: Amount,
     other_char_cost: Amount,
+    // Seconds a username with a cost is held for its invoice to be paid
+    signup_expiry: u64,
+    // Time an invoice request waits for the lightning backend or the mints
+    upstream_timeout: Duration,
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use cashu_sdk::Bolt11Invoice;
+    use nostr_sdk::Url;
 
     use super::*;
+    use crate::config::DbBackendKind;
+    use crate::types::User;
 
     #[test]
-    fn test_fee_calculation() {
-        let amount = Amount::from_sat(1);
+    fn test_sendable_range() {
+        let sendable = |args: &[&str], info: &Info| {
+            let args = CLIArgs::parse_from([&["cashu-lnurl"][..], args].concat());
+            sendable_range(args.min_sendable, args.max_sendable, info)
+        };
+
+        assert_eq!(
+            sendable(&[], &Info::default()),
+            (Amount::from_sat(1), Amount::from_sat(1000000))
+        );
+        assert_eq!(
+            sendable(&["--max-sendable", "500000"], &Info::default()),
+            (Amount::from_sat(1), Amount::from_sat(500000))
+        );
+        assert_eq!(
+            sendable(&["--min-sendable", "10"], &Info::default()),
+            (Amount::from_sat(10), Amount::from_sat(1000000))
+        );
+
+        let info = Info {
+            min_sendable: Some(Amount::from_sat(20)),
+            max_sendable: Some(Amount::from_sat(2000)),
+            ..Info::default()
+        };
+        assert_eq!(
+            sendable(&[], &info),
+            (Amount::from_sat(20), Amount::from_sat(2000))
+        );
+        assert_eq!(
+            sendable(&["--min-sendable", "30"], &info),
+            (Amount::from_sat(30), Amount::from_sat(2000))
+        );
+    }
 
-        assert_eq!(fee_for_invoice(amount, 0.0), Amount::ZERO);
+    #[tokio::test]
+    async fn test_free_expired_signup() {
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.redb", uuid::Uuid::new_v4()));
+        let db = database::open(DbBackendKind::Redb, path, "example.com")
+            .await
+            .unwrap();
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        let pending_user = |username: &str, reserved: Option<Amount>| PendingUser {
+            user: User {
+                username: username.to_string(),
+                domain: "example.com".to_string(),
+                mint: Url::from_str("https://mint.example.com").unwrap(),
+                fallback_mints: vec![],
+                mint_rules: vec![],
+                pubkey: nostr_sdk::Keys::generate().public_key().to_string(),
+                relays: HashSet::new(),
+                proxy: false,
+                min_sendable: None,
+                max_sendable: None,
+                success_message: None,
+                description: None,
+                avatar: None,
+                webhook_url: None,
+                webhook_secret: None,
+                lock_to_pubkey: false,
+                created_at: None,
+            },
+            pr: bolt11.clone(),
+            last_checked: 0,
+            expire: 0,
+            reserved,
+            invite: None,
+        };
+
+        let alice = pending_user("alice", None);
+        let bob = pending_user("bob", Some(Amount::from_sat(1000)));
+        for pending_user in [&alice, &bob] {
+            db.add_user(
+                "example.com",
+                &pending_user.user.username,
+                &UserKind::Pending(pending_user.clone()),
+            )
+            .await
+            .unwrap();
+            free_expired_signup(&db, pending_user).await.unwrap();
+        }
 
-        let amount = Amount::from_sat(100);
+        // Free usernames can be signed up again, reserved ones are reserved again
+        assert!(db.get_user("example.com", "alice").await.unwrap().is_none());
+        assert!(matches!(
+            db.get_user("example.com", "bob").await.unwrap(),
+            Some(UserKind::Reserved(amount)) if amount == Amount::from_sat(1000)
+        ));
+
+        // A user that has since signed up is kept
+        db.add_user("example.com", "alice", &UserKind::User(alice.user.clone()))
+            .await
+            .unwrap();
+        free_expired_signup(&db, &alice).await.unwrap();
+        assert!(matches!(
+            db.get_user("example.com", "alice").await.unwrap(),
+            Some(UserKind::User(_))
+        ));
+    }
+
+    fn encode_pay_index(last_pay_index: u64) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(PAY_INDEX_LEN);
+        buffer.push(PAY_INDEX_MAGIC);
+        buffer.push(PAY_INDEX_VERSION);
+        buffer.extend_from_slice(&last_pay_index.to_le_bytes());
+        let checksum = crc32fast::hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+
+        buffer
+    }
 
-        assert_eq!(fee_for_invoice(amount, 0.01), Amount::from_sat(1));
+    #[test]
+    fn test_pay_index_encoding() {
+        let encoded = encode_pay_index(0x0102030405060708);
+        assert_eq!(encoded.len(), PAY_INDEX_LEN);
+        assert_eq!(&encoded[2..10], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(decode_pay_index(&encoded).unwrap(), 0x0102030405060708);
+
+        let mut corrupt = encoded.clone();
+        corrupt[5] ^= 1;
+        assert!(decode_pay_index(&corrupt).is_err());
+
+        let mut wrong_magic = encoded.clone();
+        wrong_magic[0] = 0;
+        assert!(decode_pay_index(&wrong_magic).is_err());
+
+        let mut wrong_version = encoded.clone();
+        wrong_version[1] = PAY_INDEX_VERSION + 1;
+        assert!(decode_pay_index(&wrong_version).is_err());
+
+        assert!(decode_pay_index(&encoded[..PAY_INDEX_LEN - 1]).is_err());
+        assert_eq!(decode_pay_index(&42u64.to_ne_bytes()).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_import_pay_index_file() {
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.pay_index", uuid::Uuid::new_v4()));
+        assert_eq!(import_pay_index_file(&path), 0);
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, encode_pay_index(1234)).unwrap();
+        assert_eq!(import_pay_index_file(&path), 1234);
+
+        fs::write(&path, [0; PAY_INDEX_LEN]).unwrap();
+        assert_eq!(import_pay_index_file(&path), 0);
     }
 }
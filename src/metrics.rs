@@ -0,0 +1,200 @@
+//! Prometheus metrics served on `/metrics`
+
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Invoices handed to payers, by whether they are proxied
+    pub invoices_created: IntCounterVec,
+    /// Invoices paid by payers
+    pub invoices_paid: IntCounter,
+    /// Invoices requested from mints, by mint and outcome
+    pub mint_requests: IntCounterVec,
+    /// Mint invoice requests that failed and paid invoices that could not be minted
+    pub mint_failures: IntCounter,
+    /// Tokens minted for paid invoices
+    pub tokens_minted: IntCounter,
+    /// Tokens DMed to users over nostr, by outcome
+    pub dm_send: IntCounterVec,
+    /// Seconds taken to request an invoice from a mint
+    pub mint_request_seconds: Histogram,
+    /// Seconds taken to handle http requests, by method, route and status
+    pub http_request_seconds: HistogramVec,
+    /// Invoices waiting to be paid or minted, set when scraped
+    pub pending_invoices: IntGauge,
+    /// Signups rejected by a signup limit, by the limit exceeded
+    pub signups_rejected: IntCounterVec,
+    /// Whether each nostr relay is connected, set when scraped
+    pub relays_connected: IntGaugeVec,
+    /// Tokens that could not be DMed after every retry, set when scraped
+    pub tokens_dead_lettered: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("cashu_lnurl".to_string()), None)?;
+
+        let invoices_created = IntCounterVec::new(
+            Opts::new("invoices_created_total", "Invoices handed to payers"),
+            &["proxied"],
+        )?;
+        let invoices_paid = IntCounter::new("invoices_paid_total", "Invoices paid by payers")?;
+        let mint_requests = IntCounterVec::new(
+            Opts::new("mint_requests_total", "Invoices requested from mints"),
+            &["mint", "outcome"],
+        )?;
+        let mint_failures = IntCounter::new(
+            "mint_failures_total",
+            "Failed mint invoice requests and paid invoices that could not be minted",
+        )?;
+        let tokens_minted =
+            IntCounter::new("tokens_minted_total", "Tokens minted for paid invoices")?;
+        let dm_send = IntCounterVec::new(
+            Opts::new("dm_send_total", "Tokens DMed to users over nostr"),
+            &["outcome"],
+        )?;
+        let mint_request_seconds = Histogram::with_opts(HistogramOpts::new(
+            "mint_request_seconds",
+            "Seconds taken to request an invoice from a mint",
+        ))?;
+        let http_request_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_seconds",
+                "Seconds taken to handle http requests",
+            ),
+            &["method", "route", "status"],
+        )?;
+        let pending_invoices =
+            IntGauge::new("pending_invoices", "Invoices waiting to be paid or minted")?;
+        let signups_rejected = IntCounterVec::new(
+            Opts::new(
+                "signups_rejected_total",
+                "Signups rejected by a signup limit",
+            ),
+            &["limit"],
+        )?;
+        let relays_connected = IntGaugeVec::new(
+            Opts::new("relay_connected", "Whether a nostr relay is connected"),
+            &["relay"],
+        )?;
+        let tokens_dead_lettered = IntGauge::new(
+            "tokens_dead_lettered",
+            "Tokens that could not be DMed after every retry",
+        )?;
+
+        registry.register(Box::new(invoices_created.clone()))?;
+        registry.register(Box::new(invoices_paid.clone()))?;
+        registry.register(Box::new(mint_requests.clone()))?;
+        registry.register(Box::new(mint_failures.clone()))?;
+        registry.register(Box::new(tokens_minted.clone()))?;
+        registry.register(Box::new(dm_send.clone()))?;
+        registry.register(Box::new(mint_request_seconds.clone()))?;
+        registry.register(Box::new(http_request_seconds.clone()))?;
+        registry.register(Box::new(pending_invoices.clone()))?;
+        registry.register(Box::new(signups_rejected.clone()))?;
+        registry.register(Box::new(relays_connected.clone()))?;
+        registry.register(Box::new(tokens_dead_lettered.clone()))?;
+
+        Ok(Self {
+            registry,
+            invoices_created,
+            invoices_paid,
+            mint_requests,
+            mint_failures,
+            tokens_minted,
+            dm_send,
+            mint_request_seconds,
+            http_request_seconds,
+            pending_invoices,
+            signups_rejected,
+            relays_connected,
+            tokens_dead_lettered,
+        })
+    }
+
+    /// Metrics in the prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Outcome label of a result
+pub fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+/// Middleware timing requests to matched routes
+///
+/// Labelled by the route template rather than the path so usernames do not add series
+pub async fn track_requests<B>(
+    State(metrics): State<Metrics>,
+    matched_path: Option<MatchedPath>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let route = matched_path
+        .as_ref()
+        .map_or("unknown", |matched_path| matched_path.as_str())
+        .to_string();
+
+    let response = next.run(request).await;
+
+    metrics
+        .http_request_seconds
+        .with_label_values(&[method.as_str(), &route, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new().unwrap();
+        metrics.invoices_created.with_label_values(&["true"]).inc();
+        metrics
+            .mint_requests
+            .with_label_values(&["https://mint.example.com", outcome::<(), ()>(&Err(()))])
+            .inc();
+        metrics.pending_invoices.set(3);
+        metrics.tokens_dead_lettered.set(1);
+        metrics
+            .relays_connected
+            .with_label_values(&["wss://relay.example.com"])
+            .set(1);
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains("cashu_lnurl_invoices_created_total{proxied=\"true\"} 1"));
+        assert!(encoded.contains(
+            "cashu_lnurl_mint_requests_total{mint=\"https://mint.example.com\",outcome=\"failure\"} 1"
+        ));
+        assert!(encoded.contains("cashu_lnurl_pending_invoices 3"));
+        assert!(encoded.contains("cashu_lnurl_tokens_dead_lettered 1"));
+        assert!(
+            encoded.contains("cashu_lnurl_relay_connected{relay=\"wss://relay.example.com\"} 1")
+        );
+        assert!(encoded.contains("cashu_lnurl_mint_request_seconds_count 0"));
+    }
+}
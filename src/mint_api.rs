@@ -0,0 +1,308 @@
+//! Requests to a mint's v0 api made with the service's own http client
+//!
+//! The cashu-sdk mint client has its own http client, which cannot use the socks proxy or
+//! mint proofs with chosen secrets, so those mints are reached with these instead
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::secp256k1::{All, PublicKey, Scalar, Secp256k1, SecretKey};
+use cashu_sdk::nuts::nut00::wallet::Token;
+use cashu_sdk::nuts::nut03::RequestMintResponse;
+use cashu_sdk::Amount;
+use nostr_sdk::secp256k1::rand::random;
+use nostr_sdk::Url;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// Blind signature of an output
+#[derive(Debug, Deserialize)]
+struct Promise {
+    id: Option<String>,
+    amount: u64,
+    #[serde(rename = "C_")]
+    c: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MintResponse {
+    promises: Vec<Promise>,
+}
+
+/// Output sent to the mint to be signed
+struct BlindedOutput {
+    amount: u64,
+    secret: String,
+    /// Blinding factor, removed from the signature once it is returned
+    r: SecretKey,
+    blinded: PublicKey,
+}
+
+/// Point a secret is blinded on, the hash to curve of the v0 mint api
+pub(crate) fn hash_to_curve(message: &[u8]) -> PublicKey {
+    let mut hash: [u8; 32] = Sha256::digest(message).into();
+    loop {
+        let mut compressed = [0x02; 33];
+        compressed[1..].copy_from_slice(&hash);
+        match PublicKey::from_slice(&compressed) {
+            Ok(point) => return point,
+            Err(_) => hash = Sha256::digest(hash).into(),
+        }
+    }
+}
+
+/// Random secret of a bearer proof
+pub fn random_secret() -> String {
+    hex::encode(random::<[u8; 32]>())
+}
+
+/// Powers of two adding up to `amount`, the amounts of its proofs
+fn split_amount(amount: u64) -> Vec<u64> {
+    (0..u64::BITS)
+        .map(|bit| 1 << bit)
+        .filter(|value| amount & value != 0)
+        .collect()
+}
+
+fn blinding_factor() -> SecretKey {
+    loop {
+        if let Ok(r) = SecretKey::from_slice(&random::<[u8; 32]>()) {
+            return r;
+        }
+    }
+}
+
+fn blind(secp: &Secp256k1<All>, amount: u64, secret: String) -> Result<BlindedOutput> {
+    let r = blinding_factor();
+    let blinded = hash_to_curve(secret.as_bytes()).combine(&r.public_key(secp))?;
+
+    Ok(BlindedOutput {
+        amount,
+        secret,
+        r,
+        blinded,
+    })
+}
+
+/// Signature of the secret, the blind signature less the blinding factor times the mint key
+fn unblind(
+    secp: &Secp256k1<All>,
+    blind_signature: &PublicKey,
+    r: SecretKey,
+    mint_key: &PublicKey,
+) -> Result<PublicKey> {
+    let blinding = mint_key.mul_tweak(secp, &Scalar::from(r))?.negate(secp);
+    Ok(blind_signature.combine(&blinding)?)
+}
+
+/// Json body of a mint response, with the mint's error when it is not a success
+async fn read_json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        bail!(
+            "Mint responded {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Url of a mint endpoint, mint urls may or may not end with a slash
+fn endpoint(mint_url: &Url, path: &str) -> Result<Url> {
+    Ok(Url::parse(&format!(
+        "{}/{}",
+        mint_url.as_str().trim_end_matches('/'),
+        path
+    ))?)
+}
+
+/// Keys of the mint's active keyset by amount
+async fn get_keys(client: &reqwest::Client, mint_url: &Url) -> Result<BTreeMap<u64, PublicKey>> {
+    let keys: BTreeMap<String, String> =
+        read_json(client.get(endpoint(mint_url, "keys")?).send().await?).await?;
+
+    keys.into_iter()
+        .map(|(amount, key)| Ok((amount.parse()?, PublicKey::from_str(&key)?)))
+        .collect()
+}
+
+/// Check the mint serves its keys and keysets
+pub async fn check_mint(client: &reqwest::Client, mint_url: &Url) -> Result<()> {
+    get_keys(client, mint_url).await?;
+
+    let keysets: serde_json::Value =
+        read_json(client.get(endpoint(mint_url, "keysets")?).send().await?).await?;
+    if !keysets["keysets"].is_array() {
+        bail!("Mint has no keysets");
+    }
+
+    Ok(())
+}
+
+/// Invoice to pay for the mint to mint `amount`
+pub async fn request_mint(
+    client: &reqwest::Client,
+    mint_url: &Url,
+    amount: Amount,
+) -> Result<RequestMintResponse> {
+    let mut url = endpoint(mint_url, "mint")?;
+    url.query_pairs_mut()
+        .append_pair("amount", &amount.to_sat().to_string());
+
+    read_json(client.get(url).send().await?).await
+}
+
+/// Mint the paid mint invoice `hash` as proofs with secrets made by `secret`
+pub async fn mint(
+    client: &reqwest::Client,
+    mint_url: &Url,
+    amount: Amount,
+    hash: &str,
+    secret: impl Fn() -> String,
+) -> Result<Token> {
+    let secp = Secp256k1::new();
+    let keys = get_keys(client, mint_url).await?;
+
+    let outputs = split_amount(amount.to_sat())
+        .into_iter()
+        .map(|amount| blind(&secp, amount, secret()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut url = endpoint(mint_url, "mint")?;
+    url.query_pairs_mut().append_pair("hash", hash);
+    let body = json!({
+        "outputs": outputs
+            .iter()
+            .map(|output| json!({ "amount": output.amount, "B_": output.blinded.to_string() }))
+            .collect::<Vec<_>>(),
+    });
+    let response: MintResponse = read_json(
+        client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await?,
+    )
+    .await?;
+
+    if response.promises.len() != outputs.len() {
+        bail!(
+            "Mint signed {} of {} outputs",
+            response.promises.len(),
+            outputs.len()
+        );
+    }
+
+    let proofs = outputs
+        .into_iter()
+        .zip(response.promises)
+        .map(|(output, promise)| {
+            if promise.amount != output.amount {
+                bail!(
+                    "Mint signed {} sat for a {} sat output",
+                    promise.amount,
+                    output.amount
+                );
+            }
+            let mint_key = keys
+                .get(&output.amount)
+                .ok_or_else(|| anyhow!("Mint has no key for {} sat", output.amount))?;
+            let c = unblind(&secp, &PublicKey::from_str(&promise.c)?, output.r, mint_key)?;
+
+            Ok(json!({
+                "id": promise.id,
+                "amount": output.amount,
+                "secret": output.secret,
+                "C": c.to_string(),
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let token = json!({
+        "token": [{
+            "mint": mint_url.as_str().trim_end_matches('/'),
+            "proofs": proofs,
+        }],
+    });
+
+    Ok(serde_json::from_value(token)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_amount() {
+        assert_eq!(split_amount(0), Vec::<u64>::new());
+        assert_eq!(split_amount(1), vec![1]);
+        assert_eq!(split_amount(13), vec![1, 4, 8]);
+        assert_eq!(split_amount(1024), vec![1024]);
+    }
+
+    #[test]
+    fn test_hash_to_curve() {
+        // Vectors of the v0 mint api, the second is hashed again to find a point
+        let point = hash_to_curve(&[0u8; 32]);
+        assert_eq!(
+            point.to_string(),
+            "0266687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925"
+        );
+        let point = hash_to_curve(
+            &hex::decode("0000000000000000000000000000000000000000000000000000000000000002")
+                .unwrap(),
+        );
+        assert_eq!(
+            point.to_string(),
+            "02076c988b353fcbb748178ecb286bc9d0b4acf474d4ba31ba62334e46c97c416a"
+        );
+    }
+
+    #[test]
+    fn test_unblind() {
+        let secp = Secp256k1::new();
+        let mint_secret = SecretKey::from_slice(&[7; 32]).unwrap();
+        let output = blind(&secp, 8, "secret".to_string()).unwrap();
+
+        // What the mint signs, and what signing the unblinded secret gives
+        let blind_signature = output
+            .blinded
+            .mul_tweak(&secp, &Scalar::from(mint_secret))
+            .unwrap();
+        let signature = hash_to_curve(b"secret")
+            .mul_tweak(&secp, &Scalar::from(mint_secret))
+            .unwrap();
+
+        let unblinded = unblind(
+            &secp,
+            &blind_signature,
+            output.r,
+            &mint_secret.public_key(&secp),
+        )
+        .unwrap();
+        assert_eq!(unblinded, signature);
+    }
+
+    #[test]
+    fn test_endpoint() {
+        for mint in ["https://mint.example.com", "https://mint.example.com/"] {
+            let mint = Url::parse(mint).unwrap();
+            assert_eq!(
+                endpoint(&mint, "keys").unwrap().as_str(),
+                "https://mint.example.com/keys"
+            );
+        }
+        let mint = Url::parse("https://example.com/cashu/").unwrap();
+        assert_eq!(
+            endpoint(&mint, "mint").unwrap().as_str(),
+            "https://example.com/cashu/mint"
+        );
+    }
+}
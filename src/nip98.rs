@@ -0,0 +1,229 @@
+//! NIP-98 http auth, binding the events users sign to the request they authorize
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use axum::http::{header, HeaderMap, Method};
+use base64::engine::general_purpose;
+use base64::Engine;
+use nostr_sdk::{Event, EventId, Url};
+use sha2::{Digest, Sha256};
+
+/// Scheme of `Authorization` headers carrying a base64 json auth event
+const AUTH_SCHEME: &str = "Nostr ";
+
+/// Used events remembered before new ones are refused
+const MAX_USED_EVENTS: usize = 100_000;
+
+/// Request an auth event must have been signed for
+#[derive(Debug, Clone, Copy)]
+pub struct AuthRequest<'a> {
+    pub method: &'a Method,
+    /// Path the request was sent to, compared with the path of the `u` tag so the service
+    /// can be behind a proxy with another host
+    pub path: &'a str,
+    /// Body hashed by the `payload` tag, `None` for an event sent in the body itself
+    pub body: Option<&'a [u8]>,
+}
+
+/// Value of the first tag named `name`
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event.tags.iter().find_map(|tag| {
+        let mut values = tag.as_vec().into_iter();
+        match values.next() {
+            Some(tag_name) if tag_name == name => values.next(),
+            _ => None,
+        }
+    })
+}
+
+/// Check the `u`, `method` and `payload` tags of an event are for `request`
+///
+/// The payload is only required of events sent in a header with a body
+pub fn check_tags(event: &Event, request: &AuthRequest) -> Result<(), &'static str> {
+    let url = tag_value(event, "u").ok_or("Auth event has no u tag")?;
+    let url = Url::parse(&url).map_err(|_| "Invalid auth event u tag")?;
+    if url.path() != request.path {
+        return Err("Auth event is for another url");
+    }
+
+    let method = tag_value(event, "method").ok_or("Auth event has no method tag")?;
+    if !method.eq_ignore_ascii_case(request.method.as_str()) {
+        return Err("Auth event is for another method");
+    }
+
+    if let Some(body) = request.body.filter(|body| !body.is_empty()) {
+        let payload = tag_value(event, "payload").ok_or("Auth event has no payload tag")?;
+        if !payload.eq_ignore_ascii_case(&hex::encode(Sha256::digest(body))) {
+            return Err("Auth event is for another payload");
+        }
+    }
+
+    Ok(())
+}
+
+/// Event of an `Authorization: Nostr <base64 json event>` header
+pub fn header_event(headers: &HeaderMap) -> Result<Option<Event>, &'static str> {
+    let authorization = match headers.get(header::AUTHORIZATION) {
+        Some(authorization) => authorization,
+        None => return Ok(None),
+    };
+    // Other schemes, such as the admin bearer token, are not auth events
+    let encoded = match authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix(AUTH_SCHEME))
+    {
+        Some(encoded) => encoded.trim(),
+        None => return Ok(None),
+    };
+
+    let json = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| "Invalid auth header")?;
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|_| "Invalid auth header")
+}
+
+/// Ids of the auth events already used, kept until the events expire
+#[derive(Debug, Default)]
+pub struct UsedEvents {
+    expiries: Mutex<HashMap<EventId, u64>>,
+}
+
+impl UsedEvents {
+    /// Mark an event used until `expires`, false if it was used before
+    ///
+    /// Events are refused rather than forgotten while too many live ones are remembered
+    pub fn insert(&self, id: EventId, expires: u64, now: u64) -> bool {
+        let mut expiries = self.expiries.lock().unwrap_or_else(|err| err.into_inner());
+
+        if expiries.len() >= MAX_USED_EVENTS {
+            expiries.retain(|_, expires| *expires >= now);
+        }
+        if expiries.len() >= MAX_USED_EVENTS || expiries.contains_key(&id) {
+            return false;
+        }
+
+        expiries.insert(id, expires);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag, TagKind};
+
+    use super::*;
+
+    fn event(tags: &[(&str, &str)]) -> Event {
+        let tags: Vec<Tag> = tags
+            .iter()
+            .map(|(name, value)| {
+                Tag::Generic(TagKind::Custom(name.to_string()), vec![value.to_string()])
+            })
+            .collect();
+        EventBuilder::new(Kind::TextNote, "alice", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_tags() {
+        let body = br#"{"mint":"https://mint.example.com"}"#;
+        let payload = hex::encode(Sha256::digest(body));
+        let request = AuthRequest {
+            method: &Method::PUT,
+            path: "/users/alice",
+            body: Some(body.as_slice()),
+        };
+
+        let signed = event(&[
+            ("u", "https://example.com/users/alice"),
+            ("method", "PUT"),
+            ("payload", &payload),
+        ]);
+        assert_eq!(check_tags(&signed, &request), Ok(()));
+
+        // Events in the body cannot hash it
+        let in_body = event(&[("u", "https://example.com/users/alice"), ("method", "PUT")]);
+        assert!(check_tags(&in_body, &request).is_err());
+        let request_body = AuthRequest {
+            body: None,
+            ..request
+        };
+        assert_eq!(check_tags(&in_body, &request_body), Ok(()));
+
+        // Signed for another action on the user
+        let delete = AuthRequest {
+            method: &Method::DELETE,
+            ..request_body
+        };
+        assert_eq!(
+            check_tags(&in_body, &delete),
+            Err("Auth event is for another method")
+        );
+        let export = AuthRequest {
+            method: &Method::GET,
+            path: "/users/alice/export",
+            body: None,
+        };
+        assert_eq!(
+            check_tags(
+                &event(&[("u", "https://example.com/users/alice"), ("method", "GET")]),
+                &export
+            ),
+            Err("Auth event is for another url")
+        );
+
+        let other_payload = event(&[
+            ("u", "https://example.com/users/alice"),
+            ("method", "PUT"),
+            ("payload", &hex::encode(Sha256::digest(b"{}"))),
+        ]);
+        assert_eq!(
+            check_tags(&other_payload, &request),
+            Err("Auth event is for another payload")
+        );
+
+        assert!(check_tags(&event(&[("method", "PUT")]), &request_body).is_err());
+        assert!(check_tags(
+            &event(&[("u", "https://example.com/users/alice")]),
+            &request_body
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_header_event() {
+        let signed = event(&[("u", "https://example.com/users/alice"), ("method", "GET")]);
+        let encoded = general_purpose::STANDARD.encode(serde_json::to_vec(&signed).unwrap());
+
+        let mut headers = HeaderMap::new();
+        assert!(matches!(header_event(&headers), Ok(None)));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(matches!(header_event(&headers), Ok(None)));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Nostr {}", encoded).parse().unwrap(),
+        );
+        assert_eq!(header_event(&headers).unwrap().unwrap().id, signed.id);
+
+        headers.insert(header::AUTHORIZATION, "Nostr e30=".parse().unwrap());
+        assert!(header_event(&headers).is_err());
+    }
+
+    #[test]
+    fn test_used_events() {
+        let used = UsedEvents::default();
+        let first = event(&[]).id;
+        let second = event(&[]).id;
+
+        assert!(used.insert(first, 200, 100));
+        assert!(!used.insert(first, 200, 150));
+        assert!(used.insert(second, 200, 150));
+    }
+}
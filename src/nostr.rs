@@ -1,70 +1,134 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpStream};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{bail, Result};
-use cashu_sdk::nuts::nut00::wallet::Token;
-use cashu_sdk::Bolt11Invoice;
+use anyhow::{anyhow, bail, Result};
 use nostr_sdk::prelude::*;
-use tokio::sync::Mutex;
-use tracing::{debug, error, warn};
-use tungstenite::Message as WsMessage;
+use serde::Serialize;
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, error, info, warn};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message as WsMessage, WebSocket};
 
+use crate::config::mint_allowed;
 use crate::database::Db;
-use crate::types::{User, UserKind, UserSignUp};
+use crate::secrets::SecretKeyBytes;
+use crate::types::{
+    normalize_username, unix_time, PayerData, PendingInvoice, User, UserKind, UserSignUp,
+};
 
 const SIGNUP_KIND: u64 = 20420;
 
+/// Time between checks of relay connections
+const RELAY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Delay before the second reconnect to a relay, the first is tried on the next check
+const RELAY_BACKOFF_MIN: Duration = Duration::from_secs(30);
+/// Longest delay between reconnects to a relay
+const RELAY_BACKOFF_MAX: Duration = Duration::from_secs(600);
+
+/// Connection state of a relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RelayHealth {
+    pub url: String,
+    pub connected: bool,
+    /// Unix time the relay connected or disconnected
+    pub since: u64,
+    /// Reconnects tried since the relay disconnected
+    pub reconnect_attempts: u32,
+    /// Unix time the relay is next reconnected to while disconnected
+    #[serde(skip)]
+    next_reconnect: u64,
+}
+
+impl RelayHealth {
+    fn new(url: String, connected: bool, now: u64) -> Self {
+        Self {
+            url,
+            connected,
+            since: now,
+            reconnect_attempts: 0,
+            next_reconnect: now,
+        }
+    }
+
+    /// Record whether the relay is connected, returning whether that changed
+    fn update(&mut self, connected: bool, now: u64) -> bool {
+        if self.connected == connected {
+            return false;
+        }
+
+        self.connected = connected;
+        self.since = now;
+        self.reconnect_attempts = 0;
+        self.next_reconnect = now;
+        true
+    }
+
+    fn reconnect_due(&self, now: u64) -> bool {
+        !self.connected && now >= self.next_reconnect
+    }
+
+    /// Record a reconnect, backing off the next one
+    fn reconnect_attempted(&mut self, now: u64) {
+        self.reconnect_attempts += 1;
+        self.next_reconnect = now + relay_backoff(self.reconnect_attempts).as_secs();
+    }
+}
+
+/// Delay before reconnecting to a relay after `attempts` reconnects failed
+fn relay_backoff(attempts: u32) -> Duration {
+    RELAY_BACKOFF_MIN
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(RELAY_BACKOFF_MAX)
+}
+
 #[derive(Clone, Debug)]
 pub struct Nostr {
     db: Db,
-    keys: Keys,
+    /// Wiped when dropped, the client keeps its own copy for as long as it runs
+    secret_key: SecretKeyBytes,
+    public_key: XOnlyPublicKey,
     domain: String,
     client: Arc<Mutex<Option<Client>>>,
     relays: HashSet<String>,
+    /// Normalized mints users can sign up with
+    allowed_mints: Option<HashSet<String>>,
+    /// Socks5 proxy relays are connected through
+    proxy: Option<SocketAddr>,
+    /// Connection state of each relay by url
+    relay_health: Arc<Mutex<HashMap<String, RelayHealth>>>,
 }
 
 impl Nostr {
-    /// Convert string key to nostr keys
-    fn handle_keys(private_key: &Option<String>) -> Result<Keys> {
-        // Parse and validate private key
-        let keys = match private_key {
-            Some(pk) => {
-                // create a new identity using the provided private key
-                Keys::from_sk_str(pk.as_str())?
-            }
-            None => Keys::generate(),
-        };
-
-        debug!("Public key {:?}", keys.public_key().to_string());
-
-        Ok(keys)
-    }
-
     pub fn get_pubkey(&self) -> String {
-        self.keys.public_key().to_string()
+        self.public_key.to_string()
     }
 
     /// Init Nostr Client
     pub async fn new(
         db: Db,
         domain: String,
-        private_key: &Option<String>,
+        keys: Keys,
         relays: HashSet<String>,
+        allowed_mints: Option<HashSet<String>>,
+        proxy: Option<SocketAddr>,
     ) -> Result<Self> {
-        let keys = Self::handle_keys(private_key)?;
-
         let client = Client::new(&keys);
-        let nostr_relays = relays.iter().map(|url| (url.to_string(), None)).collect();
+        let nostr_relays = relays.iter().map(|url| (url.to_string(), proxy)).collect();
         client.add_relays(nostr_relays).await?;
 
         Ok(Self {
             db,
             domain,
-            keys,
+            secret_key: SecretKeyBytes::new(&keys)?,
+            public_key: keys.public_key(),
             client: Arc::new(Mutex::new(Some(client))),
             relays,
+            allowed_mints,
+            proxy,
+            relay_health: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
@@ -101,8 +165,9 @@ impl Nostr {
 
     /// Internal select loop for preforming nostr operations
     async fn run_internal(&mut self) -> Result<()> {
-        let mut client_guard = self.client.lock().await;
-        if let Some(client) = client_guard.as_mut() {
+        // Clone the client so the lock is not held while handling notifications
+        let client = self.client.lock().await.clone();
+        if let Some(client) = client.as_ref() {
             client.connect().await;
             let keys = client.keys();
 
@@ -124,10 +189,44 @@ impl Nostr {
                             ) {
                                 Ok(msg) => {
                                     debug!("MSG Content: {}", msg);
-                                    if let Ok(user_info) = serde_json::from_str::<UserSignUp>(&msg)
+                                    if let Ok(mut user_info) =
+                                        serde_json::from_str::<UserSignUp>(&msg)
                                     {
+                                        user_info.username =
+                                            normalize_username(&user_info.username);
+
+                                        if let Some(mint) = std::iter::once(&user_info.mint)
+                                            .chain(&user_info.fallback_mints)
+                                            .find(|mint| !mint_allowed(&self.allowed_mints, mint))
+                                        {
+                                            client
+                                                .send_direct_msg(
+                                                    event.pubkey,
+                                                    format!("Mint {} is not allowed", mint),
+                                                    None,
+                                                )
+                                                .await?;
+                                            return Ok(false);
+                                        }
+
+                                        // A `+` starts the tag of a plus address
+                                        if user_info.username.contains('+') {
+                                            client
+                                                .send_direct_msg(
+                                                    event.pubkey,
+                                                    "Username cannot contain +",
+                                                    None,
+                                                )
+                                                .await?;
+                                            return Ok(false);
+                                        }
+
                                         // Check if user exists
-                                        match self.db.get_user(&user_info.username).await? {
+                                        match self
+                                            .db
+                                            .get_user(&self.domain, &user_info.username)
+                                            .await?
+                                        {
                                             Some(UserKind::User(user)) => {
                                                 if user.pubkey.eq(&event.pubkey.to_string()) {
                                                     let relays =
@@ -138,14 +237,27 @@ impl Nostr {
 
                                                     let updated_user = User {
                                                         username: user.username,
+                                                        domain: user.domain,
                                                         mint: user_info.mint,
+                                                        fallback_mints: user_info.fallback_mints,
+                                                        mint_rules: user.mint_rules,
                                                         pubkey: user.pubkey,
                                                         proxy: user.proxy,
                                                         relays,
+                                                        min_sendable: user.min_sendable,
+                                                        max_sendable: user.max_sendable,
+                                                        success_message: user.success_message,
+                                                        description: user.description,
+                                                        avatar: user.avatar,
+                                                        webhook_url: user.webhook_url,
+                                                        webhook_secret: user.webhook_secret,
+                                                        lock_to_pubkey: user.lock_to_pubkey,
+                                                        created_at: user.created_at,
                                                     };
 
                                                     self.db
                                                         .add_user(
+                                                            &self.domain,
                                                             &user_info.username,
                                                             &UserKind::User(updated_user.clone()),
                                                         )
@@ -178,16 +290,29 @@ impl Nostr {
                                                 debug!("User relays: {:?}", relays);
                                                 let new_user = User {
                                                     username: user_info.username.clone(),
+                                                    domain: self.domain.clone(),
                                                     mint: user_info.mint,
+                                                    fallback_mints: user_info.fallback_mints,
+                                                    mint_rules: vec![],
                                                     pubkey: event.pubkey.to_string(),
                                                     // TODO: Need to change nostr to allow this be
                                                     // configured
                                                     proxy: true,
                                                     relays,
+                                                    min_sendable: None,
+                                                    max_sendable: None,
+                                                    success_message: None,
+                                                    description: None,
+                                                    avatar: None,
+                                                    webhook_url: None,
+                                                    webhook_secret: None,
+                                                    lock_to_pubkey: false,
+                                                    created_at: Some(unix_time()),
                                                 };
 
                                                 self.db
                                                     .add_user(
+                                                        &self.domain,
                                                         &user_info.username,
                                                         &UserKind::User(new_user.clone()),
                                                     )
@@ -218,17 +343,98 @@ impl Nostr {
         Ok(())
     }
 
+    /// Connection state of each relay, refreshed from the client
+    ///
+    /// Relays connecting or disconnecting since the last call are logged
+    pub async fn relay_status(&self) -> Vec<RelayHealth> {
+        let client = self.client.lock().await.clone();
+
+        let mut statuses = vec![];
+        if let Some(client) = client {
+            for (url, relay) in client.relays().await {
+                statuses.push((
+                    url.to_string(),
+                    relay.status().await == RelayStatus::Connected,
+                ));
+            }
+        }
+
+        let now = unix_time();
+        let mut relay_health = self.relay_health.lock().await;
+        for (url, connected) in statuses {
+            match relay_health.get_mut(&url) {
+                Some(health) => {
+                    if health.update(connected, now) {
+                        match connected {
+                            true => info!("Relay {} connected", url),
+                            false => warn!("Relay {} disconnected", url),
+                        }
+                    }
+                }
+                None => {
+                    relay_health.insert(url.clone(), RelayHealth::new(url, connected, now));
+                }
+            }
+        }
+
+        let mut status: Vec<RelayHealth> = relay_health.values().cloned().collect();
+        status.sort_by(|a, b| a.url.cmp(&b.url));
+        status
+    }
+
+    /// Check relay connections until shutdown, reconnecting to dropped relays with backoff
+    pub async fn monitor_relays(&self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(RELAY_CHECK_INTERVAL) => {}
+                _ = shutdown.changed() => return,
+            }
+
+            let status = self.relay_status().await;
+            if !status.is_empty() && status.iter().all(|health| !health.connected) {
+                error!("No relays are connected, DMs and zap receipts cannot be sent");
+            }
+
+            let now = unix_time();
+            let due: Vec<String> = self
+                .relay_health
+                .lock()
+                .await
+                .values_mut()
+                .filter(|health| health.reconnect_due(now))
+                .map(|health| {
+                    health.reconnect_attempted(now);
+                    health.url.clone()
+                })
+                .collect();
+            if due.is_empty() {
+                continue;
+            }
+
+            let client = self.client.lock().await.clone();
+            if let Some(client) = client {
+                for (url, relay) in client.relays().await {
+                    if due.contains(&url.to_string()) {
+                        info!("Reconnecting to relay {}", url);
+                        relay.connect(false).await;
+                    }
+                }
+            }
+        }
+    }
+
     fn sign_up_message(&self, username: &str, user: &User) -> String {
         format!(
             "Welcome! \n You're ln address is {}@{}.\n You will get cashu tokens from mint {}",
-            username, self.domain, user.mint
+            username, user.domain, user.mint
         )
     }
 
-    // TODO:
-    pub async fn _send_sign_up_message(&self, username: &str, user: &User) -> anyhow::Result<()> {
-        let mut client_guard = self.client.lock().await;
-        if let Some(client) = client_guard.as_mut() {
+    /// DM a newly signed up user their lightning address
+    pub async fn send_sign_up_message(&self, username: &str, user: &User) -> anyhow::Result<()> {
+        // Clone the client so the lock is not held while sending
+        let client = self.client.lock().await.clone();
+        if let Some(client) = client {
             client
                 .send_direct_msg(
                     XOnlyPublicKey::from_str(&user.pubkey)?,
@@ -240,32 +446,92 @@ impl Nostr {
         Ok(())
     }
 
+    /// DM the previously registered pubkey of a user the changes made to their account
+    pub async fn send_user_updated_message(
+        &self,
+        previous_pubkey: &str,
+        user: &User,
+        changes: &[String],
+    ) -> Result<()> {
+        let msg = format!(
+            "Your ln address {}@{} was updated:\n{}",
+            user.username,
+            user.domain,
+            changes.join("\n")
+        );
+
+        let client = self.client.lock().await.clone();
+        if let Some(client) = client {
+            client
+                .send_direct_msg(XOnlyPublicKey::from_str(previous_pubkey)?, msg, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// DM a user that their account was closed
+    pub async fn send_goodbye_message(&self, user: &User, pending_invoices: usize) -> Result<()> {
+        let mut msg = format!(
+            "Your ln address {}@{} was closed.",
+            user.username, user.domain
+        );
+        if pending_invoices > 0 {
+            msg.push_str(&format!(
+                "\n{} pending invoices can still be paid until they expire, their tokens are kept by the operator.",
+                pending_invoices
+            ));
+        }
+
+        let client = self.client.lock().await.clone();
+        if let Some(client) = client {
+            client
+                .send_direct_msg(XOnlyPublicKey::from_str(&user.pubkey)?, msg, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// DM a serialized token, failing if no relay could be reached
     pub async fn send_token(
         &self,
         receiver: &str,
-        token: Token,
+        token: &str,
+        comment: Option<&str>,
+        payer: Option<&PayerData>,
+        alias: Option<&str>,
         relays: &HashSet<String>,
     ) -> Result<()> {
         let receiver = XOnlyPublicKey::from_str(receiver)?;
 
-        let event = EventBuilder::new_encrypted_direct_msg(
-            &self.keys,
-            receiver,
-            token.convert_to_string()?,
-            None,
-        )?
-        .to_event(&self.keys)?;
+        let mut msg = String::new();
+        // Address paid when it is not the user's own, a plus address or sent to the catch-all user
+        if let Some(alias) = alias {
+            msg.push_str(&format!("To: {}\n", alias));
+        }
+        if let Some(payer) = payer.and_then(payer_summary) {
+            msg.push_str(&format!("From: {}\n", payer));
+        }
+        if let Some(comment) = comment {
+            msg.push_str(&format!("Comment: {}\n", sanitize_comment(comment)));
+        }
+        msg.push_str(token);
+
+        let keys = self.secret_key.keys()?;
+        let event =
+            EventBuilder::new_encrypted_direct_msg(&keys, receiver, msg, None)?.to_event(&keys)?;
 
         self.broadcast_event(relays, event).await?;
         Ok(())
     }
 
+    /// Send an event to the relays and ours, failing if it reached none of them
     async fn broadcast_event(&self, relays: &HashSet<String>, event: Event) -> Result<()> {
         let relays: HashSet<&String> = relays.union(&self.relays).collect();
         debug!("{:?}", relays);
+        let mut sent = 0;
         for relay in relays {
-            let mut socket = match tungstenite::connect(relay) {
-                Ok((s, _)) => s,
+            let mut socket = match connect_relay(relay, self.proxy) {
+                Ok(s) => s,
                 // TODO: the mutiny relay returns an http 200 its getting logged as an error
                 Err(err) => {
                     warn!("Error connecting to {relay}: {err}");
@@ -275,50 +541,376 @@ impl Nostr {
 
             // Send msg
             let msg = ClientMessage::new_event(event.clone()).as_json();
-            socket
-                .send(WsMessage::Text(msg))
-                .expect("Impossible to send message");
+            if let Err(err) = socket.send(WsMessage::Text(msg)) {
+                warn!("Error sending to {relay}: {err}");
+                continue;
+            }
+            sent += 1;
+        }
+
+        if sent == 0 {
+            bail!("Could not send event to any relay");
         }
 
         Ok(())
     }
 
+    /// Publish a kind 9735 zap receipt for a paid invoice
+    ///
+    /// Invoices without a zap request are ignored. The receipt is sent to the
+    /// relays listed in the zap request and the relays of the user
     pub async fn broadcast_zap(
         &self,
-        bolt11: Bolt11Invoice,
-        description: &str,
-        relays: &HashSet<String>,
+        invoice: &PendingInvoice,
+        preimage: Option<String>,
     ) -> Result<()> {
-        let zap_request = Event::from_json(description)?;
-
-        let mut request_relays: HashSet<String> = zap_request
-            .tags
-            .iter()
-            .filter_map(|tag| match tag {
-                Tag::Relays(values) => Some(
-                    values
-                        .iter()
-                        .map(|value| value.to_string())
-                        .collect::<Vec<String>>(),
-                ),
-                _ => None,
-            })
-            .flatten()
-            .collect();
+        let zap_request = match &invoice.description {
+            Some(zap_request) => zap_request,
+            None => return Ok(()),
+        };
 
-        debug!("req relays {:?}", request_relays);
+        let zap_event = EventBuilder::new(
+            Kind::Zap,
+            "",
+            &zap_receipt_tags(&invoice.bolt11.to_string(), zap_request, preimage)?,
+        )
+        .to_event(&self.secret_key.keys()?)?;
 
-        request_relays.extend(relays.clone());
+        let mut request_relays = zap_request_relays(&Event::from_json(zap_request)?);
+        debug!("req relays {:?}", request_relays);
 
-        if zap_request.kind.ne(&Kind::ZapRequest) {
-            bail!("Description is not a zap request");
+        if let Some(UserKind::User(user)) =
+            self.db.get_user(&invoice.domain, &invoice.username).await?
+        {
+            request_relays.extend(user.relays);
         }
 
-        let zap_event = EventBuilder::new_zap_receipt(bolt11.to_string(), None, zap_request)
-            .to_event(&self.keys)?;
         debug!("{:?}", zap_event.as_json());
         self.broadcast_event(&request_relays, zap_event).await?;
 
         Ok(())
     }
 }
+
+/// Open a websocket to a relay, through the socks proxy when one is set
+///
+/// The proxy resolves the relay's hostname so `.onion` relays never reach the system resolver
+fn connect_relay(
+    relay: &str,
+    proxy: Option<SocketAddr>,
+) -> Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return Ok(tungstenite::connect(relay)?.0),
+    };
+
+    let url = nostr_sdk::Url::parse(relay)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Relay {} has no host", relay))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("Relay {} has no port", relay))?;
+
+    let stream = socks::Socks5Stream::connect(proxy, (host, port))?.into_inner();
+    let (socket, _) = tungstenite::client_tls(relay, stream).map_err(|err| anyhow!("{}", err))?;
+
+    Ok(socket)
+}
+
+/// Check a zap request sent with an invoice request is one a receipt can be published for
+///
+/// NIP-57 appendix D
+pub fn validate_zap_request(zap_request: &str, amount_msat: u64) -> Result<Event> {
+    let zap_request = Event::from_json(zap_request)?;
+
+    zap_request.verify()?;
+
+    if zap_request.kind.ne(&Kind::ZapRequest) {
+        bail!("Nostr event is not a zap request");
+    }
+
+    let p_tags = zap_request
+        .tags
+        .iter()
+        .filter(|tag| matches!(tag.kind(), TagKind::P))
+        .count();
+    if p_tags != 1 {
+        bail!("Zap request must have exactly one p tag");
+    }
+
+    for tag in &zap_request.tags {
+        if let Tag::Amount(amount) = tag {
+            if *amount != amount_msat {
+                bail!("Zap request amount does not match invoice amount");
+            }
+        }
+    }
+
+    Ok(zap_request)
+}
+
+/// Relays a zap request asks for the receipt to be published to
+fn zap_request_relays(zap_request: &Event) -> HashSet<String> {
+    zap_request
+        .tags
+        .iter()
+        .filter_map(|tag| match tag {
+            Tag::Relays(values) => Some(
+                values
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<String>>(),
+            ),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// Tags of a NIP-57 zap receipt
+///
+/// The `p`, `e` and `a` tags are copied from the zap request
+fn zap_receipt_tags(bolt11: &str, zap_request: &str, preimage: Option<String>) -> Result<Vec<Tag>> {
+    let request = Event::from_json(zap_request)?;
+
+    if request.kind.ne(&Kind::ZapRequest) {
+        bail!("Description is not a zap request");
+    }
+
+    let mut tags: Vec<Tag> = request
+        .tags
+        .into_iter()
+        .filter(|tag| matches!(tag.kind(), TagKind::P | TagKind::E | TagKind::A))
+        .collect();
+
+    if !tags.iter().any(|tag| matches!(tag.kind(), TagKind::P)) {
+        bail!("Zap request has no p tag");
+    }
+
+    tags.push(Tag::Bolt11(bolt11.to_string()));
+    // The exact json is kept so the description hash of the invoice matches
+    tags.push(Tag::Description(zap_request.to_string()));
+    if let Some(preimage) = preimage {
+        tags.push(Tag::Preimage(preimage));
+    }
+
+    Ok(tags)
+}
+
+/// Name and identifier of a payer, `None` if they sent neither
+fn payer_summary(payer: &PayerData) -> Option<String> {
+    let name = payer.name.as_deref().map(sanitize_comment);
+    let identifier = payer.identifier.as_deref().map(sanitize_comment);
+
+    match (name, identifier) {
+        (Some(name), Some(identifier)) => Some(format!("{} ({})", name, identifier)),
+        (Some(payer), None) | (None, Some(payer)) => Some(payer),
+        (None, None) => None,
+    }
+}
+
+/// Replace control characters in a payer comment
+///
+/// Stops a comment from adding lines or terminal escapes to the DM
+fn sanitize_comment(comment: &str) -> String {
+    comment
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_relay_health() {
+        let url = "wss://relay.example.com".to_string();
+        let mut health = RelayHealth::new(url, true, 100);
+        assert!(!health.update(true, 110));
+        assert!(!health.reconnect_due(110));
+
+        // Dropped relays are reconnected on the next check, then backed off
+        assert!(health.update(false, 120));
+        assert_eq!(health.since, 120);
+        assert!(health.reconnect_due(150));
+        health.reconnect_attempted(150);
+        assert!(!health.reconnect_due(179));
+        assert!(health.reconnect_due(180));
+        health.reconnect_attempted(180);
+        assert_eq!(health.reconnect_attempts, 2);
+        assert!(!health.reconnect_due(239));
+        assert!(health.reconnect_due(240));
+
+        assert!(health.update(true, 250));
+        assert_eq!(health.reconnect_attempts, 0);
+        assert!(!health.reconnect_due(1000));
+
+        assert_eq!(relay_backoff(1), Duration::from_secs(30));
+        assert_eq!(relay_backoff(3), Duration::from_secs(120));
+        assert_eq!(relay_backoff(100), RELAY_BACKOFF_MAX);
+
+        // Only the public state is reported
+        assert_eq!(
+            serde_json::to_value(&health).unwrap(),
+            serde_json::json!({
+                "url": "wss://relay.example.com",
+                "connected": true,
+                "since": 250,
+                "reconnect_attempts": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_connect_relay_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+
+        // Socks5 proxy that records the connect request and then hangs up
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+
+            // Version, command, reserved, address type and domain length
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).unwrap();
+            let mut target = vec![0; request[4] as usize + 2];
+            stream.read_exact(&mut target).unwrap();
+            (request[3], target)
+        });
+
+        assert!(connect_relay("wss://relayabcdef.onion", Some(proxy)).is_err());
+
+        // The hostname is sent for the proxy to resolve
+        let (address_type, target) = server.join().unwrap();
+        assert_eq!(address_type, 3);
+        let (host, port) = target.split_at(target.len() - 2);
+        assert_eq!(host, b"relayabcdef.onion");
+        assert_eq!(port, 443u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_sanitize_comment() {
+        assert_eq!(sanitize_comment("Thanks!"), "Thanks!");
+        assert_eq!(
+            sanitize_comment("line\ncashuAfake\r\u{1b}[2J"),
+            "line cashuAfake  [2J"
+        );
+        assert_eq!(sanitize_comment("\tpadded\n"), "padded");
+    }
+
+    #[test]
+    fn test_payer_summary() {
+        let payer = PayerData {
+            name: Some("Alice\n".to_string()),
+            identifier: Some("alice@example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(payer_summary(&payer).unwrap(), "Alice (alice@example.com)");
+
+        let payer = PayerData {
+            identifier: None,
+            ..payer
+        };
+        assert_eq!(payer_summary(&payer).unwrap(), "Alice");
+        assert_eq!(payer_summary(&PayerData::default()), None);
+    }
+
+    fn zap_request(keys: &Keys, tags: &[Tag]) -> Event {
+        EventBuilder::new(Kind::ZapRequest, "", tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_validate_zap_request() {
+        let keys = Keys::generate();
+        let p_tag = Tag::PubKey(keys.public_key(), None);
+
+        let valid = zap_request(&keys, &[p_tag.clone(), Tag::Amount(10_000)]).as_json();
+        assert!(validate_zap_request(&valid, 10_000).is_ok());
+        assert!(validate_zap_request(&valid, 9_000).is_err());
+
+        // Amount tag is optional
+        let no_amount = zap_request(&keys, &[p_tag.clone()]).as_json();
+        assert!(validate_zap_request(&no_amount, 9_000).is_ok());
+
+        let mut tampered: serde_json::Value = serde_json::from_str(&valid).unwrap();
+        tampered["content"] = serde_json::Value::String("tampered".to_string());
+        assert!(validate_zap_request(&tampered.to_string(), 10_000).is_err());
+
+        let no_p = zap_request(&keys, &[Tag::Amount(10_000)]).as_json();
+        assert!(validate_zap_request(&no_p, 10_000).is_err());
+
+        let two_p = zap_request(
+            &keys,
+            &[
+                p_tag.clone(),
+                Tag::PubKey(Keys::generate().public_key(), None),
+            ],
+        )
+        .as_json();
+        assert!(validate_zap_request(&two_p, 10_000).is_err());
+
+        let note = EventBuilder::new_text_note("gm", &[p_tag])
+            .to_event(&keys)
+            .unwrap()
+            .as_json();
+        assert!(validate_zap_request(&note, 10_000).is_err());
+
+        assert!(validate_zap_request("not json", 10_000).is_err());
+    }
+
+    #[test]
+    fn test_zap_receipt_tags() {
+        let keys = Keys::generate();
+        let bolt11 = "lnbc1";
+
+        let zap_request = EventBuilder::new(
+            Kind::ZapRequest,
+            "",
+            &[
+                Tag::PubKey(keys.public_key(), None),
+                Tag::Relays(vec![UncheckedUrl::from("wss://relay.example.com")]),
+                Tag::Amount(10_000),
+            ],
+        )
+        .to_event(&keys)
+        .unwrap()
+        .as_json();
+
+        let tags = zap_receipt_tags(bolt11, &zap_request, Some("00".repeat(32))).unwrap();
+        let kinds: Vec<TagKind> = tags.iter().map(|tag| tag.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TagKind::P,
+                TagKind::Bolt11,
+                TagKind::Description,
+                TagKind::Preimage
+            ]
+        );
+        assert!(tags.contains(&Tag::Description(zap_request.clone())));
+
+        let relays = zap_request_relays(&Event::from_json(&zap_request).unwrap());
+        assert!(relays.contains("wss://relay.example.com"));
+
+        // Only zap requests can be receipted
+        let note = EventBuilder::new_text_note("gm", &[])
+            .to_event(&keys)
+            .unwrap()
+            .as_json();
+        assert!(zap_receipt_tags(bolt11, &note, None).is_err());
+    }
+}
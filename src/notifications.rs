@@ -0,0 +1,166 @@
+//! Notifications of paid invoices pushed to users over websockets
+
+use axum::extract::ws::{Message, WebSocket};
+use cashu_sdk::Amount;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+/// Notifications kept for subscribers that fall behind
+const NOTIFICATION_CAPACITY: usize = 256;
+
+/// A user's invoice was paid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PaymentNotification {
+    #[serde(skip)]
+    pub domain: String,
+    #[serde(skip)]
+    pub username: String,
+    /// Hash of the invoice the payer paid
+    #[serde(skip)]
+    pub payment_hash: String,
+    /// Msat paid to the user before fees
+    pub amount_msat: u64,
+    /// LUD-12 comment from the payer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Unix time the invoice was paid
+    pub time: u64,
+}
+
+impl PaymentNotification {
+    pub fn new(
+        domain: &str,
+        username: &str,
+        payment_hash: &str,
+        amount: Amount,
+        comment: Option<String>,
+        time: u64,
+    ) -> Self {
+        Self {
+            domain: domain.to_string(),
+            username: username.to_string(),
+            payment_hash: payment_hash.to_string(),
+            amount_msat: amount.to_msat(),
+            comment,
+            time,
+        }
+    }
+
+    fn is_for(&self, domain: &str, username: &str) -> bool {
+        self.domain == domain && self.username == username
+    }
+}
+
+/// Publishes paid invoices to every connected websocket
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    sender: broadcast::Sender<PaymentNotification>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(NOTIFICATION_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publish a notification, dropped when nobody is subscribed
+    pub fn notify(&self, notification: PaymentNotification) {
+        let _ = self.sender.send(notification);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PaymentNotification> {
+        self.sender.subscribe()
+    }
+}
+
+/// Send the notifications for a user over a websocket until either side closes it
+pub async fn forward_notifications(
+    mut socket: WebSocket,
+    mut notifications: broadcast::Receiver<PaymentNotification>,
+    domain: String,
+    username: String,
+) {
+    loop {
+        tokio::select! {
+            notification = notifications.recv() => match notification {
+                Ok(notification) if notification.is_for(&domain, &username) => {
+                    let message = match serde_json::to_string(&notification) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            warn!("Could not serialize notification: {:?}", err);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(message)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Websocket of {} missed {} notifications", username, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Messages from the client are ignored, pings are answered by axum
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+
+    debug!("Websocket of {} closed", username);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_notifier() {
+        let notifier = Notifier::new();
+        // Nobody is subscribed yet
+        notifier.notify(PaymentNotification::new(
+            "example.com",
+            "alice",
+            "00",
+            Amount::from_sat(1),
+            None,
+            1,
+        ));
+
+        let mut notifications = notifier.subscribe();
+        let notification = PaymentNotification::new(
+            "example.com",
+            "alice",
+            "abcd",
+            Amount::from_sat(21),
+            Some("Great post".to_string()),
+            1700000000,
+        );
+        notifier.notify(notification.clone());
+
+        let received = notifications.recv().await.unwrap();
+        assert_eq!(received, notification);
+        assert!(received.is_for("example.com", "alice"));
+        assert!(!received.is_for("example.org", "alice"));
+        assert!(!received.is_for("example.com", "bob"));
+
+        // Only the payment is sent, the user is known from the connection
+        assert_eq!(
+            serde_json::to_value(&received).unwrap(),
+            serde_json::json!({
+                "amount_msat": 21000,
+                "comment": "Great post",
+                "time": 1700000000,
+            })
+        );
+    }
+}
@@ -0,0 +1,514 @@
+//! Nostr Wallet Connect (NIP-47) backend, invoices are made and paid by a remote wallet
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use cashu_sdk::{Amount, Bolt11Invoice};
+use futures::StreamExt;
+use nostr_sdk::nips::nip04::{decrypt, encrypt};
+use nostr_sdk::secp256k1::{SecretKey, XOnlyPublicKey};
+use nostr_sdk::{
+    Client, Event, EventBuilder, EventId, Filter, Keys, Kind, RelayPoolNotification, Tag, Url,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use crate::backend::{InvoiceStream, PaidInvoice, Payment, PaymentBackend, PaymentStatus};
+use crate::types::unix_time;
+
+const URI_SCHEME: &str = "nostr+walletconnect";
+
+const REQUEST_KIND: u64 = 23194;
+const RESPONSE_KIND: u64 = 23195;
+const NOTIFICATION_KIND: u64 = 23196;
+
+/// Time the wallet has to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Error code of a lookup for an invoice the wallet does not have
+const NOT_FOUND: &str = "NOT_FOUND";
+
+/// Connection to a wallet from a `nostr+walletconnect://` uri
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NwcUri {
+    pub wallet_pubkey: XOnlyPublicKey,
+    pub relay: Url,
+    /// Key requests are signed and encrypted with
+    pub secret: SecretKey,
+}
+
+impl FromStr for NwcUri {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri).map_err(|_| anyhow!("NWC uri is not a valid uri"))?;
+        if url.scheme() != URI_SCHEME {
+            bail!("NWC uri must start with {}://", URI_SCHEME);
+        }
+
+        // Some wallets leave out the slashes so the pubkey is the path
+        let wallet_pubkey = match url.host_str() {
+            Some(host) if !host.is_empty() => host,
+            _ => url.path(),
+        };
+        let wallet_pubkey = XOnlyPublicKey::from_str(wallet_pubkey)
+            .map_err(|_| anyhow!("NWC uri does not have a valid wallet pubkey"))?;
+
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .ok_or(anyhow!("NWC uri has no {}", name))
+        };
+        let relay = Url::parse(&param("relay")?)
+            .map_err(|_| anyhow!("NWC uri does not have a valid relay"))?;
+        let secret = SecretKey::from_str(&param("secret")?)
+            .map_err(|_| anyhow!("NWC uri does not have a valid secret"))?;
+
+        Ok(Self {
+            wallet_pubkey,
+            relay,
+            secret,
+        })
+    }
+}
+
+/// Error the wallet answered a request with
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, thiserror::Error)]
+#[error("NWC wallet error {code}: {message}")]
+pub struct NwcError {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NwcResponse {
+    error: Option<NwcError>,
+    result: Option<Value>,
+}
+
+/// Invoice made, paid or received by the wallet
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    invoice: Option<String>,
+    payment_hash: String,
+    preimage: Option<String>,
+    /// Msat
+    amount: Option<u64>,
+    settled_at: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NwcNotification {
+    notification_type: String,
+    notification: Transaction,
+}
+
+/// Result of a request, or the wallet's error
+fn parse_response(method: &str, content: &str) -> Result<Value> {
+    let response: NwcResponse = serde_json::from_str(content)?;
+    if let Some(error) = response.error {
+        return Err(error.into());
+    }
+
+    response
+        .result
+        .ok_or(anyhow!("NWC wallet sent no result for {}", method))
+}
+
+/// Invoice paid to the wallet, `None` for other notifications
+fn parse_notification(content: &str) -> Result<Option<PaidInvoice>> {
+    let notification: NwcNotification = serde_json::from_str(content)?;
+    if notification.notification_type != "payment_received" {
+        return Ok(None);
+    }
+
+    let transaction = notification.notification;
+    Ok(Some(PaidInvoice {
+        payment_hash: transaction.payment_hash,
+        preimage: transaction.preimage,
+        amount_received: transaction.amount.map(Amount::from_msat),
+        // Notifications are not stored by relays so there is nothing to resume from
+        pay_index: None,
+    }))
+}
+
+/// Invoice received by the wallet from its lookup, `None` until it is settled
+fn paid_invoice(transaction: Transaction) -> Option<PaidInvoice> {
+    transaction.settled_at?;
+
+    Some(PaidInvoice {
+        payment_hash: transaction.payment_hash,
+        preimage: transaction.preimage,
+        amount_received: transaction.amount.map(Amount::from_msat),
+        pay_index: None,
+    })
+}
+
+/// Status of a payment from the wallet's lookup
+fn transaction_status(transaction: &Transaction, now: u64) -> PaymentStatus {
+    match (transaction.settled_at, transaction.expires_at) {
+        (Some(_), _) => PaymentStatus::Succeeded,
+        (None, Some(expires_at)) if expires_at < now => PaymentStatus::Failed,
+        (None, _) => PaymentStatus::Pending,
+    }
+}
+
+/// Invoice paid to the wallet from a notification event, `None` for other events
+fn read_notification(
+    secret_key: &SecretKey,
+    wallet_pubkey: &XOnlyPublicKey,
+    event: &Event,
+) -> Result<Option<PaidInvoice>> {
+    if event.kind != Kind::Custom(NOTIFICATION_KIND) || event.pubkey != *wallet_pubkey {
+        return Ok(None);
+    }
+
+    parse_notification(&decrypt(secret_key, wallet_pubkey, &event.content)?)
+}
+
+fn responds_to(event: &Event, request_id: &EventId) -> bool {
+    event
+        .tags
+        .iter()
+        .any(|tag| matches!(tag, Tag::Event(id, ..) if id == request_id))
+}
+
+/// Backend over a Nostr Wallet Connect connection, no lightning node is needed
+///
+/// Payments received while the relay is unreachable are not notified
+pub struct NwcBackend {
+    client: Client,
+    keys: Keys,
+    wallet_pubkey: XOnlyPublicKey,
+}
+
+impl NwcBackend {
+    pub async fn new(uri: &str, proxy: Option<SocketAddr>) -> Result<Self> {
+        let uri = NwcUri::from_str(uri)?;
+        let keys = Keys::new(uri.secret);
+
+        let client = Client::new(&keys);
+        client.add_relay(uri.relay.as_str(), proxy).await?;
+        client.connect().await;
+        client
+            .subscribe(vec![Filter::new()
+                .pubkey(keys.public_key())
+                .author(uri.wallet_pubkey.to_string())
+                .kinds(vec![
+                    Kind::Custom(RESPONSE_KIND),
+                    Kind::Custom(NOTIFICATION_KIND),
+                ])])
+            .await;
+        info!("Connected to NWC wallet through {}", uri.relay);
+
+        Ok(Self {
+            client,
+            keys,
+            wallet_pubkey: uri.wallet_pubkey,
+        })
+    }
+
+    fn decrypt(&self, event: &Event) -> Result<String> {
+        Ok(decrypt(
+            &self.keys.secret_key()?,
+            &self.wallet_pubkey,
+            &event.content,
+        )?)
+    }
+
+    /// Send a request to the wallet and wait for its result
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let content = json!({ "method": method, "params": params }).to_string();
+        let content = encrypt(&self.keys.secret_key()?, &self.wallet_pubkey, content)?;
+        let event = EventBuilder::new(
+            Kind::Custom(REQUEST_KIND),
+            content,
+            &[Tag::PubKey(self.wallet_pubkey, None)],
+        )
+        .to_event(&self.keys)?;
+        let request_id = event.id;
+
+        // Listen before sending so a quick response is not missed
+        let mut notifications = self.client.notifications();
+        self.client.send_event(event).await?;
+        debug!("Sent NWC {} request {}", method, request_id);
+
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            loop {
+                match notifications.recv().await {
+                    Ok(RelayPoolNotification::Event(_url, event))
+                        if event.kind == Kind::Custom(RESPONSE_KIND)
+                            && event.pubkey == self.wallet_pubkey
+                            && responds_to(&event, &request_id) =>
+                    {
+                        return Ok(event)
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => bail!("NWC relay connection closed"),
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("NWC wallet did not answer {} in time", method))??;
+
+        parse_response(method, &self.decrypt(&response)?)
+    }
+}
+
+#[async_trait]
+impl PaymentBackend for NwcBackend {
+    async fn create_invoice(
+        &self,
+        amount: Option<Amount>,
+        description: String,
+        description_hash_only: bool,
+        preimage: Option<[u8; 32]>,
+        expiry: Option<u64>,
+    ) -> Result<Bolt11Invoice> {
+        if preimage.is_some() {
+            bail!("NWC wallets pick the preimage of their invoices");
+        }
+        let amount = amount.ok_or(anyhow!("NWC wallets cannot make amountless invoices"))?;
+
+        let mut params = json!({ "amount": amount.to_msat() });
+        if description_hash_only {
+            params["description_hash"] = hex::encode(Sha256::digest(description.as_bytes())).into();
+        } else {
+            params["description"] = description.into();
+        }
+        if let Some(expiry) = expiry {
+            params["expiry"] = expiry.into();
+        }
+
+        let transaction: Transaction =
+            serde_json::from_value(self.request("make_invoice", params).await?)?;
+        let invoice = transaction
+            .invoice
+            .ok_or(anyhow!("NWC wallet sent no invoice"))?;
+
+        Ok(Bolt11Invoice::from_str(&invoice)?)
+    }
+
+    /// NIP-47 payments have no routing fee limit, so none are made rather than risk paying
+    /// more than `max_fee`
+    async fn pay(&self, bolt11: &Bolt11Invoice, max_fee: Amount) -> Result<Payment> {
+        bail!(
+            "NWC wallets cannot limit the routing fee of {} to {} msat",
+            bolt11.payment_hash(),
+            max_fee.to_msat()
+        )
+    }
+
+    async fn wait_any_invoice(&self, _last_pay_index: Option<u64>) -> Result<InvoiceStream> {
+        let notifications = self.client.notifications();
+        let secret_key = self.keys.secret_key()?;
+        let wallet_pubkey = self.wallet_pubkey;
+
+        Ok(
+            futures::stream::unfold(notifications, move |mut notifications| async move {
+                loop {
+                    let event = match notifications.recv().await {
+                        Ok(RelayPoolNotification::Event(_url, event)) => event,
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Missed {} NWC notifications", skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    };
+
+                    match read_notification(&secret_key, &wallet_pubkey, &event) {
+                        Ok(Some(paid_invoice)) => return Some((paid_invoice, notifications)),
+                        Ok(None) => (),
+                        Err(err) => warn!("Could not read NWC notification: {:?}", err),
+                    }
+                }
+            })
+            .boxed(),
+        )
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let result = match self
+            .request("lookup_invoice", json!({ "payment_hash": payment_hash }))
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => match err.downcast_ref::<NwcError>() {
+                Some(NwcError { code, .. }) if code == NOT_FOUND => {
+                    return Ok(PaymentStatus::Unknown)
+                }
+                _ => return Err(err),
+            },
+        };
+
+        let transaction: Transaction = serde_json::from_value(result)?;
+        Ok(transaction_status(&transaction, unix_time()))
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<Option<PaidInvoice>> {
+        let result = match self
+            .request("lookup_invoice", json!({ "payment_hash": payment_hash }))
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => match err.downcast_ref::<NwcError>() {
+                Some(NwcError { code, .. }) if code == NOT_FOUND => return Ok(None),
+                _ => return Err(err),
+            },
+        };
+
+        let transaction: Transaction = serde_json::from_value(result)?;
+        Ok(paid_invoice(transaction))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        match self.request("get_info", json!({})).await {
+            Ok(_) => Ok(()),
+            // Any answer shows the wallet is reachable, even one without get_info
+            Err(err) if err.downcast_ref::<NwcError>().is_some() => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WALLET_PUBKEY: &str = "b889ff5b1513b641e2a139f661a661364979c5beee91842f8f0ef42ab558e9d4";
+    const SECRET: &str = "71a8c14c1407c113601079c4302dab36460f0ccd0ad506f1f2dc73b5100e4f3c";
+
+    #[test]
+    fn test_parse_uri() {
+        let uri = NwcUri::from_str(&format!(
+            "nostr+walletconnect://{}?relay=wss%3A%2F%2Frelay.damus.io&secret={}",
+            WALLET_PUBKEY, SECRET
+        ))
+        .unwrap();
+        assert_eq!(uri.wallet_pubkey.to_string(), WALLET_PUBKEY);
+        assert_eq!(uri.relay.as_str(), "wss://relay.damus.io/");
+        assert_eq!(uri.secret.display_secret().to_string(), SECRET);
+
+        // Without slashes
+        let no_slashes = NwcUri::from_str(&format!(
+            "nostr+walletconnect:{}?relay=wss://relay.damus.io&secret={}&lud16=alice@example.com",
+            WALLET_PUBKEY, SECRET
+        ))
+        .unwrap();
+        assert_eq!(no_slashes, uri);
+
+        for invalid in [
+            format!(
+                "nostr://{}?relay=wss://relay.damus.io&secret={}",
+                WALLET_PUBKEY, SECRET
+            ),
+            format!(
+                "nostr+walletconnect://abc?relay=wss://relay.damus.io&secret={}",
+                SECRET
+            ),
+            format!("nostr+walletconnect://{}?secret={}", WALLET_PUBKEY, SECRET),
+            format!(
+                "nostr+walletconnect://{}?relay=wss://relay.damus.io",
+                WALLET_PUBKEY
+            ),
+            format!(
+                "nostr+walletconnect://{}?relay=wss://relay.damus.io&secret=abc",
+                WALLET_PUBKEY
+            ),
+        ] {
+            assert!(NwcUri::from_str(&invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let result = parse_response(
+            "make_invoice",
+            r#"{"result_type":"make_invoice","error":null,"result":{"invoice":"lnbc1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(result["invoice"], "lnbc1");
+
+        let err = parse_response(
+            "pay_invoice",
+            r#"{"result_type":"pay_invoice","error":{"code":"INSUFFICIENT_BALANCE","message":"Not enough sats"}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<NwcError>(),
+            Some(&NwcError {
+                code: "INSUFFICIENT_BALANCE".to_string(),
+                message: "Not enough sats".to_string(),
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "NWC wallet error INSUFFICIENT_BALANCE: Not enough sats"
+        );
+
+        assert!(parse_response("get_info", r#"{"result_type":"get_info"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_notification() {
+        let paid = parse_notification(
+            r#"{"notification_type":"payment_received","notification":{"type":"incoming","invoice":"lnbc1","payment_hash":"abcd","preimage":"ef01","amount":21000,"settled_at":1700000000}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(paid.payment_hash, "abcd");
+        assert_eq!(paid.preimage, Some("ef01".to_string()));
+        assert_eq!(paid.amount_received, Some(Amount::from_sat(21)));
+        assert_eq!(paid.pay_index, None);
+
+        // Payments made by the wallet are not invoices paid to it
+        assert!(parse_notification(
+            r#"{"notification_type":"payment_sent","notification":{"type":"outgoing","payment_hash":"abcd","amount":21000}}"#,
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn test_transaction_status() {
+        let transaction = |settled_at, expires_at| Transaction {
+            invoice: None,
+            payment_hash: "abcd".to_string(),
+            preimage: None,
+            amount: None,
+            settled_at,
+            expires_at,
+        };
+
+        assert_eq!(
+            transaction_status(&transaction(Some(90), Some(100)), 200),
+            PaymentStatus::Succeeded
+        );
+        assert_eq!(
+            transaction_status(&transaction(None, Some(100)), 200),
+            PaymentStatus::Failed
+        );
+        assert_eq!(
+            transaction_status(&transaction(None, Some(300)), 200),
+            PaymentStatus::Pending
+        );
+        assert_eq!(
+            transaction_status(&transaction(None, None), 200),
+            PaymentStatus::Pending
+        );
+
+        // Only settled invoices were received
+        assert!(paid_invoice(transaction(None, Some(300))).is_none());
+        let paid = paid_invoice(transaction(Some(90), Some(100))).unwrap();
+        assert_eq!(paid.payment_hash, "abcd");
+    }
+}
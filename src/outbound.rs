@@ -0,0 +1,210 @@
+//! Requests to urls chosen by users, which may only reach public hosts
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use hyper::client::connect::dns::Name;
+use nostr_sdk::Url;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect;
+
+/// Whether an IPv4 address is routable on the internet
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || a == 0
+        // 100.64.0.0/10 shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // 192.0.0.0/24 protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // 240.0.0.0/4 reserved
+        || a >= 240)
+}
+
+/// Whether an IPv6 address is routable on the internet
+///
+/// Addresses embedding an IPv4 address are judged by it
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    match segments {
+        // ::ffff:0:0/96 IPv4 mapped
+        [0, 0, 0, 0, 0, 0xffff, _, _]
+        // 64:ff9b::/96 NAT64
+        | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => {
+            let [.., hi, lo] = segments;
+            return is_global_v4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)));
+        }
+        _ => (),
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        // ::/96 IPv4 compatible and other reserved addresses
+        || segments[..6].iter().all(|segment| *segment == 0))
+}
+
+/// Whether an address is routable on the internet, not the service's own or its network's
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => is_global_v6(ip),
+    }
+}
+
+/// Addresses a host resolves to, failing unless there are some and all are global
+async fn resolve_global(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|err| anyhow!("Could not resolve {}: {}", host, err))?
+        .collect();
+    if addrs.is_empty() {
+        bail!("{} has no addresses", host);
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_global(addr.ip())) {
+        bail!("{} resolves to the non public address {}", host, addr.ip());
+    }
+
+    Ok(addrs)
+}
+
+/// Check the host of a url is a public address, or only resolves to public addresses
+pub async fn check_public_url(url: &Url) -> Result<()> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("{} has no host", url))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("{} has no port", url))?;
+
+    // IPv6 hosts are in brackets
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(host);
+    match IpAddr::from_str(ip) {
+        Ok(ip) if is_global(ip) => Ok(()),
+        Ok(ip) => bail!("{} is not a public address", ip),
+        Err(_) => resolve_global(host, port).await.map(|_| ()),
+    }
+}
+
+/// Resolver refusing hosts with non public addresses
+///
+/// Hosts are resolved again for each connection, so a host cannot pass
+/// [`check_public_url`] and then be pointed at a private address
+#[derive(Debug, Clone, Copy, Default)]
+struct GlobalResolver;
+
+impl Resolve for GlobalResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = resolve_global(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Client builder for requests to urls chosen by users
+///
+/// Only public addresses are connected to and redirects are not followed, so a url cannot
+/// lead the service to its own network
+pub fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(GlobalResolver))
+        .redirect(redirect::Policy::none())
+}
+
+/// Send a client's requests through the socks proxy when one is set
+///
+/// Hosts are resolved by the proxy, as for relays, so `.onion` urls work and lookups do not
+/// leak to the system resolver. The proxy connects to the hosts, so it is trusted to refuse
+/// the service's own network as the resolver of [`client_builder`] does
+pub fn with_socks_proxy(
+    builder: reqwest::ClientBuilder,
+    proxy: Option<SocketAddr>,
+) -> Result<reqwest::ClientBuilder> {
+    match proxy {
+        Some(proxy) => Ok(builder.proxy(reqwest::Proxy::all(format!("socks5h://{}", proxy))?)),
+        None => Ok(builder),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_global() {
+        let global = |ip: &str| is_global(IpAddr::from_str(ip).unwrap());
+
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "100.128.0.1",
+            "2606:4700:4700::1111",
+            "::ffff:1.1.1.1",
+        ] {
+            assert!(global(ip), "{}", ip);
+        }
+        for ip in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "198.18.0.1",
+            "255.255.255.255",
+            "240.0.0.1",
+            "224.0.0.1",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+            "::10.0.0.1",
+        ] {
+            assert!(!global(ip), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_public_url() {
+        for url in [
+            "https://127.0.0.1/hook",
+            "https://[::1]:8443/hook",
+            "https://[::ffff:10.0.0.1]/hook",
+            "https://localhost/hook",
+        ] {
+            let url = Url::from_str(url).unwrap();
+            assert!(check_public_url(&url).await.is_err(), "{}", url);
+        }
+
+        let url = Url::from_str("https://1.1.1.1/hook").unwrap();
+        assert!(check_public_url(&url).await.is_ok());
+    }
+}
@@ -0,0 +1,67 @@
+//! Tokens locked to a user's pubkey with a NUT-11 P2PK spending condition
+//!
+//! The cashu-sdk in use only mints proofs with random secrets, so locked proofs are minted
+//! with [`mint_api`]
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use bitcoin::secp256k1::XOnlyPublicKey;
+use cashu_sdk::nuts::nut00::wallet::Token;
+use cashu_sdk::Amount;
+use nostr_sdk::secp256k1::rand::random;
+use nostr_sdk::Url;
+use serde_json::json;
+
+use crate::mint_api;
+
+/// NUT-10 secret only spendable with a signature of `pubkey`
+///
+/// Nostr pubkeys are x only, the even key is the one wallets sign with
+fn lock_secret(pubkey: &XOnlyPublicKey) -> String {
+    json!([
+        "P2PK",
+        {
+            "nonce": hex::encode(random::<[u8; 32]>()),
+            "data": format!("02{}", pubkey),
+            "tags": [],
+        }
+    ])
+    .to_string()
+}
+
+/// Mint the paid mint invoice `hash` as proofs locked to the hex `pubkey`
+pub async fn mint_locked(
+    client: &reqwest::Client,
+    mint_url: &Url,
+    amount: Amount,
+    hash: &str,
+    pubkey: &str,
+) -> Result<Token> {
+    let pubkey =
+        XOnlyPublicKey::from_str(pubkey).map_err(|_| anyhow!("Invalid pubkey {}", pubkey))?;
+
+    mint_api::mint(client, mint_url, amount, hash, || lock_secret(&pubkey)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_secret() {
+        let pubkey = XOnlyPublicKey::from_str(
+            "9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31",
+        )
+        .unwrap();
+        let secret: serde_json::Value = serde_json::from_str(&lock_secret(&pubkey)).unwrap();
+
+        assert_eq!(secret[0], "P2PK");
+        assert_eq!(
+            secret[1]["data"],
+            "029630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31"
+        );
+        assert_eq!(secret[1]["nonce"].as_str().unwrap().len(), 64);
+        assert_ne!(lock_secret(&pubkey), lock_secret(&pubkey));
+    }
+}
@@ -0,0 +1,201 @@
+//! LUD-18 payer auth challenges, a fresh `k1` with each LNURL pay response
+//!
+//! Challenges are not stored when they are issued, a `k1` carries its expiry and a mac under a
+//! secret derived from the service's nostr key, so they outlive a restart. They are only
+//! remembered once used so each is accepted once.
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use nostr_sdk::secp256k1::rand::random;
+use sha2::{Digest, Sha256};
+
+use crate::rate_limit::RateLimiter;
+
+/// Seconds a `k1` is accepted for past the LNURL response cache lifetime, for the payer to
+/// pick an amount
+pub const K1_GRACE: u64 = 5 * 60;
+
+/// Most used challenges remembered until they expire, the soonest to expire are forgotten first
+const MAX_USED_K1S: usize = 100_000;
+
+/// Challenges each client ip can use per minute
+const K1S_PER_IP_MINUTE: u32 = 10;
+
+/// Issues and accepts the `k1` challenges payers sign
+pub struct PayerAuthK1s {
+    secret: [u8; 32],
+    /// Seconds a challenge is valid for after it is issued
+    ttl: u64,
+    /// Expiry and challenge of those already used, ordered by expiry
+    used: Mutex<BTreeSet<(u64, [u8; 32])>>,
+    per_ip: RateLimiter,
+}
+
+impl PayerAuthK1s {
+    /// Challenges with a mac keyed by `seed`, valid for `max_age` seconds plus [`K1_GRACE`]
+    pub fn new(seed: &[u8], max_age: u64) -> Self {
+        let secret = Sha256::new()
+            .chain_update(b"cashu-lnurl payer auth")
+            .chain_update(seed)
+            .finalize()
+            .into();
+
+        Self {
+            secret,
+            ttl: max_age + K1_GRACE,
+            used: Mutex::new(BTreeSet::new()),
+            per_ip: RateLimiter::new(K1S_PER_IP_MINUTE),
+        }
+    }
+
+    /// Mac of the nonce and expiry of a challenge
+    fn mac(&self, payload: &[u8]) -> [u8; 8] {
+        let hash = Sha256::new()
+            .chain_update(self.secret)
+            .chain_update(payload)
+            .finalize();
+        let mut mac = [0; 8];
+        mac.copy_from_slice(&hash[..8]);
+        mac
+    }
+
+    /// New hex `k1` of a random nonce, its expiry and their mac
+    pub fn issue(&self, now: u64) -> String {
+        let mut k1 = [0; 32];
+        k1[..16].copy_from_slice(&random::<[u8; 16]>());
+        k1[16..24].copy_from_slice(&(now + self.ttl).to_be_bytes());
+        let mac = self.mac(&k1[..24]);
+        k1[24..].copy_from_slice(&mac);
+
+        hex::encode(k1)
+    }
+
+    /// Decode a `k1` this service issued that has not expired, with its expiry
+    fn decode(&self, k1: &str, now: u64) -> Result<(u64, [u8; 32]), &'static str> {
+        let k1: [u8; 32] = hex::decode(k1)
+            .ok()
+            .and_then(|k1| k1.try_into().ok())
+            .ok_or("auth k1 was not issued by this service")?;
+        if self.mac(&k1[..24]) != k1[24..] {
+            return Err("auth k1 was not issued by this service");
+        }
+        let mut expires = [0; 8];
+        expires.copy_from_slice(&k1[16..24]);
+        let expires = u64::from_be_bytes(expires);
+        if expires < now {
+            return Err("auth k1 has expired");
+        }
+
+        Ok((expires, k1))
+    }
+
+    /// Check a `k1` could be accepted, without using it up
+    ///
+    /// Each client ip can only have a few challenges checked a minute
+    pub fn verify(&self, k1: &str, ip: Option<IpAddr>, now: u64) -> Result<(), &'static str> {
+        let k1 = self.decode(k1, now)?;
+        if self
+            .used
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .contains(&k1)
+        {
+            return Err("auth k1 was already used");
+        }
+        if let Some(ip) = ip {
+            self.per_ip
+                .check(&[ip.to_string()], Instant::now())
+                .map_err(|_| "too many auth k1s from this client")?;
+        }
+
+        Ok(())
+    }
+
+    /// Use up a `k1` this service issued that has not expired or been used before
+    pub fn consume(&self, k1: &str, now: u64) -> Result<(), &'static str> {
+        let k1 = self.decode(k1, now)?;
+
+        let mut used = self.used.lock().unwrap_or_else(|err| err.into_inner());
+        // Expired challenges are refused by their expiry, so are not kept
+        *used = used.split_off(&(now, [0; 32]));
+        if !used.insert(k1) {
+            return Err("auth k1 was already used");
+        }
+        while used.len() > MAX_USED_K1S {
+            used.pop_first();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consume() {
+        let k1s = PayerAuthK1s::new(b"seed", 300);
+        let now = 1_700_000_000;
+
+        let k1 = k1s.issue(now);
+        assert_eq!(k1.len(), 64);
+        assert_ne!(k1s.issue(now), k1);
+
+        assert_eq!(k1s.verify(&k1, None, now + 60), Ok(()));
+        assert_eq!(k1s.consume(&k1, now + 60), Ok(()));
+        assert!(k1s.verify(&k1, None, now + 60).is_err());
+        assert!(k1s.consume(&k1, now + 60).is_err());
+
+        // Expired
+        let k1 = k1s.issue(now);
+        assert!(k1s.verify(&k1, None, now + 300 + K1_GRACE + 1).is_err());
+
+        // Issued by another service, or changed
+        let other = PayerAuthK1s::new(b"other seed", 300).issue(now);
+        assert!(k1s.verify(&other, None, now).is_err());
+        let k1 = k1s.issue(now);
+        let mut changed = hex::decode(&k1).unwrap();
+        changed[0] ^= 1;
+        assert!(k1s.verify(&hex::encode(changed), None, now).is_err());
+        assert!(k1s.verify("not hex", None, now).is_err());
+        assert_eq!(k1s.consume(&k1, now), Ok(()));
+
+        // Still valid with the same seed after a restart
+        let k1 = k1s.issue(now);
+        assert_eq!(PayerAuthK1s::new(b"seed", 300).consume(&k1, now), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_per_ip() {
+        let k1s = PayerAuthK1s::new(b"seed", 300);
+        let now = 1_700_000_000;
+        let ip = IpAddr::from([203, 0, 113, 1]);
+
+        for _ in 0..K1S_PER_IP_MINUTE {
+            assert_eq!(k1s.verify(&k1s.issue(now), Some(ip), now), Ok(()));
+        }
+        assert!(k1s.verify(&k1s.issue(now), Some(ip), now).is_err());
+        let other = IpAddr::from([203, 0, 113, 2]);
+        assert_eq!(k1s.verify(&k1s.issue(now), Some(other), now), Ok(()));
+    }
+
+    #[test]
+    fn test_consume_forgets_soonest_to_expire() {
+        let k1s = PayerAuthK1s::new(b"seed", 300);
+        let now = 1_700_000_000;
+
+        let first = k1s.issue(now);
+        assert_eq!(k1s.consume(&first, now), Ok(()));
+        for _ in 0..MAX_USED_K1S {
+            assert_eq!(k1s.consume(&k1s.issue(now + 1), now), Ok(()));
+        }
+
+        // Full, the oldest was forgotten rather than refusing new challenges
+        assert_eq!(k1s.used.lock().unwrap().len(), MAX_USED_K1S);
+        assert_eq!(k1s.verify(&first, None, now), Ok(()));
+    }
+}
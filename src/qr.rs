@@ -0,0 +1,99 @@
+//! QR codes of lightning addresses
+
+use std::io::Cursor;
+
+use anyhow::Result;
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::{Deserialize, Serialize};
+
+/// Smallest width in pixels of a QR code
+pub const QR_MIN_SIZE: u32 = 64;
+/// Largest width in pixels of a QR code
+pub const QR_MAX_SIZE: u32 = 1024;
+/// Width in pixels of a QR code when none is requested
+pub const QR_DEFAULT_SIZE: u32 = 256;
+
+/// Image format of a QR code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+impl QrFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            QrFormat::Png => "image/png",
+            QrFormat::Svg => "image/svg+xml",
+        }
+    }
+}
+
+/// Render `data` as a QR code at most `size` pixels wide
+///
+/// `size` is clamped between [`QR_MIN_SIZE`] and [`QR_MAX_SIZE`]
+pub fn encode(data: &str, format: QrFormat, size: u32) -> Result<Vec<u8>> {
+    let size = size.clamp(QR_MIN_SIZE, QR_MAX_SIZE);
+    let code = QrCode::new(data.as_bytes())?;
+
+    let image = match format {
+        QrFormat::Png => {
+            let image = code.render::<Luma<u8>>().max_dimensions(size, size).build();
+
+            let mut png = Cursor::new(vec![]);
+            DynamicImage::ImageLuma8(image).write_to(&mut png, ImageOutputFormat::Png)?;
+            png.into_inner()
+        }
+        QrFormat::Svg => code
+            .render::<svg::Color>()
+            .max_dimensions(size, size)
+            .build()
+            .into_bytes(),
+    };
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LNURL: &str = "LNURL1DP68GURN8GHJ7ETCV9KHQMR99E3K7MF68QCRSVP09EMK2MRV944KUMMHDCHKCMN4WFK8QTMPD35KXEGYSF9TT";
+
+    #[test]
+    fn test_png_decodes() {
+        let png = encode(LNURL, QrFormat::Png, QR_DEFAULT_SIZE).unwrap();
+
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+        assert!(image.width() <= QR_DEFAULT_SIZE);
+
+        let mut image = rqrr::PreparedImage::prepare(image);
+        let grids = image.detect_grids();
+        assert_eq!(grids.len(), 1);
+        let (_meta, content) = grids[0].decode().unwrap();
+        assert_eq!(content, LNURL);
+    }
+
+    #[test]
+    fn test_size_bounds() {
+        // Clamped up to the min, which still fits a module per pixel
+        let small = encode(LNURL, QrFormat::Png, 1).unwrap();
+        let image = image::load_from_memory(&small).unwrap();
+        assert!(image.width() <= QR_MIN_SIZE);
+
+        let large = encode(LNURL, QrFormat::Png, u32::MAX).unwrap();
+        let image = image::load_from_memory(&large).unwrap();
+        assert!(image.width() <= QR_MAX_SIZE);
+    }
+
+    #[test]
+    fn test_svg() {
+        let svg =
+            String::from_utf8(encode(LNURL, QrFormat::Svg, QR_DEFAULT_SIZE).unwrap()).unwrap();
+        assert!(svg.contains("<svg"));
+    }
+}
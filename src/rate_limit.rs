@@ -0,0 +1,390 @@
+//! Token bucket rate limiting of invoice requests and signups
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::debug;
+
+use crate::error::LnurlError;
+use crate::LnurlState;
+
+/// Buckets kept before refilled buckets are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits requests per key to a burst of `per_minute`, refilled evenly over a minute
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute: per_minute.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    /// Take a token for each key at `now`
+    ///
+    /// Nothing is taken unless every key has a token, otherwise returns how long until they do
+    pub fn check(&self, keys: &[String], now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(self.per_minute);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        if buckets.len() > MAX_BUCKETS {
+            let refill = Duration::from_secs_f64(capacity / self.tokens_per_second());
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < refill);
+        }
+
+        let refilled: Vec<Bucket> = keys
+            .iter()
+            .map(|key| match buckets.get(key) {
+                Some(bucket) => Bucket {
+                    tokens: (bucket.tokens
+                        + now.duration_since(bucket.updated).as_secs_f64()
+                            * self.tokens_per_second())
+                    .min(capacity),
+                    updated: now,
+                },
+                None => Bucket {
+                    tokens: capacity,
+                    updated: now,
+                },
+            })
+            .collect();
+
+        let lowest = refilled
+            .iter()
+            .map(|bucket| bucket.tokens)
+            .fold(capacity, f64::min);
+        if lowest < 1.0 {
+            return Err(Duration::from_secs_f64(
+                (1.0 - lowest) / self.tokens_per_second(),
+            ));
+        }
+
+        for (key, bucket) in keys.iter().zip(refilled) {
+            buckets.insert(
+                key.clone(),
+                Bucket {
+                    tokens: bucket.tokens - 1.0,
+                    ..bucket
+                },
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Seconds in a day, signups are capped per UTC day
+const DAY: u64 = 24 * 60 * 60;
+
+/// Limits on signups, each disabled when unset
+#[derive(Debug, Default)]
+pub struct SignupLimiter {
+    per_ip: Option<RateLimiter>,
+    global: Option<RateLimiter>,
+    daily_limit: Option<u32>,
+    /// Day since the unix epoch and the accounts created on it
+    daily: Mutex<(u64, u32)>,
+}
+
+impl SignupLimiter {
+    pub fn new(per_ip: Option<u32>, global: Option<u32>, daily_limit: Option<u32>) -> Self {
+        Self {
+            per_ip: per_ip.map(RateLimiter::new),
+            global: global.map(RateLimiter::new),
+            daily_limit,
+            daily: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Check a signup from `ip` is allowed, taking a token from the rate limits
+    ///
+    /// Returns the limit that was exceeded, the label of the rejected signups metric, and how
+    /// long until it allows another signup
+    pub fn check(
+        &self,
+        ip: Option<IpAddr>,
+        now: Instant,
+        unix_now: u64,
+    ) -> Result<(), (&'static str, Duration)> {
+        if let Some(daily_limit) = self.daily_limit {
+            let daily = self.daily.lock().unwrap_or_else(|err| err.into_inner());
+            if daily.0 == unix_now / DAY && daily.1 >= daily_limit {
+                return Err(("daily", Duration::from_secs(DAY - unix_now % DAY)));
+            }
+        }
+
+        if let (Some(per_ip), Some(ip)) = (&self.per_ip, ip) {
+            per_ip
+                .check(&[ip.to_string()], now)
+                .map_err(|retry_after| ("ip", retry_after))?;
+        }
+
+        if let Some(global) = &self.global {
+            global
+                .check(&["global".to_string()], now)
+                .map_err(|retry_after| ("global", retry_after))?;
+        }
+
+        Ok(())
+    }
+
+    /// Count an account created at `unix_now` towards the daily limit
+    pub fn record(&self, unix_now: u64) {
+        let mut daily = self.daily.lock().unwrap_or_else(|err| err.into_inner());
+        let today = unix_now / DAY;
+        if daily.0 != today {
+            *daily = (today, 0);
+        }
+        daily.1 += 1;
+    }
+}
+
+/// Ip of the client, read from `X-Forwarded-For` or `X-Real-IP` only when the peer is a
+/// trusted proxy
+///
+/// `X-Forwarded-For` is read from the right, the first address that is not a trusted proxy is
+/// the client so addresses a client prepends are ignored. `X-Real-IP` is only used without it.
+/// Headers from peers that are not trusted proxies are ignored so clients cannot spoof them.
+pub fn client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &HashSet<IpAddr>,
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    if !trusted_proxies.contains(&client) {
+        return Some(client);
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    if forwarded.is_empty() {
+        return Some(
+            headers
+                .get("x-real-ip")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(client),
+        );
+    }
+
+    for addr in forwarded.into_iter().rev() {
+        match addr.trim().parse() {
+            Ok(addr) => {
+                client = addr;
+                if !trusted_proxies.contains(&client) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+
+    Some(client)
+}
+
+/// Middleware limiting invoice requests by client ip and by username
+///
+/// Responds `429` with a `Retry-After` header once either is exceeded
+pub async fn limit_invoice_requests<B>(
+    State(state): State<LnurlState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Path(username): Path<String>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let rate_limiter = match &state.invoice_rate_limiter {
+        Some(rate_limiter) => rate_limiter,
+        None => return next.run(request).await,
+    };
+
+    let mut keys = vec![format!("user:{}", username)];
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    if let Some(ip) = client_ip(peer, request.headers(), &state.trusted_proxies) {
+        keys.push(format!("ip:{}", ip));
+    }
+
+    match rate_limiter.check(&keys, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            debug!("Rate limited invoice request for {}", username);
+
+            LnurlError::too_many_requests("Too many requests", retry_after).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_burst() {
+        let limiter = RateLimiter::new(3);
+        let keys = ["ip:127.0.0.1".to_string()];
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check(&keys, start).is_ok());
+        }
+        let retry_after = limiter.check(&keys, start).unwrap_err();
+        assert_eq!(retry_after.as_secs_f64().round(), 20.0);
+
+        // One token refills every 20 seconds
+        let later = start + Duration::from_secs(19);
+        assert!(limiter.check(&keys, later).is_err());
+        let later = start + Duration::from_secs(21);
+        assert!(limiter.check(&keys, later).is_ok());
+        assert!(limiter.check(&keys, later).is_err());
+
+        // Refills up to the burst size only
+        let much_later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert!(limiter.check(&keys, much_later).is_ok());
+        }
+        assert!(limiter.check(&keys, much_later).is_err());
+    }
+
+    #[test]
+    fn test_keys_limited_together() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        let alice = "user:alice".to_string();
+        let bob = "user:bob".to_string();
+        let ip = "ip:127.0.0.1".to_string();
+
+        assert!(limiter.check(&[alice.clone(), ip.clone()], now).is_ok());
+        assert!(limiter.check(&[bob.clone(), ip.clone()], now).is_ok());
+
+        // The ip is exhausted across users
+        assert!(limiter.check(&[bob.clone(), ip.clone()], now).is_err());
+        // A rejected request takes no tokens from the other keys
+        assert!(limiter.check(&[bob.clone()], now).is_ok());
+        assert!(limiter.check(&[bob], now).is_err());
+        assert!(limiter.check(&[alice], now).is_ok());
+    }
+
+    #[test]
+    fn test_signup_limits() {
+        let now = Instant::now();
+        let alice: IpAddr = "10.0.0.1".parse().unwrap();
+        let bob: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let limiter = SignupLimiter::new(Some(1), Some(2), None);
+        let limit = |ip, now| limiter.check(ip, now, 0).map_err(|(limit, _)| limit);
+        assert_eq!(limit(Some(alice), now), Ok(()));
+        assert_eq!(
+            limiter.check(Some(alice), now, 0),
+            Err(("ip", Duration::from_secs(60)))
+        );
+        assert_eq!(limit(Some(bob), now), Ok(()));
+        assert_eq!(limit("10.0.0.3".parse().ok(), now), Err("global"));
+
+        // Only created accounts count towards the daily limit
+        let limiter = SignupLimiter::new(None, None, Some(2));
+        for _ in 0..3 {
+            assert_eq!(limiter.check(Some(alice), now, DAY - 1), Ok(()));
+        }
+        limiter.record(DAY - 2);
+        limiter.record(DAY - 1);
+        // Retried at the start of the next day
+        assert_eq!(
+            limiter.check(Some(alice), now, DAY - 1),
+            Err(("daily", Duration::from_secs(1)))
+        );
+        // A new day resets the count
+        assert_eq!(limiter.check(Some(alice), now, DAY), Ok(()));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.1, 203.0.113.7, 10.0.0.1"),
+        );
+
+        // The header is ignored without trusted proxies
+        assert_eq!(
+            client_ip(Some(proxy), &headers, &HashSet::new()),
+            Some(proxy)
+        );
+
+        let trusted = HashSet::from([proxy]);
+        // Spoofed addresses left of the client are ignored
+        assert_eq!(client_ip(Some(proxy), &headers, &trusted), Some(client));
+        // Peers that are not trusted proxies are the client
+        assert_eq!(client_ip(Some(client), &headers, &trusted), Some(client));
+        assert_eq!(
+            client_ip(Some(proxy), &HeaderMap::new(), &trusted),
+            Some(proxy)
+        );
+        // Every forwarded address is a trusted proxy
+        let mut chained = HeaderMap::new();
+        chained.insert("x-forwarded-for", HeaderValue::from_static("10.0.0.1"));
+        assert_eq!(client_ip(Some(proxy), &chained, &trusted), Some(proxy));
+        // An invalid address stops the walk at the last valid one
+        chained.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.1, not an ip, 10.0.0.1"),
+        );
+        assert_eq!(client_ip(Some(proxy), &chained, &trusted), Some(proxy));
+        assert_eq!(client_ip(None, &headers, &trusted), None);
+    }
+
+    #[test]
+    fn test_client_ip_real_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let trusted = HashSet::from([proxy]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(client_ip(Some(proxy), &headers, &trusted), Some(client));
+
+        // Spoofed headers from peers that are not trusted proxies are ignored
+        let spoofer: IpAddr = "198.51.100.9".parse().unwrap();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("10.8.0.2"));
+        headers.insert("x-real-ip", HeaderValue::from_static("10.8.0.2"));
+        assert_eq!(client_ip(Some(spoofer), &headers, &trusted), Some(spoofer));
+
+        // X-Forwarded-For is preferred when a proxy sends both
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        headers.insert("x-real-ip", HeaderValue::from_static("198.51.100.1"));
+        assert_eq!(client_ip(Some(proxy), &headers, &trusted), Some(client));
+
+        let mut invalid = HeaderMap::new();
+        invalid.insert("x-real-ip", HeaderValue::from_static("unknown"));
+        assert_eq!(client_ip(Some(proxy), &invalid, &trusted), Some(proxy));
+    }
+}
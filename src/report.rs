@@ -0,0 +1,306 @@
+//! Accounting totals of paid invoices for operators
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::types::PaymentRecord;
+
+/// Seconds in a day, payments are grouped by UTC day
+const DAY: u64 = 24 * 60 * 60;
+
+/// Header of the csv report, each row is a day, a user or the overall totals
+const CSV_HEADER: &str = "group,key,invoices_paid,gross_msat,fee_msat,tokens_minted,minted_msat";
+
+/// Header of the payments csv, each row is a paid invoice
+pub const PAYMENTS_CSV_HEADER: &str =
+    "paid_at,username,domain,payment_hash,amount_msat,fee_msat,mint,proxied,minted_msat,delivered,comment";
+
+/// Totals of a group of paid invoices
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Totals {
+    pub invoices_paid: u64,
+    /// Msat received from payers
+    pub gross_msat: u64,
+    /// Msat kept for routing to the mint
+    pub fee_msat: u64,
+    pub tokens_minted: u64,
+    /// Msat of the tokens minted
+    pub minted_msat: u64,
+}
+
+impl Totals {
+    fn add(&mut self, record: &PaymentRecord) {
+        self.invoices_paid += 1;
+        self.gross_msat += record.amount_msat;
+        self.fee_msat += record.fee_msat;
+        if let Some(minted_msat) = record.minted_msat {
+            self.tokens_minted += 1;
+            self.minted_msat += minted_msat;
+        }
+    }
+
+    fn csv_row(&self, group: &str, key: &str) -> String {
+        format!(
+            "{},{},{},{},{},{},{}",
+            group,
+            csv_field(key),
+            self.invoices_paid,
+            self.gross_msat,
+            self.fee_msat,
+            self.tokens_minted,
+            self.minted_msat
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DayTotals {
+    /// UTC day as `YYYY-MM-DD`
+    pub date: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserTotals {
+    pub username: String,
+    pub domain: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// Totals of paid invoices, overall and grouped by day and by user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub totals: Totals,
+    /// Oldest first
+    pub days: Vec<DayTotals>,
+    /// Ordered by domain then username
+    pub users: Vec<UserTotals>,
+}
+
+impl Report {
+    pub fn new(records: &[PaymentRecord]) -> Self {
+        let mut totals = Totals::default();
+        let mut days: BTreeMap<u64, Totals> = BTreeMap::new();
+        let mut users: BTreeMap<(&str, &str), Totals> = BTreeMap::new();
+
+        for record in records {
+            totals.add(record);
+            days.entry(record.paid_at / DAY).or_default().add(record);
+            users
+                .entry((record.domain.as_str(), record.username.as_str()))
+                .or_default()
+                .add(record);
+        }
+
+        Self {
+            totals,
+            days: days
+                .into_iter()
+                .map(|(day, totals)| DayTotals {
+                    date: utc_date(day * DAY),
+                    totals,
+                })
+                .collect(),
+            users: users
+                .into_iter()
+                .map(|((domain, username), totals)| UserTotals {
+                    username: username.to_string(),
+                    domain: domain.to_string(),
+                    totals,
+                })
+                .collect(),
+        }
+    }
+
+    /// Report as csv, the day rows then the user rows then the overall totals
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(CSV_HEADER);
+        csv.push('\n');
+
+        for day in &self.days {
+            let _ = writeln!(csv, "{}", day.totals.csv_row("day", &day.date));
+        }
+        for user in &self.users {
+            let address = format!("{}@{}", user.username, user.domain);
+            let _ = writeln!(csv, "{}", user.totals.csv_row("user", &address));
+        }
+        let _ = writeln!(csv, "{}", self.totals.csv_row("total", ""));
+
+        csv
+    }
+}
+
+/// Row of the payments csv for a paid invoice, ending in a line break
+pub fn payment_csv_row(record: &PaymentRecord) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{}\n",
+        record.paid_at,
+        csv_field(&record.username),
+        csv_field(&record.domain),
+        record.hash,
+        record.amount_msat,
+        record.fee_msat,
+        csv_field(record.mint.as_str()),
+        record.proxied,
+        record
+            .minted_msat
+            .map_or(String::new(), |minted_msat| minted_msat.to_string()),
+        record.delivered,
+        csv_field(record.comment.as_deref().unwrap_or_default()),
+    )
+}
+
+/// Quote a csv field that contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// UTC date of a unix time as `YYYY-MM-DD`
+fn utc_date(unix_time: u64) -> String {
+    // Days to civil date from https://howardhinnant.github.io/date_algorithms.html
+    let days = (unix_time / DAY) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::Url;
+
+    use super::*;
+
+    fn record(hash: &str, username: &str, paid_at: u64, minted: bool) -> PaymentRecord {
+        PaymentRecord {
+            hash: hash.to_string(),
+            domain: "example.com".to_string(),
+            username: username.to_string(),
+            mint: Url::parse("https://mint.example.com").unwrap(),
+            amount_msat: 10_000,
+            fee_msat: 100,
+            minted_msat: minted.then_some(9_900),
+            proxied: true,
+            comment: None,
+            delivered: false,
+            paid_at,
+        }
+    }
+
+    #[test]
+    fn test_utc_date() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(utc_date(1_700_000_000), "2023-11-14");
+        assert_eq!(utc_date(1_704_067_199), "2023-12-31");
+        assert_eq!(utc_date(1_704_067_200), "2024-01-01");
+    }
+
+    #[test]
+    fn test_report() {
+        let records = [
+            record("a", "alice", 1_700_000_000, true),
+            record("b", "bob", 1_700_000_100, true),
+            // Paid but not minted yet
+            record("c", "alice", 1_700_100_000, false),
+        ];
+        let report = Report::new(&records);
+
+        assert_eq!(
+            report.totals,
+            Totals {
+                invoices_paid: 3,
+                gross_msat: 30_000,
+                fee_msat: 300,
+                tokens_minted: 2,
+                minted_msat: 19_800,
+            }
+        );
+        assert_eq!(
+            report
+                .days
+                .iter()
+                .map(|day| (day.date.as_str(), day.totals.invoices_paid))
+                .collect::<Vec<_>>(),
+            vec![("2023-11-14", 2), ("2023-11-16", 1)]
+        );
+        assert_eq!(
+            report
+                .users
+                .iter()
+                .map(|user| (user.username.as_str(), user.totals.tokens_minted))
+                .collect::<Vec<_>>(),
+            vec![("alice", 1), ("bob", 1)]
+        );
+
+        assert_eq!(
+            serde_json::to_value(&report.users[1]).unwrap(),
+            serde_json::json!({
+                "username": "bob",
+                "domain": "example.com",
+                "invoices_paid": 1,
+                "gross_msat": 10000,
+                "fee_msat": 100,
+                "tokens_minted": 1,
+                "minted_msat": 9900,
+            })
+        );
+
+        assert_eq!(
+            report.to_csv(),
+            "group,key,invoices_paid,gross_msat,fee_msat,tokens_minted,minted_msat\n\
+             day,2023-11-14,2,20000,200,2,19800\n\
+             day,2023-11-16,1,10000,100,0,0\n\
+             user,alice@example.com,2,20000,200,1,9900\n\
+             user,bob@example.com,1,10000,100,1,9900\n\
+             total,,3,30000,300,2,19800\n"
+        );
+
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_payment_csv_row() {
+        let unminted = record("a", "alice", 1_700_000_000, false);
+        assert_eq!(
+            payment_csv_row(&unminted),
+            "1700000000,alice,example.com,a,10000,100,https://mint.example.com/,true,,false,\n"
+        );
+
+        let minted = PaymentRecord {
+            comment: Some("Thanks, \"great\" post\r\nsee you".to_string()),
+            delivered: true,
+            ..record("b", "bob", 1_700_000_100, true)
+        };
+        assert_eq!(
+            payment_csv_row(&minted),
+            "1700000100,bob,example.com,b,10000,100,https://mint.example.com/,true,9900,true,\
+             \"Thanks, \"\"great\"\" post\r\nsee you\"\n"
+        );
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(
+            PAYMENTS_CSV_HEADER.split(',').count(),
+            payment_csv_row(&unminted).split(',').count()
+        );
+    }
+}
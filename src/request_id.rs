@@ -0,0 +1,142 @@
+//! Request ids tying the logs of an http request together
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::field::display;
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+use crate::rate_limit::client_ip;
+
+/// Header the request id is returned in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Id of the request being handled, an extension set by [`assign_request_id`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Middleware assigning every request an id
+///
+/// The handler runs in a span with the id and the client ip, read from forwarded headers of
+/// `trusted_proxies`, so its logs and those of anything it awaits or spawns in the span carry
+/// them. The id is returned in the `X-Request-Id` header.
+pub async fn assign_request_id<B>(
+    State(trusted_proxies): State<Arc<HashSet<IpAddr>>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let request_id = Uuid::new_v4().to_string();
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let client = client_ip(peer, request.headers(), &trusted_proxies);
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        client_ip = client.map(display),
+        method = %request.method(),
+        path = request.uri().path(),
+    );
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+
+    let mut response = next.run(request).instrument(span).await;
+
+    // A uuid is always a valid header value
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Body;
+    use axum::extract::Extension;
+    use axum::middleware;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+    use tracing::info;
+
+    use super::*;
+
+    /// Log output kept for assertions
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let router = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(RequestId(request_id)): Extension<RequestId>| async move {
+                        // Logged from a nested span, as minting or sending a DM would be
+                        async { info!("Handling request {}", request_id) }
+                            .instrument(info_span!("nested"))
+                            .await;
+                    },
+                ),
+            )
+            .layer(middleware::from_fn_with_state(
+                Arc::new(HashSet::from(["10.0.0.1".parse::<IpAddr>().unwrap()])),
+                assign_request_id,
+            ));
+
+        let mut request = Request::builder()
+            .uri("/")
+            .header("x-forwarded-for", "203.0.113.7")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 40000))));
+        let response = router.oneshot(request).await.unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(Uuid::parse_str(&request_id).is_ok());
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Handling request"))
+            .unwrap();
+        // The handler sees the id it is returned with, and its nested logs carry it
+        assert!(line.ends_with(&format!("Handling request {}", request_id)));
+        assert!(line.contains(&format!("request_id={}", request_id)));
+        // Logged with the client behind the trusted proxy
+        assert!(line.contains("client_ip=203.0.113.7"));
+    }
+}
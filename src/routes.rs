@@ -1,24 +1,147 @@
 use std::collections::HashSet;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
+use axum::body::{Bytes, StreamBody};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, Extension, Host, OriginalUri, Path, Query, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
 use axum::Json;
+use base64::engine::general_purpose;
+use base64::Engine;
+use bech32::{ToBase32, Variant};
 use cashu_sdk::{Amount, Bolt11Invoice};
-use cln_rpc::model::requests::InvoiceRequest;
-use cln_rpc::primitives::{Amount as CLN_Amount, AmountOrAny};
-use cln_rpc::ClnRpc;
-use nostr_sdk::{Keys, Url};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use nostr_sdk::prelude::{FromPkStr, XOnlyPublicKey};
+use nostr_sdk::secp256k1::schnorr::Signature;
+use nostr_sdk::secp256k1::{ecdsa, rand, Message, PublicKey, Secp256k1};
+use nostr_sdk::{Event, Keys, Url};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-use tracing::{debug, error, warn};
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, warn, Instrument};
 use uuid::Uuid;
 
-use crate::types::{as_msat, unix_time, PendingInvoice, PendingUser, User, UserKind};
+use crate::admin::is_admin;
+use crate::config::{mint_allowed, SuccessActionKind};
+use crate::database::{Db, PaymentRecordFilter, PendingInvoiceFilter};
+use crate::error::{Error, LnurlError, LnurlStatus};
+use crate::nip98::{self, AuthRequest};
+use crate::nostr::{validate_zap_request, RelayHealth};
+use crate::notifications::forward_notifications;
+use crate::outbound;
+use crate::payer_auth::PayerAuthK1s;
+use crate::qr::{self, QrFormat, QR_DEFAULT_SIZE};
+use crate::rate_limit::client_ip;
+use crate::report::{payment_csv_row, Report, PAYMENTS_CSV_HEADER};
+use crate::request_id::RequestId;
+use crate::types::{
+    as_msat, normalize_username, unix_time, ClaimStatus, InviteCode, InviteStatus, MintRule,
+    PayerData, PayerInvoice, PaymentRecord, PendingInvoice, PendingUser, SuccessAction,
+    TokenDelivery, User, UserKind, WebhookDelivery, SUCCESS_MESSAGE_MAX_LEN,
+};
 use crate::LnurlState;
 
-/// List all users
+/// Max time a subsystem has to respond to a health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Seconds an invoice request waits for the lightning backend or the mints by default
+pub const DEFAULT_UPSTREAM_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    /// Lightning backend responds, `None` if no backend is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lightning: Option<bool>,
+    /// Same as `lightning`, kept for monitors from before other backends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cln: Option<bool>,
+    /// At least one nostr relay is connected
+    nostr: bool,
+    /// Connection state of each relay
+    relays: Vec<RelayHealth>,
+    /// Database can be read
+    db: bool,
+}
+
+/// Readiness of each subsystem
+///
+/// Returns `503` if any subsystem is unhealthy
+pub(crate) async fn get_health(
+    State(state): State<LnurlState>,
+) -> (StatusCode, Json<HealthResponse>) {
+    let lightning = async {
+        match &state.ln_backend {
+            Some(ln_backend) => Some(ln_backend.ping().await.is_ok()),
+            None => None,
+        }
+    };
+
+    let (lightning, nostr, db) = tokio::join!(
+        tokio::time::timeout(HEALTH_CHECK_TIMEOUT, lightning),
+        tokio::time::timeout(HEALTH_CHECK_TIMEOUT, state.nostr.relay_status()),
+        tokio::time::timeout(HEALTH_CHECK_TIMEOUT, state.db.ping()),
+    );
+
+    let relays = nostr.unwrap_or_default();
+    // A timeout means a backend is configured but hung
+    let lightning = lightning.unwrap_or(Some(false));
+    let health = HealthResponse {
+        lightning,
+        cln: lightning,
+        nostr: relays.iter().any(|relay| relay.connected),
+        relays,
+        db: matches!(db, Ok(Ok(()))),
+    };
+
+    let status = if health.lightning.unwrap_or(true) && health.nostr && health.db {
+        StatusCode::OK
+    } else {
+        warn!("Health check failed: {:?}", health);
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(health))
+}
+
+/// Prometheus metrics
+pub(crate) async fn get_metrics(State(state): State<LnurlState>) -> Result<String, StatusCode> {
+    match state.db.get_pending_invoices().await {
+        Ok(pending_invoices) => state
+            .metrics
+            .pending_invoices
+            .set(pending_invoices.len() as i64),
+        Err(err) => warn!("Could not count pending invoices: {:?}", err),
+    }
+
+    match state.db.get_dead_letters().await {
+        Ok(dead_letters) => state
+            .metrics
+            .tokens_dead_lettered
+            .set(dead_letters.len() as i64),
+        Err(err) => warn!("Could not count dead lettered tokens: {:?}", err),
+    }
+
+    for relay in state.nostr.relay_status().await {
+        state
+            .metrics
+            .relays_connected
+            .with_label_values(&[&relay.url])
+            .set(relay.connected as i64);
+    }
+
+    state.metrics.encode().map_err(|err| {
+        warn!("Could not encode metrics: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// List all users, without their webhook secrets
 pub(crate) async fn get_list_users(
     State(state): State<LnurlState>,
 ) -> Result<Json<Vec<User>>, StatusCode> {
@@ -27,12 +150,31 @@ pub(crate) async fn get_list_users(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    Ok(Json(users))
+    Ok(Json(users.into_iter().map(User::redacted).collect()))
+}
+
+/// Domain of the request
+///
+/// Strips any port from the `Host` header and checks it is a served domain
+pub(crate) fn request_domain(domains: &HashSet<String>, host: &str) -> Option<String> {
+    let domain = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+    .to_lowercase();
+
+    domains.contains(&domain).then_some(domain)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainParams {
+    domain: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReserveParams {
     username: String,
+    domain: Option<String>,
     cost: Amount,
 }
 
@@ -42,12 +184,21 @@ pub(crate) async fn post_reserve_user(
 ) -> Result<StatusCode, StatusCode> {
     state
         .db
-        .add_user(&params.username, &UserKind::Reserved(params.cost))
+        .add_user(
+            params.domain.as_ref().unwrap_or(&state.primary_domain),
+            &params.username,
+            &UserKind::Reserved(params.cost),
+        )
         .await
         .map_err(|err| {
             warn!("Could not reserve user: {:?}", err);
             StatusCode::OK
         })?;
+    invalidate_lnurl(
+        &state,
+        params.domain.as_ref().unwrap_or(&state.primary_domain),
+        &params.username,
+    );
 
     Ok(StatusCode::OK)
 }
@@ -55,6 +206,7 @@ pub(crate) async fn post_reserve_user(
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockParams {
     username: String,
+    domain: Option<String>,
 }
 
 pub(crate) async fn post_block_user(
@@ -63,12 +215,21 @@ pub(crate) async fn post_block_user(
 ) -> Result<StatusCode, StatusCode> {
     state
         .db
-        .add_user(&params.username, &UserKind::Blocked)
+        .add_user(
+            params.domain.as_ref().unwrap_or(&state.primary_domain),
+            &params.username,
+            &UserKind::Blocked,
+        )
         .await
         .map_err(|err| {
             warn!("Could not reserve user: {:?}", err);
             StatusCode::OK
         })?;
+    invalidate_lnurl(
+        &state,
+        params.domain.as_ref().unwrap_or(&state.primary_domain),
+        &params.username,
+    );
 
     Ok(StatusCode::OK)
 }
@@ -76,16 +237,25 @@ pub(crate) async fn post_block_user(
 /// Add user overwriting is already in db
 pub(crate) async fn post_add_user(
     State(state): State<LnurlState>,
-    Json(user): Json<User>,
+    Json(mut user): Json<User>,
 ) -> Result<StatusCode, StatusCode> {
+    if user.domain.is_empty() {
+        user.domain = state.primary_domain.clone();
+    }
+    if user.created_at.is_none() {
+        user.created_at = Some(unix_time());
+    }
+    user.username = normalize_username(&user.username);
+
     state
         .db
-        .add_user(&user.username, &UserKind::User(user.clone()))
+        .add_user(&user.domain, &user.username, &UserKind::User(user.clone()))
         .await
         .map_err(|err| {
             warn!("Could not add user: {:?}", err);
             StatusCode::OK
         })?;
+    invalidate_lnurl(&state, &user.domain, &user.username);
 
     Ok(StatusCode::OK)
 }
@@ -94,22 +264,31 @@ pub(crate) async fn post_add_user(
 pub(crate) async fn delete_user(
     State(state): State<LnurlState>,
     Path(username): Path<String>,
+    Query(params): Query<DomainParams>,
 ) -> Result<StatusCode, StatusCode> {
-    state.db.delete_user(&username).await.map_err(|err| {
-        warn!("Could not delete user: {:?}", err);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let domain = params.domain.unwrap_or(state.primary_domain.clone());
+    let username = normalize_username(&username);
+
+    state
+        .db
+        .delete_user(&domain, &username)
+        .await
+        .map_err(|err| {
+            warn!("Could not delete user: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    invalidate_lnurl(&state, &domain, &username);
 
     Ok(StatusCode::OK)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum LnurlTag {
     PayRequest,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct LnurlResponse {
     #[serde(with = "as_msat")]
@@ -122,208 +301,1441 @@ pub struct LnurlResponse {
     allows_nostr: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     nostr_pubkey: Option<String>,
+    /// Max length of a LUD-12 comment, omitted when comments are disabled
+    #[serde(skip_serializing_if = "is_zero")]
+    comment_allowed: u32,
+    payer_data: PayerDataCapabilities,
+}
+
+/// LUD-18 payer data field accepted with invoice requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayerDataField {
+    mandatory: bool,
+}
+
+/// LUD-18 payer auth accepted with invoice requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayerDataAuthField {
+    mandatory: bool,
+    /// Hex challenge the payer signs with their linking key
+    k1: String,
+}
+
+/// LUD-18 payer data fields accepted with invoice requests, none are mandatory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayerDataCapabilities {
+    name: PayerDataField,
+    pubkey: PayerDataField,
+    identifier: PayerDataField,
+    auth: PayerDataAuthField,
+}
+
+impl PayerDataCapabilities {
+    fn new(k1: &str) -> Self {
+        Self {
+            name: PayerDataField { mandatory: false },
+            pubkey: PayerDataField { mandatory: false },
+            identifier: PayerDataField { mandatory: false },
+            auth: PayerDataAuthField {
+                mandatory: false,
+                k1: k1.to_string(),
+            },
+        }
+    }
+}
+
+/// Max length of a LUD-18 name or identifier
+const PAYER_DATA_MAX_LEN: usize = 256;
+
+/// Parse and validate LUD-18 payer data
+///
+/// An `auth` proof must sign a `k1` issued by this service, used up once the invoice is stored
+fn parse_payer_data(
+    payer_data: &str,
+    k1s: &PayerAuthK1s,
+    ip: Option<IpAddr>,
+    now: u64,
+) -> Result<PayerData, LnurlError> {
+    let invalid = |reason: &str| LnurlError::bad_request(&format!("Invalid payerdata: {}", reason));
+
+    let payer_data: PayerData =
+        serde_json::from_str(payer_data).map_err(|err| invalid(&err.to_string()))?;
+
+    for field in [&payer_data.name, &payer_data.identifier]
+        .into_iter()
+        .flatten()
+    {
+        if field.chars().count() > PAYER_DATA_MAX_LEN {
+            return Err(invalid(&format!(
+                "fields cannot be longer than {} chars",
+                PAYER_DATA_MAX_LEN
+            )));
+        }
+    }
+
+    if let Some(pubkey) = &payer_data.pubkey {
+        PublicKey::from_str(pubkey).map_err(|_| invalid("pubkey"))?;
+    }
+
+    if let Some(auth) = &payer_data.auth {
+        let key = PublicKey::from_str(&auth.key).map_err(|_| invalid("auth key"))?;
+        let sig = hex::decode(&auth.sig)
+            .ok()
+            .and_then(|sig| ecdsa::Signature::from_der(&sig).ok())
+            .ok_or_else(|| invalid("auth sig"))?;
+        let message = hex::decode(&auth.k1)
+            .ok()
+            .and_then(|k1| Message::from_slice(&k1).ok())
+            .ok_or_else(|| invalid("auth k1"))?;
+
+        Secp256k1::verification_only()
+            .verify_ecdsa(&message, &sig, &key)
+            .map_err(|_| invalid("auth signature does not match"))?;
+        k1s.verify(&auth.k1, ip, now).map_err(invalid)?;
+    }
+
+    Ok(payer_data)
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// Url under `/lnurlp` on the given domain
+fn lnurlp_url(base: &Url, domain: &str, segments: &[&str]) -> anyhow::Result<Url> {
+    let mut url = base.join("lnurlp")?;
+    url.set_host(Some(domain))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Base url cannot have path"))?
+        .extend(segments);
+
+    Ok(url)
+}
+
+/// LUD-16 well known url of a lightning address
+fn well_known_url(base: &Url, domain: &str, username: &str) -> anyhow::Result<Url> {
+    let mut url = base.join(".well-known/lnurlp")?;
+    url.set_host(Some(domain))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Base url cannot have path"))?
+        .push(username);
+
+    Ok(url)
+}
+
+/// LUD-01 bech32 encoding of a url
+fn encode_lnurl(url: &Url) -> anyhow::Result<String> {
+    let lnurl = bech32::encode(
+        "lnurl",
+        url.as_str().as_bytes().to_base32(),
+        Variant::Bech32,
+    )?;
+
+    Ok(lnurl.to_uppercase())
+}
+
+/// Public info of a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfoResponse {
+    username: String,
+    pubkey: String,
+    mint: Url,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fallback_mints: Vec<Url>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mint_rules: Vec<MintRule>,
+    proxy: bool,
+    /// Only included when `expose_user_relays` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relays: Option<HashSet<String>>,
+}
+
+impl UserInfoResponse {
+    fn new(user: User, expose_relays: bool) -> Self {
+        Self {
+            username: user.username,
+            pubkey: user.pubkey,
+            mint: user.mint,
+            fallback_mints: user.fallback_mints,
+            mint_rules: user.mint_rules,
+            proxy: user.proxy,
+            relays: expose_relays.then_some(user.relays),
+        }
+    }
+}
+
+/// Username, pubkey and mint of a user
+///
+/// Only routed when `expose_user_info` is set
+pub(crate) async fn get_user_info(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+) -> Result<Json<UserInfoResponse>, LnurlError> {
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    match state.db.get_user(&domain, &username).await {
+        Ok(Some(UserKind::User(user))) => {
+            Ok(Json(UserInfoResponse::new(user, state.expose_user_relays)))
+        }
+        Ok(_) => Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            warn!("{:?}", err);
+            Err(LnurlError::internal("Could not get user"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LnurlAddressResponse {
+    address: String,
+    lnurl: String,
+    callback: Url,
+}
+
+/// Lightning address and bech32 LNURL of a user
+pub(crate) async fn get_user_lnurl(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+) -> Result<Json<LnurlAddressResponse>, LnurlError> {
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    let (well_known, lnurl) = user_lnurl(&state, &domain, &username).await?;
+    let callback = lnurlp_url(&state.api_base_address, &domain, &[&username, "invoice"])
+        .map_err(|_| LnurlError::internal("Could not create callback url"))?;
+
+    let address = lightning_address(&username, &domain, &well_known);
+
+    Ok(Json(LnurlAddressResponse {
+        address,
+        lnurl,
+        callback,
+    }))
+}
+
+/// Lightning address served at a well-known url
+///
+/// Port is only included when it is not the default for the scheme
+fn lightning_address(username: &str, domain: &str, well_known: &Url) -> String {
+    match well_known.port() {
+        Some(port) => format!("{}@{}:{}", username, domain, port),
+        None => format!("{}@{}", username, domain),
+    }
+}
+
+/// Well-known url and its bech32 LNURL of a signed up user
+async fn user_lnurl(
+    state: &LnurlState,
+    domain: &str,
+    username: &str,
+) -> Result<(Url, String), LnurlError> {
+    match state.db.get_user(domain, username).await {
+        Ok(Some(UserKind::User(_))) => (),
+        Ok(_) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            warn!("{:?}", err);
+            return Err(LnurlError::internal("Could not get user"));
+        }
+    }
+
+    let well_known = well_known_url(&state.api_base_address, domain, username)
+        .map_err(|_| LnurlError::internal("Could not create lnurl"))?;
+    let lnurl =
+        encode_lnurl(&well_known).map_err(|_| LnurlError::internal("Could not create lnurl"))?;
+
+    Ok((well_known, lnurl))
 }
 
+/// Signup page compiled into the binary, filled in by [`signup_page`]
+const SIGNUP_PAGE: &str = include_str!("../static/signup.html");
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Signup page with the service's domain and nostr pubkey, posting to `signup_url`
+fn signup_page(domain: &str, nostr_pubkey: &str, signup_url: &Url) -> String {
+    SIGNUP_PAGE
+        .replace("{{domain}}", &escape_html(domain))
+        .replace("{{nostr_pubkey}}", &escape_html(nostr_pubkey))
+        // Quotes and angle brackets are percent encoded in urls
+        .replace("{{signup_url}}", signup_url.as_str())
+}
+
+/// Built in signup page, served on `/` when enabled
+pub(crate) async fn get_signup_page(
+    Host(host): Host,
+    State(state): State<LnurlState>,
+) -> Result<Html<String>, LnurlError> {
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+
+    // Signups are for users of the domain they are posted to
+    let mut signup_url = state
+        .api_base_address
+        .join("signup")
+        .map_err(|_| LnurlError::internal("Could not create signup url"))?;
+    signup_url
+        .set_host(Some(&domain))
+        .map_err(|_| LnurlError::internal("Could not create signup url"))?;
+
+    Ok(Html(signup_page(
+        &domain,
+        state.nostr_pubkey.as_deref().unwrap_or_default(),
+        &signup_url,
+    )))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QrParams {
+    format: Option<QrFormat>,
+    /// Width in pixels, clamped to sane bounds
+    size: Option<u32>,
+}
+
+/// QR code of a user's bech32 LNURL
+pub(crate) async fn get_user_qr(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+    params: Result<Query<QrParams>, QueryRejection>,
+) -> Result<Response, LnurlError> {
+    let Query(params) = params.map_err(|_| LnurlError::bad_request("Invalid QR parameters"))?;
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    let (_, lnurl) = user_lnurl(&state, &domain, &username).await?;
+
+    let format = params.format.unwrap_or_default();
+    let image =
+        qr::encode(&lnurl, format, params.size.unwrap_or(QR_DEFAULT_SIZE)).map_err(|err| {
+            warn!("Could not create QR code: {:?}", err);
+            LnurlError::internal("Could not create QR code")
+        })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type()),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+        ],
+        image,
+    )
+        .into_response())
+}
+
+/// LNURL pay response of an address, cacheable by clients
+///
+/// Sent with an `ETag` of the response, a request with a matching `If-None-Match` gets `304`
 pub(crate) async fn get_user_lnurl_struct(
     State(state): State<LnurlState>,
+    Host(host): Host,
     Path(username): Path<String>,
-) -> Result<Json<LnurlResponse>, StatusCode> {
-    let _user = match state.db.get_user(&username).await {
+    headers: HeaderMap,
+) -> Result<Response, LnurlError> {
+    let response = user_lnurl_response(&state, &host, &username).await?;
+    let serialize = |response: &LnurlResponse| {
+        serde_json::to_vec(response).map_err(|err| {
+            warn!("Could not serialize LNURL response: {:?}", err);
+            LnurlError::internal("Could not create response")
+        })
+    };
+    let body = serialize(&response)?;
+
+    // Limits, metadata and the nostr pubkey are all in the body, the k1 differs every time
+    let tagged = serialize(&LnurlResponse {
+        payer_data: PayerDataCapabilities::new(""),
+        ..response
+    })?;
+    let etag = format!("\"{}\"", hex::encode(&Sha256::digest(&tagged)[..16]));
+    // Private so shared caches do not hand the same k1 to several payers
+    let cache_headers = [
+        (
+            header::CACHE_CONTROL,
+            match state.lnurl_max_age {
+                0 => "no-cache".to_string(),
+                max_age => format!("private, max-age={}", max_age),
+            },
+        ),
+        (header::ETAG, etag.clone()),
+    ];
+
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    Ok((
+        cache_headers,
+        [(header::CONTENT_TYPE, "application/json")],
+        body,
+    )
+        .into_response())
+}
+
+/// An `If-None-Match` header matches `etag`, weak tags compared as strong ones
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Amounts a user can be paid
+///
+/// Proxied invoices are at least the service's `min_proxied`, so something is left to mint
+/// after the fee
+fn user_sendable(state: &LnurlState, user: &User) -> (Amount, Amount) {
+    let (min_sendable, max_sendable) = user.sendable(state.min_sendable, state.max_sendable);
+    if state.proxy && user.proxy && min_sendable.lt(&state.min_proxied) {
+        return (state.min_proxied, max_sendable);
+    }
+
+    (min_sendable, max_sendable)
+}
+
+/// LNURL pay response of an address, from the cache when it is cached
+async fn user_lnurl_response(
+    state: &LnurlState,
+    host: &str,
+    username: &str,
+) -> Result<LnurlResponse, LnurlError> {
+    let domain = request_domain(&state.domains, host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(username);
+
+    if let Some(cache) = &state.lnurl_cache {
+        if let Some(response) = cache.get(&domain, &username, Instant::now()) {
+            return Ok(with_payer_auth_k1(state, response));
+        }
+    }
+
+    let (user, alias) = match resolve_user(state, &domain, &username).await {
         Ok(Some(user)) => user,
-        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Ok(None) => return Err(LnurlError::not_found("User not found")),
         Err(err) => {
             warn!("{:?}", err);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(LnurlError::internal("Could not get user"));
         }
     };
+    let address = alias.as_deref().unwrap_or(&username);
+    let identifier = format!("{}@{}", address, domain);
 
-    let mut callback = state
-        .api_base_address
-        .join("lnurlp")
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    callback
-        .path_segments_mut()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .push(&username);
-    callback
-        .path_segments_mut()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .push("invoice");
-
-    Ok(Json(LnurlResponse {
-        min_sendable: state.min_sendable,
-        max_sendable: state.max_sendable,
-        metadata: serde_json::to_string(&vec![vec!["text/plain".to_string(), state.description]])
-            .map_err(|err| {
-            warn!("{err}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?,
+    // Callback on the requested domain so the invoice request resolves the same user
+    let callback = lnurlp_url(&state.api_base_address, &domain, &[address, "invoice"])
+        .map_err(|_| LnurlError::internal("Could not create callback url"))?;
+
+    let user = match user {
+        UserKind::User(user) => Some(user),
+        _ => None,
+    };
+    let (min_sendable, max_sendable) = match &user {
+        Some(user) => user_sendable(state, user),
+        None => (state.min_sendable, state.max_sendable),
+    };
+
+    let response = LnurlResponse {
+        min_sendable,
+        max_sendable,
+        metadata: user_metadata(state, user.as_ref(), &identifier)?,
         callback,
         tag: LnurlTag::PayRequest,
         allows_nostr: state.nostr_pubkey.is_some(),
         nostr_pubkey: state.nostr_pubkey,
-    }))
+        comment_allowed: state.comment_allowed,
+        payer_data: PayerDataCapabilities::new(""),
+    };
+
+    // Aliases are not invalidated when the user they resolve to changes
+    if let (Some(cache), None) = (&state.lnurl_cache, &alias) {
+        cache.insert(&domain, &username, response.clone(), Instant::now());
+    }
+
+    Ok(with_payer_auth_k1(state, response))
+}
+
+/// LNURL pay response with a fresh LUD-18 `k1`, which differs with every response
+fn with_payer_auth_k1(state: &LnurlState, response: LnurlResponse) -> LnurlResponse {
+    LnurlResponse {
+        payer_data: PayerDataCapabilities::new(&state.payer_auth_k1s.issue(unix_time())),
+        ..response
+    }
+}
+
+/// Drop the cached LNURL response of a user that was added, changed or removed
+pub(crate) fn invalidate_lnurl(state: &LnurlState, domain: &str, username: &str) {
+    if let Some(cache) = &state.lnurl_cache {
+        cache.invalidate(domain, &normalize_username(username));
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetInvoiceParams {
-    amount: u64,
+    /// Msat amount, amountless invoices are created without it when allowed
+    amount: Option<u64>,
     nostr: Option<String>,
+    comment: Option<String>,
+    /// LUD-18 payer data json
+    payerdata: Option<String>,
+}
+
+/// Url of the page a minted token can be redeemed from
+fn redeem_url(base: &Url, domain: &str, claim_id: &str) -> anyhow::Result<Url> {
+    let mut url = base.join("redeem")?;
+    url.set_host(Some(domain))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Base url cannot have path"))?
+        .push(claim_id);
+
+    Ok(url)
 }
 
+/// Hop of a LUD-06 route to the payee
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteHop {
+    /// Hex pubkey of the node
+    node_id: String,
+    /// Hex channel update of the channel to the next hop
+    channel_update: String,
+}
+
+/// Hops of a route to the payee, first hop first
+pub type RouteHint = Vec<RouteHop>;
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetInvoiceResponse {
     pr: String,
-    // TODO: find out proper type
-    success_action: Option<String>,
-    // TODO: find out proper type
-    routes: Vec<String>,
+    success_action: Option<SuccessAction>,
+    /// Always empty, LUD-06 deprecated routes in favour of the route hints in `pr`
+    routes: Vec<RouteHint>,
+    /// LUD-21 url to check if the invoice has been paid
+    verify: Url,
 }
 
 pub(crate) async fn get_user_invoice(
     Query(params): Query<GetInvoiceParams>,
+    Host(host): Host,
     Path(username): Path<String>,
     State(state): State<LnurlState>,
-) -> Result<Json<GetInvoiceResponse>, StatusCode> {
-    let db = state.db;
+    request_id: Option<Extension<RequestId>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Result<Json<GetInvoiceResponse>, LnurlError> {
+    // Logged with every stage of the invoice, through minting and sending the token
+    let request_id = match request_id {
+        Some(Extension(RequestId(request_id))) => request_id,
+        None => Uuid::new_v4().to_string(),
+    };
 
-    let user = match db.get_user(&username).await {
-        Ok(Some(UserKind::User(user))) => user,
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    if let Some(comment) = &params.comment {
+        if comment.chars().count() > state.comment_allowed as usize {
+            debug!("Comment longer than {} chars", state.comment_allowed);
+            return Err(LnurlError::bad_request(&format!(
+                "Comment cannot be longer than {} chars",
+                state.comment_allowed
+            )));
+        }
+    }
+
+    let (user, alias) = match resolve_user(&state, &domain, &username).await {
+        Ok(Some((UserKind::User(user), alias))) => (user, alias),
         Ok(_) => {
             debug!("User {} is pending, invoice has not been paid.", username);
-            return Err(StatusCode::NOT_FOUND);
+            return Err(LnurlError::not_found("User not found"));
         }
         Err(err) => {
             warn!("{:?}", err);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(LnurlError::internal("Could not get user"));
         }
     };
+    // Invoices are for the user the address resolves to, keeping the address the payer used
+    let username = alias.clone().unwrap_or(username);
+    let identifier = format!("{}@{}", username, domain);
 
-    let mint = &user.mint;
-    let amount = Amount::from_msat(params.amount);
-
-    let pending_invoice = if state.proxy && user.proxy {
-        let client = state.cln_client.clone();
+    let db = state.db;
 
-        let cln_response = client
-            .lock()
-            .await
-            .as_mut()
-            .unwrap()
-            .call(cln_rpc::Request::Invoice(InvoiceRequest {
-                amount_msat: AmountOrAny::Amount(CLN_Amount::from_sat(amount.to_sat())),
-                description: params.nostr.clone().unwrap_or_default(),
-                label: Uuid::new_v4().to_string(),
-                expiry: None,
-                fallbacks: None,
-                preimage: None,
-                cltv: None,
-                deschashonly: Some(true),
-            }))
-            .await;
+    let proxied = state.proxy && user.proxy;
 
-        match cln_response {
-            Ok(cln_rpc::Response::Invoice(invoice_response)) => {
-                let invoice = Bolt11Invoice::from_str(&invoice_response.bolt11).unwrap();
-                let pending_invoice = PendingInvoice {
-                    mint: mint.clone(),
-                    username,
-                    description: params.clone().nostr,
-                    amount: Amount::from_msat(params.amount),
-                    time: unix_time(),
-                    hash: invoice_response.payment_hash.to_string(),
-                    bolt11: invoice,
-                    last_checked: Some(unix_time()),
-                    proxied: true,
-                };
+    let (min_sendable, max_sendable) = user_sendable(&state, &user);
+    // Zero for amountless invoices, the amount is known once paid
+    let amount = match params.amount {
+        // Mint invoices are always for whole sats
+        Some(amount_msat) => validate_invoice_amount(
+            amount_msat,
+            min_sendable,
+            max_sendable,
+            !proxied || state.sat_only,
+        )?,
+        None => {
+            validate_amountless(
+                state.allow_amountless,
+                proxied,
                 state
-                    .cashu
-                    .add_pending_invoice(&pending_invoice)
-                    .await
-                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-                Ok(pending_invoice)
-            }
-            Ok(res) => {
-                warn!("Returned Wrong Cln response: {:?}", res);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            Err(err) => {
-                error!("CLN RPC error: {:?}", err);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
+                    .ln_backend
+                    .as_ref()
+                    .map_or(false, |ln_backend| ln_backend.supports_amountless()),
+            )?;
+            Amount::ZERO
+        }
+    };
+
+    let success_template = user
+        .success_message
+        .as_deref()
+        .unwrap_or(&state.success_message);
+
+    let (success_action, claim_id, preimage) = match state.success_action {
+        SuccessActionKind::Message => (
+            Some(SuccessAction::message(success_template, &username)),
+            None,
+            None,
+        ),
+        kind @ (SuccessActionKind::Url | SuccessActionKind::Aes) => {
+            let claim_id = Uuid::new_v4().to_string();
+            let url = redeem_url(&state.api_base_address, &domain, &claim_id)
+                .map_err(|_| LnurlError::internal("Could not create claim url"))?;
+
+            // Only proxied invoices have a preimage known before they are paid
+            if kind == SuccessActionKind::Aes && proxied {
+                let preimage: [u8; 32] = rand::random();
+                (
+                    Some(SuccessAction::aes(
+                        success_template,
+                        &username,
+                        url.as_str(),
+                        &preimage,
+                    )),
+                    Some(claim_id),
+                    Some(preimage),
+                )
+            } else {
+                (
+                    Some(SuccessAction::url(success_template, &username, url)),
+                    Some(claim_id),
+                    None,
+                )
             }
         }
+        SuccessActionKind::None => (None, None, None),
+    };
+
+    if let Some(zap_request) = &params.nostr {
+        let amount_msat = params
+            .amount
+            .ok_or_else(|| LnurlError::bad_request("Zap requests need an amount"))?;
+        validate_zap_request(zap_request, amount_msat).map_err(|err| {
+            debug!("Invalid zap request: {}", err);
+            LnurlError::bad_request(&format!("Invalid zap request: {}", err))
+        })?;
+    }
+
+    // Only requests sharing an explicit key are repeats, payers may send the same params
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(|key| idempotency_key(&domain, &username, key));
+    if let Some(key) = &idempotency_key {
+        if let Some(invoice) = repeated_invoice(&db, key).await {
+            debug!("Returning invoice {} for repeated request", invoice.hash);
+            return Ok(Json(invoice_response(&state.api_base_address, &invoice)?));
+        }
+    }
+
+    // After the repeat check so a repeated request does not use up its k1 again
+    let ip = client_ip(
+        connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        &headers,
+        &state.trusted_proxies,
+    );
+    let payer_data = params
+        .payerdata
+        .as_deref()
+        .map(|payer_data| parse_payer_data(payer_data, &state.payer_auth_k1s, ip, unix_time()))
+        .transpose()?;
+
+    // Mint rules pick the mint by amount, the chosen mint is recorded on the pending invoice.
+    // The amount of amountless invoices is not known so they use the user's mints
+    let mints = match amount == Amount::ZERO {
+        true => user.mints(),
+        false => user.mints_for(amount, &state.mint_rules),
+    };
+
+    let pending_invoice = if proxied {
+        let description = invoice_description(
+            params.nostr.as_deref(),
+            user_metadata(&state, Some(&user), &identifier)?,
+            params.payerdata.as_deref(),
+        );
+        let invoice = get_invoice(
+            &state,
+            Some(amount).filter(|amount| *amount != Amount::ZERO),
+            description,
+            true,
+            preimage,
+            state.invoice_expiry_secs,
+        )
+        .await?;
+
+        let pending_invoice = PendingInvoice {
+            mint: mints[0].clone(),
+            username: user.username.clone(),
+            domain,
+            alias,
+            description: params.clone().nostr,
+            comment: params.comment.clone(),
+            payer_data,
+            claim_id,
+            request_id: Some(request_id),
+            idempotency_key,
+            success_action,
+            amount,
+            time: unix_time(),
+            hash: invoice.payment_hash().to_string(),
+            bolt11: invoice,
+            last_checked: Some(unix_time()),
+            proxied: true,
+            failed: false,
+            payer_hash: None,
+        };
+        state
+            .cashu
+            .add_pending_invoice(&pending_invoice)
+            .await
+            .map_err(|_| LnurlError::internal("Could not add pending invoice"))?;
+        pending_invoice
     } else {
-        let request_mint_response =
-            state
-                .cashu
-                .request_mint(amount, mint)
-                .await
-                .map_err(|err| {
-                    warn!("{:?}", err);
-                    StatusCode::INTERNAL_SERVER_ERROR
-                })?;
-        Ok(PendingInvoice {
-            mint: mint.clone(),
-            username,
+        // The mint that issued the invoice is the one that mints once it is paid
+        // Nothing is stored for an invoice request that timed out
+        let (mint, request_mint_response) = tokio::time::timeout(
+            state.upstream_timeout,
+            state.cashu.request_mint(amount, &mints),
+        )
+        .await
+        .map_err(|_| {
+            warn!(
+                "No mint issued an invoice within {:?}",
+                state.upstream_timeout
+            );
+            LnurlError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Timed out getting invoice from mint",
+            )
+        })?
+        .map_err(|err| {
+            warn!("{:?}", err);
+            match err {
+                Error::MintNotAllowed(_) => {
+                    LnurlError::new(StatusCode::FORBIDDEN, "User mint is not allowed")
+                }
+                Error::MintsFailed(_) => LnurlError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "None of the user's mints could issue an invoice",
+                ),
+                _ => LnurlError::internal("Could not get invoice from mint"),
+            }
+        })?;
+        let pending_invoice = PendingInvoice {
+            mint,
+            username: user.username.clone(),
+            domain,
+            alias,
             description: params.nostr,
-            amount: Amount::from_msat(params.amount),
+            comment: params.comment,
+            payer_data,
+            claim_id,
+            request_id: Some(request_id),
+            idempotency_key,
+            success_action,
+            amount,
             hash: request_mint_response.hash,
             bolt11: request_mint_response.pr,
             last_checked: None,
             proxied: false,
+            failed: false,
+            payer_hash: None,
             time: unix_time(),
-        })
+        };
+        state
+            .cashu
+            .add_pending_invoice(&pending_invoice)
+            .await
+            .map_err(|_| LnurlError::internal("Could not add pending invoice"))?;
+        pending_invoice
     };
 
-    match pending_invoice {
-        Ok(invoice) => Ok(Json(GetInvoiceResponse {
-            pr: invoice.bolt11.to_string(),
-            success_action: None,
-            routes: vec![],
-        })),
-        Err(err) => Err(err),
+    // Used up only once the invoice is stored so a request that fails does not burn it,
+    // a concurrent request with the same k1 loses and its invoice is left to expire
+    if let Some(auth) = pending_invoice
+        .payer_data
+        .as_ref()
+        .and_then(|payer_data| payer_data.auth.as_ref())
+    {
+        state
+            .payer_auth_k1s
+            .consume(&auth.k1, unix_time())
+            .map_err(|reason| LnurlError::bad_request(&format!("Invalid payerdata: {}", reason)))?;
     }
-}
 
-async fn get_invoice(
-    client: Arc<Mutex<Option<ClnRpc>>>,
-    amount: Amount,
-    description: String,
-) -> Result<Bolt11Invoice, StatusCode> {
-    let cln_response = client
-        .lock()
-        .await
-        .as_mut()
-        .unwrap()
-        .call(cln_rpc::Request::Invoice(InvoiceRequest {
-            amount_msat: AmountOrAny::Amount(CLN_Amount::from_sat(amount.to_sat())),
-            description,
-            label: Uuid::new_v4().to_string(),
-            expiry: None,
-            fallbacks: None,
-            preimage: None,
-            cltv: None,
-            deschashonly: Some(true),
-        }))
-        .await;
+    state
+        .metrics
+        .invoices_created
+        .with_label_values(&[&proxied.to_string()])
+        .inc();
 
-    match cln_response {
-        Ok(cln_rpc::Response::Invoice(invoice_response)) => {
-            Ok(Bolt11Invoice::from_str(&invoice_response.bolt11)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?)
+    // Verified on the address the payer requested the invoice from
+    let payer_invoice = PayerInvoice {
+        username: pending_invoice
+            .alias
+            .clone()
+            .unwrap_or_else(|| pending_invoice.username.clone()),
+        domain: pending_invoice.domain.clone(),
+        bolt11: pending_invoice.bolt11.clone(),
+        settled: false,
+        preimage: None,
+    };
+    db.add_payer_invoice(&pending_invoice.hash, &payer_invoice)
+        .await
+        .map_err(|err| {
+            warn!("Could not add payer invoice: {:?}", err);
+            LnurlError::internal("Could not add invoice")
+        })?;
+
+    Ok(Json(invoice_response(
+        &state.api_base_address,
+        &pending_invoice,
+    )?))
+}
+
+/// Seconds a repeated invoice request is answered with the same invoice
+const IDEMPOTENCY_WINDOW: u64 = 60;
+
+/// Header clients can set to mark repeated invoice requests
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Key matching requests to a user with the same idempotency header
+fn idempotency_key(domain: &str, username: &str, header: &str) -> String {
+    let request = serde_json::json!([domain, username, header]);
+
+    hex::encode(Sha256::digest(request.to_string().as_bytes()))
+}
+
+/// Invoice can be returned again for a repeated request at `now`
+///
+/// Invoices with a claim url are not, the url is only for whoever pays the invoice
+fn reusable_invoice(invoice: &PendingInvoice, now: u64) -> bool {
+    !invoice.failed
+        && invoice.claim_id.is_none()
+        && now.saturating_sub(invoice.time) <= IDEMPOTENCY_WINDOW
+}
+
+/// Unpaid invoice created for an earlier request with the idempotency key
+async fn repeated_invoice(db: &Db, key: &str) -> Option<PendingInvoice> {
+    let invoice = match db.get_pending_invoice_by_key(key).await {
+        Ok(Some(invoice)) if reusable_invoice(&invoice, unix_time()) => invoice,
+        Ok(_) => return None,
+        Err(err) => {
+            warn!("Could not look up repeated invoice request: {:?}", err);
+            return None;
+        }
+    };
+
+    // A paid invoice waiting to be minted is not handed out again
+    match db.get_payer_invoice(&invoice.hash).await {
+        Ok(Some(payer_invoice)) if !payer_invoice.settled => Some(invoice),
+        Ok(_) => None,
+        Err(err) => {
+            warn!("Could not get payer invoice: {:?}", err);
+            None
+        }
+    }
+}
+
+fn invoice_response(
+    api_base_address: &Url,
+    invoice: &PendingInvoice,
+) -> Result<GetInvoiceResponse, LnurlError> {
+    let username = invoice.alias.as_ref().unwrap_or(&invoice.username);
+    let verify = lnurlp_url(
+        api_base_address,
+        &invoice.domain,
+        &[username, "verify", &invoice.hash],
+    )
+    .map_err(|_| LnurlError::internal("Could not create verify url"))?;
+
+    Ok(GetInvoiceResponse {
+        pr: invoice.bolt11.to_string(),
+        success_action: invoice.success_action.clone(),
+        routes: vec![],
+        verify,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyResponse {
+    status: LnurlStatus,
+    settled: bool,
+    preimage: Option<String>,
+    pr: String,
+}
+
+/// LUD-21 check if an invoice returned by the callback has been paid
+pub(crate) async fn get_verify(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path((username, payment_hash)): Path<(String, String)>,
+) -> Result<Json<VerifyResponse>, LnurlError> {
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+
+    let invoice = match state.db.get_payer_invoice(&payment_hash).await {
+        Ok(Some(invoice))
+            if normalize_username(&invoice.username) == normalize_username(&username)
+                && invoice.domain == domain =>
+        {
+            invoice
         }
-        Ok(res) => {
-            warn!("Returned Wrong Cln response: {:?}", res);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        Ok(_) => return Err(LnurlError::not_found("Not found")),
+        Err(err) => {
+            warn!("{:?}", err);
+            return Err(LnurlError::internal("Could not get invoice"));
+        }
+    };
+
+    Ok(Json(VerifyResponse {
+        status: LnurlStatus::Ok,
+        settled: invoice.settled,
+        preimage: invoice.preimage,
+        pr: invoice.bolt11.to_string(),
+    }))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NotificationParams {
+    /// Json auth event signed by the user's pubkey with the username as content, when it
+    /// is not sent in the `Authorization` header
+    event: Option<String>,
+}
+
+/// Check a websocket subscription is signed by the registered pubkey of the user
+async fn verify_notification_params(
+    state: &LnurlState,
+    domain: &str,
+    username: &str,
+    headers: &HeaderMap,
+    request: AuthRequest<'_>,
+    params: &NotificationParams,
+) -> Result<(), LnurlError> {
+    verify_user_event(
+        state,
+        domain,
+        username,
+        headers,
+        request,
+        params.event.as_deref(),
+        "Subscription must be signed by the pubkey",
+    )
+    .await
+    .map(|_| ())
+}
+
+/// User whose registered pubkey signed the auth event of a request without a body
+///
+/// `event` is the json event of the query, used when there is none in the `Authorization`
+/// header, `missing` is the error without either
+async fn verify_user_event(
+    state: &LnurlState,
+    domain: &str,
+    username: &str,
+    headers: &HeaderMap,
+    request: AuthRequest<'_>,
+    event: Option<&str>,
+    missing: &str,
+) -> Result<User, LnurlError> {
+    let event: Option<Event> = event
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|_| LnurlError::bad_request("Invalid auth event"))?;
+
+    let user = match state.db.get_user(domain, username).await {
+        Ok(Some(UserKind::User(user))) => user,
+        Ok(_) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not get user: {:?}", err);
+            return Err(LnurlError::internal("Could not get user"));
+        }
+    };
+
+    let pubkey = XOnlyPublicKey::from_str(&user.pubkey).map_err(|err| {
+        error!("Invalid stored pubkey for {}: {:?}", username, err);
+        LnurlError::internal("Invalid user pubkey")
+    })?;
+
+    authorize_request(state, headers, event, request, &pubkey, username, missing)?;
+
+    Ok(user)
+}
+
+/// Websocket pushing a message each time one of the user's invoices is paid
+///
+/// Authorized by an auth event in the `Authorization` header or the `event` query parameter,
+/// checked once when the socket is opened
+pub(crate) async fn get_ws_notifications(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    params: Result<Query<NotificationParams>, QueryRejection>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, LnurlError> {
+    let Query(params) =
+        params.map_err(|_| LnurlError::bad_request("Invalid subscription parameters"))?;
+    let username = normalize_username(&username);
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+
+    let request = AuthRequest {
+        method: &method,
+        path: uri.path(),
+        body: None,
+    };
+    verify_notification_params(&state, &domain, &username, &headers, request, &params).await?;
+
+    // Subscribed before upgrading so no payment is missed in between
+    let notifications = state.notifier.subscribe();
+    Ok(ws.on_upgrade(move |socket| {
+        forward_notifications(socket, notifications, domain, username).in_current_span()
+    }))
+}
+
+/// Start of a user export, everything but the payments with their array left open
+fn export_head(
+    user: &User,
+    pending_invoices: &[PendingInvoice],
+    undelivered_tokens: &[TokenDelivery],
+) -> serde_json::Result<String> {
+    Ok(format!(
+        "{{\"user\":{},\"pending_invoices\":{},\"undelivered_tokens\":{},\"payments\":[",
+        serde_json::to_string(user)?,
+        serde_json::to_string(pending_invoices)?,
+        serde_json::to_string(undelivered_tokens)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// Json auth event signed by the user's registered pubkey, when it is not sent in the
+    /// `Authorization` header
+    event: Option<String>,
+}
+
+/// Everything stored for a user as one json document
+///
+/// Authorized like the notifications websocket. The user, their pending invoices and their
+/// undelivered tokens come first, the payment history is streamed a page at a time after them.
+pub(crate) async fn get_user_export(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    params: Result<Query<ExportParams>, QueryRejection>,
+) -> Result<Response, LnurlError> {
+    let Query(params) = params.map_err(|_| LnurlError::bad_request("Invalid export parameters"))?;
+    let username = normalize_username(&username);
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+
+    let request = AuthRequest {
+        method: &method,
+        path: uri.path(),
+        body: None,
+    };
+    let user = verify_user_event(
+        &state,
+        &domain,
+        &username,
+        &headers,
+        request,
+        params.event.as_deref(),
+        "Export must be signed by the pubkey",
+    )
+    .await?;
+
+    let pending_invoices = state
+        .db
+        .get_user_pending_invoices(&domain, &username)
+        .await
+        .map_err(|err| {
+            error!("Could not get pending invoices: {:?}", err);
+            LnurlError::internal("Could not get pending invoices")
+        })?;
+    let undelivered_tokens = state
+        .db
+        .get_user_token_deliveries(&domain, &username)
+        .await
+        .map_err(|err| {
+            error!("Could not get token deliveries: {:?}", err);
+            LnurlError::internal("Could not get token deliveries")
+        })?;
+
+    let head = export_head(&user, &pending_invoices, &undelivered_tokens).map_err(|err| {
+        error!("Could not serialize export: {:?}", err);
+        LnurlError::internal("Could not export user")
+    })?;
+
+    let db = state.db.clone();
+    let payments = payment_record_pages(move |after| {
+        let db = db.clone();
+        let domain = domain.clone();
+        let username = username.clone();
+        async move {
+            db.get_user_payment_records_page(&domain, &username, after, PAYMENT_RECORDS_PAGE)
+                .await
         }
+    })
+    .enumerate()
+    .map(|(page, records)| {
+        let records = records?
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        // Pages after the first continue the array
+        let separator = if page > 0 && !records.is_empty() {
+            ","
+        } else {
+            ""
+        };
+
+        Ok::<_, anyhow::Error>(format!("{}{}", separator, records.join(",")))
+    });
+    let document = stream::once(async { Ok(head) })
+        .chain(payments)
+        .chain(stream::once(async { Ok("]}".to_string()) }));
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json")],
+        StreamBody::new(document),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimResponse {
+    token: String,
+}
+
+/// Return a minted token, a token can only be claimed once
+///
+/// Served on `GET /claim/:id` for json clients and `POST /redeem/:id` for the redeem page
+pub(crate) async fn get_claim(
+    State(state): State<LnurlState>,
+    Path(id): Path<String>,
+) -> Result<Json<ClaimResponse>, LnurlError> {
+    match state.db.claim_token(&id, unix_time()).await {
+        Ok(ClaimStatus::Claimed(token)) => Ok(Json(ClaimResponse { token })),
+        Ok(ClaimStatus::Gone) => Err(LnurlError::new(
+            StatusCode::GONE,
+            "Token already claimed or expired",
+        )),
+        Ok(ClaimStatus::NotFound) => Err(LnurlError::not_found("Not found")),
         Err(err) => {
-            error!("CLN RPC error: {:?}", err);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            warn!("{:?}", err);
+            Err(LnurlError::internal("Could not claim token"))
+        }
+    }
+}
+
+/// Redeem page compiled into the binary
+const REDEEM_PAGE: &str = include_str!("../static/redeem.html");
+
+/// Web page the token of a success action url is redeemed from, for wallets without nostr
+///
+/// Loading the page does not claim the token, so link previews and prefetches cannot use it
+/// up. Its button takes the one time claim with `POST /redeem/:id`
+pub(crate) async fn get_redeem() -> Response {
+    ([(header::CACHE_CONTROL, "no-store")], Html(REDEEM_PAGE)).into_response()
+}
+
+/// Max length of a user's description in chars
+const DESCRIPTION_MAX_LEN: usize = 256;
+
+/// Max size of a user's avatar in bytes, before base64 encoding
+const AVATAR_MAX_BYTES: usize = 16 * 1024;
+
+/// Max length of the LNURL metadata of an address in bytes
+const METADATA_MAX_LEN: usize = 32 * 1024;
+
+/// First bytes of every png
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// LUD-06 metadata of the pay request
+///
+/// Includes the LUD-16 identifier of the address as it was requested, so wallets can show who
+/// is being paid, and the avatar of the user as a base64 png when they set one
+pub(crate) fn lnurl_metadata(
+    description: &str,
+    identifier: &str,
+    avatar: Option<&str>,
+) -> Result<String, LnurlError> {
+    let mut metadata = vec![["text/plain", description], ["text/identifier", identifier]];
+    if let Some(avatar) = avatar {
+        metadata.push(["image/png;base64", avatar]);
+    }
+
+    let metadata = serde_json::to_string(&metadata).map_err(|err| {
+        warn!("{err}");
+        LnurlError::internal("Could not create metadata")
+    })?;
+
+    if metadata.len() > METADATA_MAX_LEN {
+        return Err(LnurlError::bad_request(&format!(
+            "Metadata cannot be longer than {} bytes",
+            METADATA_MAX_LEN
+        )));
+    }
+
+    Ok(metadata)
+}
+
+/// Metadata of an address with the description and avatar of its user
+///
+/// Addresses without a signed up user get the service description
+fn user_metadata(
+    state: &LnurlState,
+    user: Option<&User>,
+    identifier: &str,
+) -> Result<String, LnurlError> {
+    let description = user
+        .and_then(|user| user.description.as_deref())
+        .unwrap_or(&state.description);
+    let avatar = user
+        .and_then(|user| user.avatar.as_deref())
+        .or(state.avatar.as_deref());
+
+    lnurl_metadata(description, identifier, avatar)
+}
+
+/// Check the metadata of a user's address fits, even when requested with the longest `+tag`
+fn check_metadata_len(
+    state: &LnurlState,
+    username: &str,
+    domain: &str,
+    description: Option<&str>,
+    avatar: Option<&str>,
+) -> Result<(), LnurlError> {
+    let identifier = format!("{}+{}@{}", username, "x".repeat(PLUS_TAG_MAX_LEN), domain);
+
+    lnurl_metadata(
+        description.unwrap_or(&state.description),
+        &identifier,
+        avatar.or(state.avatar.as_deref()),
+    )
+    .map(|_| ())
+}
+
+/// Base64 of the png at `path`, checked as users' avatars are
+pub(crate) fn read_avatar(path: &std::path::Path) -> anyhow::Result<String> {
+    let png = std::fs::read(path)
+        .map_err(|err| anyhow::anyhow!("Could not read avatar_path {:?}: {}", path, err))?;
+    let avatar = general_purpose::STANDARD.encode(png);
+    validate_profile(None, Some(&avatar))
+        .map_err(|err| anyhow::anyhow!("Invalid avatar_path {:?}: {}", path, err.reason))?;
+
+    Ok(avatar)
+}
+
+/// Check a description and avatar users set for their address
+fn validate_profile(description: Option<&str>, avatar: Option<&str>) -> Result<(), LnurlError> {
+    if let Some(description) = description {
+        if description.chars().count() > DESCRIPTION_MAX_LEN {
+            return Err(LnurlError::bad_request(&format!(
+                "Description cannot be longer than {} chars",
+                DESCRIPTION_MAX_LEN
+            )));
+        }
+    }
+
+    if let Some(avatar) = avatar {
+        let png = general_purpose::STANDARD
+            .decode(avatar)
+            .map_err(|_| LnurlError::bad_request("Avatar must be base64"))?;
+        if !png.starts_with(PNG_SIGNATURE) {
+            return Err(LnurlError::bad_request("Avatar must be a png"));
+        }
+        if png.len() > AVATAR_MAX_BYTES {
+            return Err(LnurlError::bad_request(&format!(
+                "Avatar cannot be larger than {} bytes",
+                AVATAR_MAX_BYTES
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Shortest webhook secret a user can set
+const WEBHOOK_SECRET_MIN_LEN: usize = 16;
+
+/// Longest webhook secret a user can set
+const WEBHOOK_SECRET_MAX_LEN: usize = 256;
+
+/// Check the webhook a user set, posts to it are signed so it needs a secret
+fn validate_webhook(url: Option<&Url>, secret: Option<&str>) -> Result<(), LnurlError> {
+    let url = match url {
+        Some(url) => url,
+        None => return Ok(()),
+    };
+    if url.scheme() != "https" {
+        return Err(LnurlError::bad_request("Webhook url must be https"));
+    }
+
+    let secret =
+        secret.ok_or_else(|| LnurlError::bad_request("A webhook_url needs a webhook_secret"))?;
+    let len = secret.chars().count();
+    if !(WEBHOOK_SECRET_MIN_LEN..=WEBHOOK_SECRET_MAX_LEN).contains(&len) {
+        return Err(LnurlError::bad_request(&format!(
+            "Webhook secret must be {} to {} chars",
+            WEBHOOK_SECRET_MIN_LEN, WEBHOOK_SECRET_MAX_LEN
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check a webhook url reaches a public host, so users cannot have the service post to its
+/// own network
+async fn check_webhook_host(url: Option<&Url>) -> Result<(), LnurlError> {
+    let url = match url {
+        Some(url) => url,
+        None => return Ok(()),
+    };
+
+    outbound::check_public_url(url).await.map_err(|err| {
+        warn!("Refused webhook url {}: {}", url, err);
+        LnurlError::bad_request("Webhook url must be a public host")
+    })
+}
+
+/// Longest `+tag` kept from a requested username
+const PLUS_TAG_MAX_LEN: usize = 32;
+
+/// Split a `+tag` off a requested username
+///
+/// The tag is sanitized to ascii letters, digits, `-`, `_` and `.` and cut to
+/// [`PLUS_TAG_MAX_LEN`] chars, it is dropped if nothing is left
+fn split_plus_tag(username: &str) -> (&str, Option<String>) {
+    match username.split_once('+') {
+        Some((base, tag)) if !base.is_empty() => {
+            let tag: String = tag
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                .take(PLUS_TAG_MAX_LEN)
+                .collect();
+
+            (base, Some(tag).filter(|tag| !tag.is_empty()))
         }
+        _ => (username, None),
+    }
+}
+
+/// User an address is paid to, and the address it was requested as when that is not the user
+///
+/// A `+tag` is split off the username before it is looked up, and the catch-all user is used
+/// when there is no user with the username
+async fn resolve_user(
+    state: &LnurlState,
+    domain: &str,
+    username: &str,
+) -> anyhow::Result<Option<(UserKind, Option<String>)>> {
+    let (base, tag) = split_plus_tag(username);
+
+    let (user, catch_all) = match state.db.get_user(domain, base).await? {
+        Some(user) => (user, false),
+        None => match &state.catch_all_user {
+            Some(catch_all_user) => match state.db.get_user(domain, catch_all_user).await? {
+                Some(user) => (user, true),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        },
+    };
+
+    let alias = match tag {
+        Some(tag) => Some(format!("{}+{}", base, tag)),
+        None => catch_all.then(|| base.to_string()),
+    };
+
+    Ok(Some((user, alias)))
+}
+
+/// Description of a proxied invoice, only its hash is included in the invoice
+///
+/// LUD-06 requires the description hash to be the hash of the metadata as served,
+/// NIP-57 requires the description hash of a zap invoice to be the hash of the zap request,
+/// LUD-18 requires payer data to be appended to the metadata as it was sent
+fn invoice_description(
+    zap_request: Option<&str>,
+    metadata: String,
+    payer_data: Option<&str>,
+) -> String {
+    match (zap_request, payer_data) {
+        (Some(zap_request), _) => zap_request.to_string(),
+        (None, Some(payer_data)) => metadata + payer_data,
+        (None, None) => metadata,
     }
 }
 
+/// Invoice from the lightning backend, given up on after the upstream timeout
+async fn get_invoice(
+    state: &LnurlState,
+    amount: Option<Amount>,
+    description: String,
+    description_hash_only: bool,
+    preimage: Option<[u8; 32]>,
+    expiry: Option<u64>,
+) -> Result<Bolt11Invoice, LnurlError> {
+    let ln_backend = state.ln_backend.as_ref().ok_or_else(|| {
+        error!("No lightning backend configured");
+        LnurlError::internal("Could not create invoice")
+    })?;
+
+    let invoice =
+        ln_backend.create_invoice(amount, description, description_hash_only, preimage, expiry);
+    tokio::time::timeout(state.upstream_timeout, invoice)
+        .await
+        .map_err(|_| {
+            error!(
+                "Lightning backend did not create an invoice within {:?}",
+                state.upstream_timeout
+            );
+            LnurlError::new(StatusCode::GATEWAY_TIMEOUT, "Timed out creating invoice")
+        })?
+        .map_err(|err| {
+            error!("Could not create invoice: {:?}", err);
+            LnurlError::internal("Could not create invoice")
+        })
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SignupParams {
     username: String,
@@ -331,7 +1743,151 @@ pub struct SignupParams {
     pubkey: Keys,
     proxy: Option<bool>,
     mint: Url,
+    /// Mints tried in order when `mint` cannot issue an invoice
+    #[serde(default)]
+    fallback_mints: Vec<Url>,
+    /// Mints picked by invoice amount ahead of `mint`
+    #[serde(default)]
+    mint_rules: Vec<MintRule>,
     relays: Option<HashSet<String>>,
+    min_sendable: Option<Amount>,
+    max_sendable: Option<Amount>,
+    /// Success action message shown to payers
+    success_message: Option<String>,
+    /// Description shown to payers instead of the service description
+    description: Option<String>,
+    /// Base64 png shown to payers
+    avatar: Option<String>,
+    /// Https url paid invoices are posted to
+    webhook_url: Option<Url>,
+    /// Key of the HMAC-SHA256 `X-Signature` of webhook posts
+    webhook_secret: Option<String>,
+    /// Lock minted tokens to `pubkey` rather than sending bearer tokens
+    lock_to_pubkey: Option<bool>,
+    /// Event signed by `pubkey` with the username as content
+    event: Option<Event>,
+    /// Hex schnorr signature by `pubkey` of the signup message, used when there is no event
+    sig: Option<String>,
+    /// Unix time the signup message was signed at
+    timestamp: Option<u64>,
+    /// Invite code, required while signups are disabled
+    invite: Option<String>,
+}
+
+/// Kinds accepted as user authorization
+const AUTH_KINDS: [u64; 2] = [1, 27235];
+
+/// Verify a request for `username` is authorized by `pubkey`
+///
+/// The event must be a text note or NIP-98 http auth event, signed by
+/// `pubkey`, created within `window` seconds of `now` with the username as
+/// its content
+fn verify_auth_event(
+    event: &Event,
+    pubkey: &XOnlyPublicKey,
+    username: &str,
+    window: u64,
+    now: u64,
+) -> Result<(), LnurlError> {
+    let unauthorized = |reason: &str| LnurlError::new(StatusCode::UNAUTHORIZED, reason);
+
+    if !AUTH_KINDS.contains(&event.kind.as_u64()) {
+        return Err(unauthorized("Invalid auth event kind"));
+    }
+
+    if event.pubkey.ne(pubkey) {
+        return Err(unauthorized("Auth event pubkey does not match"));
+    }
+
+    if event.content.ne(username) {
+        return Err(unauthorized("Auth event is not for this username"));
+    }
+
+    if now.abs_diff(event.created_at.as_u64()) > window {
+        return Err(unauthorized("Auth event expired"));
+    }
+
+    event
+        .verify()
+        .map_err(|_| unauthorized("Invalid auth event signature"))
+}
+
+/// Verify a request for `username` is authorized by `pubkey`, then mark its event used
+///
+/// The event is read from a NIP-98 `Authorization: Nostr <base64 event>` header, or else
+/// is `params_event` sent with the request. Besides [`verify_auth_event`], its `u` and
+/// `method` tags must be for the request, as must its `payload` tag when the event is in the
+/// header of a request with a body. An event can only be used once, so one seen in a log
+/// cannot be replayed.
+fn authorize_request(
+    state: &LnurlState,
+    headers: &HeaderMap,
+    params_event: Option<Event>,
+    request: AuthRequest,
+    pubkey: &XOnlyPublicKey,
+    username: &str,
+    missing: &str,
+) -> Result<(), LnurlError> {
+    let unauthorized = |reason: &str| LnurlError::new(StatusCode::UNAUTHORIZED, reason);
+
+    let (event, request) = match nip98::header_event(headers).map_err(unauthorized)? {
+        Some(event) => (event, request),
+        // An event in the body cannot hash the body it is part of
+        None => (
+            params_event.ok_or_else(|| unauthorized(missing))?,
+            AuthRequest {
+                body: None,
+                ..request
+            },
+        ),
+    };
+
+    let now = unix_time();
+    verify_auth_event(&event, pubkey, username, state.auth_window, now)?;
+    nip98::check_tags(&event, &request).map_err(unauthorized)?;
+
+    // Events are accepted up to `auth_window` after they were created
+    let expires = event.created_at.as_u64().saturating_add(state.auth_window);
+    if !state.used_auth_events.insert(event.id, expires, now) {
+        return Err(unauthorized("Auth event was already used"));
+    }
+
+    Ok(())
+}
+
+/// Params of a json body, read as bytes so the `payload` tag of an auth event can hash it
+fn json_params<T: DeserializeOwned>(body: &[u8]) -> Result<T, LnurlError> {
+    serde_json::from_slice(body)
+        .map_err(|err| LnurlError::bad_request(&format!("Invalid json body: {}", err)))
+}
+
+/// Message signed to sign up `username` without a nostr event
+fn signup_message(username: &str, timestamp: u64) -> String {
+    format!("signup:{}:{}", username, timestamp)
+}
+
+/// Verify a schnorr signature of the sha256 of the signup message by `pubkey`
+fn verify_signup_signature(
+    pubkey: &XOnlyPublicKey,
+    username: &str,
+    timestamp: u64,
+    sig: &str,
+    window: u64,
+    now: u64,
+) -> Result<(), LnurlError> {
+    let unauthorized = |reason: &str| LnurlError::new(StatusCode::UNAUTHORIZED, reason);
+
+    if now.abs_diff(timestamp) > window {
+        return Err(unauthorized("Signup signature expired"));
+    }
+
+    let sig = Signature::from_str(sig).map_err(|_| unauthorized("Invalid signup signature"))?;
+    let message = Message::from_slice(&Sha256::digest(signup_message(username, timestamp)))
+        .map_err(|_| unauthorized("Invalid signup signature"))?;
+
+    Secp256k1::verification_only()
+        .verify_schnorr(&sig, &message, pubkey)
+        .map_err(|_| unauthorized("Invalid signup signature"))
 }
 
 pub mod nostr_keys {
@@ -355,140 +1911,3935 @@ pub mod nostr_keys {
     }
 }
 
-pub(crate) async fn post_sign_up(
-    State(state): State<LnurlState>,
-    Json(params): Json<SignupParams>,
-) -> Result<Json<String>, StatusCode> {
-    match state.db.get_user(&params.username).await.map_err(|err| {
-        error!("Could not get user: {:?}", err);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })? {
-        Some(UserKind::User(_user)) => Err(StatusCode::CONFLICT),
-        Some(UserKind::Pending(_user)) => Err(StatusCode::CONFLICT),
-        Some(UserKind::Blocked) => Err(StatusCode::NOT_ACCEPTABLE),
-        Some(UserKind::Reserved(amount)) => {
-            let client = state.cln_client.clone();
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignupResponse {
+    /// Lightning address of the account
+    address: String,
+    /// LUD-16 well known url of the address
+    lnurlp: Url,
+    /// Mint tokens are minted from
+    mint: Url,
+    /// Payers pay invoices created by the mint
+    proxy: bool,
+    /// Pubkey the service sends tokens and zap receipts from
+    nostr_pubkey: Option<String>,
+    /// Invoice to pay to activate the username when it has a cost
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pr: Option<String>,
+    /// Unix time the username is freed if the invoice is not paid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
 
-            let invoice =
-                get_invoice(client, amount, format!("Payment for {}", params.username)).await?;
+impl SignupResponse {
+    /// Response for a signup, `pending` when the username has to be paid for
+    fn new(
+        state: &LnurlState,
+        user: &User,
+        pending: Option<&PendingUser>,
+    ) -> Result<Self, LnurlError> {
+        let lnurlp = well_known_url(&state.api_base_address, &user.domain, &user.username)
+            .map_err(|_| LnurlError::internal("Could not create lnurl"))?;
 
-            let user = User {
-                username: params.username.clone(),
-                mint: params.mint,
-                pubkey: params.pubkey.public_key().to_string(),
-                relays: params.relays.unwrap_or_default(),
-                proxy: params.proxy.unwrap_or_default(),
-            };
+        Ok(Self {
+            address: lightning_address(&user.username, &user.domain, &lnurlp),
+            lnurlp,
+            mint: user.mint.clone(),
+            proxy: user.proxy,
+            nostr_pubkey: state.nostr_pubkey.clone(),
+            pr: pending.map(|pending| pending.pr.to_string()),
+            expires_at: pending.map(|pending| pending.expire),
+        })
+    }
+}
 
-            let pending_user = PendingUser {
-                user,
-                pr: invoice.clone(),
-                last_checked: unix_time(),
-                expire: unix_time() + 900,
-            };
+/// Signup params sent as query parameters to the deprecated GET route
+///
+/// Relays are passed as a comma separated list
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetSignupParams {
+    username: String,
+    pubkey: String,
+    proxy: Option<bool>,
+    mint: Url,
+    relays: Option<String>,
+    min_sendable: Option<Amount>,
+    max_sendable: Option<Amount>,
+    success_message: Option<String>,
+    description: Option<String>,
+    /// Json of signed auth event
+    event: Option<String>,
+    sig: Option<String>,
+    timestamp: Option<u64>,
+    invite: Option<String>,
+}
 
-            let mut pending_users = state.pending_users.lock().await;
-            pending_users.insert(invoice.payment_hash().to_string(), pending_user.clone());
+impl TryFrom<GetSignupParams> for SignupParams {
+    type Error = LnurlError;
 
-            let pending_user = UserKind::Pending(pending_user);
-            state
-                .db
-                .add_user(&params.username, &pending_user)
-                .await
-                .unwrap();
+    fn try_from(params: GetSignupParams) -> Result<Self, Self::Error> {
+        let pubkey = Keys::from_pk_str(&params.pubkey)
+            .map_err(|_| LnurlError::bad_request("Invalid pubkey"))?;
 
-            Ok(Json(invoice.to_string()))
-        }
-        None => {
-            let relays = params.relays.unwrap_or_default();
-            let proxy = params.proxy.unwrap_or_default();
+        let event = params
+            .event
+            .map(|event| serde_json::from_str(&event))
+            .transpose()
+            .map_err(|_| LnurlError::bad_request("Invalid auth event"))?;
 
-            let user = User {
-                username: params.username.clone(),
-                mint: params.mint,
-                pubkey: params.pubkey.public_key().to_string(),
-                relays,
-                proxy,
-            };
+        let relays = params.relays.map(|relays| {
+            relays
+                .split(',')
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty())
+                .collect()
+        });
 
-            let amount = if params.username.len().le(&2) {
-                state.two_char_cost
-            } else if params.username.len().le(&3) {
-                state.three_char_cost
-            } else if params.username.len().le(&4) {
-                state.four_char_cost
-            } else {
-                state.other_char_cost
-            };
+        Ok(Self {
+            username: params.username,
+            pubkey,
+            proxy: params.proxy,
+            mint: params.mint,
+            fallback_mints: vec![],
+            mint_rules: vec![],
+            relays,
+            min_sendable: params.min_sendable,
+            max_sendable: params.max_sendable,
+            success_message: params.success_message,
+            description: params.description,
+            // Too large for a query string
+            avatar: None,
+            // Secrets do not belong in a query string
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: None,
+            event,
+            sig: params.sig,
+            timestamp: params.timestamp,
+            invite: params.invite,
+        })
+    }
+}
 
-            let user = if amount.gt(&Amount::ZERO) {
-                let client = state.cln_client.clone();
+/// Check a users min sendable does not exceed their max
+///
+/// Limits that are not overridden fall back to the service defaults
+fn validate_sendable(
+    min_sendable: Option<Amount>,
+    max_sendable: Option<Amount>,
+    default_min: Amount,
+    default_max: Amount,
+) -> Result<(), LnurlError> {
+    let min = min_sendable.unwrap_or(default_min);
+    let max = max_sendable.unwrap_or(default_max);
 
-                let pr = get_invoice(client, amount, params.username.to_string()).await?;
-                let pending_user = PendingUser {
-                    user: user.clone(),
-                    pr: pr.clone(),
-                    last_checked: unix_time(),
-                    expire: unix_time() + 900,
-                };
+    if min.gt(&max) {
+        return Err(LnurlError::bad_request(
+            "Min sendable cannot be more than max sendable",
+        ));
+    }
 
-                let mut pending_users = state.pending_users.lock().await;
-                pending_users.insert(pr.payment_hash().to_string(), pending_user.clone());
+    Ok(())
+}
 
-                UserKind::Pending(pending_user)
+/// Check a requested msat amount is one an invoice can be created for
+fn validate_invoice_amount(
+    amount_msat: u64,
+    min_sendable: Amount,
+    max_sendable: Amount,
+    whole_sats: bool,
+) -> Result<Amount, LnurlError> {
+    let amount = Amount::from_msat(amount_msat);
+
+    if amount_msat == 0 || amount.lt(&min_sendable) || amount.gt(&max_sendable) {
+        debug!("Amount {:?} outside of sendable range", amount);
+        return Err(LnurlError::bad_request("amount out of range"));
+    }
+
+    if whole_sats && amount_msat % 1000 != 0 {
+        return Err(LnurlError::bad_request(
+            "Amount must be a whole number of sats",
+        ));
+    }
+
+    Ok(amount)
+}
+
+/// Check an invoice can be created without an amount
+///
+/// Only proxied invoices can be amountless as mints need an amount to issue an invoice, and
+/// the backend has to report the amount paid
+fn validate_amountless(
+    allow_amountless: bool,
+    proxied: bool,
+    backend_supports_amountless: bool,
+) -> Result<(), LnurlError> {
+    if !allow_amountless {
+        return Err(LnurlError::bad_request("amount required"));
+    }
+    if !proxied {
+        return Err(LnurlError::bad_request(
+            "Amountless invoices are only supported for proxied invoices",
+        ));
+    }
+    if !backend_supports_amountless {
+        return Err(LnurlError::bad_request(
+            "Lightning backend does not support amountless invoices",
+        ));
+    }
+
+    Ok(())
+}
+
+impl SignupParams {
+    /// Check the signup is signed by the pubkey being registered
+    fn verify_owner(&self, window: u64, now: u64) -> Result<(), LnurlError> {
+        let pubkey = self.pubkey.public_key();
+
+        match (&self.event, &self.sig, self.timestamp) {
+            (Some(event), _, _) => verify_auth_event(event, &pubkey, &self.username, window, now),
+            (None, Some(sig), Some(timestamp)) => {
+                verify_signup_signature(&pubkey, &self.username, timestamp, sig, window, now)
+            }
+            _ => Err(LnurlError::new(
+                StatusCode::UNAUTHORIZED,
+                "Signup must be signed by the pubkey",
+            )),
+        }
+    }
+
+    fn validate(&self) -> Result<(), LnurlError> {
+        if self.username.trim().is_empty() {
+            return Err(LnurlError::bad_request("Username cannot be empty"));
+        }
+
+        // A `+` starts the tag of a plus address
+        if self.username.contains('+') {
+            return Err(LnurlError::bad_request("Username cannot contain +"));
+        }
+
+        validate_fallback_mints(&self.fallback_mints)?;
+        validate_mint_rules(&self.mint_rules)?;
+
+        if let Some(message) = &self.success_message {
+            if message.chars().count() > SUCCESS_MESSAGE_MAX_LEN {
+                return Err(LnurlError::bad_request(&format!(
+                    "Success message cannot be longer than {} chars",
+                    SUCCESS_MESSAGE_MAX_LEN
+                )));
+            }
+        }
+
+        validate_profile(self.description.as_deref(), self.avatar.as_deref())?;
+        validate_webhook(self.webhook_url.as_ref(), self.webhook_secret.as_deref())
+    }
+}
+
+/// Most fallback mints a user can register
+const MAX_FALLBACK_MINTS: usize = 3;
+
+fn validate_fallback_mints(fallback_mints: &[Url]) -> Result<(), LnurlError> {
+    if fallback_mints.len() > MAX_FALLBACK_MINTS {
+        return Err(LnurlError::bad_request(&format!(
+            "Cannot have more than {} fallback mints",
+            MAX_FALLBACK_MINTS
+        )));
+    }
+
+    Ok(())
+}
+
+/// Most mint rules a user can register
+const MAX_MINT_RULES: usize = 5;
+
+fn validate_mint_rules(mint_rules: &[MintRule]) -> Result<(), LnurlError> {
+    if mint_rules.len() > MAX_MINT_RULES {
+        return Err(LnurlError::bad_request(&format!(
+            "Cannot have more than {} mint rules",
+            MAX_MINT_RULES
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check a mint users register is allowed and working
+async fn check_mint(state: &LnurlState, mint: &Url) -> Result<(), LnurlError> {
+    if !matches!(mint.scheme(), "http" | "https") {
+        return Err(LnurlError::bad_request("Mint url must be http or https"));
+    }
+
+    if !mint_allowed(&state.allowed_mints, mint) {
+        let mut allowed_mints: Vec<&String> = state.allowed_mints.iter().flatten().collect();
+        allowed_mints.sort();
+
+        return Err(LnurlError::bad_request(&format!(
+            "Mint {} is not allowed, allowed mints are: {}",
+            mint,
+            allowed_mints
+                .into_iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+
+    // Mints the operator listed may be on their own network
+    let allow_private = state.allowed_mints.is_some();
+    state
+        .cashu
+        .check_mint(mint, allow_private)
+        .await
+        .map_err(|err| {
+            warn!("{}", err);
+            LnurlError::bad_request(&format!(
+                "Mint {} is unreachable or not a compatible cashu mint",
+                mint
+            ))
+        })
+}
+
+pub(crate) async fn post_sign_up(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    params: Result<Json<SignupParams>, JsonRejection>,
+) -> Result<Json<SignupResponse>, LnurlError> {
+    let Json(params) = params.map_err(|err| LnurlError::bad_request(&err.body_text()))?;
+    let ip = client_ip(
+        connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        &headers,
+        &state.trusted_proxies,
+    );
+
+    sign_up(state, &host, ip, params).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateLimitsParams {
+    min_sendable: Option<Amount>,
+    max_sendable: Option<Amount>,
+    /// Event signed by the users pubkey with the username as content, when it is not sent in
+    /// the `Authorization` header
+    event: Option<Event>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateUserParams {
+    mint: Option<Url>,
+    /// Replaces the fallback mints, empty to remove them
+    fallback_mints: Option<Vec<Url>>,
+    /// Replaces the mint rules, empty to remove them
+    mint_rules: Option<Vec<MintRule>>,
+    relays: Option<HashSet<String>>,
+    proxy: Option<bool>,
+    /// Hex or npub pubkey the address is moved to
+    pubkey: Option<String>,
+    /// Description shown to payers, empty to use the service description
+    description: Option<String>,
+    /// Base64 png shown to payers, empty to remove it
+    avatar: Option<String>,
+    /// Https url paid invoices are posted to, empty to remove the webhook
+    webhook_url: Option<String>,
+    /// Key of the HMAC-SHA256 `X-Signature` of webhook posts
+    webhook_secret: Option<String>,
+    /// Lock minted tokens to the pubkey rather than sending bearer tokens
+    lock_to_pubkey: Option<bool>,
+    /// Event signed by the currently registered pubkey with the username as content, when it
+    /// is not sent in the `Authorization` header
+    event: Option<Event>,
+}
+
+impl UpdateUserParams {
+    /// Apply the update to `user`, returning a description of each change
+    fn apply(self, user: &mut User) -> Result<Vec<String>, LnurlError> {
+        let mut changes = vec![];
+
+        if let Some(mint) = self.mint {
+            if mint != user.mint {
+                changes.push(format!("Mint changed to {}", mint));
+                user.mint = mint;
+            }
+        }
+
+        if let Some(fallback_mints) = self.fallback_mints {
+            if fallback_mints != user.fallback_mints {
+                changes.push(match fallback_mints.is_empty() {
+                    true => "Fallback mints removed".to_string(),
+                    false => format!(
+                        "Fallback mints changed to {}",
+                        fallback_mints
+                            .iter()
+                            .map(Url::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                });
+                user.fallback_mints = fallback_mints;
+            }
+        }
+
+        if let Some(mint_rules) = self.mint_rules {
+            if mint_rules != user.mint_rules {
+                changes.push(match mint_rules.is_empty() {
+                    true => "Mint rules removed".to_string(),
+                    false => format!(
+                        "Mint rules changed to {}",
+                        mint_rules
+                            .iter()
+                            .map(|rule| format!(
+                                "{} up to {} sat",
+                                rule.mint,
+                                rule.max_amount.to_sat()
+                            ))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                });
+                user.mint_rules = mint_rules;
+            }
+        }
+
+        if let Some(relays) = self.relays {
+            if relays != user.relays {
+                let mut sorted: Vec<&String> = relays.iter().collect();
+                sorted.sort();
+                changes.push(format!(
+                    "Relays changed to {}",
+                    sorted
+                        .into_iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+                user.relays = relays;
+            }
+        }
+
+        if let Some(proxy) = self.proxy {
+            if proxy != user.proxy {
+                changes.push(match proxy {
+                    true => "Proxy enabled".to_string(),
+                    false => "Proxy disabled".to_string(),
+                });
+                user.proxy = proxy;
+            }
+        }
+
+        if let Some(lock_to_pubkey) = self.lock_to_pubkey {
+            if lock_to_pubkey != user.lock_to_pubkey {
+                changes.push(match lock_to_pubkey {
+                    true => "Tokens locked to your pubkey".to_string(),
+                    false => "Tokens no longer locked to your pubkey".to_string(),
+                });
+                user.lock_to_pubkey = lock_to_pubkey;
+            }
+        }
+
+        if let Some(pubkey) = self.pubkey {
+            let pubkey = Keys::from_pk_str(&pubkey)
+                .map_err(|_| LnurlError::bad_request("Invalid pubkey"))?
+                .public_key()
+                .to_string();
+            if pubkey != user.pubkey {
+                changes.push(format!("Pubkey changed to {}", pubkey));
+                user.pubkey = pubkey;
+            }
+        }
+
+        let description = self
+            .description
+            .map(|description| Some(description).filter(|description| !description.is_empty()));
+        let avatar = self
+            .avatar
+            .map(|avatar| Some(avatar).filter(|avatar| !avatar.is_empty()));
+        validate_profile(
+            description.as_ref().and_then(Option::as_deref),
+            avatar.as_ref().and_then(Option::as_deref),
+        )?;
+
+        if let Some(description) = description {
+            if description != user.description {
+                changes.push(match &description {
+                    Some(description) => format!("Description changed to {}", description),
+                    None => "Description removed".to_string(),
+                });
+                user.description = description;
+            }
+        }
+
+        if let Some(avatar) = avatar {
+            if avatar != user.avatar {
+                changes.push(match &avatar {
+                    Some(_) => "Avatar changed".to_string(),
+                    None => "Avatar removed".to_string(),
+                });
+                user.avatar = avatar;
+            }
+        }
+
+        let webhook_url = self
+            .webhook_url
+            .map(|url| match url.is_empty() {
+                true => Ok(None),
+                false => Url::parse(&url).map(Some),
+            })
+            .transpose()
+            .map_err(|_| LnurlError::bad_request("Invalid webhook url"))?;
+
+        if let Some(webhook_url) = webhook_url {
+            if webhook_url != user.webhook_url {
+                changes.push(match &webhook_url {
+                    Some(url) => format!("Webhook changed to {}", url),
+                    None => "Webhook removed".to_string(),
+                });
+                if webhook_url.is_none() {
+                    user.webhook_secret = None;
+                }
+                user.webhook_url = webhook_url;
+            }
+        }
+
+        if let Some(webhook_secret) = self.webhook_secret {
+            if user.webhook_url.is_some() && Some(&webhook_secret) != user.webhook_secret.as_ref() {
+                // The secret itself is left out of the summary DM
+                changes.push("Webhook secret changed".to_string());
+                user.webhook_secret = Some(webhook_secret);
+            }
+        }
+        validate_webhook(user.webhook_url.as_ref(), user.webhook_secret.as_deref())?;
+
+        Ok(changes)
+    }
+}
+
+/// Update the mint, relays, proxy, pubkey, description, avatar or webhook of a user
+///
+/// Authorized by the currently registered pubkey, which is sent a summary of the changes
+pub(crate) async fn put_user(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, LnurlError> {
+    let params: UpdateUserParams = json_params(&body)?;
+
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    let mut user = match state.db.get_user(&domain, &username).await {
+        Ok(Some(UserKind::User(user))) => user,
+        Ok(_) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not get user: {:?}", err);
+            return Err(LnurlError::internal("Could not get user"));
+        }
+    };
+
+    let pubkey = XOnlyPublicKey::from_str(&user.pubkey).map_err(|err| {
+        error!("Invalid stored pubkey for {}: {:?}", username, err);
+        LnurlError::internal("Invalid user pubkey")
+    })?;
+
+    let request = AuthRequest {
+        method: &method,
+        path: uri.path(),
+        body: Some(&body),
+    };
+    authorize_request(
+        &state,
+        &headers,
+        params.event.clone(),
+        request,
+        &pubkey,
+        &username,
+        "Update must be signed by the pubkey",
+    )?;
+
+    if let Some(mint) = &params.mint {
+        check_mint(&state, mint).await?;
+    }
+    if let Some(fallback_mints) = &params.fallback_mints {
+        validate_fallback_mints(fallback_mints)?;
+        for mint in fallback_mints {
+            check_mint(&state, mint).await?;
+        }
+    }
+    if let Some(mint_rules) = &params.mint_rules {
+        validate_mint_rules(mint_rules)?;
+        for rule in mint_rules {
+            check_mint(&state, &rule.mint).await?;
+        }
+    }
+
+    let previous_pubkey = user.pubkey.clone();
+    let previous_webhook_url = user.webhook_url.clone();
+    let changes = params.apply(&mut user)?;
+    if changes.is_empty() {
+        return Ok(StatusCode::OK);
+    }
+    if user.webhook_url != previous_webhook_url {
+        check_webhook_host(user.webhook_url.as_ref()).await?;
+    }
+    check_metadata_len(
+        &state,
+        &username,
+        &domain,
+        user.description.as_deref(),
+        user.avatar.as_deref(),
+    )?;
+
+    match state.db.update_user(&domain, &username, &user).await {
+        Ok(true) => (),
+        Ok(false) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not update user: {:?}", err);
+            return Err(LnurlError::internal("Could not update user"));
+        }
+    }
+    invalidate_lnurl(&state, &domain, &username);
+
+    let nostr = state.nostr.clone();
+    tokio::spawn(
+        async move {
+            if let Err(err) = nostr
+                .send_user_updated_message(&previous_pubkey, &user, &changes)
+                .await
+            {
+                warn!("Could not send update message to {}: {:?}", username, err);
+            }
+        }
+        .in_current_span(),
+    );
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeleteUserParams {
+    /// Event signed by the users pubkey with the username as content
+    ///
+    /// Not needed when the admin token is sent
+    event: Option<Event>,
+    /// DM the user that their account was closed, defaults to true
+    goodbye: Option<bool>,
+}
+
+/// Close a users account
+///
+/// Authorized by an event from the registered pubkey or the admin token
+pub(crate) async fn delete_user_account(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, LnurlError> {
+    let admin = is_admin(&state.admin_token, &headers);
+    let params = match json_params::<DeleteUserParams>(&body) {
+        Ok(params) => params,
+        // Operators, and users signing in the `Authorization` header, may send no body
+        Err(_) if admin || body.is_empty() => DeleteUserParams::default(),
+        Err(err) => return Err(err),
+    };
+
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    let user = match state.db.get_user(&domain, &username).await {
+        Ok(Some(UserKind::User(user))) => user,
+        Ok(_) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not get user: {:?}", err);
+            return Err(LnurlError::internal("Could not get user"));
+        }
+    };
+
+    if !admin {
+        let pubkey = XOnlyPublicKey::from_str(&user.pubkey).map_err(|err| {
+            error!("Invalid stored pubkey for {}: {:?}", username, err);
+            LnurlError::internal("Invalid user pubkey")
+        })?;
+
+        let request = AuthRequest {
+            method: &method,
+            path: uri.path(),
+            body: Some(&body),
+        };
+        authorize_request(
+            &state,
+            &headers,
+            params.event.clone(),
+            request,
+            &pubkey,
+            &username,
+            "Deletion must be signed by the pubkey",
+        )?;
+    }
+
+    // Pending invoices are kept, paid ones are minted and dead lettered for the operator
+    let pending = match state.db.remove_user(&domain, &username).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not remove user: {:?}", err);
+            return Err(LnurlError::internal("Could not remove user"));
+        }
+    };
+    invalidate_lnurl(&state, &domain, &username);
+
+    debug!(
+        "Removed {} with {} pending invoices",
+        username,
+        pending.len()
+    );
+
+    if params.goodbye.unwrap_or(true) {
+        let nostr = state.nostr.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = nostr.send_goodbye_message(&user, pending.len()).await {
+                    warn!("Could not send goodbye message to {}: {:?}", username, err);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Users returned when no limit is requested
+const ADMIN_USERS_DEFAULT_LIMIT: usize = 100;
+/// Most users returned in one page
+const ADMIN_USERS_MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AdminUsersParams {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// User as listed to operators
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminUser {
+    username: String,
+    domain: String,
+    pubkey: String,
+    mint: Url,
+    proxy: bool,
+    created_at: Option<u64>,
+}
+
+impl From<User> for AdminUser {
+    fn from(user: User) -> Self {
+        Self {
+            username: user.username,
+            domain: user.domain,
+            pubkey: user.pubkey,
+            mint: user.mint,
+            proxy: user.proxy,
+            created_at: user.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminUsersResponse {
+    /// Number of active users across all pages
+    total: usize,
+    offset: usize,
+    limit: usize,
+    users: Vec<AdminUser>,
+}
+
+/// Page of active users, authorized by the admin token
+pub(crate) async fn get_admin_users(
+    State(state): State<LnurlState>,
+    params: Result<Query<AdminUsersParams>, QueryRejection>,
+) -> Result<Json<AdminUsersResponse>, LnurlError> {
+    let Query(params) = params.map_err(|err| LnurlError::bad_request(&err.body_text()))?;
+    let offset = params.offset.unwrap_or_default();
+    let limit = params
+        .limit
+        .unwrap_or(ADMIN_USERS_DEFAULT_LIMIT)
+        .min(ADMIN_USERS_MAX_LIMIT);
+
+    let (users, total) = state.db.get_users(offset, limit).await.map_err(|err| {
+        error!("Could not get users: {:?}", err);
+        LnurlError::internal("Could not get users")
+    })?;
+
+    Ok(Json(AdminUsersResponse {
+        total,
+        offset,
+        limit,
+        users: users.into_iter().map(AdminUser::from).collect(),
+    }))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AdminPendingInvoicesParams {
+    username: Option<String>,
+    /// Only invoices created at least this many seconds ago
+    older_than: Option<u64>,
+}
+
+/// Pending invoice as listed to operators
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminPendingInvoice {
+    hash: String,
+    /// Id logged with every stage of the invoice
+    request_id: Option<String>,
+    username: String,
+    domain: String,
+    mint: Url,
+    amount_msat: u64,
+    proxied: bool,
+    failed: bool,
+    created_at: u64,
+    last_checked: Option<u64>,
+}
+
+impl From<PendingInvoice> for AdminPendingInvoice {
+    fn from(invoice: PendingInvoice) -> Self {
+        Self {
+            hash: invoice.hash,
+            request_id: invoice.request_id,
+            username: invoice.username,
+            domain: invoice.domain,
+            mint: invoice.mint,
+            amount_msat: invoice.amount.to_msat(),
+            proxied: invoice.proxied,
+            failed: invoice.failed,
+            created_at: invoice.time,
+            last_checked: invoice.last_checked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminPendingInvoicesResponse {
+    /// Oldest first
+    invoices: Vec<AdminPendingInvoice>,
+}
+
+/// Pending invoices matching the query, authorized by the admin token
+pub(crate) async fn get_admin_pending_invoices(
+    State(state): State<LnurlState>,
+    params: Result<Query<AdminPendingInvoicesParams>, QueryRejection>,
+) -> Result<Json<AdminPendingInvoicesResponse>, LnurlError> {
+    let Query(params) = params.map_err(|err| LnurlError::bad_request(&err.body_text()))?;
+    let filter = PendingInvoiceFilter {
+        username: params.username,
+        created_before: params
+            .older_than
+            .map(|older_than| unix_time().saturating_sub(older_than)),
+    };
+
+    let invoices = state
+        .db
+        .find_pending_invoices(&filter)
+        .await
+        .map_err(|err| {
+            error!("Could not get pending invoices: {:?}", err);
+            LnurlError::internal("Could not get pending invoices")
+        })?;
+
+    Ok(Json(AdminPendingInvoicesResponse {
+        invoices: invoices
+            .into_iter()
+            .map(AdminPendingInvoice::from)
+            .collect(),
+    }))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AdminReportParams {
+    /// Unix time of the first payment included
+    from: Option<u64>,
+    /// Unix time payments are included before
+    to: Option<u64>,
+    username: Option<String>,
+}
+
+/// Whether a request asks for csv rather than json
+fn accepts_csv(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.contains("text/csv"))
+}
+
+/// Totals of paid invoices by day and by user, authorized by the admin token
+///
+/// Json unless csv is asked for with `Accept: text/csv`
+pub(crate) async fn get_admin_report(
+    State(state): State<LnurlState>,
+    headers: HeaderMap,
+    params: Result<Query<AdminReportParams>, QueryRejection>,
+) -> Result<Response, LnurlError> {
+    let Query(params) = params.map_err(|err| LnurlError::bad_request(&err.body_text()))?;
+    let filter = PaymentRecordFilter {
+        username: params.username,
+        from: params.from,
+        to: params.to,
+    };
+
+    let records = state
+        .db
+        .find_payment_records(&filter)
+        .await
+        .map_err(|err| {
+            error!("Could not get payment records: {:?}", err);
+            LnurlError::internal("Could not get payments")
+        })?;
+    let report = Report::new(&records);
+
+    match accepts_csv(&headers) {
+        true => Ok(([(header::CONTENT_TYPE, "text/csv")], report.to_csv()).into_response()),
+        false => Ok(Json(report).into_response()),
+    }
+}
+
+/// Payment records read from the database for each page of a streamed response
+const PAYMENT_RECORDS_PAGE: usize = 500;
+
+/// Pages of payment records, each read by `read_page` from after the last record of the page
+/// before, until a page is not full
+///
+/// An error ends the stream, the status is sent by then so the body is cut short
+fn payment_record_pages<F, Fut>(
+    read_page: F,
+) -> impl Stream<Item = anyhow::Result<Vec<PaymentRecord>>>
+where
+    F: Fn(Option<(u64, String)>) -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<PaymentRecord>>>,
+{
+    // The paid time and hash of the last record read, `None` once every record is read
+    stream::try_unfold(Some(None), move |after| {
+        let page = after.map(&read_page);
+        async move {
+            let records = match page {
+                Some(page) => page.await?,
+                None => return Ok(None),
+            };
+            let next = match records.last() {
+                Some(last) if records.len() == PAYMENT_RECORDS_PAGE => {
+                    Some(Some((last.paid_at, last.hash.clone())))
+                }
+                _ => None,
+            };
+
+            Ok(Some((records, next)))
+        }
+    })
+    .inspect_err(|err| error!("Could not read payment records: {:?}", err))
+}
+
+/// Every paid invoice as a csv row, authorized by the admin token
+///
+/// Records are read and sent a page at a time so the history is never held in memory
+pub(crate) async fn get_admin_payments_csv(
+    State(state): State<LnurlState>,
+    params: Result<Query<AdminReportParams>, QueryRejection>,
+) -> Result<Response, LnurlError> {
+    let Query(params) = params.map_err(|err| LnurlError::bad_request(&err.body_text()))?;
+    let filter = PaymentRecordFilter {
+        username: params.username,
+        from: params.from,
+        to: params.to,
+    };
+
+    let db = state.db.clone();
+    let rows = payment_record_pages(move |after| {
+        let db = db.clone();
+        let filter = filter.clone();
+        async move {
+            db.find_payment_records_page(&filter, after, PAYMENT_RECORDS_PAGE)
+                .await
+        }
+    })
+    .map_ok(|records| records.iter().map(payment_csv_row).collect::<String>());
+    let csv = stream::once(async { Ok(format!("{}\n", PAYMENTS_CSV_HEADER)) }).chain(rows);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"payments.csv\"",
+            ),
+        ],
+        StreamBody::new(csv),
+    )
+        .into_response())
+}
+
+/// Progress of an invoice, derived from the pending and payer invoices stored for its hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    /// Not paid yet
+    WaitingForPayment,
+    /// Paid, the token has not been minted and sent yet
+    WaitingForMint,
+    /// No longer checked, the payment failed or never happened
+    Failed,
+    /// Paid and no longer pending. Proxied invoices are minted from a separate mint
+    /// invoice, others have had their token sent.
+    Settled,
+    /// Removed before it was paid, with the user's account or for leaving nothing to mint
+    Cancelled,
+}
+
+impl InvoiceStatus {
+    /// Status of an invoice, `None` if it was never seen
+    fn new(pending: Option<&PendingInvoice>, payer: Option<&PayerInvoice>) -> Option<Self> {
+        let status = match (pending, payer) {
+            (Some(pending), _) if pending.failed => Self::Failed,
+            (Some(_), Some(payer)) if payer.settled => Self::WaitingForMint,
+            // Mint invoices of proxied payments have no payer and are paid by the service
+            (Some(pending), None) if pending.proxied => Self::WaitingForMint,
+            (Some(_), _) => Self::WaitingForPayment,
+            (None, Some(payer)) if payer.settled => Self::Settled,
+            (None, Some(_)) => Self::Cancelled,
+            (None, None) => return None,
+        };
+
+        Some(status)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminInvoiceResponse {
+    hash: String,
+    status: InvoiceStatus,
+    /// Present until the invoice is minted, failed invoices are kept
+    pending_invoice: Option<PendingInvoice>,
+    /// Invoice handed to the payer, absent for mint invoices of proxied payments
+    payer_invoice: Option<PayerInvoice>,
+    /// Posts of the paid invoice to the user's webhook
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<WebhookDelivery>,
+}
+
+/// State of a single invoice by payment hash, authorized by the admin token
+pub(crate) async fn get_admin_invoice(
+    State(state): State<LnurlState>,
+    Path(hash): Path<String>,
+) -> Result<Json<AdminInvoiceResponse>, LnurlError> {
+    let pending_invoice = state.db.get_pending_invoice(&hash).await.map_err(|err| {
+        error!("Could not get pending invoice: {:?}", err);
+        LnurlError::internal("Could not get invoice")
+    })?;
+    let payer_invoice = state.db.get_payer_invoice(&hash).await.map_err(|err| {
+        error!("Could not get payer invoice: {:?}", err);
+        LnurlError::internal("Could not get invoice")
+    })?;
+
+    let status = InvoiceStatus::new(pending_invoice.as_ref(), payer_invoice.as_ref())
+        .ok_or_else(|| LnurlError::not_found("Invoice never seen"))?;
+    let webhook = state.db.get_webhook_delivery(&hash).await.map_err(|err| {
+        error!("Could not get webhook delivery: {:?}", err);
+        LnurlError::internal("Could not get invoice")
+    })?;
+
+    Ok(Json(AdminInvoiceResponse {
+        hash,
+        status,
+        pending_invoice,
+        payer_invoice,
+        webhook,
+    }))
+}
+
+/// Remove an unpaid pending invoice, authorized by the admin token
+///
+/// Invoices created by the lightning backend are also cancelled there so they can no
+/// longer be paid
+pub(crate) async fn delete_admin_invoice(
+    State(state): State<LnurlState>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, LnurlError> {
+    let pending_invoice = state.db.get_pending_invoice(&hash).await.map_err(|err| {
+        error!("Could not get pending invoice: {:?}", err);
+        LnurlError::internal("Could not get invoice")
+    })?;
+    let payer_invoice = state.db.get_payer_invoice(&hash).await.map_err(|err| {
+        error!("Could not get payer invoice: {:?}", err);
+        LnurlError::internal("Could not get invoice")
+    })?;
+
+    match InvoiceStatus::new(pending_invoice.as_ref(), payer_invoice.as_ref()) {
+        Some(InvoiceStatus::WaitingForPayment | InvoiceStatus::Failed) => (),
+        Some(InvoiceStatus::WaitingForMint | InvoiceStatus::Settled) => {
+            return Err(LnurlError::new(
+                StatusCode::CONFLICT,
+                "Invoice has already been paid",
+            ))
+        }
+        Some(InvoiceStatus::Cancelled) | None => {
+            return Err(LnurlError::not_found("No pending invoice"))
+        }
+    }
+
+    // Payer invoices of proxied payments are the only ones created by the backend
+    let created_by_backend =
+        pending_invoice.map_or(false, |invoice| invoice.proxied) && payer_invoice.is_some();
+    if created_by_backend {
+        if let Some(ln_backend) = &state.ln_backend {
+            ln_backend.cancel_invoice(&hash).await.map_err(|err| {
+                error!("Could not cancel invoice {}: {:?}", hash, err);
+                LnurlError::internal("Could not cancel invoice")
+            })?;
+        }
+    }
+
+    state
+        .db
+        .remove_pending_invoice(&hash)
+        .await
+        .map_err(|err| {
+            error!("Could not remove pending invoice: {:?}", err);
+            LnurlError::internal("Could not remove pending invoice")
+        })?;
+
+    debug!("Cancelled pending invoice {}", hash);
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateInviteParams {
+    /// Code to create, a random one when unset
+    code: Option<String>,
+    /// Signups the code can be used for, defaults to one
+    max_uses: Option<u64>,
+    /// Seconds the code can be used for, forever when unset
+    expires_in: Option<u64>,
+}
+
+/// Create an invite code, authorized by the admin token
+pub(crate) async fn post_admin_invite(
+    State(state): State<LnurlState>,
+    params: Result<Json<CreateInviteParams>, JsonRejection>,
+) -> Result<Json<InviteCode>, LnurlError> {
+    let params = match params {
+        Ok(Json(params)) => params,
+        // A default code can be created without a body
+        Err(JsonRejection::MissingJsonContentType(_)) => CreateInviteParams::default(),
+        Err(err) => return Err(LnurlError::bad_request(&err.body_text())),
+    };
+
+    let code = params
+        .code
+        .map(|code| code.trim().to_string())
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    if code.is_empty() {
+        return Err(LnurlError::bad_request("Invite code cannot be empty"));
+    }
+    let max_uses = params.max_uses.unwrap_or(1);
+    if max_uses == 0 {
+        return Err(LnurlError::bad_request("Invite code must allow a use"));
+    }
+
+    let now = unix_time();
+    let invite = InviteCode {
+        code,
+        max_uses,
+        uses: 0,
+        expire: params
+            .expires_in
+            .map(|expires_in| now.saturating_add(expires_in)),
+        created_at: now,
+    };
+
+    state.db.add_invite_code(&invite).await.map_err(|err| {
+        error!("Could not add invite code: {:?}", err);
+        LnurlError::internal("Could not add invite code")
+    })?;
+
+    debug!("Created invite code for {} signups", invite.max_uses);
+
+    Ok(Json(invite))
+}
+
+/// Invite codes with their uses, authorized by the admin token
+pub(crate) async fn get_admin_invites(
+    State(state): State<LnurlState>,
+) -> Result<Json<Vec<InviteCode>>, LnurlError> {
+    let invites = state.db.get_invite_codes().await.map_err(|err| {
+        error!("Could not get invite codes: {:?}", err);
+        LnurlError::internal("Could not get invite codes")
+    })?;
+
+    Ok(Json(invites))
+}
+
+/// Revoke an invite code, authorized by the admin token
+pub(crate) async fn delete_admin_invite(
+    State(state): State<LnurlState>,
+    Path(code): Path<String>,
+) -> Result<StatusCode, LnurlError> {
+    match state.db.remove_invite_code(&code).await {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(LnurlError::not_found("Invite code not found")),
+        Err(err) => {
+            error!("Could not remove invite code: {:?}", err);
+            Err(LnurlError::internal("Could not remove invite code"))
+        }
+    }
+}
+
+/// Update a users min and max sendable
+///
+/// Unset limits fall back to the service defaults
+pub(crate) async fn put_user_limits(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    Path(username): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, LnurlError> {
+    let params: UpdateLimitsParams = json_params(&body)?;
+
+    let domain = request_domain(&state.domains, &host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+    let username = normalize_username(&username);
+
+    let mut user = match state.db.get_user(&domain, &username).await {
+        Ok(Some(UserKind::User(user))) => user,
+        Ok(_) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not get user: {:?}", err);
+            return Err(LnurlError::internal("Could not get user"));
+        }
+    };
+
+    let pubkey = XOnlyPublicKey::from_str(&user.pubkey).map_err(|err| {
+        error!("Invalid stored pubkey for {}: {:?}", username, err);
+        LnurlError::internal("Invalid user pubkey")
+    })?;
+
+    let request = AuthRequest {
+        method: &method,
+        path: uri.path(),
+        body: Some(&body),
+    };
+    authorize_request(
+        &state,
+        &headers,
+        params.event.clone(),
+        request,
+        &pubkey,
+        &username,
+        "Limits must be signed by the pubkey",
+    )?;
+
+    validate_sendable(
+        params.min_sendable,
+        params.max_sendable,
+        state.min_sendable,
+        state.max_sendable,
+    )?;
+
+    user.min_sendable = params.min_sendable;
+    user.max_sendable = params.max_sendable;
+
+    // Only replaces an active user, so an account deleted meanwhile is not brought back
+    match state.db.update_user(&domain, &username, &user).await {
+        Ok(true) => (),
+        Ok(false) => return Err(LnurlError::not_found("User not found")),
+        Err(err) => {
+            error!("Could not update user: {:?}", err);
+            return Err(LnurlError::internal("Could not update user"));
+        }
+    }
+    invalidate_lnurl(&state, &domain, &username);
+
+    Ok(StatusCode::OK)
+}
+
+/// Deprecated signup via query parameters
+///
+/// Only routed when `allow_get_signup` is set, kept for older clients
+pub(crate) async fn get_sign_up(
+    State(state): State<LnurlState>,
+    Host(host): Host,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    params: Result<Query<GetSignupParams>, QueryRejection>,
+) -> Result<Json<SignupResponse>, LnurlError> {
+    let Query(params) = params.map_err(|err| LnurlError::bad_request(&err.body_text()))?;
+    let ip = client_ip(
+        connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        &headers,
+        &state.trusted_proxies,
+    );
+
+    warn!("Deprecated GET signup used for {}", params.username);
+
+    sign_up(state, &host, ip, params.try_into()?).await
+}
+
+/// Check the invite code of a signup while signups are disabled, without using it up
+///
+/// Returns the code to use up once the user is stored, or once a pending user has paid, so a
+/// signup that fails does not cost a use
+async fn check_invite(
+    state: &LnurlState,
+    invite: Option<&str>,
+) -> Result<Option<String>, LnurlError> {
+    if !state.signups_disabled {
+        return Ok(None);
+    }
+
+    let invite = invite.ok_or_else(|| {
+        LnurlError::new(
+            StatusCode::FORBIDDEN,
+            "Signups are disabled, an invite code is required",
+        )
+    })?;
+
+    match state.db.get_invite_code(invite).await {
+        Ok(Some(invite)) if invite.is_usable(unix_time()) => Ok(Some(invite.code)),
+        Ok(Some(_)) => Err(invite_error(InviteStatus::Gone)),
+        Ok(None) => Err(invite_error(InviteStatus::NotFound)),
+        Err(err) => {
+            error!("Could not get invite code: {:?}", err);
+            Err(LnurlError::internal("Could not use invite code"))
+        }
+    }
+}
+
+/// Use up an invite code returned by [`check_invite`]
+async fn use_invite(state: &LnurlState, invite: &str) -> Result<(), LnurlError> {
+    match state.db.use_invite_code(invite, unix_time()).await {
+        Ok(InviteStatus::Used) => Ok(()),
+        Ok(status) => Err(invite_error(status)),
+        Err(err) => {
+            error!("Could not use invite code: {:?}", err);
+            Err(LnurlError::internal("Could not use invite code"))
+        }
+    }
+}
+
+fn invite_error(status: InviteStatus) -> LnurlError {
+    match status {
+        InviteStatus::NotFound => LnurlError::new(StatusCode::FORBIDDEN, "Invalid invite code"),
+        _ => LnurlError::new(StatusCode::FORBIDDEN, "Invite code is used up or expired"),
+    }
+}
+
+/// Seconds a username with a cost is held for its invoice to be paid by default
+pub const DEFAULT_SIGNUP_EXPIRY_SECS: u64 = 900;
+
+/// Count an account created by a signup towards the daily signup limit
+fn record_signup(state: &LnurlState) {
+    if let Some(signup_limiter) = &state.signup_limiter {
+        signup_limiter.record(unix_time());
+    }
+}
+
+/// Cost of signing up with a username, by its length in characters
+fn signup_cost(state: &LnurlState, username: &str) -> Amount {
+    match username.chars().count() {
+        0..=2 => state.two_char_cost,
+        3 => state.three_char_cost,
+        4 => state.four_char_cost,
+        _ => state.other_char_cost,
+    }
+}
+
+async fn sign_up(
+    state: LnurlState,
+    host: &str,
+    ip: Option<IpAddr>,
+    mut params: SignupParams,
+) -> Result<Json<SignupResponse>, LnurlError> {
+    if state.signups_disabled && params.invite.is_none() {
+        return Err(LnurlError::new(
+            StatusCode::FORBIDDEN,
+            "Signups are disabled, an invite code is required",
+        ));
+    }
+
+    if let Some(signup_limiter) = &state.signup_limiter {
+        if let Err((limit, retry_after)) = signup_limiter.check(ip, Instant::now(), unix_time()) {
+            warn!(
+                "Rejected signup of {} from {:?}, {} limit exceeded",
+                params.username, ip, limit
+            );
+            state
+                .metrics
+                .signups_rejected
+                .with_label_values(&[limit])
+                .inc();
+            return Err(LnurlError::too_many_requests(
+                "Too many signups",
+                retry_after,
+            ));
+        }
+    }
+
+    params.validate()?;
+    validate_sendable(
+        params.min_sendable,
+        params.max_sendable,
+        state.min_sendable,
+        state.max_sendable,
+    )?;
+
+    let domain = request_domain(&state.domains, host)
+        .ok_or_else(|| LnurlError::not_found("Unknown domain"))?;
+
+    params.verify_owner(state.auth_window, unix_time())?;
+    // Checked as signed, stored normalized so a differently cased username is taken
+    params.username = normalize_username(&params.username);
+    state
+        .username_rules
+        .validate(&params.username)
+        .map_err(|err| LnurlError::bad_request(&err.to_string()))?;
+    if state.username_rules.is_reserved(&params.username) {
+        return Err(LnurlError::new(
+            StatusCode::FORBIDDEN,
+            "Username is reserved",
+        ));
+    }
+    check_metadata_len(
+        &state,
+        &params.username,
+        &domain,
+        params.description.as_deref(),
+        params.avatar.as_deref(),
+    )?;
+
+    check_mint(&state, &params.mint).await?;
+    for mint in &params.fallback_mints {
+        check_mint(&state, mint).await?;
+    }
+    for rule in &params.mint_rules {
+        check_mint(&state, &rule.mint).await?;
+    }
+    check_webhook_host(params.webhook_url.as_ref()).await?;
+
+    let existing = state
+        .db
+        .get_user(&domain, &params.username)
+        .await
+        .map_err(|err| {
+            error!("Could not get user: {:?}", err);
+            LnurlError::internal("Could not get user")
+        })?;
+    // An unpaid signup only holds the username until it expires
+    let existing = match existing {
+        Some(UserKind::Pending(pending_user)) if pending_user.expire <= unix_time() => {
+            pending_user.expired_kind()
+        }
+        existing => existing,
+    };
+
+    match existing {
+        Some(UserKind::User(_user)) => Err(LnurlError::new(
+            StatusCode::CONFLICT,
+            "Username already taken",
+        )),
+        Some(UserKind::Pending(_user)) => Err(LnurlError::new(
+            StatusCode::CONFLICT,
+            "Username pending payment",
+        )),
+        Some(UserKind::Blocked) => Err(LnurlError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "Username not available",
+        )),
+        Some(UserKind::Reserved(amount)) => {
+            let invite = check_invite(&state, params.invite.as_deref()).await?;
+
+            let invoice = get_invoice(
+                &state,
+                Some(amount),
+                format!("Payment for {}", params.username),
+                false,
+                None,
+                Some(state.signup_expiry),
+            )
+            .await?;
+
+            let user = User {
+                username: params.username.clone(),
+                domain: domain.clone(),
+                mint: params.mint,
+                fallback_mints: params.fallback_mints,
+                mint_rules: params.mint_rules,
+                pubkey: params.pubkey.public_key().to_string(),
+                relays: params.relays.unwrap_or_default(),
+                proxy: params.proxy.unwrap_or_default(),
+                min_sendable: params.min_sendable,
+                max_sendable: params.max_sendable,
+                success_message: params.success_message,
+                description: params.description,
+                avatar: params.avatar,
+                webhook_url: params.webhook_url,
+                webhook_secret: params.webhook_secret,
+                lock_to_pubkey: params.lock_to_pubkey.unwrap_or_default(),
+                created_at: Some(unix_time()),
+            };
+
+            let pending_user = PendingUser {
+                user: user.clone(),
+                pr: invoice.clone(),
+                last_checked: unix_time(),
+                expire: unix_time() + state.signup_expiry,
+                reserved: Some(amount),
+                invite,
+            };
+
+            let mut pending_users = state.pending_users.lock().await;
+            pending_users.insert(invoice.payment_hash().to_string(), pending_user.clone());
+
+            state
+                .db
+                .add_user(
+                    &domain,
+                    &params.username,
+                    &UserKind::Pending(pending_user.clone()),
+                )
+                .await
+                .map_err(|err| {
+                    error!("Could not add user: {:?}", err);
+                    LnurlError::internal("Could not add user")
+                })?;
+            invalidate_lnurl(&state, &domain, &params.username);
+            record_signup(&state);
+
+            SignupResponse::new(&state, &user, Some(&pending_user)).map(Json)
+        }
+        None => {
+            let invite = check_invite(&state, params.invite.as_deref()).await?;
+
+            let relays = params.relays.unwrap_or_default();
+            let proxy = params.proxy.unwrap_or_default();
+
+            let user = User {
+                username: params.username.clone(),
+                domain: domain.clone(),
+                mint: params.mint,
+                fallback_mints: params.fallback_mints,
+                mint_rules: params.mint_rules,
+                pubkey: params.pubkey.public_key().to_string(),
+                relays,
+                proxy,
+                min_sendable: params.min_sendable,
+                max_sendable: params.max_sendable,
+                success_message: params.success_message,
+                description: params.description,
+                avatar: params.avatar,
+                webhook_url: params.webhook_url,
+                webhook_secret: params.webhook_secret,
+                lock_to_pubkey: params.lock_to_pubkey.unwrap_or_default(),
+                created_at: Some(unix_time()),
+            };
+
+            let amount = signup_cost(&state, &params.username);
+
+            let user = if amount.gt(&Amount::ZERO) {
+                let pr = get_invoice(
+                    &state,
+                    Some(amount),
+                    params.username.to_string(),
+                    false,
+                    None,
+                    Some(state.signup_expiry),
+                )
+                .await?;
+                let pending_user = PendingUser {
+                    user: user.clone(),
+                    pr: pr.clone(),
+                    last_checked: unix_time(),
+                    expire: unix_time() + state.signup_expiry,
+                    reserved: None,
+                    invite: invite.clone(),
+                };
+
+                let mut pending_users = state.pending_users.lock().await;
+                pending_users.insert(pr.payment_hash().to_string(), pending_user.clone());
+
+                UserKind::Pending(pending_user)
             } else {
                 UserKind::User(user)
             };
 
-            state.db.add_user(&params.username, &user).await.unwrap();
+            state
+                .db
+                .add_user(&domain, &params.username, &user)
+                .await
+                .map_err(|err| {
+                    error!("Could not add user: {:?}", err);
+                    LnurlError::internal("Could not add user")
+                })?;
+            // Pending users use up their invite once they pay
+            if let (UserKind::User(_), Some(invite)) = (&user, &invite) {
+                if let Err(err) = use_invite(&state, invite).await {
+                    // Used up by another signup since it was checked
+                    if let Err(err) = state.db.remove_user(&domain, &params.username).await {
+                        error!("Could not remove user without an invite: {:?}", err);
+                    }
+                    return Err(err);
+                }
+            }
+            invalidate_lnurl(&state, &domain, &params.username);
+            record_signup(&state);
+
+            // Welcome message is best effort and should not hold up the response
+            if let UserKind::User(user) = &user {
+                let nostr = state.nostr.clone();
+                let username = params.username.clone();
+                let user = user.clone();
+
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = nostr.send_sign_up_message(&username, &user).await {
+                            warn!("Could not send sign up message to {}: {:?}", username, err);
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+
+            match user {
+                UserKind::User(user) => SignupResponse::new(&state, &user, None).map(Json),
+                UserKind::Pending(pending) => {
+                    SignupResponse::new(&state, &pending.user, Some(&pending)).map(Json)
+                }
+                _ => {
+                    warn!("Unexpected user type");
+                    Err(LnurlError::internal("Unexpected user type"))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use std::str::FromStr;
+
+    use std::collections::HashMap;
+
+    use aes::cipher::block_padding::Pkcs7;
+    use aes::cipher::{BlockDecryptMut, KeyIvInit};
+    use axum::body::HttpBody;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use nostr_sdk::{EventBuilder, Kind, Tag, TagKind};
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::backend::{InvoiceStream, Payment, PaymentBackend, PaymentStatus};
+    use crate::cache::TtlCache;
+    use crate::cashu::Cashu;
+    use crate::config::{DbBackendKind, Settings};
+    use crate::database;
+    use crate::dry_run::dry_run_invoice;
+    use crate::metrics::Metrics;
+    use crate::nip98::UsedEvents;
+    use crate::nostr::Nostr;
+    use crate::notifications::Notifier;
+    use crate::types::Claim;
+    use crate::username::UsernameRules;
+
+    pub(crate) async fn test_state() -> LnurlState {
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.redb", Uuid::new_v4()));
+        let db = database::open(DbBackendKind::Redb, path, "example.com")
+            .await
+            .unwrap();
+        let nostr = Nostr::new(
+            db.clone(),
+            "example.com".to_string(),
+            Keys::generate(),
+            HashSet::new(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let metrics = Metrics::new().unwrap();
+        let notifier = Notifier::new();
+        let cashu = Cashu::new(
+            db.clone(),
+            nostr.clone(),
+            Settings::default(),
+            metrics.clone(),
+            notifier.clone(),
+            None,
+        )
+        .unwrap();
+
+        LnurlState {
+            api_base_address: Url::from_str("https://example.com").unwrap(),
+            domains: HashSet::from(["example.com".to_string()]),
+            primary_domain: "example.com".to_string(),
+            min_sendable: Amount::from_sat(1),
+            max_sendable: Amount::from_sat(1_000_000),
+            description: "Hello world".to_string(),
+            avatar: None,
+            success_message: "Thanks".to_string(),
+            success_action: SuccessActionKind::Message,
+            comment_allowed: 0,
+            sat_only: false,
+            allow_amountless: false,
+            signups_disabled: false,
+            catch_all_user: None,
+            username_rules: UsernameRules::default(),
+            auth_window: 300,
+            used_auth_events: Arc::new(UsedEvents::default()),
+            allowed_mints: None,
+            mint_rules: vec![],
+            expose_user_relays: false,
+            admin_token: Some("secret".to_string()),
+            invoice_rate_limiter: None,
+            lnurl_cache: None,
+            lnurl_max_age: 300,
+            invoice_expiry_secs: None,
+            trusted_proxies: HashSet::new(),
+            signup_limiter: None,
+            payer_auth_k1s: Arc::new(PayerAuthK1s::new(b"seed", 300)),
+            nostr_pubkey: Some(nostr.get_pubkey()),
+            proxy: false,
+            min_proxied: Amount::from_sat(1),
+            cashu,
+            ln_backend: None,
+            db,
+            nostr,
+            metrics,
+            notifier,
+            pending_users: Arc::new(Mutex::new(HashMap::new())),
+            two_char_cost: Amount::ZERO,
+            three_char_cost: Amount::ZERO,
+            four_char_cost: Amount::ZERO,
+            other_char_cost: Amount::ZERO,
+            signup_expiry: DEFAULT_SIGNUP_EXPIRY_SECS,
+            upstream_timeout: Duration::from_secs(DEFAULT_UPSTREAM_TIMEOUT_SECS),
+        }
+    }
+
+    /// User alice of example.com registered to `pubkey`, with the service defaults
+    ///
+    /// Tests override the fields they are about with struct update syntax
+    pub(crate) fn test_user(pubkey: &str) -> User {
+        User {
+            username: "alice".to_string(),
+            domain: "example.com".to_string(),
+            mint: Url::from_str("https://mint.example.com").unwrap(),
+            fallback_mints: vec![],
+            mint_rules: vec![],
+            pubkey: pubkey.to_string(),
+            relays: HashSet::new(),
+            proxy: false,
+            min_sendable: None,
+            max_sendable: None,
+            success_message: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: false,
+            created_at: None,
+        }
+    }
+
+    /// Pending invoice of alice at the default mint for 1000 sats, created now
+    ///
+    /// Tests override the fields they are about with struct update syntax
+    pub(crate) fn test_invoice(bolt11: Bolt11Invoice) -> PendingInvoice {
+        PendingInvoice {
+            mint: Url::from_str("https://mint.example.com").unwrap(),
+            username: "alice".to_string(),
+            domain: "example.com".to_string(),
+            alias: None,
+            description: None,
+            comment: None,
+            payer_data: None,
+            claim_id: None,
+            request_id: None,
+            idempotency_key: None,
+            success_action: None,
+            time: unix_time(),
+            amount: Amount::from_sat(1000),
+            hash: bolt11.payment_hash().to_string(),
+            bolt11,
+            last_checked: None,
+            proxied: false,
+            failed: false,
+            payer_hash: None,
+        }
+    }
+
+    /// Auth event of `username` for a `method` request to `path`
+    fn auth_event(keys: &Keys, username: &str, method: &str, path: &str) -> Event {
+        let tags = [
+            Tag::Generic(
+                TagKind::Custom("u".to_string()),
+                vec![format!("https://example.com{}", path)],
+            ),
+            Tag::Generic(
+                TagKind::Custom("method".to_string()),
+                vec![method.to_string()],
+            ),
+        ];
+        EventBuilder::new(Kind::TextNote, username, &tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    /// Backend recording the descriptions of the invoices it creates
+    #[derive(Default)]
+    struct RecordingBackend {
+        descriptions: std::sync::Mutex<Vec<(String, bool)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl PaymentBackend for RecordingBackend {
+        async fn create_invoice(
+            &self,
+            _amount: Option<Amount>,
+            description: String,
+            description_hash_only: bool,
+            _preimage: Option<[u8; 32]>,
+            _expiry: Option<u64>,
+        ) -> anyhow::Result<Bolt11Invoice> {
+            self.descriptions
+                .lock()
+                .unwrap()
+                .push((description, description_hash_only));
+
+            Ok(Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld")?)
+        }
+
+        async fn pay(&self, _bolt11: &Bolt11Invoice, _max_fee: Amount) -> anyhow::Result<Payment> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn wait_any_invoice(
+            &self,
+            _last_pay_index: Option<u64>,
+        ) -> anyhow::Result<InvoiceStream> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn payment_status(&self, _payment_hash: &str) -> anyhow::Result<PaymentStatus> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn ping(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoice_description_hash() {
+        let mut state = test_state().await;
+        let backend = Arc::new(RecordingBackend::default());
+        state.ln_backend = Some(backend.clone());
+        state.proxy = true;
+
+        let user = User {
+            proxy: true,
+            ..test_user(&Keys::generate().public_key().to_string())
+        };
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let response = user_lnurl_response(&state, "example.com", "alice")
+            .await
+            .unwrap();
+
+        get_user_invoice(
+            Query(GetInvoiceParams {
+                amount: Some(1_000_000),
+                nostr: None,
+                comment: None,
+                payerdata: None,
+            }),
+            Host("example.com".to_string()),
+            Path("alice".to_string()),
+            State(state.clone()),
+            None,
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+
+        // Wallets check the description hash is the hash of the metadata they were served
+        let (description, description_hash_only) = backend.descriptions.lock().unwrap()[0].clone();
+        assert!(description_hash_only);
+        assert_eq!(
+            Sha256::digest(description.as_bytes()),
+            Sha256::digest(response.metadata.as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invoice_mint_rules() {
+        let mut state = test_state().await;
+        state.ln_backend = Some(Arc::new(RecordingBackend::default()));
+        state.proxy = true;
+        state.mint_rules = vec![MintRule {
+            max_amount: Amount::from_sat(1000),
+            mint: Url::from_str("https://small.example.com").unwrap(),
+        }];
+
+        let user = User {
+            proxy: true,
+            ..test_user(&Keys::generate().public_key().to_string())
+        };
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let invoice_mint = |amount: u64| {
+            let state = state.clone();
+            async move {
+                get_user_invoice(
+                    Query(GetInvoiceParams {
+                        amount: Some(amount),
+                        nostr: None,
+                        comment: None,
+                        payerdata: None,
+                    }),
+                    Host("example.com".to_string()),
+                    Path("alice".to_string()),
+                    State(state.clone()),
+                    None,
+                    None,
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+
+                // The backend always returns the same invoice so it replaces the last one
+                state.db.get_pending_invoices().await.unwrap()[0]
+                    .mint
+                    .to_string()
+            }
+        };
+
+        // The rule's max amount is inclusive
+        assert_eq!(invoice_mint(1_000_000).await, "https://small.example.com/");
+        // Larger amounts fall through to the user's mint
+        assert_eq!(invoice_mint(1_001_000).await, "https://mint.example.com/");
+    }
+
+    #[tokio::test]
+    async fn test_proxied_invoice_without_backend() {
+        let mut state = test_state().await;
+        state.ln_backend = None;
+        state.proxy = true;
+
+        let user = User {
+            proxy: true,
+            ..test_user(&Keys::generate().public_key().to_string())
+        };
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        // A proxy user without a lightning backend gets an LNURL error rather than a panic
+        let err = get_user_invoice(
+            Query(GetInvoiceParams {
+                amount: Some(1_000_000),
+                nostr: None,
+                comment: None,
+                payerdata: None,
+            }),
+            Host("example.com".to_string()),
+            Path("alice".to_string()),
+            State(state.clone()),
+            None,
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.reason, "Could not create invoice");
+        assert!(state.db.get_pending_invoices().await.unwrap().is_empty());
+    }
+
+    /// Backend that never creates an invoice, as a hung lightning node
+    struct HangingBackend;
+
+    #[async_trait::async_trait]
+    impl PaymentBackend for HangingBackend {
+        async fn create_invoice(
+            &self,
+            _amount: Option<Amount>,
+            _description: String,
+            _description_hash_only: bool,
+            _preimage: Option<[u8; 32]>,
+            _expiry: Option<u64>,
+        ) -> anyhow::Result<Bolt11Invoice> {
+            futures::future::pending().await
+        }
+
+        async fn pay(&self, _bolt11: &Bolt11Invoice, _max_fee: Amount) -> anyhow::Result<Payment> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn wait_any_invoice(
+            &self,
+            _last_pay_index: Option<u64>,
+        ) -> anyhow::Result<InvoiceStream> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn payment_status(&self, _payment_hash: &str) -> anyhow::Result<PaymentStatus> {
+            anyhow::bail!("Not supported")
+        }
+
+        async fn ping(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoice_upstream_timeout() {
+        // A mint that takes longer to respond than the timeout
+        let mint = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::any())
+            .respond_with(wiremock::ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+            .mount(&mint)
+            .await;
+
+        let mut state = test_state().await;
+        state.ln_backend = Some(Arc::new(HangingBackend));
+        state.upstream_timeout = Duration::from_millis(200);
+
+        for (username, proxy) in [("alice", true), ("bob", false)] {
+            let user = User {
+                username: username.to_string(),
+                mint: Url::from_str(&mint.uri()).unwrap(),
+                proxy,
+                ..test_user(&Keys::generate().public_key().to_string())
+            };
+            state
+                .db
+                .add_user("example.com", username, &UserKind::User(user))
+                .await
+                .unwrap();
+        }
+        state.proxy = true;
+
+        let invoice = |username: &str| {
+            get_user_invoice(
+                Query(GetInvoiceParams {
+                    amount: Some(1_000_000),
+                    nostr: None,
+                    comment: None,
+                    payerdata: None,
+                }),
+                Host("example.com".to_string()),
+                Path(username.to_string()),
+                State(state.clone()),
+                None,
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        // Hung lightning backend for the proxy user, hung mint for the other
+        let err = invoice("alice").await.unwrap_err();
+        assert_eq!(err.code, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.reason, "Timed out creating invoice");
+        let err = invoice("bob").await.unwrap_err();
+        assert_eq!(err.code, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.reason, "Timed out getting invoice from mint");
+
+        assert!(state.db.get_pending_invoices().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lnurl_response_serialization() {
+        let lnurl_response = LnurlResponse {
+            min_sendable: Amount::from_sat(0),
+            max_sendable: Amount::from_sat(1000),
+            metadata: lnurl_metadata("Hello world", "alice@example.com", None).unwrap(),
+            callback: Url::from_str("http://example.com").unwrap(),
+            tag: LnurlTag::PayRequest,
+            allows_nostr: true,
+            nostr_pubkey: Some(
+                "9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31".to_string(),
+            ),
+            comment_allowed: 255,
+            payer_data: PayerDataCapabilities::new(
+                "e2af6254a8df433264fa23f67eb8188635d15ce883e8fc020989d5f82ae6f11e",
+            ),
+        };
+
+        assert_eq!("{\"minSendable\":0,\"maxSendable\":1000000,\"metadata\":\"[[\\\"text/plain\\\",\\\"Hello world\\\"],[\\\"text/identifier\\\",\\\"alice@example.com\\\"]]\",\"callback\":\"http://example.com/\",\"tag\":\"payRequest\",\"allowsNostr\":true,\"nostrPubkey\":\"9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31\",\"commentAllowed\":255,\"payerData\":{\"name\":{\"mandatory\":false},\"pubkey\":{\"mandatory\":false},\"identifier\":{\"mandatory\":false},\"auth\":{\"mandatory\":false,\"k1\":\"e2af6254a8df433264fa23f67eb8188635d15ce883e8fc020989d5f82ae6f11e\"}}}", serde_json::to_string(&lnurl_response).unwrap());
+
+        // Comments disabled
+        let lnurl_response = LnurlResponse {
+            comment_allowed: 0,
+            ..lnurl_response
+        };
+
+        assert!(!serde_json::to_string(&lnurl_response)
+            .unwrap()
+            .contains("commentAllowed"));
+    }
+
+    #[test]
+    fn test_parse_payer_data() {
+        let k1s = PayerAuthK1s::new(b"seed", 300);
+        let now = unix_time();
+
+        let payer_data = parse_payer_data(
+            r#"{"name":"Alice","identifier":"alice@example.com","email":"ignored"}"#,
+            &k1s,
+            None,
+            now,
+        )
+        .unwrap();
+        assert_eq!(payer_data.name.as_deref(), Some("Alice"));
+        assert_eq!(payer_data.identifier.as_deref(), Some("alice@example.com"));
+
+        assert!(parse_payer_data("not json", &k1s, None, now).is_err());
+        assert!(parse_payer_data(r#"{"pubkey":"abc"}"#, &k1s, None, now).is_err());
+        assert!(parse_payer_data(
+            &format!(r#"{{"name":"{}"}}"#, "a".repeat(257)),
+            &k1s,
+            None,
+            now
+        )
+        .is_err());
+
+        let secp = Secp256k1::new();
+        let secret_key =
+            nostr_sdk::secp256k1::SecretKey::from_slice(&Sha256::digest("linking key")).unwrap();
+        let key = PublicKey::from_secret_key(&secp, &secret_key);
+        let sign = |k1: &str| {
+            let message = Message::from_slice(&hex::decode(k1).unwrap()).unwrap();
+            hex::encode(secp.sign_ecdsa(&message, &secret_key).serialize_der())
+        };
+        let payer_data = |k1: &str, sig: &str| {
+            serde_json::json!({
+                "pubkey": key.to_string(),
+                "auth": { "key": key.to_string(), "k1": k1, "sig": sig },
+            })
+            .to_string()
+        };
+
+        let k1 = k1s.issue(now);
+        let payer = parse_payer_data(&payer_data(&k1, &sign(&k1)), &k1s, None, now).unwrap();
+        assert_eq!(payer.auth.unwrap().key, key.to_string());
+
+        // Each k1 is used once, when its invoice is stored
+        assert!(parse_payer_data(&payer_data(&k1, &sign(&k1)), &k1s, None, now).is_ok());
+        k1s.consume(&k1, now).unwrap();
+        assert!(parse_payer_data(&payer_data(&k1, &sign(&k1)), &k1s, None, now).is_err());
+
+        // Signed a k1 this service did not issue
+        let other_k1 = "01".repeat(32);
+        assert!(
+            parse_payer_data(&payer_data(&other_k1, &sign(&other_k1)), &k1s, None, now).is_err()
+        );
+
+        // Signature of another k1
+        let k1 = k1s.issue(now);
+        assert!(parse_payer_data(&payer_data(&k1, &sign(&other_k1)), &k1s, None, now).is_err());
+        assert!(parse_payer_data(&payer_data(&k1, &sign(&k1)), &k1s, None, now).is_ok());
+    }
+
+    #[test]
+    fn test_verify_auth_event() {
+        let keys = Keys::generate();
+        let now = unix_time();
+
+        let event = EventBuilder::new(Kind::TextNote, "alice", &[])
+            .to_event(&keys)
+            .unwrap();
+
+        assert!(verify_auth_event(&event, &keys.public_key(), "alice", 300, now).is_ok());
+
+        // Signed for a different username
+        assert!(verify_auth_event(&event, &keys.public_key(), "bob", 300, now).is_err());
+
+        // Expired
+        assert!(verify_auth_event(&event, &keys.public_key(), "alice", 300, now + 301).is_err());
+
+        // Signed by a different key than the one being registered
+        let other_keys = Keys::generate();
+        assert!(verify_auth_event(&event, &other_keys.public_key(), "alice", 300, now).is_err());
+
+        // Content changed after signing
+        let mut forged = event.clone();
+        forged.content = "bob".to_string();
+        assert!(verify_auth_event(&forged, &keys.public_key(), "bob", 300, now).is_err());
+
+        // Pubkey swapped after signing
+        let mut forged = event;
+        forged.pubkey = other_keys.public_key();
+        assert!(verify_auth_event(&forged, &other_keys.public_key(), "alice", 300, now).is_err());
+    }
+
+    #[test]
+    fn test_verify_signup_signature() {
+        use nostr_sdk::secp256k1::KeyPair;
+
+        let secp = Secp256k1::new();
+        let key_pair = KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let (pubkey, _) = key_pair.x_only_public_key();
+        let now = unix_time();
+
+        let sign = |message: String| {
+            let message = Message::from_slice(&Sha256::digest(message)).unwrap();
+            secp.sign_schnorr_no_aux_rand(&message, &key_pair)
+                .to_string()
+        };
+
+        let sig = sign(signup_message("alice", now));
+        assert!(verify_signup_signature(&pubkey, "alice", now, &sig, 300, now).is_ok());
+
+        // Signed for a different username or time
+        assert!(verify_signup_signature(&pubkey, "bob", now, &sig, 300, now).is_err());
+        assert!(verify_signup_signature(&pubkey, "alice", now - 1, &sig, 300, now).is_err());
+
+        // Stale
+        let old = now - 301;
+        let sig = sign(signup_message("alice", old));
+        let err = verify_signup_signature(&pubkey, "alice", old, &sig, 300, now).unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+
+        // Signed by a different key
+        let other = KeyPair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let (other_pubkey, _) = other.x_only_public_key();
+        let sig = sign(signup_message("alice", now));
+        assert!(verify_signup_signature(&other_pubkey, "alice", now, &sig, 300, now).is_err());
+
+        assert!(verify_signup_signature(&pubkey, "alice", now, "not hex", 300, now).is_err());
+    }
+
+    #[test]
+    fn test_validate_sendable() {
+        let default_min = Amount::from_sat(1);
+        let default_max = Amount::from_sat(100);
+
+        assert!(validate_sendable(None, None, default_min, default_max).is_ok());
+        assert!(
+            validate_sendable(Some(Amount::from_sat(10)), None, default_min, default_max).is_ok()
+        );
+        assert!(validate_sendable(
+            Some(Amount::from_sat(10)),
+            Some(Amount::from_sat(10)),
+            default_min,
+            default_max
+        )
+        .is_ok());
+        assert!(validate_sendable(
+            Some(Amount::from_sat(11)),
+            Some(Amount::from_sat(10)),
+            default_min,
+            default_max
+        )
+        .is_err());
+
+        // Overrides are checked against the defaults they are combined with
+        assert!(
+            validate_sendable(Some(Amount::from_sat(101)), None, default_min, default_max).is_err()
+        );
+        assert!(validate_sendable(None, Some(Amount::ZERO), default_min, default_max).is_err());
+        assert!(validate_sendable(
+            Some(Amount::from_sat(500)),
+            Some(Amount::from_sat(1000)),
+            default_min,
+            default_max
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_user_sendable() {
+        let mut state = test_state().await;
+        state.min_proxied = Amount::from_sat(11);
+        let user = User {
+            proxy: true,
+            ..test_user(&Keys::generate().public_key().to_string())
+        };
+
+        // Not proxied unless the service proxies
+        assert_eq!(
+            user_sendable(&state, &user),
+            (Amount::from_sat(1), Amount::from_sat(1_000_000))
+        );
+
+        state.proxy = true;
+        assert_eq!(
+            user_sendable(&state, &user),
+            (Amount::from_sat(11), Amount::from_sat(1_000_000))
+        );
+        let user = User {
+            min_sendable: Some(Amount::from_sat(20)),
+            ..user
+        };
+        assert_eq!(
+            user_sendable(&state, &user),
+            (Amount::from_sat(20), Amount::from_sat(1_000_000))
+        );
+        let user = User {
+            proxy: false,
+            min_sendable: None,
+            ..user
+        };
+        assert_eq!(
+            user_sendable(&state, &user),
+            (Amount::from_sat(1), Amount::from_sat(1_000_000))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signup_cost() {
+        let mut state = test_state().await;
+        state.two_char_cost = Amount::from_sat(4);
+        state.three_char_cost = Amount::from_sat(3);
+        state.four_char_cost = Amount::from_sat(2);
+        state.other_char_cost = Amount::from_sat(1);
+
+        assert_eq!(signup_cost(&state, "ab"), Amount::from_sat(4));
+        assert_eq!(signup_cost(&state, "abc"), Amount::from_sat(3));
+        assert_eq!(signup_cost(&state, "abcd"), Amount::from_sat(2));
+        assert_eq!(signup_cost(&state, "abcde"), Amount::from_sat(1));
+        // Characters are counted, not bytes
+        assert_eq!(signup_cost(&state, "añ"), Amount::from_sat(4));
+        assert_eq!(signup_cost(&state, "ñññ"), Amount::from_sat(3));
+    }
+
+    #[test]
+    fn test_validate_invoice_amount() {
+        let min = Amount::from_sat(1);
+        let max = Amount::from_sat(100);
+
+        assert_eq!(
+            validate_invoice_amount(1_000, min, max, true).unwrap(),
+            Amount::from_sat(1)
+        );
+        assert_eq!(
+            validate_invoice_amount(100_000, min, max, true).unwrap(),
+            Amount::from_sat(100)
+        );
+        assert!(validate_invoice_amount(0, min, max, true).is_err());
+        assert!(validate_invoice_amount(0, Amount::ZERO, max, false).is_err());
+        assert_eq!(
+            validate_invoice_amount(100_001, min, max, true)
+                .unwrap_err()
+                .reason,
+            "amount out of range"
+        );
+        assert!(validate_invoice_amount(999, min, max, true).is_err());
+        assert!(validate_invoice_amount(100_001, min, max, false).is_err());
+
+        // Proxied invoices can be for msat amounts
+        assert!(validate_invoice_amount(1_500, min, max, true).is_err());
+        assert_eq!(
+            validate_invoice_amount(1_500, min, max, false).unwrap(),
+            Amount::from_msat(1_500)
+        );
+    }
+
+    #[test]
+    fn test_validate_amountless() {
+        assert!(validate_amountless(true, true, true).is_ok());
+
+        assert!(validate_amountless(false, true, true).is_err());
+        // Mints cannot issue amountless invoices
+        assert!(validate_amountless(true, false, true).is_err());
+        assert!(validate_amountless(true, true, false).is_err());
+    }
+
+    #[test]
+    fn test_request_domain() {
+        let domains = HashSet::from(["example.com".to_string(), "::1".to_string()]);
+
+        assert_eq!(
+            request_domain(&domains, "example.com"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            request_domain(&domains, "Example.com:8080"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            request_domain(&domains, "[::1]:8080"),
+            Some("::1".to_string())
+        );
+        assert_eq!(request_domain(&domains, "example.org"), None);
+    }
+
+    #[test]
+    fn test_success_action_serialization() {
+        let action =
+            SuccessAction::message("Your ecash will be DM'd to {username} on Nostr", "bob");
+
+        assert_eq!(
+            serde_json::to_string(&action).unwrap(),
+            r#"{"tag":"message","message":"Your ecash will be DM'd to bob on Nostr"}"#
+        );
+
+        let response = GetInvoiceResponse {
+            pr: "lnbc1".to_string(),
+            success_action: Some(action),
+            routes: vec![],
+            verify: Url::from_str("https://example.com/lnurlp/bob/verify/abc").unwrap(),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"pr":"lnbc1","successAction":{"tag":"message","message":"Your ecash will be DM'd to bob on Nostr"},"routes":[],"verify":"https://example.com/lnurlp/bob/verify/abc"}"#
+        );
+
+        // Wallets expect both keys even when there is nothing to send
+        let response = GetInvoiceResponse {
+            success_action: None,
+            ..response
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"pr":"lnbc1","successAction":null,"routes":[],"verify":"https://example.com/lnurlp/bob/verify/abc"}"#
+        );
+
+        let response: GetInvoiceResponse = serde_json::from_str(
+            r#"{"pr":"lnbc1","successAction":null,"routes":[[{"nodeId":"02ab","channelUpdate":"0102"}]],"verify":"https://example.com/lnurlp/bob/verify/abc"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.routes,
+            vec![vec![RouteHop {
+                node_id: "02ab".to_string(),
+                channel_update: "0102".to_string(),
+            }]]
+        );
+
+        let action = SuccessAction::message(&"a".repeat(200), "bob");
+        assert!(matches!(
+            action,
+            SuccessAction::Message { message } if message.chars().count() == SUCCESS_MESSAGE_MAX_LEN
+        ));
+
+        let base = Url::from_str("https://example.com/").unwrap();
+        let action = SuccessAction::url(
+            "Claim the ecash sent to {username}",
+            "bob",
+            redeem_url(&base, "example.com", "abc").unwrap(),
+        );
+
+        assert_eq!(
+            serde_json::to_string(&action).unwrap(),
+            r#"{"tag":"url","description":"Claim the ecash sent to bob","url":"https://example.com/redeem/abc"}"#
+        );
+
+        let preimage = [7; 32];
+        let action = SuccessAction::aes(
+            "Claim the ecash sent to {username}",
+            "bob",
+            "https://example.com/redeem/abc",
+            &preimage,
+        );
+        let (ciphertext, iv) = match action {
+            SuccessAction::Aes {
+                description,
+                ciphertext,
+                iv,
+            } => {
+                assert_eq!(description, "Claim the ecash sent to bob");
+                (ciphertext, iv)
+            }
+            other => panic!("Unexpected action {:?}", other),
+        };
+
+        let ciphertext = general_purpose::STANDARD.decode(ciphertext).unwrap();
+        let iv: [u8; 16] = general_purpose::STANDARD
+            .decode(iv)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let plaintext = cbc::Decryptor::<aes::Aes256>::new(&preimage.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+            .unwrap();
+        assert_eq!(plaintext, b"https://example.com/redeem/abc");
+    }
+
+    #[test]
+    fn test_invoice_description() {
+        let metadata = lnurl_metadata("Hello world", "alice@example.com", None).unwrap();
+        assert_eq!(invoice_description(None, metadata.clone(), None), metadata);
+
+        let payer_data = r#"{"name":"Alice"}"#;
+        assert_eq!(
+            invoice_description(None, metadata.clone(), Some(payer_data)),
+            format!("{}{}", metadata, payer_data)
+        );
+
+        let zap_request = r#"{"kind":9734,"content":"","tags":[["p","9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31"],["amount","1000000"],["relays","wss://relay.example.com"]]}"#;
+        let description = invoice_description(Some(zap_request), metadata, Some(payer_data));
+        assert_eq!(description, zap_request);
+
+        // Invoice created with only the hash of the produced description, which zappers
+        // check is the hash of the zap request they sent
+        let bolt11 = dry_run_invoice(
+            Some(Amount::from_sat(1000)),
+            description,
+            true,
+            [1; 32],
+            None,
+        )
+        .unwrap();
+        let raw_invoice = bolt11.into_signed_raw();
+        assert!(raw_invoice.raw_invoice().description().is_none());
+        let description_hash = raw_invoice
+            .raw_invoice()
+            .description_hash()
+            .map(|hash| hash.0.to_string());
+        assert_eq!(
+            description_hash,
+            Some(hex::encode(Sha256::digest(zap_request.as_bytes())))
+        );
+    }
+
+    #[test]
+    fn test_encode_lnurl() {
+        // LUD-01 example
+        let url = Url::parse("https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df").unwrap();
+        assert_eq!(encode_lnurl(&url).unwrap(), "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS");
+
+        let base = Url::parse("https://api.example.com:8080").unwrap();
+        let url = well_known_url(&base, "example.com", "alice").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://example.com:8080/.well-known/lnurlp/alice"
+        );
+        assert_eq!(
+            encode_lnurl(&url).unwrap(),
+            "LNURL1DP68GURN8GHJ7ETCV9KHQMR99E3K7MF68QCRSVP09EMK2MRV944KUMMHDCHKCMN4WFK8QTMPD35KXEGYSF9TT"
+        );
+    }
+
+    #[test]
+    fn test_signup_page() {
+        let signup_url = Url::from_str("https://example.com/signup").unwrap();
+        let page = signup_page("<b>example.com</b>", "abc123", &signup_url);
+
+        assert!(page.contains("&lt;b&gt;example.com&lt;/b&gt;"));
+        assert!(!page.contains("<b>"));
+        assert!(page.contains("abc123"));
+        assert!(page.contains("\"https://example.com/signup\""));
+        assert!(!page.contains("{{"));
+    }
+
+    #[tokio::test]
+    async fn test_get_redeem() {
+        let state = test_state().await;
+        state
+            .db
+            .add_claim(
+                "abc",
+                &Claim {
+                    token: Some("cashuAeyJ0b2tlbiI6W119".to_string()),
+                    expire: unix_time() + 60,
+                },
+            )
+            .await
+            .unwrap();
+
+        let read_page = |response: Response| async move {
+            let mut body = response.into_body();
+            let mut page = Vec::new();
+            while let Some(chunk) = body.data().await {
+                page.extend_from_slice(&chunk.unwrap());
+            }
+            String::from_utf8(page).unwrap()
+        };
+        let claim = |id: &str| get_claim(State(state.clone()), Path(id.to_string()));
+
+        // Loading the page, as link previews do, leaves the token to be claimed
+        for _ in 0..2 {
+            let response = get_redeem().await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+            let page = read_page(response).await;
+            assert!(!page.contains("cashuAeyJ0b2tlbiI6W119"));
+            assert!(page.contains("method: \"POST\""));
+        }
+
+        // The page's button claims it once
+        let Json(claimed) = claim("abc").await.unwrap();
+        assert_eq!(claimed.token, "cashuAeyJ0b2tlbiI6W119");
+        assert_eq!(claim("abc").await.unwrap_err().code, StatusCode::GONE);
+        assert_eq!(
+            claim("missing").await.unwrap_err().code,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_split_plus_tag() {
+        assert_eq!(split_plus_tag("alice"), ("alice", None));
+        assert_eq!(
+            split_plus_tag("alice+shop"),
+            ("alice", Some("shop".to_string()))
+        );
+        assert_eq!(
+            split_plus_tag("alice+<b>sh op</b>+x"),
+            ("alice", Some("bshopbx".to_string()))
+        );
+        assert_eq!(split_plus_tag("alice+"), ("alice", None));
+        assert_eq!(split_plus_tag("+shop"), ("+shop", None));
+
+        let (_, tag) = split_plus_tag(&format!("alice+{}", "a".repeat(100)));
+        assert_eq!(tag.unwrap().len(), PLUS_TAG_MAX_LEN);
+    }
+
+    #[test]
+    fn test_lnurlp_url() {
+        let base = Url::from_str("https://example.com/").unwrap();
+
+        let url = lnurlp_url(&base, "example.org", &["alice", "verify", "abc123"]).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://example.org/lnurlp/alice/verify/abc123"
+        );
+
+        let url = redeem_url(&base, "example.org", "abc").unwrap();
+        assert_eq!(url.as_str(), "https://example.org/redeem/abc");
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_account() {
+        let mut state = test_state().await;
+        // Deleting must drop the cached response or the user is still served
+        state.lnurl_cache = Some(Arc::new(TtlCache::new(Duration::from_secs(60))));
+        let keys = Keys::generate();
+
+        let user = test_user(&keys.public_key().to_string());
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let lookup = || user_lnurl_response(&state, "example.com", "alice");
+        let delete_with = |event: Event, headers: HeaderMap| {
+            let body = serde_json::to_vec(&DeleteUserParams {
+                event: Some(event),
+                goodbye: Some(false),
+            })
+            .unwrap();
+
+            delete_user_account(
+                State(state.clone()),
+                Host("example.com".to_string()),
+                Path("alice".to_string()),
+                Method::DELETE,
+                OriginalUri("/users/alice".parse().unwrap()),
+                headers,
+                Bytes::from(body),
+            )
+        };
+        let delete = |keys: &Keys, headers: HeaderMap| {
+            delete_with(auth_event(keys, "alice", "DELETE", "/users/alice"), headers)
+        };
+
+        assert!(lookup().await.is_ok());
+        assert!(state
+            .lnurl_cache
+            .as_ref()
+            .unwrap()
+            .get("example.com", "alice", Instant::now())
+            .is_some());
+
+        // Signed by another pubkey
+        let err = delete(&Keys::generate(), HeaderMap::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+        assert!(lookup().await.is_ok());
+
+        // Signed to update the user, or without tags
+        let update = auth_event(&keys, "alice", "PUT", "/users/alice");
+        let err = delete_with(update, HeaderMap::new()).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+        let untagged = EventBuilder::new(Kind::TextNote, "alice", &[])
+            .to_event(&keys)
+            .unwrap();
+        let err = delete_with(untagged, HeaderMap::new()).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+        assert!(lookup().await.is_ok());
+
+        assert_eq!(
+            delete(&keys, HeaderMap::new()).await.unwrap(),
+            StatusCode::OK
+        );
+        assert_eq!(lookup().await.unwrap_err().code, StatusCode::NOT_FOUND);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let err = delete(&Keys::generate(), headers).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_put_user_limits_auth_header() {
+        let state = test_state().await;
+        let keys = Keys::generate();
+
+        let user = test_user(&keys.public_key().to_string());
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let body = br#"{"min_sendable":null,"max_sendable":null}"#;
+        let header = |payload: &[u8]| {
+            let tags = [
+                Tag::Generic(
+                    TagKind::Custom("u".to_string()),
+                    vec!["https://example.com/users/alice/limits".to_string()],
+                ),
+                Tag::Generic(
+                    TagKind::Custom("method".to_string()),
+                    vec!["PUT".to_string()],
+                ),
+                Tag::Generic(
+                    TagKind::Custom("payload".to_string()),
+                    vec![hex::encode(Sha256::digest(payload))],
+                ),
+            ];
+            let event = EventBuilder::new(Kind::TextNote, "alice", &tags)
+                .to_event(&keys)
+                .unwrap();
+            let encoded = general_purpose::STANDARD.encode(serde_json::to_vec(&event).unwrap());
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::AUTHORIZATION,
+                format!("Nostr {}", encoded).parse().unwrap(),
+            );
+            headers
+        };
+        let put = |headers: HeaderMap| {
+            put_user_limits(
+                State(state.clone()),
+                Host("example.com".to_string()),
+                Path("alice".to_string()),
+                Method::PUT,
+                OriginalUri("/users/alice/limits".parse().unwrap()),
+                headers,
+                Bytes::from_static(body),
+            )
+        };
+
+        // Signed for another body
+        let err = put(header(b"{}")).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+        assert_eq!(err.reason, "Auth event is for another payload");
+
+        let headers = header(body);
+        assert_eq!(put(headers.clone()).await.unwrap(), StatusCode::OK);
+        let err = put(headers).await.unwrap_err();
+        assert_eq!(err.reason, "Auth event was already used");
+
+        let err = put(HeaderMap::new()).await.unwrap_err();
+        assert_eq!(err.reason, "Limits must be signed by the pubkey");
+    }
+
+    #[tokio::test]
+    async fn test_catch_all_user() {
+        let mut state = test_state().await;
+        state.catch_all_user = Some("alice".to_string());
+        state.lnurl_cache = Some(Arc::new(TtlCache::new(Duration::from_secs(60))));
+
+        let user = test_user(&Keys::generate().public_key().to_string());
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let lookup = |host: &'static str, username: &'static str| {
+            user_lnurl_response(&state, host, username)
+        };
+
+        // The requested address is kept in the metadata and callback
+        let response = lookup("example.com", "shop").await.unwrap();
+        assert_eq!(
+            response.metadata,
+            lnurl_metadata(&state.description, "shop@example.com", None).unwrap()
+        );
+        assert_eq!(
+            response.callback.as_str(),
+            "https://example.com/lnurlp/shop/invoice"
+        );
+        // Aliases are not cached so they follow changes to the catch-all user
+        assert!(state
+            .lnurl_cache
+            .as_ref()
+            .unwrap()
+            .get("example.com", "shop", Instant::now())
+            .is_none());
+
+        let response = lookup("example.com", "alice").await.unwrap();
+        assert_eq!(
+            response.metadata,
+            lnurl_metadata(&state.description, "alice@example.com", None).unwrap()
+        );
+
+        // Plus addresses resolve to the user before the tag
+        let response = lookup("example.com", "alice+tips").await.unwrap();
+        assert_eq!(
+            response.metadata,
+            lnurl_metadata(&state.description, "alice+tips@example.com", None).unwrap()
+        );
+        assert_eq!(
+            response.callback.as_str(),
+            "https://example.com/lnurlp/alice+tips/invoice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_etag() {
+        let state = test_state().await;
+        let mut user = test_user(&Keys::generate().public_key().to_string());
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user.clone()))
+            .await
+            .unwrap();
+
+        let lookup = |if_none_match: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(etag) = if_none_match {
+                headers.insert(header::IF_NONE_MATCH, etag.parse().unwrap());
+            }
+            get_user_lnurl_struct(
+                State(state.clone()),
+                Host("example.com".to_string()),
+                Path("alice".to_string()),
+                headers,
+            )
+        };
+
+        let response = lookup(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "private, max-age=300"
+        );
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = lookup(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        let weak = format!("\"other\", W/{}", etag);
+        let response = lookup(Some(&weak)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = lookup(Some("\"other\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Changed limits change the tag
+        user.max_sendable = Some(Amount::from_sat(1000));
+        state
+            .db
+            .update_user("example.com", "alice", &user)
+            .await
+            .unwrap();
+        let response = lookup(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_lookup() {
+        let mut state = test_state().await;
+        state.lnurl_cache = Some(Arc::new(TtlCache::new(Duration::from_secs(60))));
+
+        for username in ["alice", "\u{e5}sa"] {
+            let user = User {
+                username: username.to_string(),
+                ..test_user(&Keys::generate().public_key().to_string())
+            };
+            state
+                .db
+                .add_user("example.com", username, &UserKind::User(user))
+                .await
+                .unwrap();
+        }
+
+        let lookup = |username: &'static str| user_lnurl_response(&state, "example.com", username);
+
+        for username in ["alice", "Alice", "ALICE"] {
+            let response = lookup(username).await.unwrap();
+            assert_eq!(
+                response.metadata,
+                lnurl_metadata(&state.description, "alice@example.com", None).unwrap()
+            );
+            assert_eq!(
+                response.callback.as_str(),
+                "https://example.com/lnurlp/alice/invoice"
+            );
+        }
+        // Decomposed and upper case, as some keyboards produce it
+        assert!(lookup("A\u{30a}sa").await.is_ok());
+
+        // Other lookups resolve the same user
+        let Json(info) = get_user_info(
+            State(state.clone()),
+            Host("example.com".to_string()),
+            Path("ALICE".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(info.username, "alice");
+        let Json(address) = get_user_lnurl(
+            State(state.clone()),
+            Host("example.com".to_string()),
+            Path("Alice".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(address.address, "alice@example.com");
+
+        // Cached once under the normalized username
+        let cache = state.lnurl_cache.as_ref().unwrap();
+        assert!(cache.get("example.com", "alice", Instant::now()).is_some());
+        invalidate_lnurl(&state, "example.com", "Alice");
+        assert!(cache.get("example.com", "alice", Instant::now()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_admin_users() {
+        let state = test_state().await;
+
+        for username in ["alice", "bob"] {
+            let user = User {
+                username: username.to_string(),
+                created_at: Some(1),
+                ..test_user(&Keys::generate().public_key().to_string())
+            };
+            state
+                .db
+                .add_user("example.com", username, &UserKind::User(user))
+                .await
+                .unwrap();
+        }
+
+        let Json(page) = get_admin_users(
+            State(state.clone()),
+            Ok(Query(AdminUsersParams {
+                offset: Some(1),
+                limit: Some(5000),
+            })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.offset, 1);
+        assert_eq!(page.limit, ADMIN_USERS_MAX_LIMIT);
+        assert_eq!(page.users.len(), 1);
+        assert_eq!(page.users[0].created_at, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_get_admin_pending_invoices() {
+        let state = test_state().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        let now = unix_time();
+        for (hash, time) in [("new", now), ("old", now - 3600)] {
+            let invoice = PendingInvoice {
+                time,
+                hash: hash.to_string(),
+                ..test_invoice(bolt11.clone())
+            };
+            state.db.add_pending_invoice(hash, &invoice).await.unwrap();
+        }
+
+        let list = |older_than| {
+            get_admin_pending_invoices(
+                State(state.clone()),
+                Ok(Query(AdminPendingInvoicesParams {
+                    username: Some("alice".to_string()),
+                    older_than,
+                })),
+            )
+        };
 
-            /*
-                        let nostr = state.nostr.clone();
+        let Json(all) = list(None).await.unwrap();
+        assert_eq!(all.invoices.len(), 2);
+        assert_eq!(all.invoices[0].hash, "old");
+        assert_eq!(all.invoices[0].amount_msat, 1_000_000);
 
-                        let _ = thread::spawn(move || {
-                            let _ = tokio::runtime::Runtime::new()
-                                .unwrap()
-                                .block_on(nostr.send_sign_up_message(&params.username, &user));
-                        });
-            */
+        let Json(stuck) = list(Some(600)).await.unwrap();
+        assert_eq!(stuck.invoices.len(), 1);
+        assert_eq!(stuck.invoices[0].hash, "old");
+    }
 
-            match user {
-                UserKind::User(_) => Ok(Json("Ok".to_string())),
-                UserKind::Pending(user) => Ok(Json(user.pr.to_string())),
-                _ => {
-                    warn!("Unexpected user type");
-                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    #[tokio::test]
+    async fn test_get_admin_report() {
+        let state = test_state().await;
+        state
+            .db
+            .add_payment_record(&PaymentRecord {
+                hash: "paid".to_string(),
+                domain: "example.com".to_string(),
+                username: "alice".to_string(),
+                mint: Url::from_str("https://mint.example.com").unwrap(),
+                amount_msat: 10_000,
+                fee_msat: 100,
+                minted_msat: Some(9_900),
+                proxied: true,
+                comment: None,
+                delivered: false,
+                paid_at: 1_700_000_000,
+            })
+            .await
+            .unwrap();
+
+        let report = |headers: HeaderMap| {
+            get_admin_report(
+                State(state.clone()),
+                headers,
+                Ok(Query(AdminReportParams {
+                    from: Some(1_699_920_000),
+                    to: Some(1_700_006_400),
+                    username: Some("Alice".to_string()),
+                })),
+            )
+        };
+
+        let mut headers = HeaderMap::new();
+        let response = report(headers.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        headers.insert(header::ACCEPT, "text/csv".parse().unwrap());
+        assert!(accepts_csv(&headers));
+        let response = report(headers).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+    }
+
+    #[tokio::test]
+    async fn test_get_admin_payments_csv() {
+        let state = test_state().await;
+        // More records than fit in a page
+        for i in 0..PAYMENT_RECORDS_PAGE as u64 + 1 {
+            state
+                .db
+                .add_payment_record(&PaymentRecord {
+                    hash: format!("{:04}", i),
+                    domain: "example.com".to_string(),
+                    username: "alice".to_string(),
+                    mint: Url::from_str("https://mint.example.com").unwrap(),
+                    amount_msat: 10_000,
+                    fee_msat: 100,
+                    minted_msat: None,
+                    proxied: false,
+                    comment: (i == 0).then(|| "Hi, alice".to_string()),
+                    delivered: false,
+                    paid_at: 1_700_000_000 + i,
+                })
+                .await
+                .unwrap();
+        }
+
+        let response = get_admin_payments_csv(
+            State(state.clone()),
+            Ok(Query(AdminReportParams {
+                from: Some(1_700_000_000),
+                to: None,
+                username: None,
+            })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+
+        let mut body = response.into_body();
+        let mut csv = Vec::new();
+        while let Some(chunk) = body.data().await {
+            csv.extend_from_slice(&chunk.unwrap());
+        }
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), PAYMENT_RECORDS_PAGE + 2);
+        assert_eq!(lines[0], PAYMENTS_CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "1700000000,alice,example.com,0000,10000,100,https://mint.example.com/,false,,false,\"Hi, alice\""
+        );
+        assert!(lines[PAYMENT_RECORDS_PAGE + 1].starts_with("1700000500,alice,example.com,0500,"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_admin_invoice() {
+        let state = test_state().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        for (hash, settled) in [("unpaid", false), ("paid", true)] {
+            let invoice = PendingInvoice {
+                hash: hash.to_string(),
+                ..test_invoice(bolt11.clone())
+            };
+            state.db.add_pending_invoice(hash, &invoice).await.unwrap();
+
+            let payer_invoice = PayerInvoice {
+                username: "alice".to_string(),
+                domain: "example.com".to_string(),
+                bolt11: bolt11.clone(),
+                settled,
+                preimage: None,
+            };
+            state
+                .db
+                .add_payer_invoice(hash, &payer_invoice)
+                .await
+                .unwrap();
+        }
+
+        let cancel =
+            |hash: &str| delete_admin_invoice(State(state.clone()), Path(hash.to_string()));
+
+        let err = cancel("paid").await.unwrap_err();
+        assert_eq!(err.code, StatusCode::CONFLICT);
+        assert!(state
+            .db
+            .get_pending_invoice("paid")
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(cancel("unpaid").await.unwrap(), StatusCode::OK);
+        assert!(state
+            .db
+            .get_pending_invoice("unpaid")
+            .await
+            .unwrap()
+            .is_none());
+
+        let err = cancel("unpaid").await.unwrap_err();
+        assert_eq!(err.code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_invites() {
+        let mut state = test_state().await;
+
+        let create =
+            |params: CreateInviteParams| post_admin_invite(State(state.clone()), Ok(Json(params)));
+
+        let Json(single) = create(CreateInviteParams::default()).await.unwrap();
+        assert_eq!(single.max_uses, 1);
+        assert_eq!(single.expire, None);
+        let Json(expiring) = create(CreateInviteParams {
+            code: Some("friends".to_string()),
+            max_uses: Some(2),
+            expires_in: Some(3600),
+        })
+        .await
+        .unwrap();
+        assert_eq!(expiring.code, "friends");
+        assert!(expiring.expire.unwrap() > unix_time());
+
+        let err = create(CreateInviteParams {
+            max_uses: Some(0),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, StatusCode::BAD_REQUEST);
+
+        let Json(invites) = get_admin_invites(State(state.clone())).await.unwrap();
+        assert_eq!(invites.len(), 2);
+
+        // Codes are only needed while signups are disabled
+        assert_eq!(check_invite(&state, None).await.unwrap(), None);
+        state.signups_disabled = true;
+
+        for invite in [None, Some("unknown")] {
+            let err = check_invite(&state, invite).await.unwrap_err();
+            assert_eq!(err.code, StatusCode::FORBIDDEN);
+        }
+
+        // Checking a code does not use it up
+        for _ in 0..2 {
+            assert_eq!(
+                check_invite(&state, Some(&single.code)).await.unwrap(),
+                Some(single.code.clone())
+            );
+        }
+        assert!(use_invite(&state, &single.code).await.is_ok());
+        let err = check_invite(&state, Some(&single.code)).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::FORBIDDEN);
+        assert_eq!(err.reason, "Invite code is used up or expired");
+        let err = use_invite(&state, &single.code).await.unwrap_err();
+        assert_eq!(err.reason, "Invite code is used up or expired");
+
+        assert_eq!(
+            delete_admin_invite(State(state.clone()), Path("friends".to_string()))
+                .await
+                .unwrap(),
+            StatusCode::OK
+        );
+        let err = check_invite(&state, Some("friends")).await.unwrap_err();
+        assert_eq!(err.reason, "Invalid invite code");
+        let err = use_invite(&state, "friends").await.unwrap_err();
+        assert_eq!(err.reason, "Invalid invite code");
+        let err = delete_admin_invite(State(state.clone()), Path("friends".to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_idempotency() {
+        let key = idempotency_key("example.com", "alice", "abc");
+        assert_eq!(key, idempotency_key("example.com", "alice", "abc"));
+        assert_ne!(key, idempotency_key("example.com", "bob", "abc"));
+        assert_ne!(key, idempotency_key("example.com", "alice", "abd"));
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        let invoice = PendingInvoice {
+            idempotency_key: Some(key),
+            time: 100,
+            ..test_invoice(bolt11)
+        };
+
+        assert!(reusable_invoice(&invoice, 100));
+        assert!(reusable_invoice(&invoice, 100 + IDEMPOTENCY_WINDOW));
+        assert!(!reusable_invoice(&invoice, 101 + IDEMPOTENCY_WINDOW));
+        assert!(!reusable_invoice(
+            &PendingInvoice {
+                failed: true,
+                ..invoice.clone()
+            },
+            100
+        ));
+        // A claim url is only for the payer
+        assert!(!reusable_invoice(
+            &PendingInvoice {
+                claim_id: Some("claim".to_string()),
+                ..invoice
+            },
+            100
+        ));
+    }
+
+    #[test]
+    fn test_invoice_status() {
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5wtxkappzcsrlkmgfs6g0zyct0hkhashh7hsaxz7e65slq9fkx7fssp5qgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqhp5mqr72qwjnd7y0h26zqpf78u93z544gw06tckpwenmyg3qmu96w3qxqrrssjnguka946mhe2ppv6jrp45nplmpnwulkx5qz5p0jqkl0kgq7kxxyy0qfa36894k05jqam7f0kxk8ngc4dqrgfgh4p00gg7e3l05mwcqp6qcwld").unwrap();
+        let pending = PendingInvoice {
+            time: 1,
+            ..test_invoice(bolt11.clone())
+        };
+        let payer = PayerInvoice {
+            username: "alice".to_string(),
+            domain: "example.com".to_string(),
+            bolt11,
+            settled: false,
+            preimage: None,
+        };
+        let settled = PayerInvoice {
+            settled: true,
+            ..payer.clone()
+        };
+        let failed = PendingInvoice {
+            failed: true,
+            ..pending.clone()
+        };
+        let proxied = PendingInvoice {
+            proxied: true,
+            ..pending.clone()
+        };
+
+        assert_eq!(
+            InvoiceStatus::new(Some(&pending), Some(&payer)),
+            Some(InvoiceStatus::WaitingForPayment)
+        );
+        assert_eq!(
+            InvoiceStatus::new(Some(&pending), Some(&settled)),
+            Some(InvoiceStatus::WaitingForMint)
+        );
+        assert_eq!(
+            InvoiceStatus::new(Some(&proxied), None),
+            Some(InvoiceStatus::WaitingForMint)
+        );
+        assert_eq!(
+            InvoiceStatus::new(Some(&failed), Some(&payer)),
+            Some(InvoiceStatus::Failed)
+        );
+        assert_eq!(
+            InvoiceStatus::new(None, Some(&settled)),
+            Some(InvoiceStatus::Settled)
+        );
+        assert_eq!(
+            InvoiceStatus::new(None, Some(&payer)),
+            Some(InvoiceStatus::Cancelled)
+        );
+        assert_eq!(InvoiceStatus::new(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_verify_notification_params() {
+        let state = test_state().await;
+        let keys = Keys::generate();
+
+        let user = test_user(&keys.public_key().to_string());
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let event = |keys: &Keys, username: &str| {
+            auth_event(keys, username, "GET", &format!("/ws/{}", username))
+        };
+        let params = |keys: &Keys, username: &str| NotificationParams {
+            event: Some(serde_json::to_string(&event(keys, username)).unwrap()),
+        };
+        let verify_with =
+            |username: &'static str, headers: HeaderMap, params: NotificationParams| {
+                let state = state.clone();
+                async move {
+                    let path = format!("/ws/{}", username);
+                    let request = AuthRequest {
+                        method: &Method::GET,
+                        path: &path,
+                        body: None,
+                    };
+                    verify_notification_params(
+                        &state,
+                        "example.com",
+                        username,
+                        &headers,
+                        request,
+                        &params,
+                    )
+                    .await
                 }
-            }
+            };
+        let verify = |username: &'static str, params: NotificationParams| {
+            verify_with(username, HeaderMap::new(), params)
+        };
+
+        let logged = params(&keys, "alice");
+        assert!(verify("alice", logged.clone()).await.is_ok());
+        // An event seen in a log cannot be replayed
+        let err = verify("alice", logged).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+
+        // The event may be sent in the header instead of the query
+        let mut headers = HeaderMap::new();
+        let encoded =
+            general_purpose::STANDARD.encode(serde_json::to_vec(&event(&keys, "alice")).unwrap());
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Nostr {}", encoded).parse().unwrap(),
+        );
+        assert!(verify_with("alice", headers, NotificationParams::default())
+            .await
+            .is_ok());
+
+        // Signed to export the user
+        let export = NotificationParams {
+            event: Some(
+                serde_json::to_string(&auth_event(&keys, "alice", "GET", "/users/alice/export"))
+                    .unwrap(),
+            ),
+        };
+        let err = verify("alice", export).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+
+        for (username, params, code) in [
+            (
+                "alice",
+                NotificationParams::default(),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                "alice",
+                NotificationParams {
+                    event: Some("not an event".to_string()),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "alice",
+                params(&Keys::generate(), "alice"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            ("alice", params(&keys, "bob"), StatusCode::UNAUTHORIZED),
+            ("bob", params(&keys, "bob"), StatusCode::NOT_FOUND),
+        ] {
+            assert_eq!(verify(username, params).await.unwrap_err().code, code);
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[tokio::test]
+    async fn test_get_user_export() {
+        let state = test_state().await;
+        let keys = Keys::generate();
 
-    use std::str::FromStr;
+        let user = test_user(&keys.public_key().to_string());
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+        // More records than fit in a page, and one of another user
+        for (i, username) in (0..PAYMENT_RECORDS_PAGE as u64 + 1)
+            .map(|i| (i, "alice"))
+            .chain([(PAYMENT_RECORDS_PAGE as u64 + 1, "bob")])
+        {
+            state
+                .db
+                .add_payment_record(&PaymentRecord {
+                    hash: format!("{:04}", i),
+                    domain: "example.com".to_string(),
+                    username: username.to_string(),
+                    mint: Url::from_str("https://mint.example.com").unwrap(),
+                    amount_msat: 10_000,
+                    fee_msat: 100,
+                    minted_msat: Some(9_900),
+                    proxied: true,
+                    comment: None,
+                    delivered: false,
+                    paid_at: 1_700_000_000 + i,
+                })
+                .await
+                .unwrap();
+        }
+        state
+            .db
+            .add_token_delivery(&TokenDelivery {
+                hash: "ab".to_string(),
+                payer_hash: Some("0000".to_string()),
+                domain: "example.com".to_string(),
+                username: "alice".to_string(),
+                claim_id: Some("claim".to_string()),
+                pubkey: keys.public_key().to_string(),
+                token: "cashuAtoken".to_string(),
+                comment: None,
+                payer_data: None,
+                alias: None,
+                relays: HashSet::new(),
+                attempts: 1,
+                next_attempt: 1_700_000_600,
+                error: Some("No relays".to_string()),
+                created_at: 1_700_000_000,
+            })
+            .await
+            .unwrap();
 
-    use super::*;
+        let export = |keys: &Keys| {
+            let event = auth_event(keys, "alice", "GET", "/users/alice/export");
+            get_user_export(
+                State(state.clone()),
+                Host("example.com".to_string()),
+                Path("alice".to_string()),
+                Method::GET,
+                OriginalUri("/users/alice/export".parse().unwrap()),
+                HeaderMap::new(),
+                Ok(Query(ExportParams {
+                    event: Some(serde_json::to_string(&event).unwrap()),
+                })),
+            )
+        };
+
+        let err = export(&Keys::generate()).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::UNAUTHORIZED);
+
+        let response = export(&keys).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let mut body = response.into_body();
+        let mut json = Vec::new();
+        while let Some(chunk) = body.data().await {
+            json.extend_from_slice(&chunk.unwrap());
+        }
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["user"]["username"], "alice");
+        assert_eq!(json["pending_invoices"], serde_json::json!([]));
+        assert_eq!(json["undelivered_tokens"][0]["token"], "cashuAtoken");
+
+        let payments = json["payments"].as_array().unwrap();
+        assert_eq!(payments.len(), PAYMENT_RECORDS_PAGE + 1);
+        assert_eq!(payments[0]["hash"], "0000");
+        assert_eq!(payments[PAYMENT_RECORDS_PAGE]["hash"], "0500");
+    }
+
+    #[tokio::test]
+    async fn test_signup_username_rules() {
+        let state = test_state().await;
+        let keys = Keys::generate();
+
+        let signup = |username: &str| {
+            let event = EventBuilder::new(Kind::TextNote, username, &[])
+                .to_event(&keys)
+                .unwrap();
+            let params = SignupParams {
+                username: username.to_string(),
+                pubkey: keys.clone(),
+                proxy: None,
+                mint: Url::from_str("https://mint.example.com").unwrap(),
+                fallback_mints: vec![],
+                mint_rules: vec![],
+                relays: None,
+                min_sendable: None,
+                max_sendable: None,
+                success_message: None,
+                description: None,
+                avatar: None,
+                webhook_url: None,
+                webhook_secret: None,
+                lock_to_pubkey: None,
+                event: Some(event),
+                sig: None,
+                timestamp: None,
+                invite: None,
+            };
+            sign_up(state.clone(), "example.com", None, params)
+        };
+
+        // The rule broken is returned as the reason
+        for (username, reason) in [
+            ("alice/bob", "Username must match ^[a-z0-9_.-]+$"),
+            (
+                "alice\u{26a1}",
+                "Username can only contain ascii characters",
+            ),
+            (
+                "abcdefghijklmnopqrstuvwxyzabcdefg",
+                "Username cannot be longer than 32 characters",
+            ),
+        ] {
+            let err = signup(username).await.unwrap_err();
+            assert_eq!(err.code, StatusCode::BAD_REQUEST);
+            assert_eq!(err.reason, reason);
+        }
+
+        // Reserved usernames are refused however they are cased
+        for username in ["admin", "Admin", "_"] {
+            let err = signup(username).await.unwrap_err();
+            assert_eq!(err.code, StatusCode::FORBIDDEN);
+            assert_eq!(err.reason, "Username is reserved");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signup_invite_used_once_stored() {
+        let mut state = test_state().await;
+        let mut settings = Settings::default();
+        settings.info.dry_run = Some(true);
+        state.cashu = Cashu::new(
+            state.db.clone(),
+            state.nostr.clone(),
+            settings,
+            state.metrics.clone(),
+            state.notifier.clone(),
+            None,
+        )
+        .unwrap();
+        state.signups_disabled = true;
+        state
+            .db
+            .add_invite_code(&InviteCode {
+                code: "single".to_string(),
+                max_uses: 1,
+                uses: 0,
+                expire: None,
+                created_at: unix_time(),
+            })
+            .await
+            .unwrap();
+
+        let signup = |state: LnurlState, username: &str| {
+            let keys = Keys::generate();
+            let event = EventBuilder::new(Kind::TextNote, username, &[])
+                .to_event(&keys)
+                .unwrap();
+            let params = SignupParams {
+                username: username.to_string(),
+                pubkey: keys,
+                proxy: None,
+                mint: Url::from_str("https://mint.example.com").unwrap(),
+                fallback_mints: vec![],
+                mint_rules: vec![],
+                relays: None,
+                min_sendable: None,
+                max_sendable: None,
+                success_message: None,
+                description: None,
+                avatar: None,
+                webhook_url: None,
+                webhook_secret: None,
+                lock_to_pubkey: None,
+                event: Some(event),
+                sig: None,
+                timestamp: None,
+                invite: Some("single".to_string()),
+            };
+            sign_up(state, "example.com", None, params)
+        };
+        let uses = || async {
+            state
+                .db
+                .get_invite_code("single")
+                .await
+                .unwrap()
+                .unwrap()
+                .uses
+        };
+
+        // A paid username whose invoice cannot be created does not cost a use
+        let paid = LnurlState {
+            other_char_cost: Amount::from_sat(1000),
+            ..state.clone()
+        };
+        assert!(signup(paid, "alice").await.is_err());
+        assert_eq!(uses().await, 0);
+
+        signup(state.clone(), "alice").await.unwrap();
+        assert_eq!(uses().await, 1);
+
+        let err = signup(state.clone(), "bob").await.unwrap_err();
+        assert_eq!(err.reason, "Invite code is used up or expired");
+        assert!(state
+            .db
+            .get_user("example.com", "bob")
+            .await
+            .unwrap()
+            .is_none());
+    }
 
     #[test]
-    fn test_lnurl_response_serialization() {
-        let lnurl_response = LnurlResponse {
-            min_sendable: Amount::from_sat(0),
-            max_sendable: Amount::from_sat(1000),
-            metadata: serde_json::to_string(&vec![vec![
-                "text/plain".to_string(),
-                "Hello world".to_string(),
-            ]])
-            .unwrap(),
-            callback: Url::from_str("http://example.com").unwrap(),
-            tag: LnurlTag::PayRequest,
-            allows_nostr: true,
+    fn test_update_user_params_apply() {
+        let keys = Keys::generate();
+
+        let mut user = test_user(&keys.public_key().to_string());
+
+        let new_keys = Keys::generate();
+        let params = UpdateUserParams {
+            mint: Some(Url::from_str("https://other.example.com").unwrap()),
+            fallback_mints: Some(vec![Url::from_str("https://mint.example.com").unwrap()]),
+            mint_rules: None,
+            relays: None,
+            proxy: Some(false),
+            pubkey: Some(new_keys.public_key().to_string()),
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: None,
+            event: None,
+        };
+
+        let changes = params.apply(&mut user).unwrap();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(user.mint.as_str(), "https://other.example.com/");
+        assert_eq!(
+            changes[1],
+            "Fallback mints changed to https://mint.example.com/"
+        );
+        assert_eq!(user.pubkey, new_keys.public_key().to_string());
+        assert!(!user.proxy);
+        // The registered mint is tried first
+        assert_eq!(
+            user.mints(),
+            vec![
+                Url::from_str("https://other.example.com").unwrap(),
+                Url::from_str("https://mint.example.com").unwrap()
+            ]
+        );
+
+        let params = UpdateUserParams {
+            mint: None,
+            fallback_mints: None,
+            mint_rules: Some(vec![MintRule {
+                max_amount: Amount::from_sat(1000),
+                mint: Url::from_str("https://small.example.com").unwrap(),
+            }]),
+            relays: None,
+            proxy: None,
+            pubkey: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: None,
+            event: None,
+        };
+        assert_eq!(
+            params.apply(&mut user).unwrap(),
+            vec!["Mint rules changed to https://small.example.com/ up to 1000 sat"]
+        );
+        assert_eq!(user.mint_rules.len(), 1);
+
+        let params = UpdateUserParams {
+            mint: None,
+            fallback_mints: None,
+            mint_rules: None,
+            relays: None,
+            proxy: None,
+            pubkey: Some("not a pubkey".to_string()),
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: None,
+            event: None,
+        };
+        assert!(params.apply(&mut user).is_err());
+
+        let avatar = general_purpose::STANDARD.encode([PNG_SIGNATURE, &[0u8; 16][..]].concat());
+        let update = |description: &str, avatar: &str| UpdateUserParams {
+            mint: None,
+            fallback_mints: None,
+            mint_rules: None,
+            relays: None,
+            proxy: None,
+            pubkey: None,
+            description: Some(description.to_string()),
+            avatar: Some(avatar.to_string()),
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: None,
+            event: None,
+        };
+        let changes = update("Tips for alice", &avatar).apply(&mut user).unwrap();
+        assert_eq!(
+            changes,
+            vec!["Description changed to Tips for alice", "Avatar changed"]
+        );
+        assert_eq!(user.avatar, Some(avatar.clone()));
+        assert!(update("", "not a png").apply(&mut user).is_err());
+
+        // Empty values remove them
+        let changes = update("", "").apply(&mut user).unwrap();
+        assert_eq!(changes, vec!["Description removed", "Avatar removed"]);
+        assert_eq!(user.description, None);
+        assert_eq!(user.avatar, None);
+
+        let webhook = |url: &str, secret: Option<&str>| UpdateUserParams {
+            mint: None,
+            fallback_mints: None,
+            mint_rules: None,
+            relays: None,
+            proxy: None,
+            pubkey: None,
+            description: None,
+            avatar: None,
+            webhook_url: Some(url.to_string()),
+            webhook_secret: secret.map(str::to_string),
+            lock_to_pubkey: None,
+            event: None,
+        };
+        let secret = "0123456789abcdef";
+        // Posts are always signed
+        assert!(webhook("https://shop.example.com/paid", None)
+            .apply(&mut user.clone())
+            .is_err());
+        assert!(webhook("https://shop.example.com/paid", Some("short"))
+            .apply(&mut user.clone())
+            .is_err());
+        assert!(webhook("http://shop.example.com/paid", Some(secret))
+            .apply(&mut user.clone())
+            .is_err());
+
+        let changes = webhook("https://shop.example.com/paid", Some(secret))
+            .apply(&mut user)
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "Webhook changed to https://shop.example.com/paid",
+                "Webhook secret changed"
+            ]
+        );
+        assert_eq!(user.webhook_secret.as_deref(), Some(secret));
+
+        let changes = webhook("", None).apply(&mut user).unwrap();
+        assert_eq!(changes, vec!["Webhook removed"]);
+        assert_eq!(user.webhook_url, None);
+        assert_eq!(user.webhook_secret, None);
+    }
+
+    #[tokio::test]
+    async fn test_user_metadata() {
+        let state = test_state().await;
+        let avatar = general_purpose::STANDARD.encode([PNG_SIGNATURE, &[0u8; 64][..]].concat());
+        let user = User {
+            description: Some("Tips for alice".to_string()),
+            avatar: Some(avatar.clone()),
+            ..test_user(&Keys::generate().public_key().to_string())
+        };
+        state
+            .db
+            .add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+
+        let response = user_lnurl_response(&state, "example.com", "alice")
+            .await
+            .unwrap();
+        assert_eq!(
+            response.metadata,
+            serde_json::to_string(&[
+                ["text/plain", "Tips for alice"],
+                ["text/identifier", "alice@example.com"],
+                ["image/png;base64", avatar.as_str()],
+            ])
+            .unwrap()
+        );
+
+        // Addresses without a user of their own get the service description
+        assert_eq!(
+            user_metadata(&state, None, "bob@example.com").unwrap(),
+            lnurl_metadata(&state.description, "bob@example.com", None).unwrap()
+        );
+
+        // And the service avatar when one is configured, users keep their own
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.png", Uuid::new_v4()));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [PNG_SIGNATURE, &[1u8; 32][..]].concat()).unwrap();
+        let service_avatar = read_avatar(&path).unwrap();
+        let state = LnurlState {
+            avatar: Some(service_avatar.clone()),
+            ..state
+        };
+        assert_eq!(
+            user_metadata(&state, None, "bob@example.com").unwrap(),
+            lnurl_metadata(&state.description, "bob@example.com", Some(&service_avatar)).unwrap()
+        );
+        let response = user_lnurl_response(&state, "example.com", "alice")
+            .await
+            .unwrap();
+        assert!(response.metadata.contains(&avatar));
+
+        std::fs::write(&path, b"GIF89a").unwrap();
+        let err = read_avatar(&path).unwrap_err();
+        assert!(err.to_string().ends_with("Avatar must be a png"));
+        assert!(read_avatar(&path.with_extension("missing")).is_err());
+
+        assert!(validate_profile(
+            Some("a".repeat(DESCRIPTION_MAX_LEN).as_str()),
+            Some(avatar.as_str())
+        )
+        .is_ok());
+        let too_large = general_purpose::STANDARD
+            .encode([PNG_SIGNATURE, &[0u8; AVATAR_MAX_BYTES][..]].concat());
+        for (description, avatar, reason) in [
+            (
+                Some("a".repeat(DESCRIPTION_MAX_LEN + 1)),
+                None,
+                "Description cannot be longer than 256 chars",
+            ),
+            (
+                None,
+                Some("not base64!".to_string()),
+                "Avatar must be base64",
+            ),
+            (
+                None,
+                Some(general_purpose::STANDARD.encode(b"GIF89a")),
+                "Avatar must be a png",
+            ),
+            (
+                None,
+                Some(too_large),
+                "Avatar cannot be larger than 16384 bytes",
+            ),
+        ] {
+            let err = validate_profile(description.as_deref(), avatar.as_deref()).unwrap_err();
+            assert_eq!(err.reason, reason);
+        }
+
+        let err = lnurl_metadata("Hello world", &"a".repeat(METADATA_MAX_LEN), None).unwrap_err();
+        assert_eq!(err.reason, "Metadata cannot be longer than 32768 bytes");
+    }
+
+    #[test]
+    fn test_user_info_response() {
+        let user = User {
+            relays: HashSet::from(["wss://relay.example.com".to_string()]),
+            proxy: true,
+            ..test_user("9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31")
+        };
+
+        let user_info = UserInfoResponse::new(user.clone(), false);
+        assert_eq!("{\"username\":\"alice\",\"pubkey\":\"9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31\",\"mint\":\"https://mint.example.com/\",\"proxy\":true}", serde_json::to_string(&user_info).unwrap());
+
+        let user_info = UserInfoResponse::new(user, true);
+        assert!(serde_json::to_string(&user_info)
+            .unwrap()
+            .ends_with(",\"relays\":[\"wss://relay.example.com\"]}"));
+    }
+
+    #[test]
+    fn test_signup_response_serialization() {
+        let base = Url::from_str("https://api.example.com:8080").unwrap();
+        let lnurlp = well_known_url(&base, "example.com", "alice").unwrap();
+
+        let signup_response = SignupResponse {
+            address: lightning_address("alice", "example.com", &lnurlp),
+            lnurlp,
+            mint: Url::from_str("https://mint.example.com").unwrap(),
+            proxy: false,
             nostr_pubkey: Some(
                 "9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31".to_string(),
             ),
+            pr: None,
+        };
+
+        assert_eq!("{\"address\":\"alice@example.com:8080\",\"lnurlp\":\"https://example.com:8080/.well-known/lnurlp/alice\",\"mint\":\"https://mint.example.com/\",\"proxy\":false,\"nostr_pubkey\":\"9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31\"}", serde_json::to_string(&signup_response).unwrap());
+
+        // Username with a cost
+        let signup_response = SignupResponse {
+            pr: Some("lnbc1".to_string()),
+            ..signup_response
         };
 
-        assert_eq!("{\"minSendable\":0,\"maxSendable\":1000000,\"metadata\":\"[[\\\"text/plain\\\",\\\"Hello world\\\"]]\",\"callback\":\"http://example.com/\",\"tag\":\"payRequest\",\"allowsNostr\":true,\"nostrPubkey\":\"9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31\"}", serde_json::to_string(&lnurl_response).unwrap());
+        let json = serde_json::to_string(&signup_response).unwrap();
+        assert!(json.ends_with(",\"pr\":\"lnbc1\"}"));
+        assert_eq!(
+            serde_json::from_str::<SignupResponse>(&json).unwrap(),
+            signup_response
+        );
     }
 }
@@ -0,0 +1,223 @@
+//! Secrets read from config, the environment or a secrets file, optionally age encrypted
+
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use age::armor::ArmoredReader;
+use age::secrecy::SecretString;
+use anyhow::{anyhow, bail, Result};
+use nostr_sdk::secp256k1::SecretKey;
+use nostr_sdk::Keys;
+use serde::{Deserialize, Deserializer};
+use zeroize::Zeroizing;
+
+/// Start of an armored age file
+const AGE_ARMOR_HEADER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Start of a binary age file
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1";
+
+/// Secret string of the config, wiped when dropped and never printed
+///
+/// It can only be deserialized, config fields holding one are skipped when serializing
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Self(Zeroizing::new(secret))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// Nsec set directly or read from `nsec_file`, decrypted with `passphrase` if age encrypted
+///
+/// A directly set nsec takes precedence over the file. Both can be a passphrase encrypted age
+/// file, armored or binary.
+pub fn read_nsec(
+    nsec: Option<&Secret>,
+    nsec_file: Option<&Path>,
+    passphrase: Option<&str>,
+) -> Result<Option<Zeroizing<String>>> {
+    let secret = match (nsec, nsec_file) {
+        (Some(nsec), _) => Zeroizing::new(nsec.expose().as_bytes().to_vec()),
+        (None, Some(path)) => Zeroizing::new(
+            fs::read(path)
+                .map_err(|err| anyhow!("Could not read nsec file {}: {}", path.display(), err))?,
+        ),
+        (None, None) => return Ok(None),
+    };
+
+    let secret = if is_age_encrypted(&secret) {
+        let passphrase =
+            passphrase.ok_or_else(|| anyhow!("Nsec is encrypted but no passphrase is set"))?;
+        decrypt_age(&secret, passphrase)?
+    } else {
+        secret
+    };
+
+    let nsec = std::str::from_utf8(&secret).map_err(|_| anyhow!("Nsec is not valid utf8"))?;
+
+    Ok(Some(Zeroizing::new(nsec.trim().to_string())))
+}
+
+fn is_age_encrypted(secret: &[u8]) -> bool {
+    let start = secret
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(secret.len());
+
+    secret[start..].starts_with(AGE_ARMOR_HEADER) || secret[start..].starts_with(AGE_HEADER)
+}
+
+fn decrypt_age(encrypted: &[u8], passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let decryptor = match age::Decryptor::new(ArmoredReader::new(encrypted))? {
+        age::Decryptor::Passphrase(decryptor) => decryptor,
+        age::Decryptor::Recipients(_) => bail!("Nsec must be encrypted with a passphrase"),
+    };
+
+    let mut reader = decryptor
+        .decrypt(&SecretString::new(passphrase.to_string()), None)
+        .map_err(|err| anyhow!("Could not decrypt nsec: {}", err))?;
+
+    let mut secret = Zeroizing::new(vec![]);
+    reader.read_to_end(&mut secret)?;
+
+    Ok(secret)
+}
+
+/// Secret key kept as bytes that are wiped when dropped
+///
+/// Keys are built from it when signing, so the long lived copy is the zeroized one
+#[derive(Clone)]
+pub struct SecretKeyBytes(Zeroizing<[u8; 32]>);
+
+impl SecretKeyBytes {
+    pub fn new(keys: &Keys) -> Result<Self> {
+        Ok(Self(Zeroizing::new(keys.secret_key()?.secret_bytes())))
+    }
+
+    pub fn keys(&self) -> Result<Keys> {
+        Ok(Keys::new(SecretKey::from_slice(self.0.as_ref())?))
+    }
+}
+
+impl fmt::Debug for SecretKeyBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKeyBytes(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use age::armor::{ArmoredWriter, Format};
+
+    use super::*;
+
+    const NSEC: &str = "nsec1j4c6269y9w0q2er2xjw8sv2ehyrtfxq3jwgdlxj6qfn8z4gjsq5qfvfk99";
+
+    fn encrypt(secret: &str, passphrase: &str, format: Format) -> Vec<u8> {
+        let encryptor =
+            age::Encryptor::with_user_passphrase(SecretString::new(passphrase.to_string()));
+        let mut encrypted = vec![];
+        let armored = ArmoredWriter::wrap_output(&mut encrypted, format).unwrap();
+        let mut writer = encryptor.wrap_output(armored).unwrap();
+        writer.write_all(secret.as_bytes()).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+        encrypted
+    }
+
+    #[test]
+    fn test_read_nsec() {
+        assert!(read_nsec(None, None, None).unwrap().is_none());
+        assert_eq!(
+            read_nsec(Some(&Secret::new(format!("{}\n", NSEC))), None, None)
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            NSEC
+        );
+
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, NSEC).unwrap();
+        assert_eq!(
+            read_nsec(None, Some(&path), None)
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            NSEC
+        );
+        // A directly set nsec is used over the file
+        assert_eq!(
+            read_nsec(Some(&Secret::new("direct".to_string())), Some(&path), None)
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "direct"
+        );
+
+        assert!(read_nsec(None, Some(&path.with_extension("missing")), None).is_err());
+    }
+
+    #[test]
+    fn test_read_encrypted_nsec() {
+        for format in [Format::AsciiArmor, Format::Binary] {
+            let encrypted = encrypt(NSEC, "hunter2", format);
+
+            let path = std::env::temp_dir()
+                .join("cashu-lnurl-test")
+                .join(uuid::Uuid::new_v4().to_string());
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &encrypted).unwrap();
+
+            assert_eq!(
+                read_nsec(None, Some(&path), Some("hunter2"))
+                    .unwrap()
+                    .unwrap()
+                    .as_str(),
+                NSEC
+            );
+            assert!(read_nsec(None, Some(&path), Some("wrong")).is_err());
+            assert!(read_nsec(None, Some(&path), None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_secret_key_bytes() {
+        let keys = Keys::generate();
+        let secret = SecretKeyBytes::new(&keys).unwrap();
+
+        assert_eq!(secret.keys().unwrap().public_key(), keys.public_key());
+        assert_eq!(format!("{:?}", secret), "SecretKeyBytes(..)");
+    }
+
+    #[test]
+    fn test_secret() {
+        let secret: Secret = serde_json::from_str(&format!("\"{}\"", NSEC)).unwrap();
+
+        assert_eq!(secret.expose(), NSEC);
+        assert_eq!(format!("{:?}", Some(secret)), "Some(Secret(..))");
+    }
+}
@@ -0,0 +1,1707 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use cashu_sdk::Bolt11Invoice;
+use nostr_sdk::Url;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use tracing::{info, warn};
+
+use crate::database::{
+    normalize_user, usernames_to_normalize, DbBackend, PaymentRecordFilter, PendingInvoiceFilter,
+};
+use crate::types::{
+    normalize_username, Claim, ClaimStatus, InviteCode, InviteStatus, PayerInvoice, PaymentRecord,
+    PendingInvoice, PendingUser, TokenDelivery, User, UserKind, WebhookDelivery,
+};
+
+/// SQLite store
+///
+/// Users and pending invoices are kept as json so the types are shared with redb,
+/// sqlite's json functions can be used to query them
+#[derive(Debug, Clone)]
+pub struct SqliteDb {
+    pool: SqlitePool,
+}
+
+impl SqliteDb {
+    /// Open the database, creating it and running migrations as needed
+    pub async fn new(path: PathBuf) -> Result<Self> {
+        let directory = path
+            .parent()
+            .ok_or(anyhow!("Path is not set".to_string()))?;
+
+        if let Err(err) = fs::create_dir_all(directory) {
+            warn!("Could not create db path {:?}", err);
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+
+        sqlx::migrate!("./migrations").run(&pool).await?;
+
+        let db = Self { pool };
+        db.migrate_usernames().await?;
+
+        Ok(db)
+    }
+
+    /// Rename users and their pending invoices to normalized usernames, once
+    async fn migrate_usernames(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let normalized: Option<i64> =
+            sqlx::query_scalar("SELECT value FROM meta WHERE key = 'usernames_normalized'")
+                .fetch_optional(&mut *tx)
+                .await?;
+        if normalized.is_some() {
+            return Ok(());
+        }
+
+        let users: Vec<(String, String)> = sqlx::query_as("SELECT domain, username FROM users")
+            .fetch_all(&mut *tx)
+            .await?;
+
+        for (domain, username, normalized_username) in usernames_to_normalize(&users)? {
+            let user: String =
+                sqlx::query_scalar("SELECT user FROM users WHERE domain = ? AND username = ?")
+                    .bind(&domain)
+                    .bind(&username)
+                    .fetch_one(&mut *tx)
+                    .await?;
+            let user = normalize_user(serde_json::from_str(&user)?);
+
+            sqlx::query(
+                "UPDATE users SET username = ?, user = ? WHERE domain = ? AND username = ?",
+            )
+            .bind(&normalized_username)
+            .bind(user.as_json())
+            .bind(&domain)
+            .bind(&username)
+            .execute(&mut *tx)
+            .await?;
+            info!(
+                "Renamed user {}@{} to {}",
+                username, domain, normalized_username
+            );
+        }
+
+        let invoices: Vec<(String, String)> =
+            sqlx::query_as("SELECT hash, invoice FROM pending_invoices")
+                .fetch_all(&mut *tx)
+                .await?;
+
+        for (hash, mut invoice) in invoices
+            .iter()
+            .flat_map(|(hash, invoice)| {
+                serde_json::from_str::<PendingInvoice>(invoice).map(|invoice| (hash, invoice))
+            })
+            .filter(|(_hash, invoice)| invoice.username != normalize_username(&invoice.username))
+        {
+            invoice.username = normalize_username(&invoice.username);
+            sqlx::query("UPDATE pending_invoices SET invoice = ? WHERE hash = ?")
+                .bind(invoice.as_json())
+                .bind(hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("INSERT INTO meta (key, value) VALUES ('usernames_normalized', 1)")
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Value of the `kind` column of a user
+fn user_kind(user: &UserKind) -> &'static str {
+    match user {
+        UserKind::Reserved(_) => "reserved",
+        UserKind::Blocked => "blocked",
+        UserKind::User(_) => "user",
+        UserKind::Pending(_) => "pending",
+    }
+}
+
+/// Columns of the `payments` table
+type PaymentRow = (
+    String,
+    String,
+    String,
+    String,
+    i64,
+    i64,
+    Option<i64>,
+    bool,
+    Option<String>,
+    bool,
+    i64,
+);
+
+fn payment_record(row: PaymentRow) -> Result<PaymentRecord> {
+    let (
+        hash,
+        domain,
+        username,
+        mint,
+        amount_msat,
+        fee_msat,
+        minted_msat,
+        proxied,
+        comment,
+        delivered,
+        paid_at,
+    ) = row;
+
+    Ok(PaymentRecord {
+        hash,
+        domain,
+        username,
+        mint: Url::parse(&mint)?,
+        amount_msat: amount_msat as u64,
+        fee_msat: fee_msat as u64,
+        minted_msat: minted_msat.map(|minted_msat| minted_msat as u64),
+        proxied,
+        comment,
+        delivered,
+        paid_at: paid_at as u64,
+    })
+}
+
+#[async_trait]
+impl DbBackend for SqliteDb {
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.pool.close().await;
+
+        Ok(())
+    }
+
+    async fn add_fee_paid(&self, payment_hash: &str, fee_msat: u64) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO paid_fees (hash, fee_msat) VALUES (?, ?)")
+            .bind(payment_hash)
+            .bind(fee_msat as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_fee_received(&self, payment_hash: &str, fee_msat: u64) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO received_fees (hash, fee_msat) VALUES (?, ?)")
+            .bind(payment_hash)
+            .bind(fee_msat as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_user(&self, domain: &str, username: &str, user: &UserKind) -> Result<()> {
+        let username = &normalize_username(username);
+        sqlx::query(
+            "INSERT OR REPLACE INTO users (domain, username, kind, user) VALUES (?, ?, ?, ?)",
+        )
+        .bind(domain)
+        .bind(username)
+        .bind(user_kind(user))
+        .bind(user.as_json())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_user(&self, domain: &str, username: &str) -> Result<Option<UserKind>> {
+        let username = &normalize_username(username);
+        let user: Option<String> =
+            sqlx::query_scalar("SELECT user FROM users WHERE domain = ? AND username = ?")
+                .bind(domain)
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        let user = match user {
+            Some(user) => Some(serde_json::from_str(&user)?),
+            None => None,
+        };
+
+        Ok(user)
+    }
+
+    async fn update_user(&self, domain: &str, username: &str, user: &User) -> Result<bool> {
+        let username = &normalize_username(username);
+        let user = UserKind::User(user.clone());
+        let result =
+            sqlx::query("UPDATE users SET user = ? WHERE domain = ? AND username = ? AND kind = ?")
+                .bind(user.as_json())
+                .bind(domain)
+                .bind(username)
+                .bind(user_kind(&user))
+                .execute(&self.pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>> {
+        let users: Vec<String> = sqlx::query_scalar("SELECT user FROM users WHERE kind = 'user'")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let users = users
+            .iter()
+            .flat_map(|user| match serde_json::from_str(user) {
+                Ok(UserKind::User(user)) => Some(user),
+                _ => None,
+            })
+            .collect();
+        Ok(users)
+    }
+
+    async fn get_users(&self, offset: usize, limit: usize) -> Result<(Vec<User>, usize)> {
+        let users: Vec<String> = sqlx::query_scalar(
+            "SELECT user FROM users WHERE kind = 'user' \
+             ORDER BY username, domain LIMIT ? OFFSET ?",
+        )
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE kind = 'user'")
+            .fetch_one(&self.pool)
+            .await?;
+
+        let users = users
+            .iter()
+            .flat_map(|user| match serde_json::from_str(user) {
+                Ok(UserKind::User(user)) => Some(user),
+                _ => None,
+            })
+            .collect();
+        Ok((users, total as usize))
+    }
+
+    async fn get_pending_users(&self) -> Result<Vec<PendingUser>> {
+        let users: Vec<String> =
+            sqlx::query_scalar("SELECT user FROM users WHERE kind = 'pending'")
+                .fetch_all(&self.pool)
+                .await?;
+
+        let users = users
+            .iter()
+            .flat_map(|user| match serde_json::from_str(user) {
+                Ok(UserKind::Pending(pending_user)) => Some(pending_user),
+                _ => None,
+            })
+            .collect();
+        Ok(users)
+    }
+
+    async fn delete_user(&self, domain: &str, username: &str) -> Result<()> {
+        let username = &normalize_username(username);
+        sqlx::query("DELETE FROM users WHERE domain = ? AND username = ?")
+            .bind(domain)
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_user(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Option<Vec<PendingInvoice>>> {
+        let username = &normalize_username(username);
+        let mut tx = self.pool.begin().await?;
+
+        let removed =
+            sqlx::query("DELETE FROM users WHERE domain = ? AND username = ? AND kind = 'user'")
+                .bind(domain)
+                .bind(username)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                > 0;
+
+        if !removed {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+
+        let pending: Vec<String> = sqlx::query_scalar(
+            "SELECT invoice FROM pending_invoices \
+             WHERE json_extract(invoice, '$.domain') = ? \
+             AND json_extract(invoice, '$.username') = ?",
+        )
+        .bind(domain)
+        .bind(username)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(
+            pending
+                .iter()
+                .flat_map(|invoice| serde_json::from_str(invoice))
+                .collect(),
+        ))
+    }
+
+    async fn get_user_pending_invoices(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Vec<PendingInvoice>> {
+        let username = &normalize_username(username);
+        let invoices: Vec<String> = sqlx::query_scalar(
+            "SELECT invoice FROM pending_invoices \
+             WHERE json_extract(invoice, '$.domain') = ? \
+             AND json_extract(invoice, '$.username') = ?",
+        )
+        .bind(domain)
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invoices
+            .iter()
+            .flat_map(|invoice| serde_json::from_str(invoice))
+            .collect())
+    }
+
+    async fn add_pending_invoice(&self, hash: &str, invoice: &PendingInvoice) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO pending_invoices (hash, invoice) VALUES (?, ?)")
+            .bind(hash)
+            .bind(invoice.as_json())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_pending_invoice(&self, hash: &str) -> Result<Option<PendingInvoice>> {
+        let invoice: Option<String> =
+            sqlx::query_scalar("SELECT invoice FROM pending_invoices WHERE hash = ?")
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        let invoice = match invoice {
+            Some(invoice) => Some(serde_json::from_str(&invoice)?),
+            None => None,
+        };
+
+        Ok(invoice)
+    }
+
+    async fn get_pending_invoice_by_key(&self, key: &str) -> Result<Option<PendingInvoice>> {
+        let invoice: Option<String> = sqlx::query_scalar(
+            "SELECT invoice FROM pending_invoices \
+             WHERE json_extract(invoice, '$.idempotency_key') = ? \
+             ORDER BY json_extract(invoice, '$.time') DESC LIMIT 1",
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let invoice = match invoice {
+            Some(invoice) => Some(serde_json::from_str(&invoice)?),
+            None => None,
+        };
+
+        Ok(invoice)
+    }
+
+    async fn get_pending_invoices(&self) -> Result<Vec<PendingInvoice>> {
+        let invoices: Vec<String> = sqlx::query_scalar("SELECT invoice FROM pending_invoices")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(invoices
+            .iter()
+            .flat_map(|invoice| serde_json::from_str(invoice))
+            .collect())
+    }
+
+    async fn find_pending_invoices(
+        &self,
+        filter: &PendingInvoiceFilter,
+    ) -> Result<Vec<PendingInvoice>> {
+        let invoices: Vec<String> = sqlx::query_scalar(
+            "SELECT invoice FROM pending_invoices \
+             WHERE (?1 IS NULL OR json_extract(invoice, '$.username') = ?1) \
+             AND (?2 IS NULL OR json_extract(invoice, '$.time') <= ?2) \
+             ORDER BY json_extract(invoice, '$.time'), hash",
+        )
+        .bind(filter.username.as_deref().map(normalize_username))
+        .bind(filter.created_before.map(|time| time as i64))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invoices
+            .iter()
+            .flat_map(|invoice| serde_json::from_str(invoice))
+            .collect())
+    }
+
+    async fn remove_pending_invoice(&self, hash: &str) -> Result<()> {
+        sqlx::query("DELETE FROM pending_invoices WHERE hash = ?")
+            .bind(hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_payer_invoice(&self, hash: &str, invoice: &PayerInvoice) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO payer_invoices (hash, username, domain, bolt11, settled, preimage) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(hash)
+        .bind(&invoice.username)
+        .bind(&invoice.domain)
+        .bind(invoice.bolt11.to_string())
+        .bind(invoice.settled)
+        .bind(&invoice.preimage)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_payer_invoice(&self, hash: &str) -> Result<Option<PayerInvoice>> {
+        let invoice: Option<(String, String, String, bool, Option<String>)> = sqlx::query_as(
+            "SELECT username, domain, bolt11, settled, preimage FROM payer_invoices WHERE hash = ?",
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let invoice = match invoice {
+            Some((username, domain, bolt11, settled, preimage)) => Some(PayerInvoice {
+                username,
+                domain,
+                bolt11: Bolt11Invoice::from_str(&bolt11)?,
+                settled,
+                preimage,
+            }),
+            None => None,
+        };
+
+        Ok(invoice)
+    }
+
+    async fn settle_payer_invoice(&self, hash: &str, preimage: Option<String>) -> Result<()> {
+        sqlx::query(
+            "UPDATE payer_invoices SET settled = TRUE, preimage = COALESCE(?, preimage) WHERE hash = ?",
+        )
+        .bind(preimage)
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn add_claim(&self, id: &str, claim: &Claim) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO claims (id, token, expire) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(&claim.token)
+            .bind(claim.expire as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn claim_token(&self, id: &str, now: u64) -> Result<ClaimStatus> {
+        // Kept until expiry so later requests are told it is gone
+        let token: Option<String> = sqlx::query_scalar(
+            "UPDATE claims SET token = NULL WHERE id = ? AND expire > ? AND token IS NOT NULL RETURNING token",
+        )
+        .bind(id)
+        .bind(now as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(token) = token {
+            return Ok(ClaimStatus::Claimed(token));
+        }
+
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM claims WHERE id = ?)")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        match exists {
+            true => Ok(ClaimStatus::Gone),
+            false => Ok(ClaimStatus::NotFound),
+        }
+    }
+
+    async fn remove_expired_claims(&self, now: u64) -> Result<usize> {
+        let result = sqlx::query("DELETE FROM claims WHERE expire <= ?")
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn add_invite_code(&self, invite: &InviteCode) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO invite_codes (code, max_uses, uses, expire, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&invite.code)
+        .bind(invite.max_uses as i64)
+        .bind(invite.uses as i64)
+        .bind(invite.expire.map(|expire| expire as i64))
+        .bind(invite.created_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_invite_codes(&self) -> Result<Vec<InviteCode>> {
+        let invites: Vec<(String, i64, i64, Option<i64>, i64)> = sqlx::query_as(
+            "SELECT code, max_uses, uses, expire, created_at FROM invite_codes ORDER BY code",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invites
+            .into_iter()
+            .map(|(code, max_uses, uses, expire, created_at)| InviteCode {
+                code,
+                max_uses: max_uses as u64,
+                uses: uses as u64,
+                expire: expire.map(|expire| expire as u64),
+                created_at: created_at as u64,
+            })
+            .collect())
+    }
+
+    async fn get_invite_code(&self, code: &str) -> Result<Option<InviteCode>> {
+        let invite: Option<(String, i64, i64, Option<i64>, i64)> = sqlx::query_as(
+            "SELECT code, max_uses, uses, expire, created_at FROM invite_codes WHERE code = ?",
+        )
+        .bind(code)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(
+            invite.map(|(code, max_uses, uses, expire, created_at)| InviteCode {
+                code,
+                max_uses: max_uses as u64,
+                uses: uses as u64,
+                expire: expire.map(|expire| expire as u64),
+                created_at: created_at as u64,
+            }),
+        )
+    }
+
+    async fn use_invite_code(&self, code: &str, now: u64) -> Result<InviteStatus> {
+        // Checked and counted in one statement so concurrent signups cannot share a use
+        let result = sqlx::query(
+            "UPDATE invite_codes SET uses = uses + 1 WHERE code = ? AND uses < max_uses AND (expire IS NULL OR expire > ?)",
+        )
+        .bind(code)
+        .bind(now as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            return Ok(InviteStatus::Used);
+        }
+
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM invite_codes WHERE code = ?)")
+                .bind(code)
+                .fetch_one(&self.pool)
+                .await?;
+
+        match exists {
+            true => Ok(InviteStatus::Gone),
+            false => Ok(InviteStatus::NotFound),
+        }
+    }
+
+    async fn remove_invite_code(&self, code: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM invite_codes WHERE code = ?")
+            .bind(code)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn add_payment_record(&self, record: &PaymentRecord) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO payments (hash, domain, username, mint, amount_msat, fee_msat, minted_msat, proxied, comment, delivered, paid_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&record.hash)
+        .bind(&record.domain)
+        .bind(&record.username)
+        .bind(record.mint.as_str())
+        .bind(record.amount_msat as i64)
+        .bind(record.fee_msat as i64)
+        .bind(record.minted_msat.map(|minted_msat| minted_msat as i64))
+        .bind(record.proxied)
+        .bind(&record.comment)
+        .bind(record.delivered)
+        .bind(record.paid_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_payment_record(&self, hash: &str) -> Result<Option<PaymentRecord>> {
+        let record: Option<PaymentRow> = sqlx::query_as(
+            "SELECT hash, domain, username, mint, amount_msat, fee_msat, minted_msat, proxied, comment, delivered, paid_at FROM payments WHERE hash = ?",
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        record.map(payment_record).transpose()
+    }
+
+    async fn find_payment_records(
+        &self,
+        filter: &PaymentRecordFilter,
+    ) -> Result<Vec<PaymentRecord>> {
+        let records: Vec<PaymentRow> = sqlx::query_as(
+            "SELECT hash, domain, username, mint, amount_msat, fee_msat, minted_msat, proxied, comment, delivered, paid_at FROM payments \
+             WHERE (?1 IS NULL OR username = ?1) \
+             AND (?2 IS NULL OR paid_at >= ?2) \
+             AND (?3 IS NULL OR paid_at < ?3) \
+             ORDER BY paid_at, hash",
+        )
+        .bind(filter.username.as_deref().map(normalize_username))
+        .bind(filter.from.map(|from| from as i64))
+        .bind(filter.to.map(|to| to as i64))
+        .fetch_all(&self.pool)
+        .await?;
+
+        records.into_iter().map(payment_record).collect()
+    }
+
+    async fn find_payment_records_page(
+        &self,
+        filter: &PaymentRecordFilter,
+        after: Option<(u64, String)>,
+        limit: usize,
+    ) -> Result<Vec<PaymentRecord>> {
+        let (after_paid_at, after_hash) = after.unzip();
+        let records: Vec<PaymentRow> = sqlx::query_as(
+            "SELECT hash, domain, username, mint, amount_msat, fee_msat, minted_msat, proxied, comment, delivered, paid_at FROM payments \
+             WHERE (?1 IS NULL OR username = ?1) \
+             AND (?2 IS NULL OR paid_at >= ?2) \
+             AND (?3 IS NULL OR paid_at < ?3) \
+             AND (?4 IS NULL OR paid_at > ?4 OR (paid_at = ?4 AND hash > ?5)) \
+             ORDER BY paid_at, hash LIMIT ?6",
+        )
+        .bind(filter.username.as_deref().map(normalize_username))
+        .bind(filter.from.map(|from| from as i64))
+        .bind(filter.to.map(|to| to as i64))
+        .bind(after_paid_at.map(|paid_at| paid_at as i64))
+        .bind(after_hash)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        records.into_iter().map(payment_record).collect()
+    }
+
+    async fn get_user_payment_records_page(
+        &self,
+        domain: &str,
+        username: &str,
+        after: Option<(u64, String)>,
+        limit: usize,
+    ) -> Result<Vec<PaymentRecord>> {
+        let (after_paid_at, after_hash) = after.unzip();
+        let records: Vec<PaymentRow> = sqlx::query_as(
+            "SELECT hash, domain, username, mint, amount_msat, fee_msat, minted_msat, proxied, comment, delivered, paid_at FROM payments \
+             WHERE domain = ?1 AND username = ?2 \
+             AND (?3 IS NULL OR paid_at > ?3 OR (paid_at = ?3 AND hash > ?4)) \
+             ORDER BY paid_at, hash LIMIT ?5",
+        )
+        .bind(domain)
+        .bind(normalize_username(username))
+        .bind(after_paid_at.map(|paid_at| paid_at as i64))
+        .bind(after_hash)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        records.into_iter().map(payment_record).collect()
+    }
+
+    async fn add_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO webhook_deliveries (hash, domain, username, url, attempts, delivered, status, error, last_attempt) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&delivery.hash)
+        .bind(&delivery.domain)
+        .bind(&delivery.username)
+        .bind(delivery.url.as_str())
+        .bind(delivery.attempts as i64)
+        .bind(delivery.delivered)
+        .bind(delivery.status.map(|status| status as i64))
+        .bind(&delivery.error)
+        .bind(delivery.last_attempt as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_webhook_delivery(&self, hash: &str) -> Result<Option<WebhookDelivery>> {
+        let delivery: Option<(String, String, String, i64, bool, Option<i64>, Option<String>, i64)> =
+            sqlx::query_as(
+                "SELECT domain, username, url, attempts, delivered, status, error, last_attempt FROM webhook_deliveries WHERE hash = ?",
+            )
+            .bind(hash)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        let delivery = match delivery {
+            Some((domain, username, url, attempts, delivered, status, error, last_attempt)) => {
+                Some(WebhookDelivery {
+                    hash: hash.to_string(),
+                    domain,
+                    username,
+                    url: Url::parse(&url)?,
+                    attempts: attempts as u32,
+                    delivered,
+                    status: status.map(|status| status as u16),
+                    error,
+                    last_attempt: last_attempt as u64,
+                })
+            }
+            None => None,
+        };
+
+        Ok(delivery)
+    }
+
+    async fn add_token_delivery(&self, delivery: &TokenDelivery) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO token_deliveries (hash, delivery) VALUES (?, ?)")
+            .bind(&delivery.hash)
+            .bind(delivery.as_json())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_token_deliveries(&self) -> Result<Vec<TokenDelivery>> {
+        let deliveries: Vec<String> = sqlx::query_scalar(
+            "SELECT delivery FROM token_deliveries \
+             ORDER BY json_extract(delivery, '$.next_attempt'), hash",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries
+            .iter()
+            .flat_map(|delivery| serde_json::from_str(delivery))
+            .collect())
+    }
+
+    async fn remove_token_delivery(&self, hash: &str) -> Result<()> {
+        sqlx::query("DELETE FROM token_deliveries WHERE hash = ?")
+            .bind(hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn dead_letter_token_delivery(&self, delivery: &TokenDelivery) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM token_deliveries WHERE hash = ?")
+            .bind(&delivery.hash)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT OR REPLACE INTO dead_letters (hash, delivery) VALUES (?, ?)")
+            .bind(&delivery.hash)
+            .bind(delivery.as_json())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> Result<Vec<TokenDelivery>> {
+        let dead_letters: Vec<String> = sqlx::query_scalar(
+            "SELECT delivery FROM dead_letters \
+             ORDER BY json_extract(delivery, '$.created_at'), hash",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(dead_letters
+            .iter()
+            .flat_map(|delivery| serde_json::from_str(delivery))
+            .collect())
+    }
+
+    async fn get_user_token_deliveries(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<Vec<TokenDelivery>> {
+        let deliveries: Vec<String> = sqlx::query_scalar(
+            "SELECT delivery FROM ( \
+                SELECT hash, delivery FROM token_deliveries \
+                UNION ALL SELECT hash, delivery FROM dead_letters \
+             ) \
+             WHERE json_extract(delivery, '$.domain') = ? \
+             AND json_extract(delivery, '$.username') = ? \
+             ORDER BY json_extract(delivery, '$.created_at'), hash",
+        )
+        .bind(domain)
+        .bind(normalize_username(username))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries
+            .iter()
+            .flat_map(|delivery| serde_json::from_str(delivery))
+            .collect())
+    }
+
+    async fn get_pay_index(&self) -> Result<Option<u64>> {
+        let pay_index: Option<i64> =
+            sqlx::query_scalar("SELECT value FROM meta WHERE key = 'pay_index'")
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(pay_index.map(|pay_index| pay_index as u64))
+    }
+
+    async fn set_pay_index(&self, pay_index: u64) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO meta (key, value) VALUES ('pay_index', ?)")
+            .bind(pay_index as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn replace_pending_invoice(
+        &self,
+        paid_hash: &str,
+        invoice: &PendingInvoice,
+        pay_index: Option<u64>,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM pending_invoices WHERE hash = ?")
+            .bind(paid_hash)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT OR REPLACE INTO pending_invoices (hash, invoice) VALUES (?, ?)")
+            .bind(&invoice.hash)
+            .bind(invoice.as_json())
+            .execute(&mut *tx)
+            .await?;
+        if let Some(pay_index) = pay_index {
+            sqlx::query(
+                "INSERT INTO meta (key, value) VALUES ('pay_index', ?) \
+                 ON CONFLICT (key) DO UPDATE SET value = MAX(value, excluded.value)",
+            )
+            .bind(pay_index as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use cashu_sdk::Amount;
+
+    use super::*;
+    use crate::routes::tests::test_invoice;
+    use crate::types::unix_time;
+
+    async fn test_db() -> SqliteDb {
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(format!("{}.sqlite", uuid::Uuid::new_v4()));
+
+        SqliteDb::new(path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_user_round_trip() {
+        let db = test_db().await;
+
+        let user = User {
+            username: "alice".to_string(),
+            domain: "example.com".to_string(),
+            mint: "https://mint.example.com".parse().unwrap(),
+            fallback_mints: vec![],
+            mint_rules: vec![],
+            pubkey: "npub".to_string(),
+            relays: HashSet::from(["wss://relay.example.com".to_string()]),
+            proxy: false,
+            min_sendable: Some(Amount::from_sat(1)),
+            max_sendable: None,
+            success_message: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: false,
+            created_at: None,
+        };
+        db.add_user("example.com", "alice", &UserKind::User(user.clone()))
+            .await
+            .unwrap();
+
+        match db.get_user("example.com", "alice").await.unwrap() {
+            Some(UserKind::User(stored)) => assert_eq!(
+                serde_json::to_value(stored).unwrap(),
+                serde_json::to_value(&user).unwrap()
+            ),
+            other => panic!("Unexpected user {:?}", other),
+        }
+        assert!(db.get_user("other.com", "alice").await.unwrap().is_none());
+        assert_eq!(db.get_all_users().await.unwrap().len(), 1);
+
+        let updated = User {
+            proxy: true,
+            ..user.clone()
+        };
+        assert!(db
+            .update_user("example.com", "alice", &updated)
+            .await
+            .unwrap());
+        assert!(!db
+            .update_user("example.com", "bob", &updated)
+            .await
+            .unwrap());
+        match db.get_user("example.com", "alice").await.unwrap() {
+            Some(UserKind::User(stored)) => assert!(stored.proxy),
+            other => panic!("Unexpected user {:?}", other),
+        }
+
+        db.delete_user("example.com", "alice").await.unwrap();
+        assert!(db.get_user("example.com", "alice").await.unwrap().is_none());
+    }
+
+    fn test_user(username: &str) -> User {
+        User {
+            username: username.to_string(),
+            domain: "example.com".to_string(),
+            mint: "https://mint.example.com".parse().unwrap(),
+            fallback_mints: vec![],
+            mint_rules: vec![],
+            pubkey: "npub".to_string(),
+            relays: HashSet::new(),
+            proxy: false,
+            min_sendable: None,
+            max_sendable: None,
+            success_message: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: false,
+            created_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_lookup() {
+        let db = test_db().await;
+
+        db.add_user("example.com", "alice", &UserKind::User(test_user("alice")))
+            .await
+            .unwrap();
+
+        for username in ["alice", "Alice", "ALICE"] {
+            assert!(matches!(
+                db.get_user("example.com", username).await.unwrap(),
+                Some(UserKind::User(_))
+            ));
+        }
+        assert!(db
+            .update_user("example.com", "Alice", &test_user("alice"))
+            .await
+            .unwrap());
+
+        db.delete_user("example.com", "ALICE").await.unwrap();
+        assert!(db.get_user("example.com", "alice").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_usernames() {
+        let db = test_db().await;
+
+        // Users stored before usernames were normalized
+        let insert_legacy = |username: &'static str| {
+            sqlx::query(
+                "INSERT INTO users (domain, username, kind, user) \
+                 VALUES ('example.com', ?, 'user', ?)",
+            )
+            .bind(username)
+            .bind(UserKind::User(test_user(username)).as_json())
+            .execute(&db.pool)
+        };
+        let reset = || sqlx::query("DELETE FROM meta WHERE key = 'usernames_normalized'");
+
+        insert_legacy("Alice").await.unwrap();
+        insert_legacy("bob").await.unwrap();
+        reset().execute(&db.pool).await.unwrap();
+        db.migrate_usernames().await.unwrap();
+
+        match db.get_user("example.com", "alice").await.unwrap() {
+            Some(UserKind::User(user)) => assert_eq!(user.username, "alice"),
+            other => panic!("Unexpected user {:?}", other),
+        }
+        assert_eq!(db.get_users(0, 10).await.unwrap().1, 2);
+
+        // Users that would become one are not merged
+        insert_legacy("Bob").await.unwrap();
+        reset().execute(&db.pool).await.unwrap();
+        assert!(db.migrate_usernames().await.is_err());
+        assert_eq!(db.get_users(0, 10).await.unwrap().1, 3);
+    }
+
+    #[tokio::test]
+    async fn test_pending_invoice_round_trip() {
+        let db = test_db().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5ve584t0cv27hwmy0cx9ca8uwyqyfw9y9dm3r8vus9fv36r2l9yjssp5qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqsdq6vdshx6r494kxuatjdss8getnwsxqrrssy4mqr435ppy3thzxfddsg27yr3ckll6yaq7nthlv0g3n0crerpnyqfjhc279587kp9uhzphvpnfpz7689xcrmhsl5w8ts5z4prpga2sq4gaxnq").unwrap();
+        let hash = bolt11.payment_hash().to_string();
+
+        let invoice = PendingInvoice {
+            comment: Some("Thanks!".to_string()),
+            hash: hash.clone(),
+            ..test_invoice(bolt11)
+        };
+        db.add_pending_invoice(&hash, &invoice).await.unwrap();
+
+        let stored = db.get_pending_invoice(&hash).await.unwrap().unwrap();
+        assert_eq!(stored.as_json(), invoice.as_json());
+        assert_eq!(db.get_pending_invoices().await.unwrap().len(), 1);
+
+        db.remove_pending_invoice(&hash).await.unwrap();
+        assert!(db.get_pending_invoice(&hash).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replace_pending_invoice() {
+        let db = test_db().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5ve584t0cv27hwmy0cx9ca8uwyqyfw9y9dm3r8vus9fv36r2l9yjssp5qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqsdq6vdshx6r494kxuatjdss8getnwsxqrrssy4mqr435ppy3thzxfddsg27yr3ckll6yaq7nthlv0g3n0crerpnyqfjhc279587kp9uhzphvpnfpz7689xcrmhsl5w8ts5z4prpga2sq4gaxnq").unwrap();
+        let paid = PendingInvoice {
+            idempotency_key: Some("key".to_string()),
+            hash: "paid".to_string(),
+            proxied: true,
+            ..test_invoice(bolt11)
+        };
+        let invoice = PendingInvoice {
+            idempotency_key: None,
+            hash: "mint".to_string(),
+            payer_hash: Some(paid.hash.clone()),
+            ..paid.clone()
+        };
+        db.add_pending_invoice(&paid.hash, &paid).await.unwrap();
+
+        db.replace_pending_invoice(&paid.hash, &invoice, Some(7))
+            .await
+            .unwrap();
+        assert!(db.get_pending_invoice(&paid.hash).await.unwrap().is_none());
+        assert!(db
+            .get_pending_invoice_by_key("key")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            db.get_pending_invoice(&invoice.hash)
+                .await
+                .unwrap()
+                .unwrap()
+                .payer_hash,
+            Some(paid.hash)
+        );
+        assert_eq!(db.get_pay_index().await.unwrap(), Some(7));
+
+        // Backends without a pay index, and invoices paid earlier, leave it as it is
+        db.replace_pending_invoice(&invoice.hash, &invoice, None)
+            .await
+            .unwrap();
+        db.replace_pending_invoice(&invoice.hash, &invoice, Some(3))
+            .await
+            .unwrap();
+        assert_eq!(db.get_pay_index().await.unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_find_pending_invoices() {
+        let db = test_db().await;
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5ve584t0cv27hwmy0cx9ca8uwyqyfw9y9dm3r8vus9fv36r2l9yjssp5qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqsdq6vdshx6r494kxuatjdss8getnwsxqrrssy4mqr435ppy3thzxfddsg27yr3ckll6yaq7nthlv0g3n0crerpnyqfjhc279587kp9uhzphvpnfpz7689xcrmhsl5w8ts5z4prpga2sq4gaxnq").unwrap();
+        for (hash, username, time) in [("c", "alice", 300), ("a", "alice", 100), ("b", "bob", 200)]
+        {
+            let invoice = PendingInvoice {
+                username: username.to_string(),
+                idempotency_key: Some(username.to_string()),
+                time,
+                hash: hash.to_string(),
+                ..test_invoice(bolt11.clone())
+            };
+            db.add_pending_invoice(hash, &invoice).await.unwrap();
+        }
+
+        let hashes = |invoices: Vec<PendingInvoice>| {
+            invoices
+                .into_iter()
+                .map(|invoice| invoice.hash)
+                .collect::<Vec<_>>()
+        };
+
+        let all = db
+            .find_pending_invoices(&PendingInvoiceFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(hashes(all), ["a", "b", "c"]);
+
+        let alice = db
+            .find_pending_invoices(&PendingInvoiceFilter {
+                username: Some("alice".to_string()),
+                created_before: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(hashes(alice), ["a", "c"]);
+
+        let old = db
+            .find_pending_invoices(&PendingInvoiceFilter {
+                username: Some("alice".to_string()),
+                created_before: Some(200),
+            })
+            .await
+            .unwrap();
+        assert_eq!(hashes(old), ["a"]);
+
+        let latest = db
+            .get_pending_invoice_by_key("alice")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.hash, "c");
+        assert!(db
+            .get_pending_invoice_by_key("carol")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remove_user() {
+        let db = test_db().await;
+
+        let user = User {
+            username: "alice".to_string(),
+            domain: "example.com".to_string(),
+            mint: "https://mint.example.com".parse().unwrap(),
+            fallback_mints: vec![],
+            mint_rules: vec![],
+            pubkey: "npub".to_string(),
+            relays: HashSet::new(),
+            proxy: false,
+            min_sendable: None,
+            max_sendable: None,
+            success_message: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: false,
+            created_at: None,
+        };
+        db.add_user("example.com", "alice", &UserKind::User(user))
+            .await
+            .unwrap();
+        db.add_user("example.com", "bob", &UserKind::Blocked)
+            .await
+            .unwrap();
+
+        let bolt11 = Bolt11Invoice::from_str("lnbc10u1pjtkk5qpp5ve584t0cv27hwmy0cx9ca8uwyqyfw9y9dm3r8vus9fv36r2l9yjssp5qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqsdq6vdshx6r494kxuatjdss8getnwsxqrrssy4mqr435ppy3thzxfddsg27yr3ckll6yaq7nthlv0g3n0crerpnyqfjhc279587kp9uhzphvpnfpz7689xcrmhsl5w8ts5z4prpga2sq4gaxnq").unwrap();
+        let hash = bolt11.payment_hash().to_string();
+        let invoice = PendingInvoice {
+            hash: hash.clone(),
+            ..test_invoice(bolt11)
+        };
+        db.add_pending_invoice(&hash, &invoice).await.unwrap();
+        assert_eq!(
+            db.get_user_pending_invoices("example.com", "alice")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Only active users can be removed
+        assert!(db
+            .remove_user("example.com", "bob")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db.get_user("example.com", "bob").await.unwrap().is_some());
+
+        // Pending invoices are kept as they may have been paid
+        let pending = db
+            .remove_user("example.com", "alice")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].hash, hash);
+        assert!(db.get_user("example.com", "alice").await.unwrap().is_none());
+        assert!(db.get_pending_invoice(&hash).await.unwrap().is_some());
+        assert!(db
+            .remove_user("example.com", "alice")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_users_page() {
+        let db = test_db().await;
+
+        for username in ["carol", "alice", "bob"] {
+            let user = User {
+                username: username.to_string(),
+                domain: "example.com".to_string(),
+                mint: "https://mint.example.com".parse().unwrap(),
+                fallback_mints: vec![],
+                mint_rules: vec![],
+                pubkey: "npub".to_string(),
+                relays: HashSet::new(),
+                proxy: false,
+                min_sendable: None,
+                max_sendable: None,
+                success_message: None,
+                description: None,
+                avatar: None,
+                webhook_url: None,
+                webhook_secret: None,
+                lock_to_pubkey: false,
+                created_at: Some(unix_time()),
+            };
+            db.add_user("example.com", username, &UserKind::User(user))
+                .await
+                .unwrap();
+        }
+        db.add_user("example.com", "dave", &UserKind::Blocked)
+            .await
+            .unwrap();
+
+        let (users, total) = db.get_users(1, 1).await.unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].username, "bob");
+
+        let (users, total) = db.get_users(2, 10).await.unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].username, "carol");
+
+        let (users, _) = db.get_users(5, 10).await.unwrap();
+        assert!(users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pay_index() {
+        let db = test_db().await;
+
+        assert_eq!(db.get_pay_index().await.unwrap(), None);
+        db.set_pay_index(5).await.unwrap();
+        db.set_pay_index(6).await.unwrap();
+        assert_eq!(db.get_pay_index().await.unwrap(), Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_claim_once() {
+        let db = test_db().await;
+
+        let claim = Claim {
+            token: Some("cashuAtoken".to_string()),
+            expire: 200,
+        };
+        db.add_claim("id", &claim).await.unwrap();
+
+        assert_eq!(
+            db.claim_token("id", 100).await.unwrap(),
+            ClaimStatus::Claimed("cashuAtoken".to_string())
+        );
+        assert_eq!(db.claim_token("id", 100).await.unwrap(), ClaimStatus::Gone);
+        assert_eq!(
+            db.claim_token("unknown", 100).await.unwrap(),
+            ClaimStatus::NotFound
+        );
+
+        assert_eq!(db.remove_expired_claims(300).await.unwrap(), 1);
+        assert_eq!(
+            db.claim_token("id", 300).await.unwrap(),
+            ClaimStatus::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_records() {
+        let db = test_db().await;
+
+        let record = |hash: &str, username: &str, paid_at| PaymentRecord {
+            hash: hash.to_string(),
+            domain: "example.com".to_string(),
+            username: username.to_string(),
+            mint: Url::parse("https://mint.example.com").unwrap(),
+            amount_msat: 10_000,
+            fee_msat: 100,
+            minted_msat: None,
+            proxied: true,
+            comment: None,
+            delivered: false,
+            paid_at,
+        };
+        db.add_payment_record(&record("late", "alice", 300))
+            .await
+            .unwrap();
+        db.add_payment_record(&record("early", "alice", 100))
+            .await
+            .unwrap();
+        db.add_payment_record(&record("bob", "bob", 200))
+            .await
+            .unwrap();
+
+        // Minting completes the record
+        let minted = PaymentRecord {
+            minted_msat: Some(9_900),
+            comment: Some("Thanks, see you\nsoon".to_string()),
+            delivered: true,
+            ..record("early", "alice", 100)
+        };
+        db.add_payment_record(&minted).await.unwrap();
+        assert_eq!(db.get_payment_record("early").await.unwrap(), Some(minted));
+        assert_eq!(db.get_payment_record("unknown").await.unwrap(), None);
+
+        let find = |username: Option<&str>, from, to| {
+            let filter = PaymentRecordFilter {
+                username: username.map(str::to_string),
+                from,
+                to,
+            };
+            let db = db.clone();
+            async move {
+                db.find_payment_records(&filter)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(find(None, None, None).await, vec!["early", "bob", "late"]);
+        assert_eq!(find(Some("Alice"), None, None).await, vec!["early", "late"]);
+        // From is inclusive and to is exclusive
+        assert_eq!(find(None, Some(200), Some(300)).await, vec!["bob"]);
+
+        // Pages continue after the paid time and hash of the last record of the previous one
+        db.add_payment_record(&record("carol", "carol", 200))
+            .await
+            .unwrap();
+        let page = |after: Option<(u64, &str)>| {
+            let db = db.clone();
+            let after = after.map(|(paid_at, hash)| (paid_at, hash.to_string()));
+            async move {
+                db.find_payment_records_page(&PaymentRecordFilter::default(), after, 2)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(page(None).await, vec!["early", "bob"]);
+        assert_eq!(page(Some((200, "bob"))).await, vec!["carol", "late"]);
+        assert!(page(Some((300, "late"))).await.is_empty());
+
+        // A user's records are only those on their domain
+        db.add_payment_record(&PaymentRecord {
+            domain: "example.org".to_string(),
+            ..record("other", "alice", 150)
+        })
+        .await
+        .unwrap();
+        let user_page = |after: Option<(u64, &str)>| {
+            let db = db.clone();
+            let after = after.map(|(paid_at, hash)| (paid_at, hash.to_string()));
+            async move {
+                db.get_user_payment_records_page("example.com", "Alice", after, 1)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(user_page(None).await, vec!["early"]);
+        assert_eq!(user_page(Some((100, "early"))).await, vec!["late"]);
+        assert!(user_page(Some((300, "late"))).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_deliveries() {
+        let db = test_db().await;
+
+        assert_eq!(db.get_webhook_delivery("hash").await.unwrap(), None);
+
+        let failed = WebhookDelivery {
+            hash: "hash".to_string(),
+            domain: "example.com".to_string(),
+            username: "alice".to_string(),
+            url: Url::parse("https://shop.example.com/paid").unwrap(),
+            attempts: 1,
+            delivered: false,
+            status: None,
+            error: Some("connection refused".to_string()),
+            last_attempt: 100,
+        };
+        db.add_webhook_delivery(&failed).await.unwrap();
+        assert_eq!(
+            db.get_webhook_delivery("hash").await.unwrap(),
+            Some(failed.clone())
+        );
+
+        // The latest attempt replaces the earlier one
+        let delivered = WebhookDelivery {
+            attempts: 2,
+            delivered: true,
+            status: Some(200),
+            error: None,
+            last_attempt: 110,
+            ..failed
+        };
+        db.add_webhook_delivery(&delivered).await.unwrap();
+        assert_eq!(
+            db.get_webhook_delivery("hash").await.unwrap(),
+            Some(delivered)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_deliveries() {
+        let db = test_db().await;
+
+        let delivery = |hash: &str, next_attempt| TokenDelivery {
+            hash: hash.to_string(),
+            payer_hash: None,
+            domain: "example.com".to_string(),
+            username: "alice".to_string(),
+            claim_id: Some(format!("claim-{}", hash)),
+            pubkey: "pubkey".to_string(),
+            token: "cashuA".to_string(),
+            comment: Some("Thanks".to_string()),
+            payer_data: None,
+            alias: None,
+            relays: HashSet::from(["wss://relay.example.com".to_string()]),
+            attempts: 1,
+            next_attempt,
+            error: Some("Could not reach any relay".to_string()),
+            created_at: 100,
+        };
+        let hashes = |deliveries: Vec<TokenDelivery>| {
+            deliveries
+                .into_iter()
+                .map(|delivery| delivery.hash)
+                .collect::<Vec<_>>()
+        };
+
+        db.add_token_delivery(&delivery("late", 300)).await.unwrap();
+        db.add_token_delivery(&delivery("soon", 200)).await.unwrap();
+        db.add_token_delivery(&delivery("sent", 100)).await.unwrap();
+        assert_eq!(
+            hashes(db.get_token_deliveries().await.unwrap()),
+            vec!["sent", "soon", "late"]
+        );
+
+        // A later attempt replaces the earlier one
+        let retried = TokenDelivery {
+            attempts: 2,
+            ..delivery("soon", 400)
+        };
+        db.add_token_delivery(&retried).await.unwrap();
+        assert_eq!(
+            hashes(db.get_token_deliveries().await.unwrap()),
+            vec!["sent", "late", "soon"]
+        );
+
+        db.remove_token_delivery("sent").await.unwrap();
+        db.dead_letter_token_delivery(&retried).await.unwrap();
+        assert_eq!(
+            hashes(db.get_token_deliveries().await.unwrap()),
+            vec!["late"]
+        );
+        assert_eq!(db.get_dead_letters().await.unwrap(), vec![retried]);
+
+        // Queued and dead lettered tokens of a user, oldest first
+        db.add_token_delivery(&TokenDelivery {
+            username: "bob".to_string(),
+            ..delivery("bob", 100)
+        })
+        .await
+        .unwrap();
+        db.add_token_delivery(&TokenDelivery {
+            created_at: 50,
+            ..delivery("first", 100)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            hashes(
+                db.get_user_token_deliveries("example.com", "alice")
+                    .await
+                    .unwrap()
+            ),
+            vec!["first", "late", "soon"]
+        );
+        assert!(db
+            .get_user_token_deliveries("example.org", "alice")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invite_codes() {
+        let db = test_db().await;
+
+        let invite = InviteCode {
+            code: "single".to_string(),
+            max_uses: 1,
+            uses: 0,
+            expire: Some(200),
+            created_at: 100,
+        };
+        db.add_invite_code(&invite).await.unwrap();
+        db.add_invite_code(&InviteCode {
+            code: "twice".to_string(),
+            max_uses: 2,
+            expire: None,
+            ..invite.clone()
+        })
+        .await
+        .unwrap();
+
+        // Concurrent signups cannot both use a single use code
+        let uses = futures::future::join_all(
+            (0..5).map(|_| async { db.use_invite_code("single", 150).await.unwrap() }),
+        )
+        .await;
+        assert_eq!(
+            uses.iter()
+                .filter(|status| **status == InviteStatus::Used)
+                .count(),
+            1
+        );
+        assert_eq!(
+            db.use_invite_code("single", 150).await.unwrap(),
+            InviteStatus::Gone
+        );
+
+        assert_eq!(
+            db.use_invite_code("twice", 1000).await.unwrap(),
+            InviteStatus::Used
+        );
+        assert_eq!(
+            db.use_invite_code("twice", 1000).await.unwrap(),
+            InviteStatus::Used
+        );
+        assert_eq!(
+            db.use_invite_code("twice", 1000).await.unwrap(),
+            InviteStatus::Gone
+        );
+        assert_eq!(
+            db.use_invite_code("unknown", 150).await.unwrap(),
+            InviteStatus::NotFound
+        );
+
+        assert_eq!(db.get_invite_code("twice").await.unwrap().unwrap().uses, 2);
+        assert_eq!(db.get_invite_code("unknown").await.unwrap(), None);
+
+        let invites = db.get_invite_codes().await.unwrap();
+        assert_eq!(
+            invites
+                .iter()
+                .map(|invite| (invite.code.as_str(), invite.uses))
+                .collect::<Vec<_>>(),
+            vec![("single", 1), ("twice", 2)]
+        );
+
+        assert!(db.remove_invite_code("single").await.unwrap());
+        assert!(!db.remove_invite_code("single").await.unwrap());
+        assert_eq!(
+            db.use_invite_code("single", 150).await.unwrap(),
+            InviteStatus::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invite_code_expiry() {
+        let db = test_db().await;
+
+        db.add_invite_code(&InviteCode {
+            code: "expiring".to_string(),
+            max_uses: 1,
+            uses: 0,
+            expire: Some(200),
+            created_at: 100,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            db.use_invite_code("expiring", 200).await.unwrap(),
+            InviteStatus::Gone
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Serving the api over https, with the certificate reloaded when it is renewed
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use rustls_pemfile::Item;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How often the certificate files are checked for a renewal
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Signed with the private key to check it belongs to the certificate
+const KEY_CHECK_MESSAGE: &[u8] = b"cashu-lnurl tls key check";
+
+/// Modification times of the certificate and key files
+type Modified = Option<(SystemTime, SystemTime)>;
+
+/// Certificate chain and private key of the https listener, as pem files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl TlsFiles {
+    /// Files of the https listener, `None` to serve plain http
+    pub fn new(cert_path: Option<PathBuf>, key_path: Option<PathBuf>) -> Result<Option<Self>> {
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Ok(Some(Self {
+                cert_path,
+                key_path,
+            })),
+            (None, None) => Ok(None),
+            _ => bail!("tls_cert_path and tls_key_path must be set together"),
+        }
+    }
+
+    /// Config of the files, fails if the private key is not the certificate's
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let certs = read_certs(&self.cert_path)?;
+        let key = read_key(&self.key_path)?;
+        if !key_matches(&certs[0], &key)? {
+            bail!(
+                "The private key in {:?} does not match the certificate in {:?}",
+                self.key_path,
+                self.cert_path
+            );
+        }
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+
+    pub fn rustls_config(&self) -> Result<RustlsConfig> {
+        Ok(RustlsConfig::from_config(Arc::new(self.server_config()?)))
+    }
+
+    fn modified(&self) -> Modified {
+        let modified = |path: &Path| fs::metadata(path).and_then(|file| file.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    /// Load the files into `config` if they changed since `loaded`, returns if they were
+    ///
+    /// A certificate that cannot be loaded leaves the current one in use
+    fn reload_if_changed(&self, config: &RustlsConfig, loaded: &mut Modified) -> bool {
+        let modified = self.modified();
+        if modified.is_none() || modified == *loaded {
+            return false;
+        }
+        // Renewals may replace the certificate and the key one after the other,
+        // the second write changes the times again so the pair is tried once more
+        *loaded = modified;
+
+        match self.server_config() {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                info!("Reloaded the tls certificate from {:?}", self.cert_path);
+                true
+            }
+            Err(err) => {
+                warn!("Could not reload the tls certificate: {:?}", err);
+                false
+            }
+        }
+    }
+
+    /// Reload the certificate into `config` when its files change, until shutdown
+    pub async fn reload_on_change(
+        self,
+        config: RustlsConfig,
+        mut shutdown_rx: watch::Receiver<bool>,
+    ) {
+        let mut loaded = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.reload_if_changed(&config, &mut loaded);
+                }
+                _ = shutdown_rx.changed() => break,
+            }
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| anyhow!("Could not open {:?}: {}", path, err))
+}
+
+/// Certificate chain, starting with the server's own certificate
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(path)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!("No certificate in {:?}", path);
+    }
+
+    Ok(certs)
+}
+
+/// First PKCS#8, RSA or EC private key of the file
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => bail!("No private key in {:?}", path),
+        }
+    }
+}
+
+/// Whether a signature by the key verifies with the certificate's public key
+fn key_matches(cert: &Certificate, key: &PrivateKey) -> Result<bool> {
+    let signer = rustls::sign::any_supported_type(key)
+        .map_err(|_| anyhow!("Unsupported tls private key"))?
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .ok_or_else(|| anyhow!("Unsupported tls private key"))?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let signature = signer.sign(KEY_CHECK_MESSAGE)?;
+
+    let cert = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|err| anyhow!("Invalid tls certificate: {:?}", err))?;
+
+    // A key of another type than the certificate's fails verification too
+    Ok(cert
+        .verify_signature(algorithm, KEY_CHECK_MESSAGE, &signature)
+        .is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// Pem certificate and private key of a new self signed certificate
+    fn self_signed() -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        (
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    fn write_files(files: &TlsFiles, cert: &str, key: &str) {
+        fs::write(&files.cert_path, cert).unwrap();
+        fs::write(&files.key_path, key).unwrap();
+    }
+
+    #[test]
+    fn test_tls_files() {
+        let dir = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(TlsFiles::new(None, None).unwrap(), None);
+        assert!(TlsFiles::new(Some(dir.join("cert.pem")), None).is_err());
+        let files = TlsFiles::new(Some(dir.join("cert.pem")), Some(dir.join("key.pem")))
+            .unwrap()
+            .unwrap();
+
+        let err = files.server_config().unwrap_err();
+        assert!(err.to_string().contains("Could not open"));
+
+        let (cert, key) = self_signed();
+        let (_, other_key) = self_signed();
+        write_files(&files, &cert, &key);
+        let config = files.server_config().unwrap();
+        assert_eq!(config.alpn_protocols[1], b"http/1.1");
+
+        write_files(&files, &cert, &other_key);
+        let err = files.server_config().unwrap_err();
+        assert!(err.to_string().contains("does not match the certificate"));
+
+        write_files(&files, &cert, "");
+        let err = files.server_config().unwrap_err();
+        assert!(err.to_string().contains("No private key"));
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let dir = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let files = TlsFiles::new(Some(dir.join("cert.pem")), Some(dir.join("key.pem")))
+            .unwrap()
+            .unwrap();
+
+        let (cert, key) = self_signed();
+        write_files(&files, &cert, &key);
+        let config = files.rustls_config().unwrap();
+        let mut loaded = files.modified();
+        let initial = config.get_inner();
+
+        // Nothing changed on disk
+        assert!(!files.reload_if_changed(&config, &mut loaded));
+        assert!(Arc::ptr_eq(&initial, &config.get_inner()));
+
+        // Half way through a renewal the old certificate stays in use
+        let (renewed_cert, renewed_key) = self_signed();
+        write_files(&files, &renewed_cert, &key);
+        let mut stale = Some((UNIX_EPOCH, UNIX_EPOCH));
+        assert!(!files.reload_if_changed(&config, &mut stale));
+        assert!(Arc::ptr_eq(&initial, &config.get_inner()));
+
+        write_files(&files, &renewed_cert, &renewed_key);
+        let mut stale = Some((UNIX_EPOCH, UNIX_EPOCH));
+        assert!(files.reload_if_changed(&config, &mut stale));
+        assert!(!Arc::ptr_eq(&initial, &config.get_inner()));
+        assert_eq!(stale, files.modified());
+    }
+}
@@ -1,14 +1,26 @@
 use std::collections::HashSet;
 use std::time::SystemTime;
 
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit};
+use base64::engine::general_purpose;
+use base64::Engine;
 use cashu_sdk::{Amount, Bolt11Invoice};
+use nostr_sdk::secp256k1::rand;
 use nostr_sdk::Url;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{info_span, Span};
+use unicode_normalization::UnicodeNormalization;
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserSignUp {
     /// Cashu mint
     pub mint: Url,
+    /// Mints tried in order when `mint` cannot issue an invoice
+    #[serde(default)]
+    pub fallback_mints: Vec<Url>,
     /// LN Address username
     pub username: String,
     /// Nostr Relays
@@ -19,14 +31,120 @@ pub struct UserSignUp {
 pub struct User {
     /// Username
     pub username: String,
+    /// Domain of the ln address
+    #[serde(default)]
+    pub domain: String,
     /// Cashu mint
     pub mint: Url,
+    /// Mints tried in order when `mint` cannot issue an invoice
+    #[serde(default)]
+    pub fallback_mints: Vec<Url>,
+    /// Mints picked by invoice amount ahead of `mint`, the first matching rule is used
+    #[serde(default)]
+    pub mint_rules: Vec<MintRule>,
     /// Nostr Pubkey
     pub pubkey: String,
     /// Nostr Relays
     pub relays: HashSet<String>,
     /// Proxy Invoice to mint
     pub proxy: bool,
+    /// Min sendable overriding the service default
+    ///
+    /// Users created before per user limits have `None`
+    #[serde(default)]
+    pub min_sendable: Option<Amount>,
+    /// Max sendable overriding the service default
+    #[serde(default)]
+    pub max_sendable: Option<Amount>,
+    /// Success action message overriding the service template
+    #[serde(default)]
+    pub success_message: Option<String>,
+    /// Description in the LNURL metadata overriding the service description
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Base64 png shown by wallets paying the user
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// Url paid invoices are posted to
+    #[serde(default)]
+    pub webhook_url: Option<Url>,
+    /// Key of the HMAC signing webhook posts, set with `webhook_url`
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+    /// Mint tokens locked to `pubkey` with a NUT-11 P2PK spending condition
+    ///
+    /// Unset users are sent bearer tokens, which every wallet can redeem
+    #[serde(default)]
+    pub lock_to_pubkey: bool,
+    /// Unix time the user signed up, unknown for users created before it was recorded
+    #[serde(default)]
+    pub created_at: Option<u64>,
+}
+
+impl User {
+    /// Mints invoices are requested from, in the order they are tried
+    pub fn mints(&self) -> Vec<Url> {
+        let mut mints = vec![self.mint.clone()];
+        for mint in &self.fallback_mints {
+            if !mints.contains(mint) {
+                mints.push(mint.clone());
+            }
+        }
+        mints
+    }
+
+    /// Mints to request an invoice for `amount` from, in the order they are tried
+    ///
+    /// The mint of the first of the user's rules, then of the service's rules, that the amount
+    /// is within comes first, followed by the user's own mints
+    pub fn mints_for(&self, amount: Amount, service_rules: &[MintRule]) -> Vec<Url> {
+        let mut mints = vec![];
+        if let Some(mint) =
+            rule_mint(&self.mint_rules, amount).or_else(|| rule_mint(service_rules, amount))
+        {
+            mints.push(mint.clone());
+        }
+        for mint in self.mints() {
+            if !mints.contains(&mint) {
+                mints.push(mint);
+            }
+        }
+        mints
+    }
+
+    /// Min and max sendable of the user, falling back to the defaults
+    pub fn sendable(&self, default_min: Amount, default_max: Amount) -> (Amount, Amount) {
+        (
+            self.min_sendable.unwrap_or(default_min),
+            self.max_sendable.unwrap_or(default_max),
+        )
+    }
+
+    /// The user without its webhook secret, to be listed over the api
+    ///
+    /// `User` is serialized as is to the database, so the secret is cleared here
+    pub fn redacted(self) -> Self {
+        Self {
+            webhook_secret: None,
+            ..self
+        }
+    }
+}
+
+/// Mint used for invoices of up to an amount
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MintRule {
+    /// Largest amount in sats the rule applies to, inclusive
+    pub max_amount: Amount,
+    pub mint: Url,
+}
+
+/// Mint of the first rule `amount` is within, `None` when no rule matches
+pub fn rule_mint(rules: &[MintRule], amount: Amount) -> Option<&Url> {
+    rules
+        .iter()
+        .find(|rule| amount <= rule.max_amount)
+        .map(|rule| &rule.mint)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,20 +153,63 @@ pub struct PendingUser {
     pub pr: Bolt11Invoice,
     pub last_checked: u64,
     pub expire: u64,
+    /// Price of the username if it was reserved, so it is reserved again if the signup expires
+    #[serde(default)]
+    pub reserved: Option<Amount>,
+    /// Invite code of the signup, only used up once the invoice is paid
+    #[serde(default)]
+    pub invite: Option<String>,
+}
+
+impl PendingUser {
+    /// What the username goes back to once the signup expires, `None` if it is free
+    pub fn expired_kind(&self) -> Option<UserKind> {
+        self.reserved.map(UserKind::Reserved)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PendingInvoice {
     pub mint: Url,
     pub username: String,
+    #[serde(default)]
+    pub domain: String,
+    /// Local part the invoice was requested for when it is a plus address or the catch-all user's
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// NIP-57 zap request json, committed to by the invoice description hash
     pub description: Option<String>,
+    /// LUD-12 comment from the payer
+    pub comment: Option<String>,
+    /// LUD-18 identity of the payer
+    #[serde(default)]
+    pub payer_data: Option<PayerData>,
+    /// Id the minted token can be claimed with
+    #[serde(default)]
+    pub claim_id: Option<String>,
+    /// Id in the logs of every stage of the invoice, unset for invoices created before it
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Key a repeated request for the same invoice is matched by
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Success action returned with the invoice, returned again for repeated requests
+    #[serde(default)]
+    pub success_action: Option<SuccessAction>,
     pub time: u64,
+    /// Zero for amountless invoices, which are minted for the amount received
     #[serde(with = "as_msat")]
     pub amount: Amount,
     pub hash: String,
     pub bolt11: Bolt11Invoice,
     pub last_checked: Option<u64>,
     pub proxied: bool,
+    /// No longer checked, the payment failed or never happened
+    #[serde(default)]
+    pub failed: bool,
+    /// Hash of the invoice the payer paid, for mint invoices of proxied payments
+    #[serde(default)]
+    pub payer_hash: Option<String>,
 }
 
 impl PendingInvoice {
@@ -57,21 +218,233 @@ impl PendingInvoice {
         serde_json::json!(self).to_string()
     }
 
+    /// Span tying log lines about the invoice to its request id
+    pub fn span(&self) -> Span {
+        info_span!(
+            "invoice",
+            request_id = self.request_id.as_deref().unwrap_or("unknown")
+        )
+    }
+
+    /// Unix time the invoice expires, from the bolt11 whether it was created by the mint or proxied
+    pub fn expires_at(&self) -> u64 {
+        (self.bolt11.duration_since_epoch() + self.bolt11.expiry_time()).as_secs()
+    }
+
     pub fn update_checked_time(&self) -> Self {
         Self {
-            mint: self.mint.clone(),
-            username: self.username.clone(),
-            description: self.description.clone(),
-            time: self.time,
-            amount: self.amount,
-            hash: self.hash.clone(),
-            bolt11: self.bolt11.clone(),
             last_checked: Some(unix_time()),
-            proxied: self.proxied,
+            ..self.clone()
         }
     }
 }
 
+/// LUD-18 identity sent by a payer with an invoice request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayerData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Internet identifier such as a lightning address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    /// Hex secp256k1 pubkey
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<PayerAuth>,
+}
+
+/// LUD-18 proof the payer controls a linking key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayerAuth {
+    /// Hex linking key
+    pub key: String,
+    /// Hex challenge advertised by the service
+    pub k1: String,
+    /// Hex DER ecdsa signature of `k1` by `key`
+    pub sig: String,
+}
+
+/// Invoice handed to a payer, kept after payment for LUD-21 verify
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayerInvoice {
+    pub username: String,
+    pub domain: String,
+    pub bolt11: Bolt11Invoice,
+    pub settled: bool,
+    /// Hex preimage, unknown when the invoice was issued by the mint
+    pub preimage: Option<String>,
+}
+
+impl PayerInvoice {
+    /// Get transaction as json string
+    pub fn as_json(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+}
+
+/// Minted token waiting to be claimed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claim {
+    /// Serialized cashu token, cleared once claimed
+    pub token: Option<String>,
+    pub expire: u64,
+}
+
+impl Claim {
+    /// Get transaction as json string
+    pub fn as_json(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+}
+
+/// Result of claiming a token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClaimStatus {
+    Claimed(String),
+    /// Already claimed or expired
+    Gone,
+    NotFound,
+}
+
+/// Code letting a signup through while signups are disabled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub code: String,
+    /// Signups the code can be used for
+    pub max_uses: u64,
+    pub uses: u64,
+    /// Unix time the code stops working, never if unset
+    pub expire: Option<u64>,
+    pub created_at: u64,
+}
+
+impl InviteCode {
+    /// Get invite code as json string
+    pub fn as_json(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+
+    /// Whether the code can still be used at `now`
+    pub fn is_usable(&self, now: u64) -> bool {
+        self.uses < self.max_uses && self.expire.map_or(true, |expire| now < expire)
+    }
+}
+
+/// Invoice paid to a user, kept for accounting after its pending invoice is removed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentRecord {
+    /// Hash of the invoice the payer paid
+    pub hash: String,
+    pub domain: String,
+    pub username: String,
+    pub mint: Url,
+    /// Msat received from the payer
+    pub amount_msat: u64,
+    /// Msat kept for routing the payment to the mint
+    pub fee_msat: u64,
+    /// Msat of the token minted, unset until it is minted
+    pub minted_msat: Option<u64>,
+    pub proxied: bool,
+    /// LUD-12 comment from the payer
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// Whether the minted token was DMed to the user
+    #[serde(default)]
+    pub delivered: bool,
+    /// Unix time the payer's invoice was paid
+    pub paid_at: u64,
+}
+
+impl PaymentRecord {
+    /// Get payment record as json string
+    pub fn as_json(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+}
+
+/// Outcome of posting a paid invoice to a user's webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    /// Payment hash of the paid invoice
+    pub hash: String,
+    pub domain: String,
+    pub username: String,
+    pub url: Url,
+    pub attempts: u32,
+    pub delivered: bool,
+    /// Http status of the last attempt, unset if there was no response
+    pub status: Option<u16>,
+    /// Error of the last attempt
+    pub error: Option<String>,
+    /// Unix time of the last attempt
+    pub last_attempt: u64,
+}
+
+impl WebhookDelivery {
+    /// Get webhook delivery as json string
+    pub fn as_json(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+}
+
+/// Minted token whose DM failed, queued to be sent again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenDelivery {
+    /// Payment hash of the minted invoice
+    pub hash: String,
+    /// Hash of the invoice the payer paid when it was proxied, its payment record's key
+    #[serde(default)]
+    pub payer_hash: Option<String>,
+    pub domain: String,
+    pub username: String,
+    /// Claim the token can also be taken with, if the success action gave the payer a claim url
+    #[serde(default)]
+    pub claim_id: Option<String>,
+    /// Hex pubkey the token is DMed to
+    pub pubkey: String,
+    pub token: String,
+    pub comment: Option<String>,
+    pub payer_data: Option<PayerData>,
+    /// Address paid when it is not the user's own
+    pub alias: Option<String>,
+    pub relays: HashSet<String>,
+    pub attempts: u32,
+    /// Unix time of the next attempt
+    pub next_attempt: u64,
+    /// Error of the last attempt
+    pub error: Option<String>,
+    pub created_at: u64,
+}
+
+impl TokenDelivery {
+    /// Get token delivery as json string
+    pub fn as_json(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+}
+
+/// Result of using an invite code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InviteStatus {
+    Used,
+    /// Used up or expired
+    Gone,
+    NotFound,
+}
+
+/// Username as it is stored and looked up, NFC normalized and lowercase
+///
+/// Wallets do not agree on the case of an address, so `Alice` and `alice` are one user
+pub fn normalize_username(username: &str) -> String {
+    username.nfc().collect::<String>().to_lowercase()
+}
+
+/// Key of a user in the db, the users ln address
+pub fn user_key(domain: &str, username: &str) -> String {
+    format!("{}@{}", username, domain)
+}
+
 pub fn unix_time() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -94,6 +467,68 @@ impl UserKind {
     }
 }
 
+/// Max length of a LUD-09 message or url description
+pub const SUCCESS_MESSAGE_MAX_LEN: usize = 144;
+
+/// LUD-09 action shown to the payer once the invoice is paid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: Url,
+    },
+    /// LUD-10 secret only the payer can decrypt, keyed by the payment preimage
+    Aes {
+        description: String,
+        /// Base64 AES-256-CBC ciphertext
+        ciphertext: String,
+        /// Base64 initialization vector
+        iv: String,
+    },
+}
+
+impl SuccessAction {
+    /// Message action from a template, truncated to the LUD-09 max length
+    pub fn message(template: &str, username: &str) -> Self {
+        Self::Message {
+            message: render_success_message(template, username),
+        }
+    }
+
+    /// Url action described by a template
+    pub fn url(template: &str, username: &str, url: Url) -> Self {
+        Self::Url {
+            description: render_success_message(template, username),
+            url,
+        }
+    }
+
+    /// Aes action described by a template, encrypting `plaintext` with the preimage
+    pub fn aes(template: &str, username: &str, plaintext: &str, preimage: &[u8; 32]) -> Self {
+        let iv: [u8; 16] = rand::random();
+        let ciphertext = Aes256CbcEnc::new(preimage.into(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+
+        Self::Aes {
+            description: render_success_message(template, username),
+            ciphertext: general_purpose::STANDARD.encode(ciphertext),
+            iv: general_purpose::STANDARD.encode(iv),
+        }
+    }
+}
+
+fn render_success_message(template: &str, username: &str) -> String {
+    template
+        .replace("{username}", username)
+        .chars()
+        .take(SUCCESS_MESSAGE_MAX_LEN)
+        .collect()
+}
+
 pub mod as_msat {
     use super::*;
 
@@ -113,3 +548,117 @@ pub mod as_msat {
         Ok(Amount::from_msat(msat))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mint(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    fn rule(max_sat: u64, url: &str) -> MintRule {
+        MintRule {
+            max_amount: Amount::from_sat(max_sat),
+            mint: mint(url),
+        }
+    }
+
+    #[test]
+    fn test_rule_mint() {
+        let rules = [
+            rule(1000, "https://small.example.com"),
+            rule(100_000, "https://large.example.com"),
+        ];
+
+        assert_eq!(
+            rule_mint(&rules, Amount::from_sat(1)),
+            Some(&mint("https://small.example.com"))
+        );
+        // The max amount is inclusive
+        assert_eq!(
+            rule_mint(&rules, Amount::from_sat(1000)),
+            Some(&mint("https://small.example.com"))
+        );
+        assert_eq!(
+            rule_mint(&rules, Amount::from_msat(1_000_001)),
+            Some(&mint("https://large.example.com"))
+        );
+        assert_eq!(
+            rule_mint(&rules, Amount::from_sat(100_000)),
+            Some(&mint("https://large.example.com"))
+        );
+        assert_eq!(rule_mint(&rules, Amount::from_sat(100_001)), None);
+        assert_eq!(rule_mint(&[], Amount::from_sat(1)), None);
+
+        // Rules are evaluated in order
+        let rules = [
+            rule(100_000, "https://large.example.com"),
+            rule(1000, "https://small.example.com"),
+        ];
+        assert_eq!(
+            rule_mint(&rules, Amount::from_sat(10)),
+            Some(&mint("https://large.example.com"))
+        );
+    }
+
+    #[test]
+    fn test_mints_for() {
+        let user = User {
+            username: "alice".to_string(),
+            domain: "example.com".to_string(),
+            mint: mint("https://mint.example.com"),
+            fallback_mints: vec![mint("https://fallback.example.com")],
+            mint_rules: vec![rule(1000, "https://small.example.com")],
+            pubkey: "pubkey".to_string(),
+            relays: HashSet::new(),
+            proxy: false,
+            min_sendable: None,
+            max_sendable: None,
+            success_message: None,
+            description: None,
+            avatar: None,
+            webhook_url: None,
+            webhook_secret: None,
+            lock_to_pubkey: false,
+            created_at: None,
+        };
+        let service_rules = [
+            rule(1000, "https://service-small.example.com"),
+            rule(100_000, "https://fallback.example.com"),
+        ];
+
+        // The user's rules come before the service's
+        assert_eq!(
+            user.mints_for(Amount::from_sat(1000), &service_rules),
+            vec![
+                mint("https://small.example.com"),
+                mint("https://mint.example.com"),
+                mint("https://fallback.example.com"),
+            ]
+        );
+        // A mint picked by a rule is not tried twice
+        assert_eq!(
+            user.mints_for(Amount::from_sat(1001), &service_rules),
+            vec![
+                mint("https://fallback.example.com"),
+                mint("https://mint.example.com"),
+            ]
+        );
+        // Amounts no rule matches fall through to the user's mints
+        assert_eq!(
+            user.mints_for(Amount::from_sat(100_001), &service_rules),
+            user.mints()
+        );
+        assert_eq!(user.mints_for(Amount::from_sat(1001), &[]), user.mints());
+
+        let user = User {
+            webhook_url: Some(mint("https://shop.example.com/paid")),
+            webhook_secret: Some("0123456789abcdef".to_string()),
+            ..user
+        };
+        let redacted = serde_json::to_value(user.redacted()).unwrap();
+        assert_eq!(redacted["webhook_url"], "https://shop.example.com/paid");
+        assert_eq!(redacted["webhook_secret"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,168 @@
+//! Serving the api on a unix domain socket instead of a tcp port
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{anyhow, bail, Result};
+use hyper::server::accept::Accept;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+/// Prefix of a `listen` address that is the path of a unix socket
+const UNIX_PREFIX: &str = "unix:";
+
+/// Path of the socket in a `listen` address such as `unix:/run/cashu-lnurl.sock`
+pub fn parse_listen(listen: &str) -> Result<PathBuf> {
+    match listen.strip_prefix(UNIX_PREFIX) {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => bail!(
+            "Invalid listen {:?}, expected a unix socket such as unix:/run/cashu-lnurl.sock",
+            listen
+        ),
+    }
+}
+
+/// Permissions of the socket file from an octal mode such as `660`
+pub fn parse_socket_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid socket_mode {:?}, expected an octal mode such as 660",
+                mode
+            )
+        })
+}
+
+/// Remove a socket file left by a previous run that did not shut down cleanly
+///
+/// Fails if the path is not a socket or another process is still listening on it
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{:?} exists and is not a socket", path);
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        bail!("{:?} is in use by another process", path);
+    }
+
+    info!("Removing stale socket {:?}", path);
+    fs::remove_file(path)?;
+
+    Ok(())
+}
+
+/// Remove the socket file once the server has stopped
+pub fn remove_socket(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        warn!("Could not remove socket {:?}: {:?}", path, err);
+    }
+}
+
+/// Connections accepted on a unix socket, served by hyper
+#[derive(Debug)]
+pub struct UnixAccept {
+    listener: UnixListener,
+}
+
+impl UnixAccept {
+    /// Listen on `path`, replacing a stale socket, with the file's permissions set to `mode`
+    pub fn bind(path: &Path, mode: Option<u32>) -> Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(Self { listener })
+    }
+}
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let (stream, _) = ready!(self.listener.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(
+            parse_listen("unix:/run/cashu-lnurl.sock").unwrap(),
+            PathBuf::from("/run/cashu-lnurl.sock")
+        );
+        assert!(parse_listen("unix:").is_err());
+        assert!(parse_listen("127.0.0.1:8080").is_err());
+
+        assert_eq!(parse_socket_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0o600").unwrap(), 0o600);
+        assert!(parse_socket_mode("888").is_err());
+        assert!(parse_socket_mode("7777").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unix_accept() {
+        let dir = std::env::temp_dir().join("cashu-lnurl-test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.sock", Uuid::new_v4()));
+
+        // A socket left behind with nothing listening is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let accept = UnixAccept::bind(&path, Some(0o600)).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // Another instance cannot take the socket while it is listened on
+        assert!(UnixAccept::bind(&path, None).is_err());
+
+        let router = Router::new().route("/health", get(|| async { "ok" }));
+        let server = tokio::spawn(axum::Server::builder(accept).serve(router.into_make_service()));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+
+        server.abort();
+        remove_socket(&path);
+        assert!(!path.exists());
+
+        // Other files are never removed
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(UnixAccept::bind(&path, None).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+//! Rules usernames must follow to be signed up
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use crate::types::normalize_username;
+
+/// Pattern usernames must match unless one is configured
+pub const DEFAULT_USERNAME_PATTERN: &str = "^[a-z0-9_.-]+$";
+
+/// Default pattern when unicode usernames are allowed, letters of any script
+pub const DEFAULT_UNICODE_USERNAME_PATTERN: &str = r"^[\p{Ll}\p{Lo}\p{Nd}_.-]+$";
+
+pub const DEFAULT_USERNAME_MIN_LENGTH: usize = 1;
+
+pub const DEFAULT_USERNAME_MAX_LENGTH: usize = 32;
+
+/// Usernames that can never be signed up, in addition to the configured ones
+pub const DEFAULT_RESERVED_USERNAMES: [&str; 12] = [
+    "_",
+    "abuse",
+    "admin",
+    "administrator",
+    "hostmaster",
+    "noreply",
+    "postmaster",
+    "root",
+    "security",
+    "support",
+    "webmaster",
+    "www",
+];
+
+/// Rule a username breaks
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum UsernameError {
+    #[error("Username must be at least {0} characters")]
+    TooShort(usize),
+    #[error("Username cannot be longer than {0} characters")]
+    TooLong(usize),
+    #[error("Username can only contain ascii characters")]
+    NotAscii,
+    #[error("Username must match {0}")]
+    Pattern(String),
+    #[error("Username cannot start or end with .")]
+    Dot,
+}
+
+/// Length, characters and pattern of usernames that can be signed up
+///
+/// Usernames are checked once normalized, so rules only need to accept lowercase
+#[derive(Debug, Clone)]
+pub struct UsernameRules {
+    pattern: Regex,
+    min_length: usize,
+    max_length: usize,
+    allow_unicode: bool,
+    /// Normalized usernames that cannot be signed up
+    reserved: HashSet<String>,
+}
+
+impl Default for UsernameRules {
+    fn default() -> Self {
+        Self {
+            pattern: Regex::new(DEFAULT_USERNAME_PATTERN).expect("Valid default pattern"),
+            min_length: DEFAULT_USERNAME_MIN_LENGTH,
+            max_length: DEFAULT_USERNAME_MAX_LENGTH,
+            allow_unicode: false,
+            reserved: reserved_usernames(None),
+        }
+    }
+}
+
+/// The built in reserved usernames along with `reserved`, normalized
+pub fn reserved_usernames(reserved: Option<HashSet<String>>) -> HashSet<String> {
+    DEFAULT_RESERVED_USERNAMES
+        .iter()
+        .map(|username| username.to_string())
+        .chain(reserved.unwrap_or_default())
+        .map(|username| normalize_username(username.trim()))
+        .filter(|username| !username.is_empty())
+        .collect()
+}
+
+/// Usernames listed in a file, one per line
+///
+/// Blank lines and lines starting with `#` are ignored
+pub fn read_reserved_usernames(path: &Path) -> Result<HashSet<String>> {
+    let reserved = fs::read_to_string(path).map_err(|err| {
+        anyhow!(
+            "Could not read reserved usernames {}: {}",
+            path.display(),
+            err
+        )
+    })?;
+
+    Ok(reserved
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+impl UsernameRules {
+    /// Rules with the configured values, failing if `pattern` is not a valid regex
+    pub fn new(
+        pattern: Option<&str>,
+        min_length: Option<usize>,
+        max_length: Option<usize>,
+        allow_unicode: bool,
+    ) -> Result<Self> {
+        let pattern = pattern.unwrap_or(match allow_unicode {
+            true => DEFAULT_UNICODE_USERNAME_PATTERN,
+            false => DEFAULT_USERNAME_PATTERN,
+        });
+
+        Ok(Self {
+            pattern: Regex::new(pattern)?,
+            min_length: min_length.unwrap_or(DEFAULT_USERNAME_MIN_LENGTH),
+            max_length: max_length.unwrap_or(DEFAULT_USERNAME_MAX_LENGTH),
+            allow_unicode,
+            reserved: reserved_usernames(None),
+        })
+    }
+
+    /// Reserve the built in usernames along with `reserved`
+    pub fn with_reserved(mut self, reserved: HashSet<String>) -> Self {
+        self.reserved = reserved_usernames(Some(reserved));
+        self
+    }
+
+    /// Whether a username is reserved, compared normalized
+    pub fn is_reserved(&self, username: &str) -> bool {
+        self.reserved.contains(&normalize_username(username))
+    }
+
+    /// Check a username follows the rules, returning the first rule it breaks
+    ///
+    /// Lengths are counted in characters
+    pub fn validate(&self, username: &str) -> Result<(), UsernameError> {
+        let length = username.chars().count();
+        if length < self.min_length {
+            return Err(UsernameError::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            return Err(UsernameError::TooLong(self.max_length));
+        }
+
+        if !self.allow_unicode && !username.is_ascii() {
+            return Err(UsernameError::NotAscii);
+        }
+
+        if !self.pattern.is_match(username) {
+            return Err(UsernameError::Pattern(self.pattern.to_string()));
+        }
+
+        // Whatever the pattern, `.`, `..` and `.well-known` would break path routing
+        if username.starts_with('.') || username.ends_with('.') {
+            return Err(UsernameError::Dot);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_username() {
+        let rules = UsernameRules::default();
+        let unicode = UsernameRules::new(None, None, None, true).unwrap();
+        let custom = UsernameRules::new(Some("^[a-z]+$"), Some(3), Some(5), false).unwrap();
+
+        let pattern = UsernameError::Pattern(DEFAULT_USERNAME_PATTERN.to_string());
+        let longest = "a".repeat(32);
+        let too_long = "a".repeat(33);
+        let huge = "a".repeat(10_000);
+        let cases = [
+            (&rules, "alice", Ok(())),
+            (&rules, "a", Ok(())),
+            (&rules, "alice_b.c-d9", Ok(())),
+            (&rules, longest.as_str(), Ok(())),
+            (&rules, "", Err(UsernameError::TooShort(1))),
+            (&rules, too_long.as_str(), Err(UsernameError::TooLong(32))),
+            (&rules, huge.as_str(), Err(UsernameError::TooLong(32))),
+            (&rules, "alice/bob", Err(pattern.clone())),
+            (&rules, "alice bob", Err(pattern.clone())),
+            (&rules, "Alice", Err(pattern)),
+            (&rules, "a.b", Ok(())),
+            (&rules, ".", Err(UsernameError::Dot)),
+            (&rules, "..", Err(UsernameError::Dot)),
+            (&rules, ".well-known", Err(UsernameError::Dot)),
+            (&rules, "alice.", Err(UsernameError::Dot)),
+            (&unicode, ".well-known", Err(UsernameError::Dot)),
+            (&rules, "alice\u{26a1}", Err(UsernameError::NotAscii)),
+            (&rules, "\u{e5}sa", Err(UsernameError::NotAscii)),
+            (&unicode, "\u{e5}sa", Ok(())),
+            (&unicode, "\u{5c71}\u{7530}", Ok(())),
+            (
+                &unicode,
+                "alice\u{26a1}",
+                Err(UsernameError::Pattern(
+                    DEFAULT_UNICODE_USERNAME_PATTERN.to_string(),
+                )),
+            ),
+            (&custom, "bob", Ok(())),
+            (&custom, "al", Err(UsernameError::TooShort(3))),
+            (&custom, "alice1", Err(UsernameError::TooLong(5))),
+            (
+                &custom,
+                "bob1",
+                Err(UsernameError::Pattern("^[a-z]+$".to_string())),
+            ),
+        ];
+
+        for (rules, username, expected) in cases {
+            assert_eq!(rules.validate(username), expected, "{}", username);
+        }
+    }
+
+    #[test]
+    fn test_reserved_usernames() {
+        let rules = UsernameRules::default();
+        for username in ["admin", "Admin", "POSTMASTER", "_"] {
+            assert!(rules.is_reserved(username), "{}", username);
+        }
+        assert!(!rules.is_reserved("alice"));
+
+        let path = std::env::temp_dir()
+            .join("cashu-lnurl-test")
+            .join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "# Brands\nMyBrand\n\n  hodl  \n").unwrap();
+
+        let reserved = read_reserved_usernames(&path).unwrap();
+        assert_eq!(
+            reserved,
+            HashSet::from(["MyBrand".to_string(), "hodl".to_string()])
+        );
+
+        // Configured names are added to the built in ones
+        let rules = rules.with_reserved(reserved);
+        for username in ["mybrand", "MYBRAND", "hodl", "admin"] {
+            assert!(rules.is_reserved(username), "{}", username);
+        }
+        assert!(!rules.is_reserved("# brands"));
+
+        assert!(read_reserved_usernames(&path.with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(UsernameRules::new(Some("[a-z"), None, None, false).is_err());
+    }
+}
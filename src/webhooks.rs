@@ -0,0 +1,239 @@
+//! Paid invoices posted to the webhooks users register
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::database::Db;
+use crate::notifications::PaymentNotification;
+use crate::outbound;
+use crate::types::{unix_time, UserKind, WebhookDelivery};
+
+/// Retries of a failed post when `webhook_max_retries` is not set
+pub const DEFAULT_WEBHOOK_MAX_RETRIES: u32 = 5;
+
+/// Header with the hex HMAC-SHA256 of the body keyed by the user's secret
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Time a webhook has to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before the first retry, doubled for each one after
+const WEBHOOK_BACKOFF_MIN: Duration = Duration::from_secs(10);
+/// Longest delay between retries
+const WEBHOOK_BACKOFF_MAX: Duration = Duration::from_secs(3600);
+
+/// Body posted to a webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WebhookPayload {
+    pub username: String,
+    pub payment_hash: String,
+    /// Msat paid to the user before fees
+    pub amount_msat: u64,
+    /// LUD-12 comment from the payer
+    pub comment: Option<String>,
+    /// Unix time the invoice was paid
+    pub timestamp: u64,
+}
+
+impl From<&PaymentNotification> for WebhookPayload {
+    fn from(notification: &PaymentNotification) -> Self {
+        Self {
+            username: notification.username.clone(),
+            payment_hash: notification.payment_hash.clone(),
+            amount_msat: notification.amount_msat,
+            comment: notification.comment.clone(),
+            timestamp: notification.time,
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of a body
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Delay before retrying a post that failed `attempts` times
+fn webhook_backoff(attempts: u32) -> Duration {
+    WEBHOOK_BACKOFF_MIN
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(WEBHOOK_BACKOFF_MAX)
+}
+
+/// Posts paid invoices to the webhooks of their users
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    db: Db,
+    client: reqwest::Client,
+    max_retries: u32,
+}
+
+impl Webhooks {
+    /// Webhooks are posted through `socks_proxy` when it is set
+    pub fn new(db: Db, max_retries: u32, socks_proxy: Option<SocketAddr>) -> Result<Self> {
+        let client = outbound::with_socks_proxy(outbound::client_builder(), socks_proxy)?
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            db,
+            client,
+            max_retries,
+        })
+    }
+
+    /// Post each paid invoice until the notifier is dropped
+    pub async fn run(self, mut notifications: broadcast::Receiver<PaymentNotification>) {
+        loop {
+            match notifications.recv().await {
+                Ok(notification) => {
+                    let webhooks = self.clone();
+                    // Retries of one webhook do not hold up the others
+                    tokio::spawn(async move {
+                        if let Err(err) = webhooks.deliver(&notification).await {
+                            warn!(
+                                "Could not post invoice {} to webhook: {:?}",
+                                notification.payment_hash, err
+                            );
+                        }
+                    });
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Webhooks missed {} paid invoices", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Post a paid invoice to its user's webhook, retrying with backoff
+    ///
+    /// Every attempt is recorded so the outcome can be looked up by payment hash
+    async fn deliver(&self, notification: &PaymentNotification) -> Result<()> {
+        let user = match self
+            .db
+            .get_user(&notification.domain, &notification.username)
+            .await?
+        {
+            Some(UserKind::User(user)) => user,
+            _ => return Ok(()),
+        };
+        let (url, secret) = match (user.webhook_url, user.webhook_secret) {
+            (Some(url), Some(secret)) => (url, secret),
+            _ => return Ok(()),
+        };
+
+        let body = serde_json::to_vec(&WebhookPayload::from(notification))?;
+        let signature = sign(&secret, &body);
+
+        let mut delivery = WebhookDelivery {
+            hash: notification.payment_hash.clone(),
+            domain: notification.domain.clone(),
+            username: notification.username.clone(),
+            url: url.clone(),
+            attempts: 0,
+            delivered: false,
+            status: None,
+            error: None,
+            last_attempt: 0,
+        };
+
+        loop {
+            // The host may have been pointed at a private address since the url was saved
+            let response = match outbound::check_public_url(&url).await {
+                Ok(()) => self
+                    .client
+                    .post(url.as_str())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(SIGNATURE_HEADER, &signature)
+                    .body(body.clone())
+                    .send()
+                    .await
+                    .map_err(anyhow::Error::from),
+                Err(err) => Err(err),
+            };
+
+            delivery.attempts += 1;
+            delivery.last_attempt = unix_time();
+            match response {
+                Ok(response) => {
+                    delivery.status = Some(response.status().as_u16());
+                    delivery.delivered = response.status().is_success();
+                    delivery.error = None;
+                }
+                Err(err) => {
+                    delivery.status = None;
+                    delivery.error = Some(err.to_string());
+                }
+            }
+            self.db.add_webhook_delivery(&delivery).await?;
+
+            if delivery.delivered {
+                debug!("Posted invoice {} to webhook", delivery.hash);
+                return Ok(());
+            }
+            if delivery.attempts > self.max_retries {
+                warn!(
+                    "Gave up posting invoice {} to webhook after {} attempts",
+                    delivery.hash, delivery.attempts
+                );
+                return Ok(());
+            }
+
+            tokio::time::sleep(webhook_backoff(delivery.attempts)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cashu_sdk::Amount;
+
+    use super::*;
+
+    #[test]
+    fn test_webhook_payload() {
+        let notification = PaymentNotification::new(
+            "example.com",
+            "alice",
+            "abcd",
+            Amount::from_sat(21),
+            Some("Order 42".to_string()),
+            1700000000,
+        );
+        let body = serde_json::to_vec(&WebhookPayload::from(&notification)).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "username": "alice",
+                "payment_hash": "abcd",
+                "amount_msat": 21000,
+                "comment": "Order 42",
+                "timestamp": 1700000000,
+            })
+        );
+
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_webhook_backoff() {
+        assert_eq!(webhook_backoff(1), Duration::from_secs(10));
+        assert_eq!(webhook_backoff(2), Duration::from_secs(20));
+        assert_eq!(webhook_backoff(4), Duration::from_secs(80));
+        assert_eq!(webhook_backoff(20), WEBHOOK_BACKOFF_MAX);
+        assert_eq!(webhook_backoff(u32::MAX), WEBHOOK_BACKOFF_MAX);
+    }
+}